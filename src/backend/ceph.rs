@@ -1,13 +1,16 @@
 use std::collections::BTreeMap;
 use std::fmt;
 use std::fs::{
-    create_dir, read_dir, read_to_string, remove_dir_all, symlink_metadata, File, OpenOptions,
+    create_dir, read_dir, read_to_string, remove_dir_all, rename, symlink_metadata, File,
+    OpenOptions,
 };
-use std::io::Write;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::os::unix::{fs::symlink, io::AsRawFd};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::backend::Backend;
 
@@ -18,17 +21,21 @@ use ceph::CephVersion;
 use ceph_safe_disk::diag::{DiagMap, Format, Status};
 use dirs::home_dir;
 use fstab::FsTab;
+use hashicorp_vault::client::VaultClient;
 use helpers::{error::*, host_information::Host};
 use init_daemon::{detect_daemon, Daemon};
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use lvm::*;
 use nix::{
-    convert_ioctl_res, ioc, ioctl_none, request_code_none,
+    convert_ioctl_res,
+    fcntl::{flock, FlockArg},
+    ioc, ioctl_none, request_code_none,
     unistd::chown,
     unistd::{Gid, Uid},
 };
 use pwd::Passwd;
 use serde_derive::*;
+use serde_json::json;
 use tempdir::TempDir;
 
 /// Ceph cluster
@@ -36,6 +43,13 @@ pub struct CephBackend {
     cluster_handle: Rados,
     config: CephConfig,
     version: CephVersion,
+    // The ceph.json this cluster was loaded from
+    config_path: PathBuf,
+    // Where drain progress is saved so disk-manager can report it
+    drain_file: PathBuf,
+    // Cluster flags bynar has set.  Checked at startup so they get cleared
+    // even if disk-manager died in the middle of an operation
+    flags_file: PathBuf,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -93,16 +107,497 @@ fn test_journal_sorting() {
     assert_eq!(journal_devices, vec![b, a]);
 }
 
+#[test]
+fn test_osd_ids_from_tags() {
+    let tags = vec![
+        "ceph.type=block".to_string(),
+        "ceph.osd_id=12".to_string(),
+        "ceph.osd_fsid=3c4a5d8e-9f3a-4b7e-a8c1-2d6f0e5b9a71".to_string(),
+    ];
+    assert_eq!(osd_ids_from_tags(&tags).unwrap(), Some(12));
+    assert_eq!(osd_ids_from_tags(&tags[..1]).unwrap(), None);
+}
+
+#[test]
+fn test_down_or_out_osds() {
+    let dump = r#"{"epoch": 1201, "osds": [
+        {"osd": 1, "up": 1, "in": 1},
+        {"osd": 2, "up": 0, "in": 1},
+        {"osd": 3, "up": 0, "in": 0},
+        {"osd": 5, "up": 1, "in": 0}
+    ]}"#;
+    assert_eq!(down_or_out_osds(dump, &[1, 2, 4, 5]).unwrap(), vec![2, 5]);
+}
+
+#[test]
+fn test_drain_weights() {
+    assert_eq!(
+        drain_weights(1.0, 0.25).unwrap(),
+        vec![0.75, 0.5, 0.25, 0.0]
+    );
+    // A step bigger than the weight goes straight to 0
+    assert_eq!(drain_weights(0.5, 1.0).unwrap(), vec![0.0]);
+    assert!(drain_weights(1.0, 0.0).is_err());
+}
+
+#[test]
+fn test_crush_weight() {
+    let tree = r#"{"nodes": [
+        {"id": -1, "name": "default", "type": "root", "children": [-2]},
+        {"id": -2, "name": "node1", "type": "host", "children": [4, 5]},
+        {"id": 4, "name": "osd.4", "type": "osd", "crush_weight": 3.6379, "status": "up"},
+        {"id": 5, "name": "osd.5", "type": "osd", "crush_weight": 1.8189, "status": "up"}
+    ], "stray": []}"#;
+    assert_eq!(crush_weight(tree, 5).unwrap(), 1.8189);
+    assert!(crush_weight(tree, 6).is_err());
+}
+
+#[test]
+fn test_crush_host_of() {
+    let tree = r#"{"nodes": [
+        {"id": -1, "name": "default", "type": "root", "children": [-2]},
+        {"id": -2, "name": "node1-storage", "type": "host", "children": [4, 5]},
+        {"id": 4, "name": "osd.4", "type": "osd", "crush_weight": 3.6379, "status": "up"},
+        {"id": 5, "name": "osd.5", "type": "osd", "crush_weight": 1.8189, "status": "up"}
+    ], "stray": [{"id": 6, "name": "osd.6"}]}"#;
+    assert_eq!(
+        crush_host_of(tree, 5).unwrap(),
+        Some("node1-storage".to_string())
+    );
+    assert_eq!(crush_host_of(tree, 6).unwrap(), None);
+    assert!(crush_bucket_exists(tree, "host", "node1-storage").unwrap());
+    assert!(!crush_bucket_exists(tree, "root", "node1-storage").unwrap());
+    assert_eq!(short_hostname("node1.dc1.example.com"), "node1");
+    assert_eq!(short_hostname("node1"), "node1");
+}
+
+#[test]
+fn test_bluestore_volumes() {
+    let volumes = BluestoreVolumes {
+        devices: vec![PathBuf::from("/dev/nvme0n1")],
+        size: None,
+        size_percent: Some(4),
+    };
+    assert_eq!(
+        volumes.volume_size(4_000_000_000_000),
+        Some(160_000_000_000)
+    );
+    let volumes = BluestoreVolumes {
+        size: Some(2_147_483_648),
+        ..volumes
+    };
+    assert_eq!(volumes.volume_size(4_000_000_000_000), Some(2_147_483_648));
+
+    assert_eq!(
+        bluestore_volume_from_tag(
+            "ceph.db_device=/dev/ceph-db-1f0e/osd-db-3c4a5d8e-9f3a-4b7e-a8c1-2d6f0e5b9a71"
+        ),
+        Some(PathBuf::from(
+            "/dev/ceph-db-1f0e/osd-db-3c4a5d8e-9f3a-4b7e-a8c1-2d6f0e5b9a71"
+        ))
+    );
+    // Journal partitions are left alone
+    assert_eq!(bluestore_volume_from_tag("ceph.wal_device=/dev/sdb1"), None);
+    assert_eq!(bluestore_volume_from_tag("ceph.osd_id=3"), None);
+}
+
+#[test]
+fn test_luks_keys() {
+    let key = generate_luks_key().unwrap();
+    assert_eq!(key.len(), 128);
+    assert_ne!(key, generate_luks_key().unwrap());
+    let fsid = uuid::Uuid::parse_str("3c4a5d8e-9f3a-4b7e-a8c1-2d6f0e5b9a71").unwrap();
+    assert_eq!(
+        luks_key_name(&KeyStore::Monitor, &fsid),
+        "dm-crypt/osd/3c4a5d8e-9f3a-4b7e-a8c1-2d6f0e5b9a71/luks"
+    );
+    let auth = r#"[{"entity": "client.osd-lockbox.3c4a5d8e-9f3a-4b7e-a8c1-2d6f0e5b9a71",
+        "key": "AQBGzz1fAAAAABAAa2FWbxxQjF0ZS7bKyiGfKw==", "caps": {"mon": "allow"}}]"#;
+    assert_eq!(
+        lockbox_secret(auth).unwrap(),
+        "AQBGzz1fAAAAABAAa2FWbxxQjF0ZS7bKyiGfKw=="
+    );
+    assert!(lockbox_secret("[]").is_err());
+}
+
+#[test]
+fn test_crush_location_args() {
+    assert_eq!(
+        crush_location_args(None, "node1", None, false),
+        vec!["host=node1", "root=default"]
+    );
+    // An existing host stays under whatever root it's in
+    assert_eq!(
+        crush_location_args(None, "node1", None, true),
+        vec!["host=node1"]
+    );
+    let mut location = BTreeMap::new();
+    location.insert("rack".to_string(), "r12".to_string());
+    location.insert("root".to_string(), "ssd-root".to_string());
+    assert_eq!(
+        crush_location_args(Some(&location), "node1", Some("east-a"), true),
+        vec![
+            "datacenter=east-a",
+            "host=node1",
+            "rack=r12",
+            "root=ssd-root"
+        ]
+    );
+    assert_eq!(device_class(&block_utils::MediaType::NVME), Some("nvme"));
+    assert_eq!(device_class(&block_utils::MediaType::Loopback), None);
+}
+
+#[test]
+fn test_unneeded_flags() {
+    let noout = |who: Option<&str>| FlagSetting {
+        flag: "noout".to_string(),
+        who: who.map(|w| w.to_string()),
+    };
+    let norebalance = FlagSetting {
+        flag: "norebalance".to_string(),
+        who: None,
+    };
+    let cleared = vec![FlagRecord {
+        device: "/dev/sdb".to_string(),
+        settings: vec![noout(Some("osd.3")), norebalance.clone()],
+        expires: 1_000,
+    }];
+    let remaining = vec![FlagRecord {
+        device: "/dev/sdc".to_string(),
+        settings: vec![noout(Some("osd.4")), norebalance.clone()],
+        expires: 2_000,
+    }];
+    // norebalance is still needed for sdc
+    assert_eq!(
+        unneeded_flags(&cleared, &remaining),
+        vec![noout(Some("osd.3"))]
+    );
+    assert_eq!(
+        unneeded_flags(&cleared, &[]),
+        vec![noout(Some("osd.3")), norebalance]
+    );
+}
+
+#[test]
+fn test_stops_data_movement() {
+    assert!(stops_data_movement("norebalance"));
+    assert!(!stops_data_movement("noout"));
+}
+
+#[test]
+fn test_destroyed_osds() {
+    let tree = r#"{"nodes": [
+        {"id": -2, "name": "node1", "type": "host", "children": [4, 5]},
+        {"id": 4, "name": "osd.4", "type": "osd", "crush_weight": 0.0, "status": "destroyed"},
+        {"id": 5, "name": "osd.5", "type": "osd", "crush_weight": 1.8189, "status": "up"}
+    ], "stray": []}"#;
+    assert_eq!(destroyed_osds(tree).unwrap(), vec![4]);
+}
+
+#[test]
+fn test_pgs_active_clean() {
+    let luminous = r#"{"num_pg_by_state": [
+        {"name": "active+clean", "num": 120},
+        {"name": "active+remapped+backfilling", "num": 8}
+    ], "num_pgs": 128}"#;
+    assert!(!pgs_active_clean(luminous).unwrap());
+    let nautilus = r#"{"pg_ready": true, "pg_summary": {"num_pg_by_state": [
+        {"name": "active+clean", "num": 120},
+        {"name": "active+clean+scrubbing+deep", "num": 8}
+    ], "num_pgs": 128}}"#;
+    assert!(pgs_active_clean(nautilus).unwrap());
+    assert!(!pg_state_clean("active+clean+degraded"));
+    assert!(!pg_state_clean("active+undersized"));
+}
+
+#[test]
+fn test_safe_to_add_checks() {
+    let pg_stat = r#"{"pg_summary": {"num_pg_by_state": [
+        {"name": "active+clean", "num": 120},
+        {"name": "active+recovery_wait+degraded", "num": 5},
+        {"name": "active+remapped+backfilling", "num": 3}
+    ], "num_pgs": 128}}"#;
+    assert_eq!(recovering_pgs(pg_stat).unwrap(), 8);
+
+    let dump = r#"{"epoch": 12, "flags": "noin,sortbitwise,recovery_deletes", "osds": []}"#;
+    assert_eq!(
+        cluster_flags(dump).unwrap(),
+        vec!["noin", "sortbitwise", "recovery_deletes"]
+    );
+    assert!(cluster_flags(r#"{"flags": ""}"#).unwrap().is_empty());
+
+    let tree = r#"{"nodes": [
+        {"id": -1, "name": "default", "type": "root", "children": [-3]},
+        {"id": -3, "name": "r12", "type": "rack", "children": []}
+    ], "stray": []}"#;
+    let location: Vec<String> = vec!["host=node1", "rack=r12", "root=default"]
+        .into_iter()
+        .map(|l| l.to_string())
+        .collect();
+    assert!(missing_crush_buckets(tree, &location).unwrap().is_empty());
+    let location = vec!["rack=r13".to_string(), "root=defualt".to_string()];
+    assert_eq!(
+        missing_crush_buckets(tree, &location).unwrap(),
+        vec!["rack=r13", "root=defualt"]
+    );
+}
+
+#[test]
+fn test_parse_clusters() {
+    let single = r#"{"config_file": "/etc/ceph/ceph.conf", "user_id": "admin"}"#;
+    let clusters = parse_clusters(single).unwrap();
+    assert_eq!(clusters.len(), 1);
+    assert_eq!(clusters[0].name, None);
+
+    let multi = r#"{"clusters": [
+        {"name": "ceph", "config_file": "/etc/ceph/ceph.conf", "user_id": "admin"},
+        {"name": "backup", "config_file": "/etc/ceph/backup.conf", "user_id": "bynar",
+         "keyring": "/etc/ceph/backup.client.bynar.keyring",
+         "journal_devices": [{"device": "/dev/sdb"}]}
+    ]}"#;
+    let clusters = parse_clusters(multi).unwrap();
+    assert_eq!(clusters.len(), 2);
+    assert_eq!(clusters[1].name, Some("backup".to_string()));
+    assert_eq!(
+        clusters[1].keyring,
+        Some("/etc/ceph/backup.client.bynar.keyring".to_string())
+    );
+    assert_eq!(clusters[1].journal_devices.as_ref().unwrap().len(), 1);
+
+    let unnamed = r#"{"clusters": [
+        {"config_file": "/etc/ceph/ceph.conf", "user_id": "admin"},
+        {"config_file": "/etc/ceph/backup.conf", "user_id": "admin"}
+    ]}"#;
+    assert!(parse_clusters(unnamed).is_err());
+}
+
+#[test]
+fn test_conf_fsid_and_keyring() {
+    let conf = "[global]\n\tfsid = 8e5b6a5c-1d1e-4f5a-9c7d-0b6e2f3a4d5e\n\tmon host = 10.0.0.1\n\
+                [client]\n\tkeyring = /etc/ceph/ceph.client.admin.keyring\n";
+    assert_eq!(
+        fsid_from_conf(conf),
+        Some("8e5b6a5c-1d1e-4f5a-9c7d-0b6e2f3a4d5e".to_string())
+    );
+    assert_eq!(fsid_from_conf("[osd]\nfsid = 1\n"), None);
+
+    let rewritten = conf_with_keyring(conf, "/etc/ceph/backup.keyring");
+    assert!(rewritten.starts_with("[global]\nkeyring = /etc/ceph/backup.keyring\n"));
+    assert!(!rewritten.contains("ceph.client.admin.keyring"));
+    assert_eq!(
+        conf_with_keyring("", "/k"),
+        "[global]\nkeyring = /k\n".to_string()
+    );
+}
+
+/// ceph.json either configures one cluster or lists several
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum CephClusters {
+    Clusters { clusters: Vec<CephConfig> },
+    Single(CephConfig),
+}
+
 #[derive(Deserialize, Debug)]
 struct CephConfig {
+    /// Name of the cluster.  Required when ceph.json lists more than one.
+    /// This is the --cluster name ceph tools are run with and defaults
+    /// to ceph
+    name: Option<String>,
     /// The location of the ceph.conf file
     config_file: String,
     /// The cephx user to connect to the Ceph service with
     user_id: String,
+    /// The keyring for user_id if ceph.conf doesn't point at it
+    keyring: Option<String>,
     /// The /dev/xxx devices to use for journal partitions.
     /// Bynar will create new partitions on these devices as needed
     /// if no journal_partition_id is given
     journal_devices: Option<Vec<JournalDevice>>,
+    /// Bluestore block.db volumes
+    db: Option<BluestoreVolumes>,
+    /// Bluestore block.wal volumes.  Used instead of journal_devices when set
+    wal: Option<BluestoreVolumes>,
+    /// Encrypt new osds with dm-crypt
+    dmcrypt: Option<DmcryptConfig>,
+    /// Step the CRUSH weight of an osd down to 0 before removing it
+    /// instead of removing it all at once
+    drain: Option<DrainConfig>,
+    /// Seconds to wait between checks for safe-to-destroy while removing
+    /// an osd in the background
+    #[serde(default = "default_poll_interval")]
+    safe_to_destroy_poll_interval: u64,
+    /// Give up on the removal if the osd isn't safe to destroy within this
+    /// many seconds
+    #[serde(default = "default_drain_timeout")]
+    safe_to_destroy_timeout: u64,
+    /// Cluster flags to set while disks are being removed or added
+    maintenance_flags: Option<MaintenanceFlags>,
+    /// CRUSH bucket type -> name to place new osds under.  Ex:
+    /// {"root": "default", "datacenter": "dc1", "rack": "r12"}.  The host
+    /// bucket defaults to this host's name
+    crush_location: Option<BTreeMap<String, String>>,
+    /// Use the region from this host's name as the datacenter bucket when
+    /// crush_location doesn't give one
+    #[serde(default)]
+    crush_datacenter_from_region: bool,
+    /// Name the host bucket after the short hostname instead of the full
+    /// one, for clusters whose osds were created by ceph's own tools
+    #[serde(default)]
+    crush_short_hostname: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct MaintenanceFlags {
+    /// Ex: noout, norebalance
+    flags: Vec<String>,
+    /// What to set the flags on.  Flags ceph can't scope to a host or
+    /// osd are always set on the whole cluster
+    #[serde(default)]
+    scope: FlagScope,
+    /// Seconds after which the flags are cleared even if the operation
+    /// hasn't finished
+    expiry: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum FlagScope {
+    Cluster,
+    Host,
+    Osd,
+}
+
+impl Default for FlagScope {
+    fn default() -> Self {
+        FlagScope::Osd
+    }
+}
+
+// The only flags ceph allows on a group of osds or crush nodes
+const SCOPED_FLAGS: &[&str] = &["noup", "nodown", "noin", "noout"];
+// Flags that stop data from moving.  They'd stall a drain so removals hold
+// them back until the data is off the osd
+const DATA_MOVEMENT_FLAGS: &[&str] = &["norebalance", "nobackfill", "norecover", "pause"];
+// Placement group states that mean data is missing or still moving
+const UNCLEAN_PG_STATES: &[&str] = &[
+    "degraded",
+    "recovering",
+    "backfilling",
+    "peering",
+    "undersized",
+];
+
+/// A flag bynar set.  who is the osd or host it was set on or None if
+/// it was set on the whole cluster
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+struct FlagSetting {
+    flag: String,
+    who: Option<String>,
+}
+
+/// The flags set for an operation on a device
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+struct FlagRecord {
+    device: String,
+    settings: Vec<FlagSetting>,
+    /// Seconds since the epoch
+    expires: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct BluestoreVolumes {
+    /// Flash devices the volumes are carved out of.  These are shared by
+    /// the osds on the host
+    devices: Vec<PathBuf>,
+    /// Size of each volume in bytes
+    size: Option<u64>,
+    /// Size of each volume as a percent of the osd's data device.  Used
+    /// if size isn't given
+    size_percent: Option<u64>,
+}
+
+impl BluestoreVolumes {
+    // How big the volume for a data device of data_size bytes should be
+    fn volume_size(&self, data_size: u64) -> Option<u64> {
+        match (self.size, self.size_percent) {
+            (Some(size), _) => Some(size),
+            (None, Some(percent)) => Some(data_size / 100 * percent),
+            (None, None) => None,
+        }
+    }
+}
+
+// Ceph recommends block.db be at least 4% of the data device
+const DEFAULT_DB_PERCENT: u64 = 4;
+const DEFAULT_WAL_SIZE: u64 = 2 * 1_073_741_824;
+// Marks the volume groups on shared flash devices that Bynar made
+const BLUESTORE_VG_TAG: &str = "ceph.type=bluestore_volumes";
+
+/// A block.db or block.wal logical volume
+#[derive(Clone, Debug)]
+struct BluestoreVolume {
+    /// db or wal
+    kind: String,
+    path: PathBuf,
+    uuid: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DmcryptConfig {
+    /// Where the LUKS keys are kept
+    key_store: KeyStore,
+    /// Needed if the key_store is vault
+    vault_endpoint: Option<String>,
+    vault_token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum KeyStore {
+    /// The monitor config-key store.  This is where ceph-volume keeps them
+    Monitor,
+    Vault,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DrainConfig {
+    /// How much CRUSH weight to take off the osd at each step
+    weight_step: f64,
+    /// Seconds to wait between checks for active+clean
+    #[serde(default = "default_poll_interval")]
+    poll_interval: u64,
+    /// Give up if a step doesn't reach active+clean within this many seconds
+    #[serde(default = "default_drain_timeout")]
+    timeout: u64,
+}
+
+fn default_poll_interval() -> u64 {
+    30
+}
+
+fn default_drain_timeout() -> u64 {
+    3600
+}
+
+/// How far along draining an osd is
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+struct DrainProgress {
+    osd_id: u64,
+    crush_weight: f64,
+    step: usize,
+    total_steps: usize,
+    stage: String,
+}
+
+impl fmt::Display for DrainProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "osd.{} step {}/{} crush weight {}: {}",
+            self.osd_id, self.step, self.total_steps, self.crush_weight, self.stage
+        )
+    }
 }
 
 fn choose_ceph_config(config_dir: Option<&Path>) -> BynarResult<PathBuf> {
@@ -132,26 +627,62 @@ fn choose_ceph_config(config_dir: Option<&Path>) -> BynarResult<PathBuf> {
 }
 
 impl CephBackend {
+    /// Connect to the first cluster in ceph.json.  select_cluster switches
+    /// to another one
     pub fn new(config_dir: Option<&Path>) -> BynarResult<CephBackend> {
         let ceph_config = choose_ceph_config(config_dir)?;
-        if !ceph_config.exists() {
-            error!("ceph config {} does not exist", ceph_config.display());
-        }
-        let s = read_to_string(ceph_config)?;
-        let deserialized: CephConfig = serde_json::from_str(&s)?;
+        let config = load_clusters(&ceph_config)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| BynarError::new(format!("{} has no clusters", ceph_config.display())))?;
+        CephBackend::connect(config, &ceph_config)
+    }
 
+    fn connect(config: CephConfig, ceph_config: &Path) -> BynarResult<CephBackend> {
         info!("Connecting to Ceph");
-        let cluster_handle = connect_to_ceph(&deserialized.user_id, &deserialized.config_file)?;
+        let cluster_handle = match config.keyring {
+            Some(ref keyring) => {
+                // connect_to_ceph only takes a config file so hand it a
+                // copy of ceph.conf that points at the keyring
+                let conf = read_to_string(&config.config_file)?;
+                let tmp_dir = TempDir::new("bynar")?;
+                let conf_path = tmp_dir.path().join("ceph.conf");
+                let mut f = File::create(&conf_path)?;
+                f.write_all(conf_with_keyring(&conf, keyring).as_bytes())?;
+                connect_to_ceph(&config.user_id, &conf_path.to_string_lossy())?
+            }
+            None => connect_to_ceph(&config.user_id, &config.config_file)?,
+        };
         info!("Connected to Ceph");
         let version_str = version(&cluster_handle)?;
         let version: CephVersion = version_str.parse()?;
+        // Each cluster keeps its own state files
+        let suffix = match config.name {
+            Some(ref name) => format!("_{}", name),
+            None => String::new(),
+        };
         Ok(CephBackend {
             cluster_handle,
-            config: deserialized,
+            config,
             version,
+            config_path: ceph_config.to_path_buf(),
+            drain_file: ceph_config.with_file_name(format!("ceph_drain{}.json", suffix)),
+            flags_file: ceph_config.with_file_name(format!("ceph_flags{}.json", suffix)),
         })
     }
 
+    // The --cluster name for ceph tools
+    fn cluster_name(&self) -> &str {
+        match self.config.name {
+            Some(ref name) => name,
+            None => "ceph",
+        }
+    }
+
+    fn osd_dir(&self, osd_id: u64) -> PathBuf {
+        Path::new("/var/lib/ceph/osd").join(format!("{}-{}", self.cluster_name(), osd_id))
+    }
+
     fn add_bluestore_osd(
         &self,
         dev_path: &Path,
@@ -162,18 +693,104 @@ impl CephBackend {
         //TODO  What is the deal with this tmpfs??
         mount, "-t", "tmpfs", "tmpfs", "/var/lib/ceph/osd/ceph-2"
             */
-        // Create the journal device if requested
-        let journal = self.select_journal()?;
+        // Create the journal device if requested.  A wal volume takes its
+        // place if those are configured
+        let journal = if self.config.wal.is_some() {
+            None
+        } else {
+            self.select_journal()?
+        };
 
-        // Create a new osd id
-        let new_osd_id = osd_create(&self.cluster_handle, id, simulate)?;
-        debug!("New osd id created: {:?}", new_osd_id);
         let osd_fsid = uuid::Uuid::new_v4();
-        let (lv_dev_name, vg_size) =
-            self.create_lvm(&osd_fsid, new_osd_id, &dev_path, journal.as_ref())?;
+        let new_osd_id = match id {
+            // Destroyed osds have to be brought back with osd new
+            Some(id) if self.is_destroyed(id)? => {
+                debug!("Reusing destroyed osd id {}", id);
+                if !simulate {
+                    self.cluster_handle.ceph_mon_command_without_data(&json!({
+                        "prefix": "osd new",
+                        "uuid": osd_fsid.to_hyphenated().to_string(),
+                        "id": id,
+                    }))?;
+                }
+                id
+            }
+            _ => {
+                // Create a new osd id
+                let new_osd_id = osd_create(&self.cluster_handle, id, simulate)?;
+                debug!("New osd id created: {:?}", new_osd_id);
+                new_osd_id
+            }
+        };
+        let info = block_utils::get_device_info(dev_path)?;
+        let volumes = self.create_bluestore_volumes(&info, &osd_fsid, new_osd_id, simulate)?;
+        // The db and wal devices are shared with other osds so a failed add
+        // can't leave its volumes behind taking up the space
+        let paths: Vec<PathBuf> = volumes.iter().map(|v| v.path.clone()).collect();
+        if let Err(e) = self.setup_bluestore_osd(
+            dev_path, &info, journal, &osd_fsid, new_osd_id, volumes, simulate,
+        ) {
+            if self.config.dmcrypt.is_some() {
+                // The mapper devices have to be closed before their volumes
+                // can be removed.  They're named after the logical volumes
+                let mut names = vec![format!("osd-block-{}", osd_fsid)];
+                names.extend(
+                    paths
+                        .iter()
+                        .filter_map(|p| p.file_name())
+                        .map(|n| n.to_string_lossy().into_owned()),
+                );
+                if let Err(e) = self
+                    .close_dmcrypt(&names, &osd_fsid, simulate)
+                    .and_then(|_| self.delete_lockbox(&osd_fsid, simulate))
+                {
+                    error!("Failed to clean up dmcrypt for osd {}: {:?}", osd_fsid, e);
+                }
+            }
+            if !simulate {
+                self.remove_bluestore_volumes(&paths);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Everything after the db and wal volumes are created
+    #[allow(clippy::too_many_arguments)]
+    fn setup_bluestore_osd(
+        &self,
+        dev_path: &Path,
+        info: &block_utils::Device,
+        journal: Option<JournalDevice>,
+        osd_fsid: &uuid::Uuid,
+        new_osd_id: u64,
+        volumes: Vec<BluestoreVolume>,
+        simulate: bool,
+    ) -> BynarResult<()> {
+        // The lockbox secret goes in the lvm tags so it's made first
+        let lockbox_secret = match self.config.dmcrypt {
+            Some(ref dmcrypt) => self.create_lockbox(dmcrypt, osd_fsid, simulate)?,
+            None => None,
+        };
+        let (lv_dev_name, vg_size) = self.create_lvm(
+            osd_fsid,
+            new_osd_id,
+            &dev_path,
+            journal.as_ref(),
+            &volumes,
+            lockbox_secret.as_ref().map(|s| s.as_str()),
+        )?;
+        // Encrypted osds use the opened mapper devices from here on.  The
+        // lvm tags keep pointing at the logical volumes.
+        let (lv_dev_name, volumes) = match self.config.dmcrypt {
+            Some(ref dmcrypt) => {
+                self.open_dmcrypt(dmcrypt, osd_fsid, &lv_dev_name, volumes, simulate)?
+            }
+            None => (lv_dev_name, volumes),
+        };
 
         // Mount the drive
-        let mount_point = Path::new("/var/lib/ceph/osd").join(&format!("ceph-{}", new_osd_id));
+        let mount_point = self.osd_dir(new_osd_id);
         if !mount_point.exists() {
             debug!(
                 "Mount point {} doesn't exist.  Creating.",
@@ -208,6 +825,15 @@ impl CephBackend {
                 .ok_or_else(|| BynarError::from("ceph user id not found"))?;
             self.change_permissions(&[&Path::new(&format!("{}", journal))], &ceph_user)?;
         }
+        for volume in &volumes {
+            let link = mount_point.join(format!("block.{}", volume.kind));
+            debug!("Symlinking {} to {}", volume.path.display(), link.display());
+            symlink(&volume.path, &link)?;
+            let ceph_user = Passwd::from_name("ceph")?
+                .ok_or_else(|| BynarError::from("ceph user id not found"))?;
+            let backer = self.resolve_lvm_device(&volume.path)?;
+            self.change_permissions(&[&backer], &ceph_user)?;
+        }
 
         // Write activate monmap out
         debug!("Getting latest monmap from ceph");
@@ -228,38 +854,37 @@ impl CephBackend {
         osd_auth_add(&self.cluster_handle, new_osd_id, simulate)?;
         let auth_key = auth_get_key(&self.cluster_handle, "osd", &new_osd_id.to_string())?;
         debug!("Saving ceph keyring");
-        save_keyring(new_osd_id, &auth_key, Some(0), Some(0), simulate)?;
+        save_keyring(
+            &mount_point,
+            new_osd_id,
+            &auth_key,
+            Some(0),
+            Some(0),
+            simulate,
+        )?;
 
         // Format the osd with the osd filesystem
         ceph_mkfs(
+            self.cluster_name(),
             new_osd_id,
             journal.as_ref(),
             true,
             Some(&activate_path),
             Some(&mount_point),
-            Some(&osd_fsid),
+            Some(osd_fsid),
             Some("ceph"),
             Some("ceph"),
+            &volumes,
             simulate,
         )?;
-        ceph_bluestore_tool(&lv_dev_name, &mount_point, simulate)?;
+        ceph_bluestore_tool(self.cluster_name(), &lv_dev_name, &mount_point, simulate)?;
 
-        let host_info = Host::new()?;
         let gb_capacity = vg_size / 1_073_741_824;
         let osd_weight = gb_capacity as f64 * 0.001_f64;
-        debug!(
-            "Adding OSD {} to crushmap under host {} with weight: {}",
-            new_osd_id, host_info.hostname, osd_weight
-        );
-        osd_crush_add(
-            &self.cluster_handle,
-            new_osd_id,
-            osd_weight,
-            &host_info.hostname,
-            simulate,
-        )?;
-        systemctl_enable(new_osd_id, &osd_fsid, simulate)?;
-        setup_osd_init(new_osd_id, simulate)?;
+        self.crush_add(new_osd_id, osd_weight, simulate)?;
+        self.set_device_class(new_osd_id, &info.media_type, simulate)?;
+        systemctl_enable(self.cluster_name(), new_osd_id, osd_fsid, simulate)?;
+        setup_osd_init(self.cluster_name(), &mount_point, new_osd_id, simulate)?;
         Ok(())
     }
 
@@ -306,7 +931,7 @@ impl CephBackend {
         debug!("New osd id created: {:?}", new_osd_id);
 
         // Mount the drive
-        let mount_point = Path::new("/var/lib/ceph/osd/").join(format!("ceph-{}", new_osd_id));
+        let mount_point = self.osd_dir(new_osd_id);
         if !simulate {
             if !mount_point.exists() {
                 debug!(
@@ -322,6 +947,7 @@ impl CephBackend {
 
         // Format the osd with the osd filesystem
         ceph_mkfs(
+            self.cluster_name(),
             new_osd_id,
             journal.as_ref(),
             false,
@@ -330,30 +956,129 @@ impl CephBackend {
             None,
             None,
             None,
+            &[],
             simulate,
         )?;
         debug!("Creating ceph authorization entry");
         osd_auth_add(&self.cluster_handle, new_osd_id, simulate)?;
         let auth_key = auth_get_key(&self.cluster_handle, "osd", &new_osd_id.to_string())?;
         debug!("Saving ceph keyring");
-        save_keyring(new_osd_id, &auth_key, None, None, simulate)?;
-        let host_info = Host::new()?;
+        save_keyring(&mount_point, new_osd_id, &auth_key, None, None, simulate)?;
         let gb_capacity = info.capacity / 1_073_741_824;
         let osd_weight = gb_capacity as f64 * 0.001_f64;
+        self.crush_add(new_osd_id, osd_weight, simulate)?;
+        add_osd_to_fstab(&info, &mount_point, simulate)?;
+        // This step depends on whether it's systemctl, upstart, etc
+        setup_osd_init(self.cluster_name(), &mount_point, new_osd_id, simulate)?;
+        Ok(())
+    }
+
+    // Add the osd to the crushmap under this host and the buckets from
+    // crush_location
+    fn crush_add(&self, osd_id: u64, weight: f64, simulate: bool) -> BynarResult<()> {
+        let location = self.crush_location()?;
         debug!(
-            "Adding OSD {} to crushmap under host {} with weight: {}",
-            new_osd_id, host_info.hostname, osd_weight
+            "Adding OSD {} to crushmap at {:?} with weight: {}",
+            osd_id, location, weight
         );
-        osd_crush_add(
-            &self.cluster_handle,
-            new_osd_id,
-            osd_weight,
-            &host_info.hostname,
-            simulate,
-        )?;
-        add_osd_to_fstab(&info, new_osd_id, simulate)?;
-        // This step depends on whether it's systemctl, upstart, etc
-        setup_osd_init(new_osd_id, simulate)?;
+        if !simulate {
+            // This moves the osd if it's already in the crushmap, like a
+            // destroyed osd being reused
+            self.cluster_handle.ceph_mon_command_without_data(&json!({
+                "prefix": "osd crush create-or-move",
+                "id": osd_id,
+                "weight": weight,
+                "args": location,
+            }))?;
+            // create-or-move leaves the weight of an existing entry alone
+            self.cluster_handle.ceph_mon_command_without_data(&json!({
+                "prefix": "osd crush reweight",
+                "name": format!("osd.{}", osd_id),
+                "weight": weight,
+            }))?;
+        }
+        Ok(())
+    }
+
+    // The bucket_type=name arguments new osds on this host are placed with
+    fn crush_location(&self) -> BynarResult<Vec<String>> {
+        let host_info = Host::new()?;
+        let region = if self.config.crush_datacenter_from_region {
+            Some(host_info.region.as_str())
+        } else {
+            None
+        };
+        let hostname = if self.config.crush_short_hostname {
+            short_hostname(&host_info.hostname)
+        } else {
+            host_info.hostname.as_str()
+        };
+        let (tree, _) = self.cluster_handle.ceph_mon_command_without_data(&json!({
+            "prefix": "osd tree",
+            "format": "json",
+        }))?;
+        let host_in_crush = crush_bucket_exists(&String::from_utf8_lossy(&tree), "host", hostname)?;
+        Ok(crush_location_args(
+            self.config.crush_location.as_ref(),
+            hostname,
+            region,
+            host_in_crush,
+        ))
+    }
+
+    // The CRUSH host bucket an osd is under.  New osds go under the host
+    // from crush_location.  This often isn't the machine's hostname
+    fn crush_host(&self, osd_id: Option<u64>) -> BynarResult<String> {
+        if let Some(id) = osd_id {
+            let (tree, _) = self.cluster_handle.ceph_mon_command_without_data(&json!({
+                "prefix": "osd tree",
+                "format": "json",
+            }))?;
+            if let Some(host) = crush_host_of(&String::from_utf8_lossy(&tree), id)? {
+                return Ok(host);
+            }
+            debug!("osd.{} isn't under a CRUSH host", id);
+        }
+        Ok(self
+            .crush_location()?
+            .iter()
+            .find(|arg| arg.starts_with("host="))
+            .map(|arg| arg["host=".len()..].to_string())
+            .unwrap_or_default())
+    }
+
+    // Device classes only exist on luminous and newer.  The class is
+    // removed first because ceph won't overwrite one a reused osd id
+    // still has
+    fn set_device_class(
+        &self,
+        osd_id: u64,
+        media_type: &block_utils::MediaType,
+        simulate: bool,
+    ) -> BynarResult<()> {
+        if self.version < CephVersion::Luminous {
+            return Ok(());
+        }
+        let class = match device_class(media_type) {
+            Some(class) => class,
+            None => {
+                debug!("No device class for media type {:?}", media_type);
+                return Ok(());
+            }
+        };
+        debug!("Setting osd {} device class to {}", osd_id, class);
+        if !simulate {
+            let ids = vec![osd_id.to_string()];
+            self.cluster_handle.ceph_mon_command_without_data(&json!({
+                "prefix": "osd crush rm-device-class",
+                "ids": ids,
+            }))?;
+            self.cluster_handle.ceph_mon_command_without_data(&json!({
+                "prefix": "osd crush set-device-class",
+                "class": class,
+                "ids": ids,
+            }))?;
+        }
         Ok(())
     }
 
@@ -377,6 +1102,8 @@ impl CephBackend {
         new_osd_id: u64,
         dev_path: &Path,
         journal_device: Option<&JournalDevice>,
+        volumes: &[BluestoreVolume],
+        lockbox_secret: Option<&str>,
     ) -> BynarResult<(PathBuf, u64)> {
         debug!("udev Probing device {:?}", dev_path);
         let info = block_utils::get_device_info(dev_path)?;
@@ -409,11 +1136,14 @@ impl CephBackend {
             new_osd_id,
             &info,
             journal_device,
+            volumes,
+            lockbox_secret,
         )?;
         Ok((lv_dev_name.to_path_buf(), vg.get_size()))
     }
 
     // Add the lvm tags that ceph requires to identify the osd
+    #[allow(clippy::too_many_arguments)]
     fn create_lvm_tags(
         &self,
         lv: &LogicalVolume<'_, '_>,
@@ -422,6 +1152,8 @@ impl CephBackend {
         new_osd_id: u64,
         info: &block_utils::Device,
         journal_device: Option<&JournalDevice>,
+        volumes: &[BluestoreVolume],
+        lockbox_secret: Option<&str>,
     ) -> BynarResult<()> {
         debug!("Creating lvm tags");
         let mut tags = vec![
@@ -429,11 +1161,13 @@ impl CephBackend {
             format!("ceph.block_device={}", lv_dev_name.display()),
             format!("ceph.osd_id={}", new_osd_id),
             format!("ceph.osd_fsid={}", osd_fsid),
-            // TODO: Find out where to find this.
-            format!("ceph.cluster_name={}", "ceph"),
+            format!("ceph.cluster_name={}", self.cluster_name()),
             format!("ceph.cluster_fsid={}", self.cluster_handle.rados_fsid()?),
-            format!("ceph.encrypted={}", "0"),
-            "ceph.cephx_lockbox_secret=".to_string(),
+            format!(
+                "ceph.encrypted={}",
+                if self.config.dmcrypt.is_some() { 1 } else { 0 }
+            ),
+            format!("ceph.cephx_lockbox_secret={}", lockbox_secret.unwrap_or("")),
             format!("ceph.block_uuid={}", lv.get_uuid()),
         ];
         if let Some(journal_dev) = journal_device {
@@ -450,22 +1184,21 @@ impl CephBackend {
             // Get the partition uuid from the device
             tags.push(format!("ceph.wal_uuid={}", uuid));
         }
+        // Removal finds the db and wal volumes through these
+        for volume in volumes {
+            tags.push(format!(
+                "ceph.{}_device={}",
+                volume.kind,
+                volume.path.display()
+            ));
+            tags.push(format!("ceph.{}_uuid={}", volume.kind, volume.uuid));
+        }
 
         // Tell ceph what type of underlying media this is
-        match info.media_type {
-            block_utils::MediaType::SolidState => {
-                tags.push("ceph.crush_device_class=ssd".into());
-            }
-            block_utils::MediaType::Rotational => {
-                tags.push("ceph.crush_device_class=hdd".into());
-            }
-            block_utils::MediaType::NVME => {
-                tags.push("ceph.crush_device_class=nvme".into());
-            }
-            _ => {
-                tags.push("ceph.crush_device_class=None".into());
-            }
-        };
+        tags.push(format!(
+            "ceph.crush_device_class={}",
+            device_class(&info.media_type).unwrap_or("None")
+        ));
 
         // Add all the tags to the lvm
         debug!("Adding tags {:?} to logical volume", tags);
@@ -506,9 +1239,13 @@ impl CephBackend {
         // List the tags to get the osd id
         let mut osd_id = None;
         let mut osd_fsid = None;
+        let mut volumes: Vec<PathBuf> = Vec::new();
+        let mut encrypted = false;
         for lv in &lvs {
             let tags = lv.get_tags()?;
             debug!("Found tags for logical volume: {:?}", tags);
+            volumes.extend(tags.iter().filter_map(|t| bluestore_volume_from_tag(t)));
+            encrypted |= tags.iter().any(|t| t == "ceph.encrypted=1");
             let id_tag = tags.iter().find(|t| t.starts_with("ceph.osd_id"));
             if let Some(tag) = id_tag {
                 let parts: Vec<String> = tag.split('=').map(|s| s.to_string()).collect();
@@ -531,15 +1268,34 @@ impl CephBackend {
             )));
         }
         let osd_id = osd_id.unwrap();
+        // The rest are set once the data is off the osd
+        self.set_maintenance_flags(
+            dev_path,
+            Some(osd_id),
+            &|f: &str| !stops_data_movement(f),
+            simulate,
+        )?;
+        if let Some(ref drain) = self.config.drain {
+            self.drain_osd(dev_path, osd_id, drain, simulate)?;
+        }
         debug!("Setting osd {} out", osd_id);
         osd_out(&self.cluster_handle, osd_id, simulate)?;
-        debug!("Removing osd {} from crush", osd_id);
-        osd_crush_remove(&self.cluster_handle, osd_id, simulate)?;
-        debug!("Deleting osd {} auth key", osd_id);
-        auth_del(&self.cluster_handle, osd_id, simulate)?;
-        systemctl_stop(osd_id, simulate)?;
-        debug!("Removing osd {}", osd_id);
-        osd_rm(&self.cluster_handle, osd_id, simulate)?;
+        self.wait_for_safe_to_destroy(osd_id, simulate)?;
+        self.set_maintenance_flags(dev_path, Some(osd_id), &stops_data_movement, simulate)?;
+        systemctl_stop(self.cluster_name(), osd_id, simulate)?;
+        self.destroy_osd(osd_id, simulate)?;
+
+        if encrypted {
+            // The mapper devices are named after the logical volumes
+            let mut names: Vec<String> = lvs.iter().map(|lv| lv.get_name()).collect();
+            names.extend(
+                volumes
+                    .iter()
+                    .filter_map(|v| v.file_name())
+                    .map(|n| n.to_string_lossy().into_owned()),
+            );
+            self.close_dmcrypt(&names, &osd_fsid.unwrap(), simulate)?;
+        }
 
         // Wipe the disk
         debug!("Erasing disk {}", dev_path.display());
@@ -553,6 +1309,11 @@ impl CephBackend {
             vg.remove()?;
             // Remove the physical volume
             lvm.pv_remove(&dev_path.to_string_lossy())?;
+            // The db and wal devices are shared so only this osd's volumes
+            // are removed from them
+            for volume in &volumes {
+                self.remove_bluestore_volume(&lvm, volume)?;
+            }
 
             // Erase the physical volume
             match block_utils::erase_block_device(&dev_path) {
@@ -565,11 +1326,15 @@ impl CephBackend {
                     error!("{} failed to erase: {:?}", dev_path.display(), e);
                 }
             };
-            debug!("Cleaning up /var/lib/ceph/osd/ceph-{}", osd_id);
-            remove_dir_all(Path::new("/var/lib/ceph/osd/").join(&format!("ceph-{}", osd_id)))?;
+            let osd_dir = self.osd_dir(osd_id);
+            debug!("Cleaning up {}", osd_dir.display());
+            remove_dir_all(&osd_dir)?;
         }
 
-        systemctl_disable(osd_id, &osd_fsid.unwrap(), simulate)?;
+        systemctl_disable(self.cluster_name(), osd_id, &osd_fsid.unwrap(), simulate)?;
+        if self.config.drain.is_some() {
+            self.save_drain_progress(dev_path, None)?;
+        }
 
         Ok(())
     }
@@ -596,14 +1361,20 @@ impl CephBackend {
                 get_osd_id_from_path(&mount_point)?
             }
         };
+        self.set_maintenance_flags(
+            dev_path,
+            Some(osd_id),
+            &|f: &str| !stops_data_movement(f),
+            simulate,
+        )?;
+        if let Some(ref drain) = self.config.drain {
+            self.drain_osd(dev_path, osd_id, drain, simulate)?;
+        }
         debug!("Setting osd {} out", osd_id);
         osd_out(&self.cluster_handle, osd_id, simulate)?;
-        debug!("Removing osd {} from crush", osd_id);
-        osd_crush_remove(&self.cluster_handle, osd_id, simulate)?;
-        debug!("Deleting osd {} auth key", osd_id);
-        auth_del(&self.cluster_handle, osd_id, simulate)?;
-        debug!("Removing osd {}", osd_id);
-        osd_rm(&self.cluster_handle, osd_id, simulate)?;
+        self.wait_for_safe_to_destroy(osd_id, simulate)?;
+        self.set_maintenance_flags(dev_path, Some(osd_id), &stops_data_movement, simulate)?;
+        self.destroy_osd(osd_id, simulate)?;
 
         // Wipe the disk
         debug!("Erasing disk {}", dev_path.display());
@@ -619,31 +1390,724 @@ impl CephBackend {
                 }
             };
         }
+        if self.config.drain.is_some() {
+            self.save_drain_progress(dev_path, None)?;
+        }
 
         Ok(())
     }
 
-    // lvm devices are symlinks.  They need to be resolved back into an
-    // absolute path to do anything useful with them.
-    fn resolve_lvm_device(&self, lv_dev_name: &Path) -> BynarResult<PathBuf> {
-        debug!("Resolving lvm {} device", lv_dev_name.display());
-        let tmp = lv_dev_name.read_link()?;
-        if tmp.is_relative() {
-            let p = lv_dev_name
-                .parent()
-                .ok_or_else(|| {
-                    BynarError::new(format!(
-                        "LVM device {} has no parent directory",
-                        lv_dev_name.display()
-                    ))
-                })?
-                .join(tmp)
-                .canonicalize()?;
-            Ok(p)
-        } else {
-            Ok(tmp)
+    // Luminous and newer keep the osd id and CRUSH entry around as destroyed
+    // so the replacement disk can take them over.  Older releases remove
+    // the osd completely.
+    fn destroy_osd(&self, osd_id: u64, simulate: bool) -> BynarResult<()> {
+        if self.version >= CephVersion::Luminous {
+            debug!("Destroying osd {}", osd_id);
+            if !simulate {
+                self.cluster_handle.ceph_mon_command_without_data(&json!({
+                    "prefix": "osd destroy",
+                    "id": osd_id,
+                    "sure": "--yes-i-really-mean-it",
+                }))?;
+            }
+            return Ok(());
+        }
+        debug!("Removing osd {} from crush", osd_id);
+        osd_crush_remove(&self.cluster_handle, osd_id, simulate)?;
+        debug!("Deleting osd {} auth key", osd_id);
+        auth_del(&self.cluster_handle, osd_id, simulate)?;
+        debug!("Removing osd {}", osd_id);
+        osd_rm(&self.cluster_handle, osd_id, simulate)?;
+        Ok(())
+    }
+
+    // Set the configured flags that wanted picks for the duration of an
+    // operation on a device
+    fn set_maintenance_flags(
+        &self,
+        dev_path: &Path,
+        osd_id: Option<u64>,
+        wanted: &dyn Fn(&str) -> bool,
+        simulate: bool,
+    ) -> BynarResult<()> {
+        let maintenance = match self.config.maintenance_flags {
+            Some(ref m) => m,
+            None => return Ok(()),
+        };
+        let flags: Vec<&String> = maintenance.flags.iter().filter(|f| wanted(f)).collect();
+        if flags.is_empty() {
+            return Ok(());
+        }
+        let who = match (&maintenance.scope, osd_id) {
+            (FlagScope::Cluster, _) => None,
+            (FlagScope::Osd, Some(id)) => Some(format!("osd.{}", id)),
+            // The osd id isn't known yet when adding a new disk
+            (FlagScope::Osd, None) | (FlagScope::Host, _) => Some(self.crush_host(osd_id)?),
+        };
+        // Set-group needs mimic or newer
+        let scoped = self.version >= CephVersion::Mimic;
+        let settings: Vec<FlagSetting> = flags
+            .into_iter()
+            .map(|flag| FlagSetting {
+                flag: flag.clone(),
+                who: if scoped && SCOPED_FLAGS.contains(&flag.as_str()) {
+                    who.clone()
+                } else {
+                    None
+                },
+            })
+            .collect();
+        let record = FlagRecord {
+            device: dev_path.to_string_lossy().into_owned(),
+            settings: settings.clone(),
+            expires: now()? + maintenance.expiry,
+        };
+        // Record the flags before setting them so a crash in between
+        // still gets them cleaned up
+        self.update_flag_records(|records| {
+            records.push(record);
+            Vec::new()
+        })?;
+        for setting in &settings {
+            self.set_flag(setting, true, simulate)?;
+        }
+        Ok(())
+    }
+
+    // Clear the flags set for a device that nothing else still needs
+    fn clear_maintenance_flags(&self, dev_path: &Path, simulate: bool) -> BynarResult<()> {
+        if self.config.maintenance_flags.is_none() && !self.flags_file.exists() {
+            return Ok(());
+        }
+        let device = dev_path.to_string_lossy().into_owned();
+        let unneeded = self.update_flag_records(|records| {
+            let (cleared, remaining): (Vec<FlagRecord>, Vec<FlagRecord>) =
+                records.drain(..).partition(|r| r.device == device);
+            *records = remaining;
+            unneeded_flags(&cleared, records)
+        })?;
+        for setting in &unneeded {
+            self.set_flag(setting, false, simulate)?;
+        }
+        Ok(())
+    }
+
+    fn set_flag(&self, setting: &FlagSetting, set: bool, simulate: bool) -> BynarResult<()> {
+        let cmd = match setting.who {
+            Some(ref who) => json!({
+                "prefix": if set { "osd set-group" } else { "osd unset-group" },
+                "flags": setting.flag,
+                "who": [who],
+            }),
+            None => json!({
+                "prefix": if set { "osd set" } else { "osd unset" },
+                "key": setting.flag,
+            }),
+        };
+        debug!(
+            "{} flag {:?}",
+            if set { "Setting" } else { "Clearing" },
+            setting
+        );
+        if !simulate {
+            self.cluster_handle.ceph_mon_command_without_data(&cmd)?;
+        }
+        Ok(())
+    }
+
+    // Lock the flags file, let f change the records and save them.  Returns
+    // whatever f does
+    fn update_flag_records<F>(&self, f: F) -> BynarResult<Vec<FlagSetting>>
+    where
+        F: FnOnce(&mut Vec<FlagRecord>) -> Vec<FlagSetting>,
+    {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&self.flags_file)?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+        let mut s = String::new();
+        file.read_to_string(&mut s)?;
+        let mut records: Vec<FlagRecord> = if s.trim().is_empty() {
+            Vec::new()
+        } else {
+            serde_json::from_str(&s)?
+        };
+        let result = f(&mut records);
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(serde_json::to_string_pretty(&records)?.as_bytes())?;
+        // The lock is released when file is closed
+        Ok(result)
+    }
+
+    // Clear the flags recorded in flags_file that are no longer needed
+    fn clear_flag_records(&self, expired_only: bool) -> BynarResult<()> {
+        let now = now()?;
+        let unneeded = self.update_flag_records(|records| {
+            let (cleared, remaining): (Vec<FlagRecord>, Vec<FlagRecord>) = records
+                .drain(..)
+                .partition(|r| !expired_only || r.expires <= now);
+            *records = remaining;
+            unneeded_flags(&cleared, records)
+        })?;
+        for setting in &unneeded {
+            info!("Clearing leftover flag {:?}", setting);
+            self.set_flag(setting, false, false)?;
+        }
+        Ok(())
+    }
+
+    // Check the osd tree to see if an osd id was destroyed
+    fn is_destroyed(&self, osd_id: u64) -> BynarResult<bool> {
+        let (tree, _) = self.cluster_handle.ceph_mon_command_without_data(&json!({
+            "prefix": "osd tree",
+            "format": "json",
+        }))?;
+        destroyed_osds(&String::from_utf8_lossy(&tree)).map(|ids| ids.contains(&osd_id))
+    }
+
+    // Step the CRUSH weight of the osd down to 0 and wait for the cluster
+    // to go active+clean after each step.  This spreads the data movement
+    // out instead of having it all happen when the osd is removed.
+    fn drain_osd(
+        &self,
+        dev_path: &Path,
+        osd_id: u64,
+        drain: &DrainConfig,
+        simulate: bool,
+    ) -> BynarResult<()> {
+        let (tree, _) = self.cluster_handle.ceph_mon_command_without_data(&json!({
+            "prefix": "osd tree",
+            "format": "json",
+        }))?;
+        let start_weight = crush_weight(&String::from_utf8_lossy(&tree), osd_id)?;
+        let weights = drain_weights(start_weight, drain.weight_step)?;
+        let mut progress = DrainProgress {
+            osd_id,
+            crush_weight: start_weight,
+            step: 0,
+            total_steps: weights.len(),
+            stage: "starting".to_string(),
+        };
+        info!(
+            "Draining osd.{} from crush weight {} in {} steps",
+            osd_id,
+            start_weight,
+            weights.len()
+        );
+        for (i, weight) in weights.into_iter().enumerate() {
+            progress.step = i + 1;
+            progress.crush_weight = weight;
+            progress.stage = "reweighting".to_string();
+            self.save_drain_progress(dev_path, Some(&progress))?;
+            debug!("Setting osd.{} crush weight to {}", osd_id, weight);
+            if !simulate {
+                self.cluster_handle.ceph_mon_command_without_data(&json!({
+                    "prefix": "osd crush reweight",
+                    "name": format!("osd.{}", osd_id),
+                    "weight": weight,
+                }))?;
+            }
+            progress.stage = "waiting for active+clean".to_string();
+            self.save_drain_progress(dev_path, Some(&progress))?;
+            if let Err(e) = self.wait_for_clean(drain, simulate) {
+                progress.stage = format!("failed: {}", e);
+                self.save_drain_progress(dev_path, Some(&progress))?;
+                return Err(e);
+            }
+        }
+        progress.stage = "removing".to_string();
+        self.save_drain_progress(dev_path, Some(&progress))?;
+        Ok(())
+    }
+
+    // Poll pg stat until every placement group is active+clean
+    fn wait_for_clean(&self, drain: &DrainConfig, simulate: bool) -> BynarResult<()> {
+        if simulate {
+            return Ok(());
+        }
+        let start = Instant::now();
+        loop {
+            // Give the cluster a chance to peer before checking
+            thread::sleep(Duration::from_secs(drain.poll_interval));
+            let (stat, _) = self.cluster_handle.ceph_mon_command_without_data(&json!({
+                "prefix": "pg stat",
+                "format": "json",
+            }))?;
+            if pgs_active_clean(&String::from_utf8_lossy(&stat))? {
+                return Ok(());
+            }
+            if start.elapsed() > Duration::from_secs(drain.timeout) {
+                return Err(BynarError::new(format!(
+                    "Cluster didn't reach active+clean within {} seconds",
+                    drain.timeout
+                )));
+            }
+            trace!("Waiting for active+clean");
+        }
+    }
+
+    // ok-to-stop only says the cluster stays available without the osd.
+    // Wait for safe-to-destroy to say its data is stored elsewhere before
+    // anything destructive happens to it.  Only removals running in the
+    // background wait.  The others answer right away and go on the
+    // ok-to-stop check from safe_to_remove
+    fn wait_for_safe_to_destroy(&self, osd_id: u64, simulate: bool) -> BynarResult<()> {
+        if simulate || self.version < CephVersion::Luminous || !self.removes_in_background() {
+            return Ok(());
+        }
+        let poll_interval = self.config.safe_to_destroy_poll_interval;
+        let timeout = self.config.safe_to_destroy_timeout;
+        let start = Instant::now();
+        loop {
+            match self.cluster_handle.ceph_mon_command_without_data(&json!({
+                "prefix": "osd safe-to-destroy",
+                "ids": [osd_id.to_string()],
+            })) {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    if start.elapsed() > Duration::from_secs(timeout) {
+                        return Err(BynarError::new(format!(
+                            "osd.{} wasn't safe to destroy within {} seconds: {}",
+                            osd_id, timeout, e
+                        )));
+                    }
+                    debug!("osd.{} isn't safe to destroy yet: {}", osd_id, e);
+                }
+            }
+            thread::sleep(Duration::from_secs(poll_interval));
+        }
+    }
+
+    fn load_drain_progress(&self) -> BynarResult<BTreeMap<String, DrainProgress>> {
+        if !self.drain_file.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let s = read_to_string(&self.drain_file)?;
+        Ok(serde_json::from_str(&s)?)
+    }
+
+    // Record how far along draining a device is.  None clears the record.
+    // Removals of different disks update the file at the same time so the
+    // updates take a lock, and RemoveStatus only ever reads a whole file
+    fn save_drain_progress(
+        &self,
+        dev_path: &Path,
+        progress: Option<&DrainProgress>,
+    ) -> BynarResult<()> {
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(self.drain_file.with_extension("lock"))?;
+        flock(lock.as_raw_fd(), FlockArg::LockExclusive)?;
+        let mut drains = self.load_drain_progress()?;
+        let key = dev_path.to_string_lossy().into_owned();
+        match progress {
+            Some(p) => {
+                drains.insert(key, p.clone());
+            }
+            None => {
+                drains.remove(&key);
+            }
+        };
+        let tmp = self.drain_file.with_extension("tmp");
+        let mut f = File::create(&tmp)?;
+        f.write_all(serde_json::to_string_pretty(&drains)?.as_bytes())?;
+        f.sync_all()?;
+        rename(&tmp, &self.drain_file)?;
+        // The lock is released when lock is closed
+        Ok(())
+    }
+
+    // Format and open the osd's logical volumes with a new LUKS key.
+    // Returns the mapper devices to use in their place
+    fn open_dmcrypt(
+        &self,
+        dmcrypt: &DmcryptConfig,
+        osd_fsid: &uuid::Uuid,
+        block: &Path,
+        volumes: Vec<BluestoreVolume>,
+        simulate: bool,
+    ) -> BynarResult<(PathBuf, Vec<BluestoreVolume>)> {
+        let key = generate_luks_key()?;
+        // Save the key first so it's never lost if something below fails
+        self.save_luks_key(dmcrypt, osd_fsid, &key, simulate)?;
+        let block = luks_open(block, &key, simulate)?;
+        let mut opened = Vec::new();
+        for volume in volumes {
+            let path = luks_open(&volume.path, &key, simulate)?;
+            opened.push(BluestoreVolume { path, ..volume });
+        }
+        Ok((block, opened))
+    }
+
+    // Close the osd's mapper devices and delete its key
+    fn close_dmcrypt(
+        &self,
+        names: &[String],
+        osd_fsid: &uuid::Uuid,
+        simulate: bool,
+    ) -> BynarResult<()> {
+        for name in names {
+            if Path::new("/dev/mapper").join(name).exists() {
+                debug!("Closing dmcrypt device {}", name);
+                run_cryptsetup(&["remove", name], None, simulate)?;
+            }
+        }
+        self.delete_luks_key(osd_fsid, simulate)
+    }
+
+    // ceph-volume reopens encrypted osds at boot by authenticating as the
+    // osd's lockbox and reading the LUKS key from the monitors.  It can't
+    // read keys from vault so those osds get no lockbox
+    fn create_lockbox(
+        &self,
+        dmcrypt: &DmcryptConfig,
+        osd_fsid: &uuid::Uuid,
+        simulate: bool,
+    ) -> BynarResult<Option<String>> {
+        if dmcrypt.key_store != KeyStore::Monitor {
+            return Ok(None);
+        }
+        let entity = format!("client.osd-lockbox.{}", osd_fsid);
+        debug!("Creating lockbox {}", entity);
+        if simulate {
+            return Ok(None);
+        }
+        let (out, _) = self.cluster_handle.ceph_mon_command_without_data(&json!({
+            "prefix": "auth get-or-create",
+            "entity": entity,
+            "caps": [
+                "mon",
+                format!(
+                    "allow command \"config-key get\" with key=\"{}\"",
+                    luks_key_name(&KeyStore::Monitor, osd_fsid)
+                ),
+            ],
+            "format": "json",
+        }))?;
+        lockbox_secret(&String::from_utf8_lossy(&out)).map(Some)
+    }
+
+    fn delete_lockbox(&self, osd_fsid: &uuid::Uuid, simulate: bool) -> BynarResult<()> {
+        let entity = format!("client.osd-lockbox.{}", osd_fsid);
+        debug!("Deleting lockbox {}", entity);
+        if simulate {
+            return Ok(());
+        }
+        self.cluster_handle.ceph_mon_command_without_data(&json!({
+            "prefix": "auth del",
+            "entity": entity,
+        }))?;
+        Ok(())
+    }
+
+    fn save_luks_key(
+        &self,
+        dmcrypt: &DmcryptConfig,
+        osd_fsid: &uuid::Uuid,
+        key: &str,
+        simulate: bool,
+    ) -> BynarResult<()> {
+        let name = luks_key_name(&dmcrypt.key_store, osd_fsid);
+        debug!("Saving dmcrypt key to {:?} {}", dmcrypt.key_store, name);
+        if simulate {
+            return Ok(());
+        }
+        match dmcrypt.key_store {
+            KeyStore::Monitor => {
+                self.cluster_handle.ceph_mon_command_without_data(&json!({
+                    "prefix": "config-key set",
+                    "key": name,
+                    "val": key,
+                }))?;
+            }
+            KeyStore::Vault => {
+                let (endpoint, token) = vault_settings(dmcrypt)?;
+                let client = VaultClient::new(endpoint, token)?;
+                client.set_secret(name, key)?;
+            }
+        };
+        Ok(())
+    }
+
+    fn delete_luks_key(&self, osd_fsid: &uuid::Uuid, simulate: bool) -> BynarResult<()> {
+        // Keys from before dmcrypt was configured can only be on the monitors
+        let key_store = match self.config.dmcrypt {
+            Some(ref dmcrypt) => dmcrypt.key_store.clone(),
+            None => KeyStore::Monitor,
+        };
+        let name = luks_key_name(&key_store, osd_fsid);
+        debug!("Deleting dmcrypt key {:?} {}", key_store, name);
+        if simulate {
+            return Ok(());
+        }
+        match key_store {
+            KeyStore::Monitor => {
+                self.cluster_handle.ceph_mon_command_without_data(&json!({
+                    "prefix": "config-key rm",
+                    "key": name,
+                }))?;
+            }
+            KeyStore::Vault => {
+                let dmcrypt = self
+                    .config
+                    .dmcrypt
+                    .as_ref()
+                    .ok_or_else(|| BynarError::from("dmcrypt isn't configured"))?;
+                let (endpoint, token) = vault_settings(dmcrypt)?;
+                let client = VaultClient::new(endpoint, token)?;
+                client.delete_secret(&name)?;
+            }
+        };
+        Ok(())
+    }
+
+    // Create the configured db and wal volumes for a new osd.  If one
+    // can't be made the ones already made are removed again
+    fn create_bluestore_volumes(
+        &self,
+        info: &block_utils::Device,
+        osd_fsid: &uuid::Uuid,
+        osd_id: u64,
+        simulate: bool,
+    ) -> BynarResult<Vec<BluestoreVolume>> {
+        let configured = [
+            (
+                "db",
+                self.config.db.as_ref(),
+                info.capacity / 100 * DEFAULT_DB_PERCENT,
+            ),
+            ("wal", self.config.wal.as_ref(), DEFAULT_WAL_SIZE),
+        ];
+        let mut volumes: Vec<BluestoreVolume> = Vec::new();
+        for &(kind, config, default_size) in &configured {
+            let config = match config {
+                Some(config) => config,
+                None => continue,
+            };
+            let size = config.volume_size(info.capacity).unwrap_or(default_size);
+            match self.create_bluestore_volume(kind, config, size, osd_fsid, osd_id, simulate) {
+                Ok(volume) => volumes.push(volume),
+                Err(e) => {
+                    if !simulate {
+                        let paths: Vec<PathBuf> = volumes.iter().map(|v| v.path.clone()).collect();
+                        self.remove_bluestore_volumes(&paths);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(volumes)
+    }
+
+    // Carve a block.db or block.wal volume for an osd out of whichever
+    // shared flash device has the most free space
+    fn create_bluestore_volume(
+        &self,
+        kind: &str,
+        volumes: &BluestoreVolumes,
+        size: u64,
+        osd_fsid: &uuid::Uuid,
+        osd_id: u64,
+        simulate: bool,
+    ) -> BynarResult<BluestoreVolume> {
+        debug!("initializing LVM");
+        let lvm = Lvm::new(None)?;
+        lvm.scan()?;
+        // The device, its volume group if it has one yet and its free space
+        let mut best: Option<(&PathBuf, Option<String>, u64)> = None;
+        for device in &volumes.devices {
+            let (vg_name, free) = match lvm.vg_name_from_device(&device.to_string_lossy())? {
+                Some(name) => {
+                    let vg = lvm.vg_open(&name, &OpenMode::Read)?;
+                    // Never carve volumes out of something that isn't ours
+                    if !vg.get_tags()?.iter().any(|t| t == BLUESTORE_VG_TAG) {
+                        warn!(
+                            "Skipping {}.  Volume group {} isn't tagged {}",
+                            device.display(),
+                            name,
+                            BLUESTORE_VG_TAG
+                        );
+                        continue;
+                    }
+                    let free = vg.get_free_extents() * vg.get_extent_size();
+                    (Some(name), free)
+                }
+                // First time this device is used
+                None => (None, block_utils::get_device_info(device)?.capacity),
+            };
+            debug!("{} has {} bytes free", device.display(), free);
+            if free > size && best.as_ref().map(|b| free > b.2).unwrap_or(true) {
+                best = Some((device, vg_name, free));
+            }
+        }
+        let vg_name = match best {
+            Some((_, Some(name), _)) => name,
+            Some((device, None, _)) => {
+                let name = format!("ceph-{}-{}", kind, uuid::Uuid::new_v4());
+                debug!("Creating volume group {} on {}", name, device.display());
+                if !simulate {
+                    let vg = lvm.vg_create(&name)?;
+                    vg.extend(device)?;
+                    vg.add_tag(BLUESTORE_VG_TAG)?;
+                    vg.write()?;
+                }
+                name
+            }
+            None => {
+                return Err(BynarError::new(format!(
+                    "None of the {} devices have {} bytes free",
+                    kind, size
+                )))
+            }
+        };
+        let lv_name = format!("osd-{}-{}", kind, osd_fsid);
+        let path = Path::new("/dev").join(&vg_name).join(&lv_name);
+        debug!("Creating {} volume {} of size {}", kind, lv_name, size);
+        if simulate {
+            return Ok(BluestoreVolume {
+                kind: kind.to_string(),
+                path,
+                uuid: String::new(),
+            });
+        }
+        let tags = vec![
+            format!("ceph.type={}", kind),
+            format!("ceph.osd_id={}", osd_id),
+            format!("ceph.osd_fsid={}", osd_fsid),
+            format!("ceph.cluster_fsid={}", self.cluster_handle.rados_fsid()?),
+        ];
+        let vg = lvm.vg_open(&vg_name, &OpenMode::Write)?;
+        let lv = vg.create_lv_linear(&lv_name, size)?;
+        for t in &tags {
+            if let Err(e) = lv.add_tag(t) {
+                // An untagged volume would never be cleaned up
+                error!("Tagging {} failed.  Removing it", path.display());
+                if let Err(e) = lv.deactivate().and_then(|_| lv.remove()) {
+                    error!("Failed to remove {}: {:?}", path.display(), e);
+                }
+                return Err(e.into());
+            }
+        }
+        Ok(BluestoreVolume {
+            kind: kind.to_string(),
+            path,
+            uuid: lv.get_uuid(),
+        })
+    }
+
+    // Best effort removal of the db and wal volumes a failed add created
+    fn remove_bluestore_volumes(&self, volumes: &[PathBuf]) {
+        let remove = || -> BynarResult<()> {
+            let lvm = Lvm::new(None)?;
+            lvm.scan()?;
+            for volume in volumes {
+                self.remove_bluestore_volume(&lvm, volume)?;
+            }
+            Ok(())
+        };
+        if let Err(e) = remove() {
+            error!("Failed to remove {:?}: {:?}", volumes, e);
+        }
+    }
+
+    // Remove a /dev/<vg>/<lv> db or wal volume
+    fn remove_bluestore_volume(&self, lvm: &Lvm, volume: &Path) -> BynarResult<()> {
+        let (vg_name, lv_name) = match (
+            volume.parent().and_then(|p| p.file_name()),
+            volume.file_name(),
+        ) {
+            (Some(vg), Some(lv)) => (vg.to_string_lossy(), lv.to_string_lossy()),
+            _ => {
+                return Err(BynarError::new(format!(
+                    "{} isn't a logical volume path",
+                    volume.display()
+                )))
+            }
+        };
+        debug!("Removing {}", volume.display());
+        let vg = lvm.vg_open(&vg_name, &OpenMode::Write)?;
+        for lv in &vg.list_lvs()? {
+            if lv.get_name() == lv_name {
+                lv.deactivate()?;
+                lv.remove()?;
+            }
+        }
+        Ok(())
+    }
+
+    // lvm devices are symlinks.  They need to be resolved back into an
+    // absolute path to do anything useful with them.
+    fn resolve_lvm_device(&self, lv_dev_name: &Path) -> BynarResult<PathBuf> {
+        debug!("Resolving lvm {} device", lv_dev_name.display());
+        let tmp = lv_dev_name.read_link()?;
+        if tmp.is_relative() {
+            let p = lv_dev_name
+                .parent()
+                .ok_or_else(|| {
+                    BynarError::new(format!(
+                        "LVM device {} has no parent directory",
+                        lv_dev_name.display()
+                    ))
+                })?
+                .join(tmp)
+                .canonicalize()?;
+            Ok(p)
+        } else {
+            Ok(tmp)
+        }
+    }
+
+    // Find the osds living on a device.  Bluestore osds are found through
+    // their lvm tags.  Filestore osds through the mount path of the device
+    // or one of its partitions.
+    fn osd_ids_for_device(&self, dev_path: &Path) -> BynarResult<Vec<u64>> {
+        let mut osd_ids = Vec::new();
+        let lvm = Lvm::new(None)?;
+        lvm.scan()?;
+        if let Some(vg_name) = lvm.vg_name_from_device(&dev_path.to_string_lossy())? {
+            debug!("Found volume group: {}", vg_name);
+            let vg = lvm.vg_open(&vg_name, &OpenMode::Read)?;
+            for lv in &vg.list_lvs()? {
+                if let Some(id) = osd_ids_from_tags(&lv.get_tags()?)? {
+                    osd_ids.push(id);
+                }
+            }
+            if !osd_ids.is_empty() {
+                return Ok(osd_ids);
+            }
         }
-    }
+
+        let mut paths = vec![dev_path.to_path_buf()];
+        if let Some(name) = dev_path.file_name() {
+            let sys_path = Path::new("/sys/class/block").join(name);
+            if let Ok(entries) = read_dir(&sys_path) {
+                for entry in entries {
+                    let part_name = entry?.file_name();
+                    if part_name
+                        .to_string_lossy()
+                        .starts_with(&*name.to_string_lossy())
+                    {
+                        paths.push(Path::new("/dev").join(part_name));
+                    }
+                }
+            }
+        }
+        for p in paths {
+            if let Some(mount_point) = block_utils::get_mountpoint(&p)? {
+                debug!("{} mounted at: {}", p.display(), mount_point.display());
+                let id = match get_osd_id(&mount_point, false) {
+                    Ok(id) => id,
+                    Err(_) => get_osd_id_from_path(&mount_point)?,
+                };
+                osd_ids.push(id);
+            }
+        }
+        Ok(osd_ids)
+    }
 
     // Find the journal device that has enough free space
     fn select_journal(&self) -> BynarResult<Option<JournalDevice>> {
@@ -686,33 +2150,727 @@ impl CephBackend {
 impl Backend for CephBackend {
     fn add_disk(&self, device: &Path, id: Option<u64>, simulate: bool) -> BynarResult<()> {
         debug!("ceph version: {:?}", self.version,);
-        if self.version >= CephVersion::Luminous {
-            self.add_bluestore_osd(device, id, simulate)?;
+        self.set_maintenance_flags(device, id, &|_: &str| true, simulate)?;
+        let result = if self.version >= CephVersion::Luminous {
+            self.add_bluestore_osd(device, id, simulate)
         } else {
-            self.add_filestore_osd(device, id, simulate)?;
+            self.add_filestore_osd(device, id, simulate)
+        };
+        // Clear the flags whether the add worked or not
+        if let Err(e) = self.clear_maintenance_flags(device, simulate) {
+            error!(
+                "Clearing maintenance flags for {} failed: {:?}",
+                device.display(),
+                e
+            );
         }
-        Ok(())
+        if result.is_ok() && !simulate {
+            save_device_cluster(&self.config_path, device, None)?;
+        }
+        result
     }
 
     fn remove_disk(&self, device: &Path, simulate: bool) -> BynarResult<()> {
-        if self.version >= CephVersion::Luminous {
+        let result = if self.version >= CephVersion::Luminous {
             // Check if the type file exists
-            self.remove_bluestore_osd(device, simulate)?;
+            self.remove_bluestore_osd(device, simulate)
         } else {
-            self.remove_filestore_osd(device, simulate)?;
+            self.remove_filestore_osd(device, simulate)
+        };
+        if let Err(e) = self.clear_maintenance_flags(device, simulate) {
+            error!(
+                "Clearing maintenance flags for {} failed: {:?}",
+                device.display(),
+                e
+            );
         }
-        Ok(())
+        if result.is_ok() && !simulate && self.config.name.is_some() {
+            save_device_cluster(&self.config_path, device, self.config.name.as_ref())?;
+        }
+        result
+    }
+
+    fn clear_maintenance(&self, expired_only: bool) -> BynarResult<()> {
+        let result = if self.flags_file.exists() {
+            self.clear_flag_records(expired_only)
+        } else {
+            Ok(())
+        };
+        // disk-manager only holds one backend so the other clusters in
+        // ceph.json are cleaned up from here too.  One being unreachable
+        // shouldn't stop the rest from being cleaned up
+        for config in load_clusters(&self.config_path)? {
+            if config.name == self.config.name {
+                continue;
+            }
+            let name = config.name.clone();
+            if let Err(e) = CephBackend::connect(config, &self.config_path).and_then(|other| {
+                if other.flags_file.exists() {
+                    other.clear_flag_records(expired_only)
+                } else {
+                    Ok(())
+                }
+            }) {
+                error!("Clearing maintenance on cluster {:?} failed: {:?}", name, e);
+            }
+        }
+        result
+    }
+
+    fn select_cluster(&mut self, name: Option<&str>, device: &Path) -> BynarResult<Option<String>> {
+        let clusters = load_clusters(&self.config_path)?;
+        if name.is_none() && clusters.len() < 2 {
+            return Ok(self.config.name.clone());
+        }
+        // A replacement disk is blank so it goes back to the cluster its
+        // predecessor was removed from
+        let wanted = match name {
+            Some(name) => Some(name.to_string()),
+            None => load_device_clusters(&self.config_path)?.remove(&*device.to_string_lossy()),
+        };
+        let config = match wanted {
+            Some(wanted) => clusters
+                .into_iter()
+                .find(|c| c.name.as_ref() == Some(&wanted))
+                .ok_or_else(|| {
+                    BynarError::new(format!(
+                        "Cluster {} is not in {}",
+                        wanted,
+                        self.config_path.display()
+                    ))
+                })?,
+            None => {
+                let fsid = match device_cluster_fsid(device)? {
+                    Some(fsid) => fsid,
+                    // A new disk.  Stay on the default cluster
+                    None => return Ok(self.config.name.clone()),
+                };
+                let mut found = None;
+                for c in clusters {
+                    if fsid_from_conf(&read_to_string(&c.config_file)?).as_ref() == Some(&fsid) {
+                        found = Some(c);
+                        break;
+                    }
+                }
+                found.ok_or_else(|| {
+                    BynarError::new(format!(
+                        "{} belongs to cluster {} which is not in {}",
+                        device.display(),
+                        fsid,
+                        self.config_path.display()
+                    ))
+                })?
+            }
+        };
+        if config.name == self.config.name {
+            return Ok(self.config.name.clone());
+        }
+        debug!("Switching to cluster {:?}", config.name);
+        *self = CephBackend::connect(config, &self.config_path)?;
+        Ok(self.config.name.clone())
+    }
+
+    fn replacement_id(&self, device: &Path) -> BynarResult<Option<u64>> {
+        if self.version < CephVersion::Luminous {
+            // Removed osd ids aren't kept before luminous
+            return Ok(None);
+        }
+        Ok(self.osd_ids_for_device(device)?.into_iter().next())
+    }
+
+    fn removes_in_background(&self) -> bool {
+        self.config.drain.is_some()
+    }
+
+    fn remove_progress(&self, device: &Path) -> BynarResult<Option<String>> {
+        let drains = self.load_drain_progress()?;
+        Ok(drains
+            .get(&*device.to_string_lossy())
+            .map(|p| p.to_string()))
+    }
+
+    fn safe_to_remove(&self, device: &Path, simulate: bool) -> BynarResult<(bool, Option<String>)> {
+        if simulate {
+            return Ok((true, None));
+        }
+        if self.version < CephVersion::Luminous {
+            // ok-to-stop and safe-to-destroy don't exist yet.  Fall back
+            // on checking the whole cluster
+            let diag_map = DiagMap::new().map_err(|e| BynarError::new(e.to_string()))?;
+            debug!("Checking if a disk is safe to remove from ceph");
+            return match diag_map.exhaustive_diag(Format::Json) {
+                Status::Safe => Ok((true, None)),
+                Status::NonSafe => Ok((false, Some("Cluster is not healthy".to_string()))),
+                Status::Unknown => Ok((false, Some("Cluster health is unknown".to_string()))),
+            };
+        }
+        let osd_ids = self.osd_ids_for_device(device)?;
+        if osd_ids.is_empty() {
+            debug!("No osds found on {}", device.display());
+            return Ok((true, None));
+        }
+        debug!("Checking if osds {:?} are safe to remove", osd_ids);
+        let ids: Vec<String> = osd_ids.iter().map(|id| id.to_string()).collect();
+
+        // Osds that are down or out already have their data moving
+        // elsewhere so the question is whether it's all there.  Osds still
+        // up and in only need to be ok to stop here.  remove_disk takes
+        // them out and, when it runs in the background, waits for
+        // safe-to-destroy before destroying them
+        let (dump, _) = self.cluster_handle.ceph_mon_command_without_data(&json!({
+            "prefix": "osd dump",
+            "format": "json",
+        }))?;
+        let stopped = down_or_out_osds(&String::from_utf8_lossy(&dump), &osd_ids)?;
+        let prefix = if stopped.len() == osd_ids.len() {
+            "osd safe-to-destroy"
+        } else {
+            "osd ok-to-stop"
+        };
+        match self.cluster_handle.ceph_mon_command_without_data(&json!({
+            "prefix": prefix,
+            "ids": ids,
+        })) {
+            Ok(_) => Ok((true, None)),
+            Err(e) => {
+                // The monitors return EBUSY/EAGAIN along with the reason
+                let reason = format!("{} {:?} failed: {}", prefix, osd_ids, e);
+                info!("{}", reason);
+                Ok((false, Some(reason)))
+            }
+        }
+    }
+
+    /// Wait for recovery to finish and for anyone who set noin to clear it
+    /// before adding an osd.  The CRUSH buckets it goes under have to exist
+    /// already so a typo in crush_location doesn't create a new tree.
+    fn safe_to_add(&self, _device: &Path, simulate: bool) -> BynarResult<(bool, Option<String>)> {
+        if simulate {
+            return Ok((true, None));
+        }
+        let (pg_stat, _) = self.cluster_handle.ceph_mon_command_without_data(&json!({
+            "prefix": "pg stat",
+            "format": "json",
+        }))?;
+        let recovering = recovering_pgs(&String::from_utf8_lossy(&pg_stat))?;
+        let (dump, _) = self.cluster_handle.ceph_mon_command_without_data(&json!({
+            "prefix": "osd dump",
+            "format": "json",
+        }))?;
+        let flags = cluster_flags(&String::from_utf8_lossy(&dump))?;
+        let (tree, _) = self.cluster_handle.ceph_mon_command_without_data(&json!({
+            "prefix": "osd tree",
+            "format": "json",
+        }))?;
+        let missing =
+            missing_crush_buckets(&String::from_utf8_lossy(&tree), &self.crush_location()?)?;
+
+        let reason = if recovering > 0 {
+            Some(format!("{} placement groups are recovering", recovering))
+        } else if flags.iter().any(|f| f == "noin") {
+            Some("The noin flag is set".to_string())
+        } else if !missing.is_empty() {
+            Some(format!("CRUSH buckets {:?} do not exist", missing))
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) => {
+                info!("{}", reason);
+                Ok((false, Some(reason)))
+            }
+            None => Ok((true, None)),
+        }
+    }
+}
+
+fn load_clusters(ceph_config: &Path) -> BynarResult<Vec<CephConfig>> {
+    if !ceph_config.exists() {
+        error!("ceph config {} does not exist", ceph_config.display());
+    }
+    let s = read_to_string(ceph_config)?;
+    parse_clusters(&s)
+}
+
+fn parse_clusters(s: &str) -> BynarResult<Vec<CephConfig>> {
+    let clusters = match serde_json::from_str(s)? {
+        CephClusters::Clusters { clusters } => clusters,
+        CephClusters::Single(config) => vec![config],
+    };
+    if clusters.len() > 1 {
+        let mut names = Vec::new();
+        for c in &clusters {
+            match c.name {
+                Some(ref name) if !names.contains(&name) => names.push(name),
+                Some(ref name) => {
+                    return Err(BynarError::new(format!("Cluster {} is listed twice", name)));
+                }
+                None => {
+                    return Err(BynarError::from(
+                        "Every cluster needs a name when more than one is listed",
+                    ));
+                }
+            }
+        }
+    }
+    Ok(clusters)
+}
+
+// Devices removed from a named cluster -> the cluster name.  Kept next to
+// ceph.json so every cluster sees it
+fn load_device_clusters(ceph_config: &Path) -> BynarResult<BTreeMap<String, String>> {
+    let path = ceph_config.with_file_name("ceph_clusters.json");
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let s = read_to_string(&path)?;
+    Ok(serde_json::from_str(&s)?)
+}
+
+// Record the cluster a device was removed from.  None clears the record
+fn save_device_cluster(
+    ceph_config: &Path,
+    dev_path: &Path,
+    cluster: Option<&String>,
+) -> BynarResult<()> {
+    let mut devices = load_device_clusters(ceph_config)?;
+    let key = dev_path.to_string_lossy().into_owned();
+    let changed = match cluster {
+        Some(c) => devices.insert(key, c.clone()).as_ref() != Some(c),
+        None => devices.remove(&key).is_some(),
+    };
+    if changed {
+        let mut f = File::create(ceph_config.with_file_name("ceph_clusters.json"))?;
+        f.write_all(serde_json::to_string_pretty(&devices)?.as_bytes())?;
+    }
+    Ok(())
+}
+
+// The fsid from the [global] section of a ceph.conf
+fn fsid_from_conf(conf: &str) -> Option<String> {
+    let mut section = String::new();
+    for line in conf.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            section = line.trim_matches(|c| c == '[' || c == ']').to_string();
+            continue;
+        }
+        let mut parts = line.splitn(2, '=');
+        if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+            if section == "global" && key.trim() == "fsid" {
+                return Some(value.trim().to_string());
+            }
+        }
+    }
+    None
+}
+
+// ceph.conf with any keyring settings replaced by keyring
+fn conf_with_keyring(conf: &str, keyring: &str) -> String {
+    let mut out = String::new();
+    for line in conf.lines() {
+        if line.trim().starts_with("keyring") {
+            continue;
+        }
+        out.push_str(line);
+        out.push('\n');
+        if line.trim() == "[global]" {
+            out.push_str(&format!("keyring = {}\n", keyring));
+        }
+    }
+    if !conf.lines().any(|l| l.trim() == "[global]") {
+        out.push_str(&format!("[global]\nkeyring = {}\n", keyring));
+    }
+    out
+}
+
+// The fsid of the cluster the osd on a device belongs to.  Bluestore osds
+// have it in their lvm tags and filestore osds in their data directory
+fn device_cluster_fsid(dev_path: &Path) -> BynarResult<Option<String>> {
+    let lvm = Lvm::new(None)?;
+    lvm.scan()?;
+    if let Some(vg_name) = lvm.vg_name_from_device(&dev_path.to_string_lossy())? {
+        let vg = lvm.vg_open(&vg_name, &OpenMode::Read)?;
+        for lv in &vg.list_lvs()? {
+            for tag in lv.get_tags()? {
+                if tag.starts_with("ceph.cluster_fsid=") {
+                    return Ok(Some(tag["ceph.cluster_fsid=".len()..].to_string()));
+                }
+            }
+        }
+    }
+    if let Some(mount_point) = block_utils::get_mountpoint(dev_path)? {
+        let fsid_path = mount_point.join("ceph_fsid");
+        if fsid_path.exists() {
+            return Ok(Some(read_to_string(&fsid_path)?.trim().to_string()));
+        }
+    }
+    Ok(None)
+}
+
+// The CRUSH weights to step an osd through while draining it.  Always
+// ends at 0
+fn drain_weights(start: f64, step: f64) -> BynarResult<Vec<f64>> {
+    if step <= 0.0 {
+        return Err(BynarError::from("drain weight_step must be greater than 0"));
+    }
+    let mut weights = Vec::new();
+    let mut weight = start - step;
+    // Stop short of tiny leftover weights from float rounding
+    while weight > 0.0001 {
+        weights.push(weight);
+        weight -= step;
+    }
+    weights.push(0.0);
+    Ok(weights)
+}
+
+// Look up the CRUSH weight of an osd in the osd tree json
+fn crush_weight(osd_tree: &str, osd_id: u64) -> BynarResult<f64> {
+    let v: serde_json::Value = serde_json::from_str(osd_tree)?;
+    v["nodes"]
+        .as_array()
+        .and_then(|nodes| {
+            nodes
+                .iter()
+                .find(|n| n["id"].as_i64() == Some(osd_id as i64))
+        })
+        .and_then(|n| n["crush_weight"].as_f64())
+        .ok_or_else(|| BynarError::new(format!("osd.{} not found in osd tree", osd_id)))
+}
+
+// The path of a db or wal volume bynar created from the tags on an osd's
+// block volume.  Journal partitions don't match
+fn bluestore_volume_from_tag(tag: &str) -> Option<PathBuf> {
+    let path = if tag.starts_with("ceph.db_device=") {
+        Path::new(&tag["ceph.db_device=".len()..])
+    } else if tag.starts_with("ceph.wal_device=") {
+        Path::new(&tag["ceph.wal_device=".len()..])
+    } else {
+        return None;
+    };
+    let name = path.file_name()?.to_string_lossy();
+    if name.starts_with("osd-db-") || name.starts_with("osd-wal-") {
+        Some(path.to_path_buf())
+    } else {
+        None
+    }
+}
+
+// The key from auth get-or-create json output
+fn lockbox_secret(auth: &str) -> BynarResult<String> {
+    let v: serde_json::Value = serde_json::from_str(auth)?;
+    v[0]["key"]
+        .as_str()
+        .map(|k| k.to_string())
+        .ok_or_else(|| BynarError::from("auth get-or-create output is missing the key"))
+}
+
+// 64 random bytes hex encoded
+fn generate_luks_key() -> BynarResult<String> {
+    let mut f = File::open("/dev/urandom")?;
+    let mut buf = [0u8; 64];
+    f.read_exact(&mut buf)?;
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// Where an osd's key is kept in the key store
+fn luks_key_name(key_store: &KeyStore, osd_fsid: &uuid::Uuid) -> String {
+    match *key_store {
+        KeyStore::Monitor => format!("dm-crypt/osd/{}/luks", osd_fsid),
+        KeyStore::Vault => format!("bynar/dmcrypt/{}", osd_fsid),
     }
+}
 
-    fn safe_to_remove(&self, _device: &Path, _simulate: bool) -> BynarResult<bool> {
-        let diag_map = DiagMap::new().map_err(|e| BynarError::new(e.to_string()))?;
-        debug!("Checking if a disk is safe to remove from ceph");
-        match diag_map.exhaustive_diag(Format::Json) {
-            Status::Safe => Ok(true),
-            Status::NonSafe => Ok(false),
-            Status::Unknown => Ok(false),
+fn vault_settings(dmcrypt: &DmcryptConfig) -> BynarResult<(&str, &str)> {
+    match (&dmcrypt.vault_endpoint, &dmcrypt.vault_token) {
+        (Some(endpoint), Some(token)) => Ok((endpoint, token)),
+        _ => Err(BynarError::from(
+            "vault_endpoint and vault_token must be set to keep dmcrypt keys in vault",
+        )),
+    }
+}
+
+// luksFormat a logical volume and open it.  The mapper device is named
+// after the logical volume
+fn luks_open(lv: &Path, key: &str, simulate: bool) -> BynarResult<PathBuf> {
+    let lv_str = lv.to_string_lossy().into_owned();
+    let name = lv
+        .file_name()
+        .ok_or_else(|| BynarError::new(format!("{} has no file name", lv.display())))?
+        .to_string_lossy()
+        .into_owned();
+    debug!("Encrypting {} as {}", lv.display(), name);
+    run_cryptsetup(
+        &["--batch-mode", "--key-file", "-", "luksFormat", &lv_str],
+        Some(key),
+        simulate,
+    )?;
+    run_cryptsetup(
+        &[
+            "--key-file",
+            "-",
+            "--allow-discards",
+            "luksOpen",
+            &lv_str,
+            &name,
+        ],
+        Some(key),
+        simulate,
+    )?;
+    Ok(Path::new("/dev/mapper").join(name))
+}
+
+// Run cryptsetup passing the key on stdin
+fn run_cryptsetup(args: &[&str], key: Option<&str>, simulate: bool) -> BynarResult<()> {
+    debug!("cmd: cryptsetup {:?}", args);
+    if simulate {
+        return Ok(());
+    }
+    let mut child = Command::new("cryptsetup")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let (Some(key), Some(stdin)) = (key, child.stdin.as_mut()) {
+        stdin.write_all(key.as_bytes())?;
+    }
+    // This closes stdin before waiting
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        error!("cryptsetup cmd failed: {}", stderr);
+        return Err(BynarError::new(stderr));
+    }
+    Ok(())
+}
+
+// The CRUSH device class for a type of media
+fn device_class(media_type: &block_utils::MediaType) -> Option<&'static str> {
+    match *media_type {
+        block_utils::MediaType::SolidState => Some("ssd"),
+        block_utils::MediaType::Rotational => Some("hdd"),
+        block_utils::MediaType::NVME => Some("nvme"),
+        _ => None,
+    }
+}
+
+// bucket_type=name arguments for placing an osd in the crushmap
+fn crush_location_args(
+    location: Option<&BTreeMap<String, String>>,
+    hostname: &str,
+    region: Option<&str>,
+    host_in_crush: bool,
+) -> Vec<String> {
+    let mut buckets = location.cloned().unwrap_or_default();
+    buckets
+        .entry("host".to_string())
+        .or_insert_with(|| hostname.to_string());
+    if let Some(region) = region {
+        buckets
+            .entry("datacenter".to_string())
+            .or_insert_with(|| region.to_string());
+    }
+    // Without a root ceph leaves the host bucket wherever it already is.
+    // A new host bucket needs one to be placed under
+    if !host_in_crush {
+        buckets
+            .entry("root".to_string())
+            .or_insert_with(|| "default".to_string());
+    }
+    buckets
+        .iter()
+        .map(|(bucket_type, name)| format!("{}={}", bucket_type, name))
+        .collect()
+}
+
+fn now() -> BynarResult<u64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| BynarError::new(e.to_string()))?
+        .as_secs())
+}
+
+fn stops_data_movement(flag: &str) -> bool {
+    DATA_MOVEMENT_FLAGS.contains(&flag)
+}
+
+// The flag settings from the cleared records that none of the remaining
+// records still need
+fn unneeded_flags(cleared: &[FlagRecord], remaining: &[FlagRecord]) -> Vec<FlagSetting> {
+    let mut unneeded: Vec<FlagSetting> = Vec::new();
+    for setting in cleared.iter().flat_map(|r| r.settings.iter()) {
+        let still_needed = remaining
+            .iter()
+            .any(|r| r.settings.iter().any(|s| s == setting));
+        if !still_needed && !unneeded.contains(setting) {
+            unneeded.push(setting.clone());
         }
     }
+    unneeded
+}
+
+// Destroyed osd ids from the osd tree json.  Their CRUSH entries are kept
+// so the tree still lists them
+fn destroyed_osds(osd_tree: &str) -> BynarResult<Vec<u64>> {
+    let v: serde_json::Value = serde_json::from_str(osd_tree)?;
+    let nodes = v["nodes"]
+        .as_array()
+        .ok_or_else(|| BynarError::from("osd tree is missing nodes"))?;
+    Ok(nodes
+        .iter()
+        .filter(|n| n["status"].as_str() == Some("destroyed"))
+        .filter_map(|n| n["id"].as_u64())
+        .collect())
+}
+
+// The name of the host bucket holding the osd in the osd tree json
+fn crush_host_of(osd_tree: &str, osd_id: u64) -> BynarResult<Option<String>> {
+    let v: serde_json::Value = serde_json::from_str(osd_tree)?;
+    let nodes = v["nodes"]
+        .as_array()
+        .ok_or_else(|| BynarError::from("osd tree is missing nodes"))?;
+    Ok(nodes
+        .iter()
+        .filter(|n| n["type"].as_str() == Some("host"))
+        .find(|n| {
+            n["children"]
+                .as_array()
+                .map(|c| c.iter().any(|id| id.as_i64() == Some(osd_id as i64)))
+                .unwrap_or(false)
+        })
+        .and_then(|n| n["name"].as_str())
+        .map(|name| name.to_string()))
+}
+
+// Whether the osd tree json has a bucket of this type and name
+fn crush_bucket_exists(osd_tree: &str, bucket_type: &str, name: &str) -> BynarResult<bool> {
+    let v: serde_json::Value = serde_json::from_str(osd_tree)?;
+    let nodes = v["nodes"]
+        .as_array()
+        .ok_or_else(|| BynarError::from("osd tree is missing nodes"))?;
+    Ok(nodes
+        .iter()
+        .any(|n| n["type"].as_str() == Some(bucket_type) && n["name"].as_str() == Some(name)))
+}
+
+// ceph's own tools name host buckets after the short hostname
+fn short_hostname(hostname: &str) -> &str {
+    hostname.split('.').next().unwrap_or(hostname)
+}
+
+// Check the pg stat json to see if every placement group is active+clean
+fn pgs_active_clean(pg_stat: &str) -> BynarResult<bool> {
+    let v: serde_json::Value = serde_json::from_str(pg_stat)?;
+    // Nautilus and newer nest the counts under pg_summary
+    let summary = if v["pg_summary"].is_object() {
+        &v["pg_summary"]
+    } else {
+        &v
+    };
+    let num_pgs = summary["num_pgs"]
+        .as_u64()
+        .ok_or_else(|| BynarError::from("pg stat is missing num_pgs"))?;
+    let states = summary["num_pg_by_state"]
+        .as_array()
+        .ok_or_else(|| BynarError::from("pg stat is missing num_pg_by_state"))?;
+    let clean: u64 = states
+        .iter()
+        .filter(|s| s["name"].as_str().map(pg_state_clean).unwrap_or(false))
+        .filter_map(|s| s["num"].as_u64())
+        .sum();
+    Ok(clean == num_pgs)
+}
+
+// Whether a placement group in this state has all its data where it
+// belongs.  Scrubbing and other background work don't matter
+fn pg_state_clean(state: &str) -> bool {
+    let parts: Vec<&str> = state.split('+').collect();
+    parts.contains(&"active")
+        && parts.contains(&"clean")
+        && !parts.iter().any(|p| UNCLEAN_PG_STATES.contains(p))
+}
+
+// How many placement groups in the pg stat json are recovering or
+// backfilling
+fn recovering_pgs(pg_stat: &str) -> BynarResult<u64> {
+    let v: serde_json::Value = serde_json::from_str(pg_stat)?;
+    let summary = if v["pg_summary"].is_object() {
+        &v["pg_summary"]
+    } else {
+        &v
+    };
+    let states = summary["num_pg_by_state"]
+        .as_array()
+        .ok_or_else(|| BynarError::from("pg stat is missing num_pg_by_state"))?;
+    Ok(states
+        .iter()
+        .filter(|s| {
+            s["name"]
+                .as_str()
+                .map(|n| n.contains("recover") || n.contains("backfill"))
+                .unwrap_or(false)
+        })
+        .filter_map(|s| s["num"].as_u64())
+        .sum())
+}
+
+// The cluster wide flags from the osd dump json.  Ex: noout, noin
+fn cluster_flags(osd_dump: &str) -> BynarResult<Vec<String>> {
+    let v: serde_json::Value = serde_json::from_str(osd_dump)?;
+    let flags = v["flags"]
+        .as_str()
+        .ok_or_else(|| BynarError::from("osd dump is missing flags"))?;
+    Ok(flags
+        .split(',')
+        .filter(|f| !f.is_empty())
+        .map(|f| f.to_string())
+        .collect())
+}
+
+// The bucket_type=name locations missing from the osd tree json.  The host
+// bucket is left out because ceph creates it with the first osd
+fn missing_crush_buckets(osd_tree: &str, location: &[String]) -> BynarResult<Vec<String>> {
+    let v: serde_json::Value = serde_json::from_str(osd_tree)?;
+    let nodes = v["nodes"]
+        .as_array()
+        .ok_or_else(|| BynarError::from("osd tree is missing nodes"))?;
+    Ok(location
+        .iter()
+        .filter(|l| {
+            let mut parts = l.splitn(2, '=');
+            let bucket_type = parts.next().unwrap_or("");
+            let name = parts.next().unwrap_or("");
+            bucket_type != "host"
+                && !nodes.iter().any(|n| {
+                    n["type"].as_str() == Some(bucket_type) && n["name"].as_str() == Some(name)
+                })
+        })
+        .cloned()
+        .collect())
+}
+
+// Pull the osd id out of the ceph-volume lvm tags
+fn osd_ids_from_tags(tags: &[String]) -> BynarResult<Option<u64>> {
+    match tags.iter().find(|t| t.starts_with("ceph.osd_id=")) {
+        Some(tag) => Ok(Some(u64::from_str(&tag["ceph.osd_id=".len()..])?)),
+        None => Ok(None),
+    }
+}
+
+// Which of the osd ids are marked down or out in the osd dump json
+fn down_or_out_osds(osd_dump: &str, osd_ids: &[u64]) -> BynarResult<Vec<u64>> {
+    let v: serde_json::Value = serde_json::from_str(osd_dump)?;
+    let osds = v["osds"]
+        .as_array()
+        .ok_or_else(|| BynarError::from("osd dump is missing osds"))?;
+    Ok(osds
+        .iter()
+        .filter(|o| o["up"].as_u64() == Some(0) || o["in"].as_u64() == Some(0))
+        .filter_map(|o| o["osd"].as_u64())
+        .filter(|id| osd_ids.contains(id))
+        .collect())
 }
 
 // A fallback function to get the osd id from the mount path.  This isn't
@@ -745,6 +2903,7 @@ fn get_osd_id(path: &Path, simulate: bool) -> BynarResult<u64> {
 }
 
 fn save_keyring(
+    base_dir: &Path,
     osd_id: u64,
     key: &str,
     uid: Option<u32>,
@@ -753,8 +2912,7 @@ fn save_keyring(
 ) -> BynarResult<()> {
     let uid = uid.and_then(|u| Some(Uid::from_raw(u)));
     let gid = gid.and_then(|g| Some(Gid::from_raw(g)));
-    let base_dir = Path::new("/var/lib/ceph/osd").join(&format!("ceph-{}", osd_id));
-    if !Path::new(&base_dir).exists() {
+    if !base_dir.exists() {
         return Err(BynarError::new(format!(
             "{} directory doesn't exist",
             base_dir.display()
@@ -771,7 +2929,7 @@ fn save_keyring(
 
 fn add_osd_to_fstab(
     device_info: &block_utils::Device,
-    osd_id: u64,
+    mount_point: &Path,
     simulate: bool,
 ) -> BynarResult<()> {
     let fstab = FsTab::default();
@@ -780,7 +2938,7 @@ fn add_osd_to_fstab(
             "UUID={}",
             device_info.id.unwrap().to_hyphenated().to_string()
         ),
-        mountpoint: PathBuf::from(&format!("/var/lib/ceph/osd/ceph-{}", osd_id)),
+        mountpoint: mount_point.to_path_buf(),
         vfs_type: device_info.fs_type.to_string(),
         mount_options: vec![
             "noatime".into(),
@@ -864,12 +3022,24 @@ fn partition_in_use(partition_uuid: &uuid::Uuid) -> BynarResult<bool> {
     Ok(false)
 }
 
-fn systemctl_disable(osd_id: u64, osd_uuid: &uuid::Uuid, simulate: bool) -> BynarResult<()> {
+// ceph-volume only activates osds of the default cluster.  Osds of other
+// clusters are enabled through their own cluster's osd unit
+fn osd_unit(cluster: &str, osd_id: u64, osd_uuid: &uuid::Uuid) -> String {
+    if cluster == "ceph" {
+        format!("ceph-volume@lvm-{}-{}", osd_id, osd_uuid.to_hyphenated())
+    } else {
+        format!("{}-osd@{}", cluster, osd_id)
+    }
+}
+
+fn systemctl_disable(
+    cluster: &str,
+    osd_id: u64,
+    osd_uuid: &uuid::Uuid,
+    simulate: bool,
+) -> BynarResult<()> {
     if !simulate {
-        let args: Vec<String> = vec![
-            "disable".to_string(),
-            format!("ceph-volume@lvm-{}-{}", osd_id, osd_uuid.to_hyphenated()),
-        ];
+        let args: Vec<String> = vec!["disable".to_string(), osd_unit(cluster, osd_id, osd_uuid)];
         debug!("cmd: systemctl {:?}", args);
         let output = Command::new("systemctl").args(&args).output()?;
         if !output.status.success() {
@@ -881,12 +3051,14 @@ fn systemctl_disable(osd_id: u64, osd_uuid: &uuid::Uuid, simulate: bool) -> Byna
     Ok(())
 }
 
-fn systemctl_enable(osd_id: u64, osd_uuid: &uuid::Uuid, simulate: bool) -> BynarResult<()> {
+fn systemctl_enable(
+    cluster: &str,
+    osd_id: u64,
+    osd_uuid: &uuid::Uuid,
+    simulate: bool,
+) -> BynarResult<()> {
     if !simulate {
-        let args: Vec<String> = vec![
-            "enable".to_string(),
-            format!("ceph-volume@lvm-{}-{}", osd_id, osd_uuid.to_hyphenated()),
-        ];
+        let args: Vec<String> = vec!["enable".to_string(), osd_unit(cluster, osd_id, osd_uuid)];
         debug!("cmd: systemctl {:?}", args);
         let output = Command::new("systemctl").args(&args).output()?;
         if !output.status.success() {
@@ -898,9 +3070,12 @@ fn systemctl_enable(osd_id: u64, osd_uuid: &uuid::Uuid, simulate: bool) -> Bynar
     Ok(())
 }
 
-fn systemctl_stop(osd_id: u64, simulate: bool) -> BynarResult<()> {
+fn systemctl_stop(cluster: &str, osd_id: u64, simulate: bool) -> BynarResult<()> {
     if !simulate {
-        let args: Vec<String> = vec!["stop".to_string(), format!("ceph-osd@{}.service", osd_id)];
+        let args: Vec<String> = vec![
+            "stop".to_string(),
+            format!("{}-osd@{}.service", cluster, osd_id),
+        ];
         debug!("cmd: systemctl {:?}", args);
         let output = Command::new("systemctl").args(&args).output()?;
         if !output.status.success() {
@@ -912,15 +3087,20 @@ fn systemctl_stop(osd_id: u64, simulate: bool) -> BynarResult<()> {
     Ok(())
 }
 
-fn setup_osd_init(osd_id: u64, simulate: bool) -> BynarResult<()> {
+// Mark the osd dir with the init system that manages it, the way
+// ceph-disk did, and start the osd
+fn setup_osd_init(cluster: &str, osd_dir: &Path, osd_id: u64, simulate: bool) -> BynarResult<()> {
     debug!("Detecting init system");
     let init_daemon = detect_daemon()?;
     match init_daemon {
         Daemon::Systemd => {
             debug!("Systemd detected.  Starting OSD");
+            if !simulate {
+                File::create(osd_dir.join("systemd"))?;
+            }
             let mut cmd = Command::new("systemctl");
             cmd.arg("start");
-            cmd.arg(format!("ceph-osd@{}", osd_id));
+            cmd.arg(format!("{}-osd@{}", cluster, osd_id));
             debug!("cmd: {:?}", cmd);
             if !simulate {
                 let output = cmd.output()?;
@@ -934,8 +3114,12 @@ fn setup_osd_init(osd_id: u64, simulate: bool) -> BynarResult<()> {
         }
         Daemon::Upstart => {
             debug!("Upstart detected.  Starting OSD");
+            if !simulate {
+                File::create(osd_dir.join("upstart"))?;
+            }
             let mut cmd = Command::new("start");
             cmd.arg("ceph-osd");
+            cmd.arg(format!("cluster={}", cluster));
             cmd.arg(format!("id={}", osd_id));
             debug!("cmd: {:?}", cmd);
             if !simulate {
@@ -966,6 +3150,7 @@ fn settle_udev() -> BynarResult<()> {
 
 // Run ceph-osd --mkfs and return the osd UUID
 fn ceph_mkfs(
+    cluster: &str,
     osd_id: u64,
     journal: Option<&JournalDevice>,
     bluestore: bool,
@@ -974,12 +3159,13 @@ fn ceph_mkfs(
     osd_uuid: Option<&uuid::Uuid>,
     user_id: Option<&str>,
     group_id: Option<&str>,
+    volumes: &[BluestoreVolume],
     simulate: bool,
 ) -> BynarResult<()> {
     debug!("Running ceph-osd --mkfs");
     let mut args: Vec<String> = vec![
         "--cluster".to_string(),
-        "ceph".to_string(),
+        cluster.to_string(),
         "-i".to_string(),
         osd_id.to_string(),
         "--mkfs".to_string(),
@@ -1011,6 +3197,10 @@ fn ceph_mkfs(
         args.push("--setgroup".to_string());
         args.push(g_id.to_string());
     }
+    for volume in volumes {
+        args.push(format!("--bluestore-block-{}-path", volume.kind));
+        args.push(volume.path.to_string_lossy().into_owned());
+    }
 
     debug!("cmd: ceph-osd {:?}", args);
     if simulate {
@@ -1029,10 +3219,16 @@ fn ceph_mkfs(
     Ok(())
 }
 
-fn ceph_bluestore_tool(device: &Path, mount_path: &Path, simulate: bool) -> BynarResult<()> {
+fn ceph_bluestore_tool(
+    cluster: &str,
+    device: &Path,
+    mount_path: &Path,
+    simulate: bool,
+) -> BynarResult<()> {
     let dev_str = device.to_string_lossy().into_owned();
     let mnt_str = mount_path.to_string_lossy().into_owned();
-    let mut args: Vec<&str> = vec!["--cluster=ceph", "prime-osd-dir"];
+    let cluster_arg = format!("--cluster={}", cluster);
+    let mut args: Vec<&str> = vec![&cluster_arg, "prime-osd-dir"];
 
     args.push("--dev");
     args.push(&dev_str);
@@ -1,36 +1,634 @@
+use std::collections::BTreeMap;
+use std::fs::{canonicalize, create_dir_all, read_dir, read_to_string, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
 use crate::backend::Backend;
 
+use fstab::FsTab;
+use gluster::get_local_ip;
+use gluster::volume::{volume_info, volume_list, volume_status, BrickStatus, VolumeType};
 use helpers::error::*;
-use std::path::Path;
-
-pub struct GlusterBackend;
-/*
-    Add a disk
-    0. Make sure the brick pid isn't running
-    1. Need to know which brick we're replacing.
-      a. Pull from sqlite3
-    2. Create a dir on the gluster mount that doesn't exist
-    Using Temp
-         mkdir /mnt/r2/<name-of-nonexistent-dir>
-         rmdir /mnt/r2/<name-of-nonexistent-dir>
-         setfattr -n trusted.non-existent-key -v abc /mnt/r2
-         setfattr -x trusted.non-existent-key  /mnt/r2
-
-    Remove a disk
-    1. Kill the brick pid
-    2. wipe it
-    3. Record in sqlite where it was mounted so we can replace it
-    later
-*/
+use hostname::get_hostname;
+use log::{debug, error, info};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde_derive::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_brick_layout() {
+        // One replica or arbiter set spans every brick
+        assert_eq!(
+            brick_layout("test", &VolumeType::Arbiter, 3, None).unwrap(),
+            BrickLayout::Replicate { replica_count: 3 }
+        );
+        // Sets of a distributed volume need their size from gluster.json
+        assert!(brick_layout("test", &VolumeType::DistributedAndReplicate, 6, None).is_err());
+        let config = VolumeConfig {
+            subvolume_size: Some(3),
+            redundancy: None,
+        };
+        assert_eq!(
+            brick_layout(
+                "test",
+                &VolumeType::DistributedAndReplicate,
+                6,
+                Some(&config)
+            )
+            .unwrap(),
+            BrickLayout::Replicate { replica_count: 3 }
+        );
+        let config = VolumeConfig {
+            subvolume_size: None,
+            redundancy: Some(2),
+        };
+        assert_eq!(
+            brick_layout("test", &VolumeType::Disperse, 6, Some(&config)).unwrap(),
+            BrickLayout::Disperse {
+                disperse_count: 6,
+                redundancy: 2
+            }
+        );
+        assert!(brick_layout("test", &VolumeType::Disperse, 6, None).is_err());
+        assert_eq!(
+            brick_layout("test", &VolumeType::Distribute, 4, None).unwrap(),
+            BrickLayout::Distribute
+        );
+    }
+
+    #[test]
+    fn test_keeps_quorum() {
+        let replica = BrickLayout::Replicate { replica_count: 3 };
+        // All up, removing one leaves 2 of 3
+        assert!(keeps_quorum(&replica, &[true, true, true], 1));
+        // One already down, removing another leaves 1 of 3
+        assert!(!keeps_quorum(&replica, &[true, false, true], 2));
+
+        // Replica 2 keeps quorum only if the first brick stays up
+        let replica = BrickLayout::Replicate { replica_count: 2 };
+        assert!(keeps_quorum(&replica, &[true, true], 1));
+        assert!(!keeps_quorum(&replica, &[true, true], 0));
+
+        let disperse = BrickLayout::Disperse {
+            disperse_count: 6,
+            redundancy: 2,
+        };
+        assert!(keeps_quorum(
+            &disperse,
+            &[true, false, true, true, true, true],
+            0
+        ));
+        assert!(!keeps_quorum(
+            &disperse,
+            &[true, false, false, true, true, true],
+            0
+        ));
+
+        assert!(!keeps_quorum(&BrickLayout::Distribute, &[true], 0));
+    }
+
+    #[test]
+    fn test_parse_gluster_version() {
+        let output = "glusterfs 3.12.2\nRepository revision: git://git.gluster.org/glusterfs.git";
+        assert_eq!(parse_gluster_version(output).unwrap(), (3, 12));
+        let output = "glusterfs 6.0\n";
+        assert_eq!(parse_gluster_version(output).unwrap(), (6, 0));
+    }
+}
+
+/// Gluster cluster.  Bricks removed from a failed disk are recorded in
+/// gluster_bricks.json under the config dir so the replacement disk can
+/// be mounted back where the old brick lived.  The records are keyed by
+/// brick path and remember the disk slot because /dev names can change
+/// across reboots.
+pub struct GlusterBackend {
+    state_file: PathBuf,
+    config: GlusterConfig,
+    /// host:/brick/path entries from the Operation.  Used to find the brick
+    /// a new disk replaces when there's no local record of it
+    replica_set: Vec<String>,
+}
+
+/// Optional gluster.json.  The gluster API reports a volume's type but not
+/// how its bricks are grouped so distributed volumes need it spelled out
+#[derive(Clone, Debug, Default, Deserialize)]
+struct GlusterConfig {
+    #[serde(default)]
+    volumes: BTreeMap<String, VolumeConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct VolumeConfig {
+    /// Bricks in each replica or disperse set
+    subvolume_size: Option<usize>,
+    /// How many bricks a disperse set can lose
+    redundancy: Option<usize>,
+}
+
+/// Where a brick on a removed disk lived
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct BrickRecord {
+    volume: String,
+    /// The host name gluster knows this brick by
+    host: String,
+    brick_path: PathBuf,
+    mount_point: PathBuf,
+    /// The /dev/disk/by-path name of the slot the disk was in.  The
+    /// replacement shows up in the same slot
+    slot: String,
+}
+
+/// How bricks are grouped into subvolumes
+#[derive(Debug, PartialEq)]
+enum BrickLayout {
+    Distribute,
+    Replicate {
+        replica_count: usize,
+    },
+    Disperse {
+        disperse_count: usize,
+        redundancy: usize,
+    },
+}
+
+impl BrickLayout {
+    fn subvolume_size(&self) -> usize {
+        match *self {
+            BrickLayout::Distribute => 1,
+            BrickLayout::Replicate { replica_count } => replica_count,
+            BrickLayout::Disperse { disperse_count, .. } => disperse_count,
+        }
+    }
+}
+
+// Work out how a volume's bricks are grouped from its type.  Sets that
+// don't span the whole volume are sized from gluster.json
+fn brick_layout(
+    volume: &str,
+    vol_type: &VolumeType,
+    brick_count: usize,
+    config: Option<&VolumeConfig>,
+) -> BynarResult<BrickLayout> {
+    let subvolume_size = config.and_then(|c| c.subvolume_size);
+    let distributed_size = || {
+        subvolume_size.ok_or_else(|| {
+            BynarError::new(format!(
+                "Volume {} is distributed.  Set its subvolume_size in gluster.json",
+                volume
+            ))
+        })
+    };
+    match *vol_type {
+        // Arbiter bricks count towards quorum
+        VolumeType::Replicate | VolumeType::Arbiter => Ok(BrickLayout::Replicate {
+            replica_count: subvolume_size.unwrap_or(brick_count),
+        }),
+        VolumeType::DistributedAndReplicate
+        | VolumeType::StripedAndReplicate
+        | VolumeType::DistributedAndStripedAndReplicate => Ok(BrickLayout::Replicate {
+            replica_count: distributed_size()?,
+        }),
+        VolumeType::Disperse | VolumeType::DistributedAndDisperse => {
+            let disperse_count = match *vol_type {
+                VolumeType::Disperse => subvolume_size.unwrap_or(brick_count),
+                _ => distributed_size()?,
+            };
+            let redundancy = config.and_then(|c| c.redundancy).ok_or_else(|| {
+                BynarError::new(format!(
+                    "Volume {} is dispersed.  Set its redundancy in gluster.json",
+                    volume
+                ))
+            })?;
+            Ok(BrickLayout::Disperse {
+                disperse_count,
+                redundancy,
+            })
+        }
+        VolumeType::Distribute | VolumeType::Stripe | VolumeType::DistributedAndStripe => {
+            Ok(BrickLayout::Distribute)
+        }
+    }
+}
+
+// Check if a subvolume still has quorum after the brick at index is
+// taken away.  online holds the status of every brick in the subvolume.
+fn keeps_quorum(layout: &BrickLayout, online: &[bool], index: usize) -> bool {
+    let remaining = online
+        .iter()
+        .enumerate()
+        .filter(|&(i, up)| i != index && *up)
+        .count();
+    match *layout {
+        // No redundancy.  The data goes with the brick
+        BrickLayout::Distribute => false,
+        BrickLayout::Replicate { replica_count } => {
+            // Client quorum auto: more than half the bricks or exactly
+            // half including the first brick
+            remaining * 2 > replica_count
+                || (remaining * 2 == replica_count && index != 0 && online[0])
+        }
+        BrickLayout::Disperse {
+            disperse_count,
+            redundancy,
+        } => remaining >= disperse_count - redundancy,
+    }
+}
+
+fn parse_gluster_version(output: &str) -> BynarResult<(u32, u32)> {
+    let version = output
+        .lines()
+        .next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .ok_or_else(|| BynarError::from("Unable to find gluster version"))?;
+    let mut parts = version.split('.');
+    let major = u32::from_str(parts.next().unwrap_or(""))?;
+    let minor = u32::from_str(parts.next().unwrap_or("0"))?;
+    Ok((major, minor))
+}
+
+fn gluster_version() -> BynarResult<(u32, u32)> {
+    let output = Command::new("gluster").arg("--version").output()?;
+    parse_gluster_version(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn run_gluster(args: &[&str], simulate: bool) -> BynarResult<String> {
+    debug!("cmd: gluster {:?}", args);
+    if simulate {
+        return Ok(String::new());
+    }
+    let output = Command::new("gluster")
+        .arg("--mode=script")
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        return Err(BynarError::new(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// The names gluster could know this host by
+fn local_names() -> Vec<String> {
+    let mut names = Vec::new();
+    if let Some(hostname) = get_hostname() {
+        names.push(hostname);
+    }
+    if let Ok(ip) = get_local_ip() {
+        names.push(ip.to_string());
+    }
+    names
+}
+
+// Every brick this host serves along with its volume
+fn local_bricks() -> BynarResult<Vec<(String, BrickStatus)>> {
+    let names = local_names();
+    let mut bricks = Vec::new();
+    for volume in volume_list().unwrap_or_else(Vec::new) {
+        for status in volume_status(&volume)? {
+            if names.contains(&status.brick.peer.hostname) {
+                bricks.push((volume.clone(), status));
+            }
+        }
+    }
+    Ok(bricks)
+}
+
+// The by-path link names the port the disk is plugged into so it stays
+// the same across reboots and for a replacement in the same slot.  Falls
+// back to the device path when udev didn't create one
+fn slot_id(device: &Path) -> String {
+    let target = canonicalize(device).unwrap_or_else(|_| device.to_path_buf());
+    if let Ok(entries) = read_dir("/dev/disk/by-path") {
+        for entry in entries.filter_map(|e| e.ok()) {
+            if canonicalize(entry.path()).ok().as_ref() == Some(&target) {
+                return entry.file_name().to_string_lossy().into_owned();
+            }
+        }
+    }
+    device.display().to_string()
+}
+
+fn device_mount(device: &Path) -> BynarResult<PathBuf> {
+    block_utils::get_mountpoint(device)?.ok_or_else(|| {
+        BynarError::new(format!(
+            "{} is not mounted.  Unable to find its bricks",
+            device.display()
+        ))
+    })
+}
+
+fn add_brick_to_fstab(
+    device_info: &block_utils::Device,
+    mount_point: &Path,
+    simulate: bool,
+) -> BynarResult<()> {
+    let id = device_info
+        .id
+        .ok_or_else(|| BynarError::new(format!("{} has no filesystem UUID", device_info.name)))?;
+    let fstab = FsTab::default();
+    let fstab_entry = fstab::FsEntry {
+        fs_spec: format!("UUID={}", id.to_hyphenated().to_string()),
+        mountpoint: mount_point.to_path_buf(),
+        vfs_type: device_info.fs_type.to_string(),
+        mount_options: vec!["noatime".into(), "inode64".into()],
+        dump: false,
+        fsck_order: 2,
+    };
+    debug!("Saving Fstab entry {:?}", fstab_entry);
+    if !simulate {
+        fstab.add_entry(fstab_entry)?;
+    }
+    Ok(())
+}
+
+impl GlusterBackend {
+    pub fn new(config_dir: Option<&Path>, replica_set: &[String]) -> BynarResult<GlusterBackend> {
+        let dir = config_dir.unwrap_or_else(|| Path::new("/etc/bynar"));
+        let config_file = dir.join("gluster.json");
+        let config = if config_file.exists() {
+            serde_json::from_str(&read_to_string(&config_file)?)?
+        } else {
+            GlusterConfig::default()
+        };
+        Ok(GlusterBackend {
+            state_file: dir.join("gluster_bricks.json"),
+            config,
+            replica_set: replica_set.to_vec(),
+        })
+    }
+
+    fn load_records(&self) -> BynarResult<BTreeMap<String, BrickRecord>> {
+        if !self.state_file.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let s = read_to_string(&self.state_file)?;
+        Ok(serde_json::from_str(&s)?)
+    }
+
+    fn save_records(&self, records: &BTreeMap<String, BrickRecord>) -> BynarResult<()> {
+        let mut f = File::create(&self.state_file)?;
+        f.write_all(serde_json::to_string_pretty(records)?.as_bytes())?;
+        Ok(())
+    }
+
+    // Find out which bricks a new disk is replacing.  Either from our own
+    // record of the removal or from the replica_set given in the request
+    fn bricks_to_replace(&self, device: &Path) -> BynarResult<Vec<BrickRecord>> {
+        let slot = slot_id(device);
+        let records: Vec<BrickRecord> = self
+            .load_records()?
+            .into_iter()
+            .map(|(_, record)| record)
+            .filter(|record| record.slot == slot)
+            .collect();
+        if !records.is_empty() {
+            return Ok(records);
+        }
+        let names = local_names();
+        let mut records = Vec::new();
+        for entry in &self.replica_set {
+            let mut parts = entry.splitn(2, ':');
+            let host = parts.next().unwrap_or("");
+            let brick_path = match parts.next() {
+                Some(p) => PathBuf::from(p),
+                None => {
+                    return Err(BynarError::new(format!(
+                        "Invalid replica_set entry {}.  Expected host:/brick/path",
+                        entry
+                    )))
+                }
+            };
+            if !names.iter().any(|n| n == host) {
+                continue;
+            }
+            for volume in volume_list().unwrap_or_else(Vec::new) {
+                let info = volume_info(&volume)?;
+                if info
+                    .bricks
+                    .iter()
+                    .any(|b| b.peer.hostname == host && b.path == brick_path)
+                {
+                    // Assume the brick is one directory below the mount
+                    let mount_point = brick_path
+                        .parent()
+                        .unwrap_or_else(|| Path::new("/"))
+                        .to_path_buf();
+                    records.push(BrickRecord {
+                        volume: volume.clone(),
+                        host: host.to_string(),
+                        brick_path: brick_path.clone(),
+                        mount_point,
+                        slot: slot.clone(),
+                    });
+                }
+            }
+        }
+        if records.is_empty() {
+            return Err(BynarError::new(format!(
+                "No record of bricks previously on {} and no local replica_set entry given",
+                device.display()
+            )));
+        }
+        Ok(records)
+    }
+
+    // Check that every subvolume holding a brick on this device keeps
+    // quorum without it
+    fn bricks_keep_quorum(&self, device: &Path) -> BynarResult<(bool, Option<String>)> {
+        let mount = device_mount(device)?;
+        let bricks: Vec<(String, BrickStatus)> = local_bricks()?
+            .into_iter()
+            .filter(|(_, status)| status.brick.path.starts_with(&mount))
+            .collect();
+        if bricks.is_empty() {
+            debug!("No gluster bricks found on {}", device.display());
+            return Ok((true, None));
+        }
+        for (volume, brick) in bricks {
+            let info = volume_info(&volume)?;
+            let layout = brick_layout(
+                &volume,
+                &info.vol_type,
+                info.bricks.len(),
+                self.config.volumes.get(&volume),
+            )?;
+            let statuses = volume_status(&volume)?;
+            let index = match statuses.iter().position(|s| {
+                s.brick.peer.hostname == brick.brick.peer.hostname
+                    && s.brick.path == brick.brick.path
+            }) {
+                Some(i) => i,
+                None => {
+                    return Err(BynarError::new(format!(
+                        "Brick {}:{} missing from volume {} status",
+                        brick.brick.peer.hostname,
+                        brick.brick.path.display(),
+                        volume
+                    )))
+                }
+            };
+            // Bricks are listed in subvolume order
+            let size = layout.subvolume_size();
+            let start = index / size * size;
+            let online: Vec<bool> = statuses
+                .iter()
+                .skip(start)
+                .take(size)
+                .map(|s| s.online)
+                .collect();
+            if !keeps_quorum(&layout, &online, index - start) {
+                let reason = format!(
+                    "Removing {}:{} would break quorum for volume {}",
+                    brick.brick.peer.hostname,
+                    brick.brick.path.display(),
+                    volume
+                );
+                info!("{}", reason);
+                return Ok((false, Some(reason)));
+            }
+        }
+        Ok((true, None))
+    }
+}
 
 impl Backend for GlusterBackend {
-    fn add_disk(&self, _device: &Path, _id: Option<u64>, _simulate: bool) -> BynarResult<()> {
+    /// Format and mount the new disk where the old brick lived, reset the
+    /// brick so gluster uses it again and heal the data back onto it.
+    fn add_disk(&self, device: &Path, _id: Option<u64>, simulate: bool) -> BynarResult<()> {
+        let records = self.bricks_to_replace(device)?;
+        let mount_point = records[0].mount_point.clone();
+
+        let xfs_options = block_utils::Filesystem::Xfs {
+            stripe_size: None,
+            stripe_width: None,
+            block_size: None,
+            agcount: None,
+            inode_size: Some(512),
+            force: true,
+        };
+        debug!(
+            "Formatting {:?} with XFS options: {:?}",
+            device, xfs_options
+        );
+        if !simulate {
+            block_utils::format_block_device(device, &xfs_options)?;
+        }
+        let info = block_utils::get_device_info(device)?;
+        if !simulate {
+            if !mount_point.exists() {
+                create_dir_all(&mount_point)?;
+            }
+            block_utils::mount_device(&info, &mount_point)?;
+        }
+        add_brick_to_fstab(&info, &mount_point, simulate)?;
+
+        // reset-brick showed up in 3.9.  Older versions need the brick
+        // replaced with one at a new path
+        let reset_brick = gluster_version()? >= (3, 9);
+        let mut remaining = self.load_records()?;
+        for record in &records {
+            let old_brick = format!("{}:{}", record.host, record.brick_path.display());
+            if reset_brick {
+                if !simulate {
+                    create_dir_all(&record.brick_path)?;
+                }
+                run_gluster(
+                    &[
+                        "volume",
+                        "reset-brick",
+                        &record.volume,
+                        &old_brick,
+                        &old_brick,
+                        "commit",
+                        "force",
+                    ],
+                    simulate,
+                )?;
+            } else {
+                let mut new_path = record.brick_path.clone().into_os_string();
+                new_path.push(".new");
+                let new_path = PathBuf::from(new_path);
+                if !simulate {
+                    create_dir_all(&new_path)?;
+                }
+                run_gluster(
+                    &[
+                        "volume",
+                        "replace-brick",
+                        &record.volume,
+                        &old_brick,
+                        &format!("{}:{}", record.host, new_path.display()),
+                        "commit",
+                        "force",
+                    ],
+                    simulate,
+                )?;
+            }
+            debug!("Starting a full heal of {}", record.volume);
+            run_gluster(&["volume", "heal", &record.volume, "full"], simulate)?;
+        }
+        if !simulate {
+            for record in &records {
+                remaining.remove(&record.brick_path.display().to_string());
+            }
+            self.save_records(&remaining)?;
+        }
         Ok(())
     }
 
-    /// Remove a disk from a cluster
-    /// If simulate is passed no action should be taken
-    fn remove_disk(&self, _device: &Path, _simulate: bool) -> BynarResult<()> {
+    /// Kill the brick processes on the disk and record where they were
+    /// mounted so they can be replaced later
+    fn remove_disk(&self, device: &Path, simulate: bool) -> BynarResult<()> {
+        let mount = device_mount(device)?;
+        let slot = slot_id(device);
+        let mut records = Vec::new();
+        for (volume, status) in local_bricks()? {
+            if !status.brick.path.starts_with(&mount) {
+                continue;
+            }
+            debug!(
+                "Stopping brick {}:{} pid {}",
+                status.brick.peer.hostname,
+                status.brick.path.display(),
+                status.pid
+            );
+            if !simulate && status.online {
+                if let Err(e) = kill(Pid::from_raw(i32::from(status.pid)), Signal::SIGTERM) {
+                    error!("Unable to kill brick pid {}: {:?}", status.pid, e);
+                }
+            }
+            records.push(BrickRecord {
+                volume,
+                host: status.brick.peer.hostname.clone(),
+                brick_path: status.brick.path.clone(),
+                mount_point: mount.clone(),
+                slot: slot.clone(),
+            });
+        }
+        if records.is_empty() {
+            return Err(BynarError::new(format!(
+                "No gluster bricks found on {}",
+                device.display()
+            )));
+        }
+        if !simulate {
+            if let Err(e) = block_utils::unmount_device(&mount) {
+                // The disk is dead anyways
+                error!("Unable to unmount {}: {:?}", mount.display(), e);
+            }
+            let mut all_records = self.load_records()?;
+            for record in records {
+                all_records.insert(record.brick_path.display().to_string(), record);
+            }
+            self.save_records(&all_records)?;
+        }
         Ok(())
     }
 
@@ -38,7 +636,30 @@ impl Backend for GlusterBackend {
     /// If simulate is passed then this always returns true
     /// Take any actions needed with this call to figure out if a disk is safe
     /// to remove from the cluster.
-    fn safe_to_remove(&self, _device: &Path, _simulate: bool) -> BynarResult<bool> {
-        Ok(true)
+    fn safe_to_remove(&self, device: &Path, simulate: bool) -> BynarResult<(bool, Option<String>)> {
+        if simulate {
+            return Ok((true, None));
+        }
+        self.bricks_keep_quorum(device)
+    }
+
+    /// Safe to add once we know which bricks the disk replaces and their
+    /// volumes are started
+    fn safe_to_add(&self, device: &Path, simulate: bool) -> BynarResult<(bool, Option<String>)> {
+        if simulate {
+            return Ok((true, None));
+        }
+        let records = match self.bricks_to_replace(device) {
+            Ok(records) => records,
+            Err(e) => return Ok((false, Some(e.to_string()))),
+        };
+        for record in &records {
+            if volume_info(&record.volume)?.status != "Started" {
+                let reason = format!("Volume {} is not started", record.volume);
+                info!("{}", reason);
+                return Ok((false, Some(reason)));
+            }
+        }
+        Ok((true, None))
     }
 }
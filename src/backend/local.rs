@@ -0,0 +1,956 @@
+//! md raid arrays and LVM mirrors on hosts that aren't part of a storage
+//! cluster.  The disk is taken out of every array or mirror it's a member
+//! of and the replacement is partitioned like its siblings and added back.
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use crate::backend::Backend;
+
+use helpers::error::*;
+use log::{debug, error, info};
+use serde_derive::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mdstat() {
+        let mdstat = "Personalities : [raid1] [raid10]
+md0 : active raid1 sdb1[1] sda1[0]
+      1047552 blocks super 1.2 [2/2] [UU]
+
+md1 : active raid10 sdd2[3] sdc2[2](F) sdb2[1] sda2[0] sde2[4](S)
+      2095104 blocks super 1.2 512K chunks 2 near-copies [4/3] [UU_U]
+      [=>...................]  recovery =  8.5% (89088/1047552) finish=0.2min speed=89088K/sec
+
+unused devices: <none>
+";
+        let arrays = parse_mdstat(mdstat);
+        assert_eq!(arrays.len(), 2);
+        assert_eq!(arrays[0].name, "md0");
+        assert_eq!(arrays[0].level, "raid1");
+        assert_eq!(arrays[0].up, vec![true, true]);
+        assert_eq!(arrays[0].sync, None);
+        assert_eq!(
+            arrays[0].members[0],
+            MdMember {
+                name: "sdb1".to_string(),
+                role: 1,
+                failed: false,
+                spare: false,
+            }
+        );
+
+        assert_eq!(arrays[1].level, "raid10");
+        assert_eq!(arrays[1].near_copies, 2);
+        assert_eq!(arrays[1].up, vec![true, true, false, true]);
+        assert!(arrays[1].members[1].failed);
+        assert!(arrays[1].members[4].spare);
+        assert_eq!(
+            arrays[1].sync,
+            Some("recovery =  8.5% (89088/1047552) finish=0.2min speed=89088K/sec".to_string())
+        );
+    }
+
+    #[test]
+    fn test_keeps_redundancy() {
+        let mut array = MdArray {
+            name: "md0".to_string(),
+            level: "raid1".to_string(),
+            members: vec![],
+            up: vec![true, true],
+            near_copies: 2,
+            sync: None,
+        };
+        assert!(keeps_redundancy(&array, &[0]));
+        array.up = vec![false, true];
+        assert!(!keeps_redundancy(&array, &[1]));
+
+        array.level = "raid10".to_string();
+        array.up = vec![true, true, false, true];
+        // Roles 0 and 1 mirror each other as do 2 and 3
+        assert!(keeps_redundancy(&array, &[0]));
+        assert!(!keeps_redundancy(&array, &[3]));
+        assert!(!keeps_redundancy(&array, &[0, 1]));
+
+        array.level = "raid6".to_string();
+        assert!(keeps_redundancy(&array, &[0]));
+        assert!(!keeps_redundancy(&array, &[0, 1]));
+
+        array.level = "raid0".to_string();
+        array.up = vec![true, true];
+        assert!(!keeps_redundancy(&array, &[0]));
+    }
+
+    #[test]
+    fn test_lvm_mirrors() {
+        let lvs = "  vg0;root;raid1;root_rimage_0(0),root_rimage_1(0);100.00
+  vg0;[root_rimage_0];linear;/dev/sda2(1);
+  vg0;[root_rimage_1];linear;/dev/sdb2(1);
+  vg0;[root_rmeta_0];linear;/dev/sda2(0);
+  vg0;[root_rmeta_1];linear;/dev/sdb2(0);
+  vg0;swap;linear;/dev/sda3(0);
+  vg1;data;raid1;data_rimage_0(0),data_rimage_1(0),data_rimage_2(0);42.17
+  vg1;[data_rimage_0];linear;/dev/sdc(1);
+  vg1;[data_rimage_1];linear;/dev/sdd(1);
+  vg1;[data_rimage_2];linear;/dev/sde(1);
+";
+        let mirrors = lvm_mirrors(&parse_lvs(lvs));
+        assert_eq!(mirrors.len(), 2);
+        assert_eq!(mirrors[0].vg, "vg0");
+        assert_eq!(mirrors[0].lv, "root");
+        assert_eq!(
+            mirrors[0].images,
+            vec![vec!["/dev/sda2".to_string()], vec!["/dev/sdb2".to_string()]]
+        );
+        assert!(mirrors[0].synced());
+        assert_eq!(mirrors[1].images.len(), 3);
+        assert!(!mirrors[1].synced());
+    }
+
+    #[test]
+    fn test_lv_exists() {
+        // A 2-way mirror that was converted to linear when it lost a disk
+        let lvs = "  vg0;root;linear;/dev/sda2(1);
+  vg0;swap;linear;/dev/sda3(0);
+";
+        let rows = parse_lvs(lvs);
+        assert!(lvm_mirrors(&rows).is_empty());
+        assert!(lv_exists(&rows, "vg0", "root"));
+        assert!(!lv_exists(&rows, "vg0", "home"));
+        assert!(!lv_exists(&rows, "vg1", "root"));
+    }
+
+    #[test]
+    fn test_split_partition() {
+        assert_eq!(split_partition("sda1"), ("sda".to_string(), Some(1)));
+        assert_eq!(split_partition("sda"), ("sda".to_string(), None));
+        assert_eq!(
+            split_partition("nvme0n1p2"),
+            ("nvme0n1".to_string(), Some(2))
+        );
+        assert_eq!(split_partition("nvme0n1"), ("nvme0n1".to_string(), None));
+        assert_eq!(
+            partition_path(Path::new("/dev/nvme1n1"), 3),
+            PathBuf::from("/dev/nvme1n1p3")
+        );
+        assert_eq!(
+            partition_path(Path::new("/dev/sdc"), 3),
+            PathBuf::from("/dev/sdc3")
+        );
+    }
+
+    #[test]
+    fn test_strip_disk_ids() {
+        let dump = "label: gpt
+label-id: 5B0C9C2F-6A55-4A2E-9D4B-7A3C2E1F0D11
+device: /dev/sda
+unit: sectors
+
+/dev/sda1 : start=        2048, size=     2097152, type=A19D880F-05FC-4D3B-A006-743F0F84911E, uuid=0F6E1D2C-3B4A-5968-7A8B-9C0D1E2F3A4B, name=\"boot\"
+";
+        let stripped = strip_disk_ids(dump);
+        assert!(!stripped.contains("label-id"));
+        assert!(!stripped.contains("uuid="));
+        assert!(stripped.contains(
+            "/dev/sda1 : start=        2048, size=     2097152, type=A19D880F-05FC-4D3B-A006-743F0F84911E, name=\"boot\""
+        ));
+    }
+}
+
+/// Hosts with local md raid or LVM mirrors.  What a removed disk was a
+/// member of is recorded in local_disks.json under the config dir so the
+/// replacement can be added back in its place.
+pub struct LocalBackend {
+    state_file: PathBuf,
+}
+
+/// Something a removed disk was a member of
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Member {
+    Md {
+        array: String,
+        /// None if the whole disk was the member
+        partition: Option<u32>,
+    },
+    Lvm {
+        vg: String,
+        lv: String,
+        /// How many images the mirror had before the disk was removed
+        images: usize,
+        partition: Option<u32>,
+    },
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct DiskRecord {
+    /// A disk in the same arrays to copy the partition table from
+    sibling: Option<PathBuf>,
+    members: Vec<Member>,
+    /// Set once the replacement is added.  The record is kept until the
+    /// arrays and mirrors finish rebuilding onto it
+    #[serde(default)]
+    added: bool,
+}
+
+/// An md array from /proc/mdstat
+#[derive(Debug, PartialEq)]
+struct MdArray {
+    name: String,
+    level: String,
+    members: Vec<MdMember>,
+    /// Whether each role is working.  The [UU_U] part of mdstat
+    up: Vec<bool>,
+    /// Copies of each block for raid10
+    near_copies: usize,
+    /// The resync or recovery line if one is running
+    sync: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+struct MdMember {
+    name: String,
+    role: usize,
+    failed: bool,
+    spare: bool,
+}
+
+/// A row of lvs output
+#[derive(Debug, PartialEq)]
+struct LvRow {
+    vg: String,
+    lv: String,
+    segtype: String,
+    devices: Vec<String>,
+    copy_percent: Option<f64>,
+}
+
+/// A raid1 or mirror logical volume
+#[derive(Debug, PartialEq)]
+struct LvmMirror {
+    vg: String,
+    lv: String,
+    /// The physical volumes each image lives on
+    images: Vec<Vec<String>>,
+    copy_percent: Option<f64>,
+}
+
+impl LvmMirror {
+    fn synced(&self) -> bool {
+        self.copy_percent.map(|p| p >= 100.0).unwrap_or(true)
+    }
+}
+
+fn parse_mdstat(mdstat: &str) -> Vec<MdArray> {
+    let mut arrays: Vec<MdArray> = Vec::new();
+    for line in mdstat.lines() {
+        if line.starts_with("md") && line.contains(" : ") {
+            let mut parts = line.split_whitespace();
+            let name = parts.next().unwrap_or("").to_string();
+            // Skip the : and active/inactive along with things like
+            // (auto-read-only)
+            let mut parts = parts.skip(2).skip_while(|p| p.starts_with('('));
+            let level = parts.next().unwrap_or("").to_string();
+            let members = parts
+                .filter_map(|p| {
+                    let open = p.find('[')?;
+                    let close = p.find(']')?;
+                    Some(MdMember {
+                        name: p[..open].to_string(),
+                        role: usize::from_str(&p[open + 1..close]).ok()?,
+                        failed: p.ends_with("(F)"),
+                        spare: p.ends_with("(S)"),
+                    })
+                })
+                .collect();
+            arrays.push(MdArray {
+                name,
+                level,
+                members,
+                up: vec![],
+                near_copies: 2,
+                sync: None,
+            });
+            continue;
+        }
+        let array = match arrays.last_mut() {
+            Some(a) if line.starts_with(' ') => a,
+            _ => continue,
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if let Some(status) = words.iter().rev().find(|w| {
+            w.starts_with('[')
+                && w.trim_matches(|c| c == '[' || c == ']')
+                    .chars()
+                    .all(|c| c == 'U' || c == '_')
+        }) {
+            array.up = status
+                .trim_matches(|c| c == '[' || c == ']')
+                .chars()
+                .map(|c| c == 'U')
+                .collect();
+        }
+        if let Some(i) = words.iter().position(|w| *w == "near-copies") {
+            if let Some(copies) = i
+                .checked_sub(1)
+                .and_then(|j| usize::from_str(words[j]).ok())
+            {
+                array.near_copies = copies;
+            }
+        }
+        for kind in &["recovery", "resync", "reshape", "check"] {
+            if let Some(idx) = line.find(&format!("{} =", kind)) {
+                array.sync = Some(line[idx..].trim().to_string());
+            }
+        }
+    }
+    arrays
+}
+
+// Whether the array keeps a full copy of the data with the given roles
+// taken out
+fn keeps_redundancy(array: &MdArray, removing: &[usize]) -> bool {
+    let up: Vec<bool> = array
+        .up
+        .iter()
+        .enumerate()
+        .map(|(i, up)| *up && !removing.contains(&i))
+        .collect();
+    let down = up.iter().filter(|u| !**u).count();
+    match array.level.as_ref() {
+        "raid1" => up.iter().any(|u| *u),
+        // Each group of near_copies roles holds the same data
+        "raid10" => up
+            .chunks(array.near_copies.max(1))
+            .all(|c| c.iter().any(|u| *u)),
+        "raid4" | "raid5" => down <= 1,
+        "raid6" => down <= 2,
+        // raid0 and linear have no redundancy
+        _ => false,
+    }
+}
+
+// Parse lvs -a --noheadings --separator ';' -o
+// vg_name,lv_name,segtype,devices,copy_percent
+fn parse_lvs(lvs: &str) -> Vec<LvRow> {
+    lvs.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.trim().split(';').map(|f| f.trim()).collect();
+            if fields.len() < 5 {
+                return None;
+            }
+            Some(LvRow {
+                vg: fields[0].to_string(),
+                // Hidden sub volumes are shown in brackets
+                lv: fields[1].trim_matches(|c| c == '[' || c == ']').to_string(),
+                segtype: fields[2].to_string(),
+                devices: fields[3]
+                    .split(',')
+                    .filter(|d| !d.is_empty())
+                    // Strip the extent offset.  Ex: /dev/sda2(1)
+                    .map(|d| d.split('(').next().unwrap_or(d).to_string())
+                    .collect(),
+                copy_percent: f64::from_str(fields[4]).ok(),
+            })
+        })
+        .collect()
+}
+
+fn lvm_mirrors(rows: &[LvRow]) -> Vec<LvmMirror> {
+    rows.iter()
+        .filter(|r| r.segtype == "raid1" || r.segtype == "mirror")
+        .map(|r| LvmMirror {
+            vg: r.vg.clone(),
+            lv: r.lv.clone(),
+            images: r
+                .devices
+                .iter()
+                .map(|image| {
+                    rows.iter()
+                        .filter(|i| i.vg == r.vg && &i.lv == image)
+                        .flat_map(|i| i.devices.clone())
+                        .collect()
+                })
+                .collect(),
+            copy_percent: r.copy_percent,
+        })
+        .collect()
+}
+
+// Split a block device name into its disk and partition number.
+// Ex: sda1 -> (sda, 1), nvme0n1p2 -> (nvme0n1, 2)
+fn split_partition(name: &str) -> (String, Option<u32>) {
+    let base = name.trim_end_matches(|c: char| c.is_ascii_digit());
+    if base.len() == name.len() {
+        return (name.to_string(), None);
+    }
+    let number = u32::from_str(&name[base.len()..]).ok();
+    if base.ends_with('p') && base[..base.len() - 1].ends_with(|c: char| c.is_ascii_digit()) {
+        return (base[..base.len() - 1].to_string(), number);
+    }
+    if base.starts_with("nvme") || base.starts_with("mmcblk") || base.starts_with("loop") {
+        // Whole disks that end in a number
+        return (name.to_string(), None);
+    }
+    (base.to_string(), number)
+}
+
+fn partition_path(disk: &Path, partition: u32) -> PathBuf {
+    let name = disk.to_string_lossy();
+    if name.ends_with(|c: char| c.is_ascii_digit()) {
+        PathBuf::from(format!("{}p{}", name, partition))
+    } else {
+        PathBuf::from(format!("{}{}", name, partition))
+    }
+}
+
+// Drop the disk and partition ids from sfdisk --dump output so the copy
+// doesn't clash with the disk it came from
+fn strip_disk_ids(dump: &str) -> String {
+    let mut table = String::new();
+    for line in dump.lines() {
+        if line.starts_with("label-id:") || line.starts_with("device:") {
+            continue;
+        }
+        let fields: Vec<&str> = line
+            .split(", ")
+            .filter(|f| !f.trim_start().starts_with("uuid="))
+            .collect();
+        table.push_str(&fields.join(", "));
+        table.push('\n');
+    }
+    table
+}
+
+// Is this device name the disk or one of its partitions
+fn on_disk(name: &str, device: &Path) -> bool {
+    let name = name.trim_start_matches("/dev/");
+    match device.file_name() {
+        Some(disk) => split_partition(name).0 == disk.to_string_lossy(),
+        None => false,
+    }
+}
+
+fn run_cmd(cmd: &str, args: &[&str], simulate: bool) -> BynarResult<String> {
+    debug!("cmd: {} {:?}", cmd, args);
+    if simulate {
+        return Ok(String::new());
+    }
+    let output = Command::new(cmd).args(args).output()?;
+    if !output.status.success() {
+        return Err(BynarError::new(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn md_arrays() -> BynarResult<Vec<MdArray>> {
+    let mdstat = Path::new("/proc/mdstat");
+    if !mdstat.exists() {
+        return Ok(vec![]);
+    }
+    Ok(parse_mdstat(&read_to_string(mdstat)?))
+}
+
+// Whether a logical volume is still there.  A 2-way mirror that lost a
+// disk is linear until the replacement is added so any segtype counts
+fn lv_exists(rows: &[LvRow], vg: &str, lv: &str) -> bool {
+    rows.iter().any(|r| r.vg == vg && r.lv == lv)
+}
+
+fn lv_rows() -> BynarResult<Vec<LvRow>> {
+    let lvs = match run_cmd(
+        "lvs",
+        &[
+            "-a",
+            "--noheadings",
+            "--separator",
+            ";",
+            "-o",
+            "vg_name,lv_name,segtype,devices,copy_percent",
+        ],
+        false,
+    ) {
+        Ok(lvs) => lvs,
+        Err(e) => {
+            // No lvm on this host
+            debug!("lvs failed: {}", e);
+            return Ok(vec![]);
+        }
+    };
+    Ok(parse_lvs(&lvs))
+}
+
+fn mirrors() -> BynarResult<Vec<LvmMirror>> {
+    Ok(lvm_mirrors(&lv_rows()?))
+}
+
+// Copy the partition table of a sibling disk onto the replacement
+fn copy_partition_table(sibling: &Path, device: &Path, simulate: bool) -> BynarResult<()> {
+    let dump = run_cmd("sfdisk", &["--dump", &sibling.to_string_lossy()], false)?;
+    let table = strip_disk_ids(&dump);
+    debug!("cmd: sfdisk {} < {}", device.display(), table);
+    if simulate {
+        return Ok(());
+    }
+    let mut child = Command::new("sfdisk")
+        .arg(device)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .as_mut()
+        .ok_or_else(|| BynarError::from("Unable to write to sfdisk"))?
+        .write_all(table.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(BynarError::new(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+    run_cmd("udevadm", &["settle"], simulate)?;
+    Ok(())
+}
+
+fn member_path(device: &Path, partition: Option<u32>) -> PathBuf {
+    match partition {
+        Some(p) => partition_path(device, p),
+        None => device.to_path_buf(),
+    }
+}
+
+impl LocalBackend {
+    pub fn new(config_dir: Option<&Path>) -> BynarResult<LocalBackend> {
+        let dir = config_dir.unwrap_or_else(|| Path::new("/etc/bynar"));
+        Ok(LocalBackend {
+            state_file: dir.join("local_disks.json"),
+        })
+    }
+
+    fn load_records(&self) -> BynarResult<BTreeMap<String, DiskRecord>> {
+        if !self.state_file.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let s = read_to_string(&self.state_file)?;
+        Ok(serde_json::from_str(&s)?)
+    }
+
+    fn save_records(&self, records: &BTreeMap<String, DiskRecord>) -> BynarResult<()> {
+        let mut f = File::create(&self.state_file)?;
+        f.write_all(serde_json::to_string_pretty(records)?.as_bytes())?;
+        Ok(())
+    }
+
+    // Save what's been done to a disk so far.  Saved after every step so a
+    // failure part way through doesn't lose what was already removed
+    fn save_record(&self, device: &Path, record: &DiskRecord) -> BynarResult<()> {
+        let mut records = self.load_records()?;
+        records.insert(device.display().to_string(), record.clone());
+        self.save_records(&records)
+    }
+
+    // Log how the rebuilds onto added disks are going and forget the disks
+    // once they're finished
+    fn track_resync(&self) -> BynarResult<()> {
+        let mut records = self.load_records()?;
+        let mut finished = Vec::new();
+        for (device, record) in records.iter().filter(|(_, r)| r.added) {
+            let syncing = resyncing(&record.members)?;
+            if syncing.is_empty() {
+                info!("{} finished rebuilding", device);
+                finished.push(device.clone());
+            }
+            for sync in syncing {
+                info!("{}: {}", device, sync);
+            }
+        }
+        if finished.is_empty() {
+            return Ok(());
+        }
+        for device in finished {
+            records.remove(&device);
+        }
+        self.save_records(&records)
+    }
+}
+
+// How far along rebuilding each of the arrays and mirrors that hasn't
+// finished is
+fn resyncing(members: &[Member]) -> BynarResult<Vec<String>> {
+    let arrays = md_arrays()?;
+    let mirrors = mirrors()?;
+    let mut syncing = Vec::new();
+    for member in members {
+        match *member {
+            Member::Md { ref array, .. } => {
+                if let Some(sync) = arrays
+                    .iter()
+                    .find(|a| &a.name == array)
+                    .and_then(|a| a.sync.as_ref())
+                {
+                    syncing.push(format!("{} {}", array, sync));
+                }
+            }
+            Member::Lvm { ref vg, ref lv, .. } => {
+                if let Some(m) = mirrors
+                    .iter()
+                    .find(|m| &m.vg == vg && &m.lv == lv && !m.synced())
+                {
+                    syncing.push(format!(
+                        "{}/{} is {}% in sync",
+                        vg,
+                        lv,
+                        m.copy_percent.unwrap_or(0.0)
+                    ));
+                }
+            }
+        }
+    }
+    Ok(syncing)
+}
+
+impl Backend for LocalBackend {
+    /// Partition the new disk like its siblings and add it back to the
+    /// arrays and mirrors the old one was removed from.  The rebuild runs
+    /// in the background.
+    fn add_disk(&self, device: &Path, _id: Option<u64>, simulate: bool) -> BynarResult<()> {
+        let mut records = self.load_records()?;
+        let record = records
+            .get(&device.display().to_string())
+            .cloned()
+            .ok_or_else(|| {
+                BynarError::new(format!(
+                    "No record of md arrays or LVM mirrors previously on {}",
+                    device.display()
+                ))
+            })?;
+        if record.added {
+            return Err(BynarError::new(format!(
+                "{} was already added back",
+                device.display()
+            )));
+        }
+        let partitioned = record.members.iter().any(|m| match *m {
+            Member::Md { partition, .. } | Member::Lvm { partition, .. } => partition.is_some(),
+        });
+        if partitioned {
+            let sibling = record.sibling.as_ref().ok_or_else(|| {
+                BynarError::new(format!(
+                    "No disk to copy the partition table onto {} from",
+                    device.display()
+                ))
+            })?;
+            debug!(
+                "Copying the partition table of {} to {}",
+                sibling.display(),
+                device.display()
+            );
+            copy_partition_table(sibling, device, simulate)?;
+        }
+        for member in &record.members {
+            match *member {
+                Member::Md {
+                    ref array,
+                    partition,
+                } => {
+                    let path = member_path(device, partition);
+                    run_cmd(
+                        "mdadm",
+                        &[
+                            "--manage",
+                            &format!("/dev/{}", array),
+                            "--add",
+                            &path.to_string_lossy(),
+                        ],
+                        simulate,
+                    )?;
+                }
+                Member::Lvm {
+                    ref vg,
+                    ref lv,
+                    images,
+                    partition,
+                } => {
+                    let pv = member_path(device, partition)
+                        .to_string_lossy()
+                        .into_owned();
+                    run_cmd("pvcreate", &["-y", &pv], simulate)?;
+                    run_cmd("vgextend", &[vg, &pv], simulate)?;
+                    run_cmd(
+                        "lvconvert",
+                        &[
+                            "-y",
+                            "-m",
+                            &(images - 1).to_string(),
+                            &format!("{}/{}", vg, lv),
+                            &pv,
+                        ],
+                        simulate,
+                    )?;
+                }
+            }
+        }
+        if !simulate {
+            // Kept until the rebuild finishes so it can be followed up on
+            records.insert(
+                device.display().to_string(),
+                DiskRecord {
+                    added: true,
+                    ..record.clone()
+                },
+            );
+            self.save_records(&records)?;
+            for sync in resyncing(&record.members)? {
+                info!("{}", sync);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail and remove the disk from every md array and LVM mirror it's a
+    /// member of and record them so the replacement can be added back
+    fn remove_disk(&self, device: &Path, simulate: bool) -> BynarResult<()> {
+        // A removal that failed part way through already recorded some
+        let mut record = match self.load_records()?.remove(&device.display().to_string()) {
+            Some(ref r) if !r.added => r.clone(),
+            _ => DiskRecord::default(),
+        };
+        for array in md_arrays()? {
+            let on_device: Vec<&MdMember> = array
+                .members
+                .iter()
+                .filter(|m| on_disk(&m.name, device))
+                .collect();
+            if on_device.is_empty() {
+                continue;
+            }
+            if record.sibling.is_none() {
+                record.sibling = array
+                    .members
+                    .iter()
+                    .find(|m| !on_disk(&m.name, device) && !m.failed)
+                    .map(|m| Path::new("/dev").join(split_partition(&m.name).0));
+            }
+            for m in on_device {
+                let path = format!("/dev/{}", m.name);
+                run_cmd(
+                    "mdadm",
+                    &[
+                        "--manage",
+                        &format!("/dev/{}", array.name),
+                        "--fail",
+                        &path,
+                        "--remove",
+                        &path,
+                    ],
+                    simulate,
+                )?;
+                record.members.push(Member::Md {
+                    array: array.name.clone(),
+                    partition: split_partition(&m.name).1,
+                });
+                if !simulate {
+                    self.save_record(device, &record)?;
+                }
+            }
+        }
+        for mirror in mirrors()? {
+            let pvs: Vec<&String> = mirror
+                .images
+                .iter()
+                .flatten()
+                .filter(|pv| on_disk(pv, device))
+                .collect();
+            if pvs.is_empty() {
+                continue;
+            }
+            if record.sibling.is_none() {
+                record.sibling = mirror
+                    .images
+                    .iter()
+                    .flatten()
+                    .find(|pv| !on_disk(pv, device))
+                    .map(|pv| {
+                        Path::new("/dev").join(split_partition(pv.trim_start_matches("/dev/")).0)
+                    });
+            }
+            let remaining = mirror
+                .images
+                .iter()
+                .filter(|pvs| !pvs.iter().any(|pv| on_disk(pv, device)))
+                .count();
+            let lv = format!("{}/{}", mirror.vg, mirror.lv);
+            let mirror_count = remaining.saturating_sub(1).to_string();
+            let mut args = vec!["-y", "-m", mirror_count.as_str(), lv.as_str()];
+            args.extend(pvs.iter().map(|pv| pv.as_str()));
+            run_cmd("lvconvert", &args, simulate)?;
+            record.members.push(Member::Lvm {
+                vg: mirror.vg.clone(),
+                lv: mirror.lv.clone(),
+                images: mirror.images.len(),
+                partition: split_partition(pvs[0].trim_start_matches("/dev/")).1,
+            });
+            if !simulate {
+                self.save_record(device, &record)?;
+            }
+            for pv in &pvs {
+                if let Err(e) = run_cmd("vgreduce", &[&mirror.vg, pv], simulate) {
+                    // Another volume may still be using it
+                    error!("Unable to remove {} from {}: {}", pv, mirror.vg, e);
+                }
+            }
+        }
+        if record.members.is_empty() {
+            return Err(BynarError::new(format!(
+                "No md arrays or LVM mirrors found on {}",
+                device.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// A disk is safe to remove if every array and mirror it's in still
+    /// has a full copy of the data without it and isn't rebuilding
+    fn safe_to_remove(&self, device: &Path, simulate: bool) -> BynarResult<(bool, Option<String>)> {
+        if simulate {
+            return Ok((true, None));
+        }
+        for array in md_arrays()? {
+            // Spares don't hold any data
+            let roles: Vec<usize> = array
+                .members
+                .iter()
+                .filter(|m| on_disk(&m.name, device) && !m.spare)
+                .map(|m| m.role)
+                .collect();
+            if roles.is_empty() {
+                continue;
+            }
+            let reason = match array.sync {
+                Some(ref sync) => Some(format!("{} is rebuilding: {}", array.name, sync)),
+                None if !keeps_redundancy(&array, &roles) => Some(format!(
+                    "Removing {} would leave {} without a full copy of the data",
+                    device.display(),
+                    array.name
+                )),
+                None => None,
+            };
+            if let Some(reason) = reason {
+                info!("{}", reason);
+                return Ok((false, Some(reason)));
+            }
+        }
+        for mirror in mirrors()? {
+            let remaining = mirror
+                .images
+                .iter()
+                .filter(|pvs| !pvs.iter().any(|pv| on_disk(pv, device)))
+                .count();
+            if remaining == mirror.images.len() {
+                continue;
+            }
+            let reason = if !mirror.synced() {
+                Some(format!(
+                    "{}/{} is rebuilding: {}% in sync",
+                    mirror.vg,
+                    mirror.lv,
+                    mirror.copy_percent.unwrap_or(0.0)
+                ))
+            } else if remaining == 0 {
+                Some(format!(
+                    "Removing {} would leave {}/{} without a full copy of the data",
+                    device.display(),
+                    mirror.vg,
+                    mirror.lv
+                ))
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                info!("{}", reason);
+                return Ok((false, Some(reason)));
+            }
+        }
+        Ok((true, None))
+    }
+
+    /// Safe to add once we know which arrays and mirrors the disk goes
+    /// back into and they're all still there
+    fn safe_to_add(&self, device: &Path, simulate: bool) -> BynarResult<(bool, Option<String>)> {
+        if simulate {
+            return Ok((true, None));
+        }
+        let record = match self.load_records()?.remove(&device.display().to_string()) {
+            Some(record) => record,
+            None => {
+                return Ok((
+                    false,
+                    Some(format!(
+                        "No record of md arrays or LVM mirrors previously on {}",
+                        device.display()
+                    )),
+                ))
+            }
+        };
+        if record.added {
+            let syncing = resyncing(&record.members)?;
+            let reason = if syncing.is_empty() {
+                format!("{} was already added back", device.display())
+            } else {
+                format!(
+                    "{} was already added back and is rebuilding: {}",
+                    device.display(),
+                    syncing.join(", ")
+                )
+            };
+            return Ok((false, Some(reason)));
+        }
+        let arrays = md_arrays()?;
+        let lvs = lv_rows()?;
+        let mut missing = Vec::new();
+        if let Some(ref sibling) = record.sibling {
+            if !sibling.exists() {
+                missing.push(sibling.display().to_string());
+            }
+        }
+        for member in &record.members {
+            match *member {
+                Member::Md { ref array, .. } => {
+                    if !arrays.iter().any(|a| &a.name == array) {
+                        missing.push(format!("/dev/{}", array));
+                    }
+                }
+                Member::Lvm { ref vg, ref lv, .. } => {
+                    if !lv_exists(&lvs, vg, lv) {
+                        missing.push(format!("{}/{}", vg, lv));
+                    }
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok((true, None));
+        }
+        let reason = format!(
+            "{} can't be added back.  {} no longer exist",
+            device.display(),
+            missing.join(", ")
+        );
+        info!("{}", reason);
+        Ok((false, Some(reason)))
+    }
+
+    /// disk-manager calls this periodically so it's where rebuilds onto
+    /// added disks are followed up on
+    fn clear_maintenance(&self, _expired_only: bool) -> BynarResult<()> {
+        self.track_resync()
+    }
+}
@@ -0,0 +1,210 @@
+//! A backend that doesn't touch any disks.  What it answers is scripted
+//! with mock.json in the config dir and every call it gets is appended to
+//! mock_calls.json so tests can check what disk-manager was asked to do.
+use std::fs::{read_to_string, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::backend::Backend;
+
+use helpers::error::*;
+use log::debug;
+use serde_derive::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_scripted_calls() {
+        let dir = TempDir::new("mock").unwrap();
+        let config = r#"{
+            "unsafe_to_remove": ["/dev/sdc"],
+            "fail": ["add_disk"],
+            "replacement_id": 4
+        }"#;
+        std::fs::write(dir.path().join("mock.json"), config).unwrap();
+        let backend = MockBackend::new(Some(dir.path())).unwrap();
+
+        assert_eq!(
+            backend
+                .safe_to_remove(Path::new("/dev/sdb"), false)
+                .unwrap(),
+            (true, None)
+        );
+        let (safe, reason) = backend
+            .safe_to_remove(Path::new("/dev/sdc"), false)
+            .unwrap();
+        assert!(!safe);
+        assert!(reason.is_some());
+        assert_eq!(
+            backend.replacement_id(Path::new("/dev/sdb")).unwrap(),
+            Some(4)
+        );
+        backend.remove_disk(Path::new("/dev/sdb"), false).unwrap();
+        assert!(backend
+            .add_disk(Path::new("/dev/sdb"), Some(4), false)
+            .is_err());
+
+        let calls = recorded_calls(dir.path()).unwrap();
+        let names: Vec<&str> = calls.iter().map(|c| c.call.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "safe_to_remove",
+                "safe_to_remove",
+                "replacement_id",
+                "remove_disk",
+                "add_disk"
+            ]
+        );
+        assert_eq!(calls[4].id, Some(4));
+        assert_eq!(calls[3].device, Some("/dev/sdb".to_string()));
+    }
+}
+
+/// mock.json.  Everything defaults to a backend where every call works
+/// and every disk is safe
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MockConfig {
+    /// Devices safe_to_remove says no to
+    pub unsafe_to_remove: Vec<PathBuf>,
+    /// Devices safe_to_add says no to
+    pub unsafe_to_add: Vec<PathBuf>,
+    /// Calls that return an error.  Ex: add_disk, remove_disk
+    pub fail: Vec<String>,
+    /// Milliseconds each call takes before it answers
+    pub latency: u64,
+    /// The id remove_disk keeps for the replacement disk
+    pub replacement_id: Option<u64>,
+    /// Run remove_disk in the background like a backend that drains disks
+    pub drain: bool,
+}
+
+/// A call the mock backend got
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct MockCall {
+    pub call: String,
+    pub device: Option<String>,
+    pub id: Option<u64>,
+    pub simulate: bool,
+}
+
+pub struct MockBackend {
+    config: MockConfig,
+    calls_file: PathBuf,
+}
+
+/// The calls recorded in mock_calls.json in the config dir, oldest first
+pub fn recorded_calls(config_dir: &Path) -> BynarResult<Vec<MockCall>> {
+    let calls_file = config_dir.join("mock_calls.json");
+    if !calls_file.exists() {
+        return Ok(Vec::new());
+    }
+    let mut calls = Vec::new();
+    for line in read_to_string(&calls_file)?.lines() {
+        if !line.trim().is_empty() {
+            calls.push(serde_json::from_str(line)?);
+        }
+    }
+    Ok(calls)
+}
+
+impl MockBackend {
+    pub fn new(config_dir: Option<&Path>) -> BynarResult<MockBackend> {
+        let dir = config_dir.unwrap_or_else(|| Path::new("/etc/bynar"));
+        let config_file = dir.join("mock.json");
+        let config = if config_file.exists() {
+            serde_json::from_str(&read_to_string(&config_file)?)?
+        } else {
+            MockConfig::default()
+        };
+        Ok(MockBackend {
+            config,
+            calls_file: dir.join("mock_calls.json"),
+        })
+    }
+
+    // Record the call, wait out the latency and fail if told to
+    fn call(
+        &self,
+        call: &str,
+        device: Option<&Path>,
+        id: Option<u64>,
+        simulate: bool,
+    ) -> BynarResult<()> {
+        let record = MockCall {
+            call: call.to_string(),
+            device: device.map(|d| d.display().to_string()),
+            id,
+            simulate,
+        };
+        debug!("Mock backend call: {:?}", record);
+        // One line per call so concurrent removals don't clobber each other
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.calls_file)?;
+        f.write_all(format!("{}\n", serde_json::to_string(&record)?).as_bytes())?;
+        if self.config.latency > 0 {
+            thread::sleep(Duration::from_millis(self.config.latency));
+        }
+        if self.config.fail.iter().any(|f| f == call) {
+            return Err(BynarError::new(format!("Mock {} failure", call)));
+        }
+        Ok(())
+    }
+}
+
+impl Backend for MockBackend {
+    fn add_disk(&self, device: &Path, id: Option<u64>, simulate: bool) -> BynarResult<()> {
+        self.call("add_disk", Some(device), id, simulate)
+    }
+
+    fn remove_disk(&self, device: &Path, simulate: bool) -> BynarResult<()> {
+        self.call("remove_disk", Some(device), None, simulate)
+    }
+
+    fn replacement_id(&self, device: &Path) -> BynarResult<Option<u64>> {
+        self.call("replacement_id", Some(device), None, false)?;
+        Ok(self.config.replacement_id)
+    }
+
+    fn removes_in_background(&self) -> bool {
+        self.config.drain
+    }
+
+    fn safe_to_remove(&self, device: &Path, simulate: bool) -> BynarResult<(bool, Option<String>)> {
+        self.call("safe_to_remove", Some(device), None, simulate)?;
+        if self.config.unsafe_to_remove.iter().any(|d| d == device) {
+            return Ok((
+                false,
+                Some(format!("Mock: {} is unsafe to remove", device.display())),
+            ));
+        }
+        Ok((true, None))
+    }
+
+    fn safe_to_add(&self, device: &Path, simulate: bool) -> BynarResult<(bool, Option<String>)> {
+        self.call("safe_to_add", Some(device), None, simulate)?;
+        if self.config.unsafe_to_add.iter().any(|d| d == device) {
+            return Ok((
+                false,
+                Some(format!("Mock: {} is unsafe to add", device.display())),
+            ));
+        }
+        Ok((true, None))
+    }
+
+    fn clear_maintenance(&self, expired_only: bool) -> BynarResult<()> {
+        // Only the startup clean up is interesting
+        if expired_only {
+            return Ok(());
+        }
+        self.call("clear_maintenance", None, None, false)
+    }
+}
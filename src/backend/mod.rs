@@ -1,6 +1,10 @@
 pub mod ceph;
 //#[cfg(feature = "gluster")]
 pub mod gluster;
+pub mod local;
+pub mod mock;
+pub mod plugin;
+pub mod scaleio;
 
 use std::path::Path;
 use std::str::FromStr;
@@ -9,6 +13,10 @@ use std::str::FromStr;
 use serde_derive::*;
 use self::ceph::CephBackend;
 use self::gluster::GlusterBackend;
+use self::local::LocalBackend;
+use self::mock::MockBackend;
+use self::plugin::PluginBackend;
+use self::scaleio::ScaleioBackend;
 use helpers::error::*;
 
 /// Different distributed storage clusters have different ways of adding and removing
@@ -25,11 +33,59 @@ pub trait Backend {
     /// If simulate is passed no action should be taken
     fn remove_disk(&self, device: &Path, simulate: bool) -> BynarResult<()>;
 
+    /// The id the replacement for this disk should be added back with, if
+    /// the backend keeps one.  Ceph destroys osds instead of removing them
+    /// so the replacement can reuse the osd id and its place in CRUSH.
+    fn replacement_id(&self, _device: &Path) -> BynarResult<Option<u64>> {
+        Ok(None)
+    }
+
+    /// How far along a removal that's still running is, if the backend
+    /// keeps track.  Ceph reports the progress of draining the osd.
+    fn remove_progress(&self, _device: &Path) -> BynarResult<Option<String>> {
+        Ok(None)
+    }
+
+    /// Whether remove_disk can take long enough that disk-manager should
+    /// answer right away and let the client poll RemoveStatus.  Ceph does
+    /// when it drains osds first.
+    fn removes_in_background(&self) -> bool {
+        false
+    }
+
     /// Check if it's safe to remove a disk from a cluster
     /// If simulate is passed then this always returns true
     /// Take any actions needed with this call to figure out if a disk is safe
-    /// to remove from the cluster.
-    fn safe_to_remove(&self, device: &Path, simulate: bool) -> BynarResult<bool>;
+    /// to remove from the cluster.  When it isn't safe the reason is
+    /// returned along with false
+    fn safe_to_remove(&self, device: &Path, simulate: bool) -> BynarResult<(bool, Option<String>)>;
+
+    /// Check if it's safe to add a disk to a cluster
+    /// If simulate is passed then this always returns true
+    /// Sometimes it's safe to remove a disk but not safe to add one, like
+    /// while the cluster is still recovering.  When it isn't safe the
+    /// reason is returned along with false
+    fn safe_to_add(&self, device: &Path, simulate: bool) -> BynarResult<(bool, Option<String>)>;
+
+    /// Undo anything an operation left in place, like cluster flags.  If
+    /// expired_only is set only what has outlasted its expiry is undone.
+    /// disk-manager calls this at startup to clean up after a crash and
+    /// periodically after that.
+    fn clear_maintenance(&self, _expired_only: bool) -> BynarResult<()> {
+        Ok(())
+    }
+
+    /// Point the backend at the cluster a request is for when a host serves
+    /// more than one.  Without a name the cluster the device already
+    /// belongs to is used.  Returns the name of the cluster picked, if it
+    /// has one.  Backends with one cluster can ignore this.
+    fn select_cluster(
+        &mut self,
+        _name: Option<&str>,
+        _device: &Path,
+    ) -> BynarResult<Option<String>> {
+        Ok(None)
+    }
 }
 
 /// The supported backend types
@@ -37,6 +93,14 @@ pub trait Backend {
 pub enum BackendType {
     Ceph,
     Gluster,
+    /// md raid arrays and LVM mirrors on a host that isn't part of a cluster
+    Local,
+    /// ScaleIO/PowerFlex SDS devices managed through the REST gateway
+    Scaleio,
+    /// Scripted from mock.json for testing without a cluster
+    Mock,
+    /// An out of tree backend loaded from plugin.json
+    Plugin,
 }
 
 impl FromStr for BackendType {
@@ -47,20 +111,30 @@ impl FromStr for BackendType {
         match match_str.as_ref() {
             "ceph" => Ok(BackendType::Ceph),
             "gluster" => Ok(BackendType::Gluster),
+            "local" => Ok(BackendType::Local),
+            "scaleio" => Ok(BackendType::Scaleio),
+            "mock" => Ok(BackendType::Mock),
+            "plugin" => Ok(BackendType::Plugin),
             _ => Err(BynarError::new(format!("Unknown backend type: {}", s))),
         }
     }
 }
 
-/// Given a backendType, return a Backend.
+/// Given a backendType, return a Backend.  replica_set holds the Gluster
+/// host:/brick/path entries given in the request, if any
 pub fn load_backend(
     backend_type: &BackendType,
     config_dir: Option<&Path>,
+    replica_set: &[String],
 ) -> BynarResult<Box<dyn Backend>> {
     let backend: Box<dyn Backend> = match *backend_type {
         BackendType::Ceph => Box::new(CephBackend::new(config_dir)?),
         //#[cfg(feature = "gluster")]
-        BackendType::Gluster => Box::new(GlusterBackend {}),
+        BackendType::Gluster => Box::new(GlusterBackend::new(config_dir, replica_set)?),
+        BackendType::Local => Box::new(LocalBackend::new(config_dir)?),
+        BackendType::Scaleio => Box::new(ScaleioBackend::new(config_dir)?),
+        BackendType::Mock => Box::new(MockBackend::new(config_dir)?),
+        BackendType::Plugin => Box::new(PluginBackend::new(config_dir, replica_set)?),
     };
 
     Ok(backend)
@@ -0,0 +1,378 @@
+//! Out of tree backends.  A plugin is either a shared library or an
+//! executable and both speak the same protocol: one JSON request in, one
+//! JSON response out.
+//!
+//! A library exports `char *bynar_backend_call(const char *request)` and
+//! `void bynar_backend_free(char *response)`.  An executable is run once per
+//! call with the request on stdin and writes the response to stdout.
+use std::ffi::{CStr, CString};
+use std::fs::read_to_string;
+use std::io::Write;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::backend::Backend;
+
+use helpers::error::*;
+use libloading::{Library, Symbol};
+use log::{debug, error};
+use serde_derive::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{set_permissions, File, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use tempdir::TempDir;
+
+    // Answers based on the op and records every request next to itself
+    const SCRIPT: &str = r#"#!/bin/sh
+read request
+echo "$request" >> "$(dirname "$0")/requests"
+case "$request" in
+    *'"op":"safe_to_remove"'*) echo '{"safe": false, "reason": "osd busy"}' ;;
+    *'"op":"replacement_id"'*) echo '{"id": 7}' ;;
+    *'"op":"removes_in_background"'*) echo '{"background": true}' ;;
+    *'"op":"add_disk"'*) echo '{"error": "no space"}' ;;
+    *'"op":"remove_disk"'*) echo '{}' ;;
+    *) echo '{"unsupported": true}' ;;
+esac
+"#;
+
+    fn executable_plugin(dir: &Path, executable: &Path) -> PluginBackend {
+        let config = format!(r#"{{"executable": "{}"}}"#, executable.display());
+        File::create(dir.join("plugin.json"))
+            .unwrap()
+            .write_all(config.as_bytes())
+            .unwrap();
+        PluginBackend::new(Some(dir), &[]).unwrap()
+    }
+
+    #[test]
+    fn test_executable_plugin() {
+        let dir = TempDir::new("plugin").unwrap();
+        let script = dir.path().join("backend.sh");
+        File::create(&script)
+            .unwrap()
+            .write_all(SCRIPT.as_bytes())
+            .unwrap();
+        set_permissions(&script, Permissions::from_mode(0o755)).unwrap();
+        let mut backend = executable_plugin(dir.path(), &script);
+        backend
+            .select_cluster(Some("prod"), Path::new("/dev/sdb"))
+            .unwrap();
+        let dev = Path::new("/dev/sdb");
+
+        assert_eq!(
+            backend.safe_to_remove(dev, false).unwrap(),
+            (false, Some("osd busy".to_string()))
+        );
+        assert_eq!(backend.replacement_id(dev).unwrap(), Some(7));
+        backend.remove_disk(dev, false).unwrap();
+        assert_eq!(
+            backend
+                .add_disk(dev, Some(7), false)
+                .unwrap_err()
+                .to_string(),
+            BynarError::from("no space").to_string()
+        );
+        assert!(backend.removes_in_background());
+        // Optional calls fall back to the defaults.  Adding never does
+        assert_eq!(
+            backend.safe_to_add(dev, false).unwrap(),
+            (
+                false,
+                Some("Plugin doesn't support safe_to_add".to_string())
+            )
+        );
+        assert_eq!(backend.remove_progress(dev).unwrap(), None);
+        backend.clear_maintenance(true).unwrap();
+
+        let requests = read_to_string(dir.path().join("requests")).unwrap();
+        let requests: Vec<serde_json::Value> = requests
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(requests.len(), 8);
+        assert_eq!(requests[0]["device"], "/dev/sdb");
+        assert_eq!(requests[0]["cluster"], "prod");
+        assert_eq!(requests[3]["op"], "add_disk");
+        assert_eq!(requests[3]["id"], 7);
+        assert_eq!(requests[4]["op"], "removes_in_background");
+        assert_eq!(requests[7]["expired_only"], true);
+    }
+
+    #[test]
+    fn test_failing_executable() {
+        let dir = TempDir::new("plugin").unwrap();
+        let backend = executable_plugin(dir.path(), Path::new("/bin/false"));
+        assert!(backend.remove_disk(Path::new("/dev/sdb"), false).is_err());
+    }
+}
+
+/// plugin.json.  Give either library or executable
+#[derive(Clone, Debug, Deserialize)]
+struct PluginConfig {
+    /// Shared library exporting bynar_backend_call and bynar_backend_free
+    library: Option<PathBuf>,
+    /// Executable run once per call
+    executable: Option<PathBuf>,
+    /// Extra arguments for the executable
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+/// What's sent to the plugin.  The cluster and replica_set from the
+/// request are passed along with every call
+#[derive(Debug, Serialize)]
+struct PluginRequest<'a> {
+    op: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    simulate: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expired_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cluster: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    replica_set: &'a [String],
+}
+
+/// What the plugin answers with.  Only the fields the call needs are set
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PluginResponse {
+    /// Set when the call failed
+    error: Option<String>,
+    /// The plugin doesn't implement this call
+    unsupported: bool,
+    /// safe_to_remove and safe_to_add
+    safe: Option<bool>,
+    /// Why it isn't safe
+    reason: Option<String>,
+    /// replacement_id
+    id: Option<u64>,
+    /// remove_progress
+    progress: Option<String>,
+    /// removes_in_background
+    background: Option<bool>,
+}
+
+enum Transport {
+    Library(Library),
+    Executable { path: PathBuf, args: Vec<String> },
+}
+
+type CallFn = unsafe extern "C" fn(*const c_char) -> *mut c_char;
+type FreeFn = unsafe extern "C" fn(*mut c_char);
+
+fn call_library(lib: &Library, request: &str) -> BynarResult<String> {
+    let request = CString::new(request).map_err(|e| BynarError::new(e.to_string()))?;
+    unsafe {
+        let call: Symbol<CallFn> = lib.get(b"bynar_backend_call\0")?;
+        let free: Symbol<FreeFn> = lib.get(b"bynar_backend_free\0")?;
+        let response = call(request.as_ptr());
+        if response.is_null() {
+            return Err(BynarError::from("Plugin returned no response"));
+        }
+        let s = CStr::from_ptr(response).to_string_lossy().into_owned();
+        // The plugin allocated it so the plugin frees it
+        free(response);
+        Ok(s)
+    }
+}
+
+fn call_executable(path: &Path, args: &[String], request: &str) -> BynarResult<String> {
+    let mut child = Command::new(path)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(request.as_bytes())?;
+        stdin.write_all(b"\n")?;
+    }
+    let output = child.wait_with_output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in stderr.lines() {
+        debug!("{}: {}", path.display(), line);
+    }
+    if !output.status.success() {
+        return Err(BynarError::new(format!(
+            "{} failed with {}: {}",
+            path.display(),
+            output.status,
+            stderr
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// A backend loaded from plugin.json in the config dir
+pub struct PluginBackend {
+    transport: Transport,
+    cluster: Option<String>,
+    replica_set: Vec<String>,
+}
+
+impl PluginBackend {
+    pub fn new(config_dir: Option<&Path>, replica_set: &[String]) -> BynarResult<PluginBackend> {
+        let dir = config_dir.unwrap_or_else(|| Path::new("/etc/bynar"));
+        let config_file = dir.join("plugin.json");
+        if !config_file.exists() {
+            return Err(BynarError::new(format!(
+                "{} does not exist.  Please create",
+                config_file.display()
+            )));
+        }
+        let config: PluginConfig = serde_json::from_str(&read_to_string(&config_file)?)?;
+        let transport = match (config.library, config.executable) {
+            (Some(library), None) => {
+                debug!("Loading backend plugin {}", library.display());
+                Transport::Library(Library::new(&library)?)
+            }
+            (None, Some(path)) => Transport::Executable {
+                path,
+                args: config.args,
+            },
+            _ => {
+                return Err(BynarError::new(format!(
+                    "{} needs either library or executable",
+                    config_file.display()
+                )))
+            }
+        };
+        Ok(PluginBackend {
+            transport,
+            cluster: None,
+            replica_set: replica_set.to_vec(),
+        })
+    }
+
+    fn call(
+        &self,
+        op: &str,
+        device: Option<&Path>,
+        id: Option<u64>,
+        simulate: bool,
+        expired_only: Option<bool>,
+    ) -> BynarResult<PluginResponse> {
+        let request = serde_json::to_string(&PluginRequest {
+            op,
+            device,
+            id,
+            simulate,
+            expired_only,
+            cluster: self.cluster.as_deref(),
+            replica_set: &self.replica_set,
+        })?;
+        debug!("Plugin request: {}", request);
+        let response = match self.transport {
+            Transport::Library(ref lib) => call_library(lib, &request)?,
+            Transport::Executable { ref path, ref args } => call_executable(path, args, &request)?,
+        };
+        debug!("Plugin response: {}", response.trim());
+        let response: PluginResponse = serde_json::from_str(&response)?;
+        match response.error {
+            Some(e) => Err(BynarError::new(e)),
+            None => Ok(response),
+        }
+    }
+
+    // Calls every plugin has to implement
+    fn required(
+        &self,
+        op: &str,
+        device: &Path,
+        id: Option<u64>,
+        simulate: bool,
+    ) -> BynarResult<PluginResponse> {
+        let response = self.call(op, Some(device), id, simulate, None)?;
+        if response.unsupported {
+            return Err(BynarError::new(format!("Plugin doesn't support {}", op)));
+        }
+        Ok(response)
+    }
+}
+
+impl Backend for PluginBackend {
+    fn add_disk(&self, device: &Path, id: Option<u64>, simulate: bool) -> BynarResult<()> {
+        self.required("add_disk", device, id, simulate)?;
+        Ok(())
+    }
+
+    fn remove_disk(&self, device: &Path, simulate: bool) -> BynarResult<()> {
+        self.required("remove_disk", device, None, simulate)?;
+        Ok(())
+    }
+
+    fn replacement_id(&self, device: &Path) -> BynarResult<Option<u64>> {
+        Ok(self
+            .call("replacement_id", Some(device), None, false, None)?
+            .id)
+    }
+
+    fn removes_in_background(&self) -> bool {
+        match self.call("removes_in_background", None, None, false, None) {
+            Ok(response) => response.background.unwrap_or(false),
+            Err(e) => {
+                error!("Plugin removes_in_background failed: {}", e);
+                false
+            }
+        }
+    }
+
+    fn remove_progress(&self, device: &Path) -> BynarResult<Option<String>> {
+        Ok(self
+            .call("remove_progress", Some(device), None, false, None)?
+            .progress)
+    }
+
+    fn safe_to_remove(&self, device: &Path, simulate: bool) -> BynarResult<(bool, Option<String>)> {
+        if simulate {
+            return Ok((true, None));
+        }
+        let response = self.required("safe_to_remove", device, None, simulate)?;
+        let safe = response
+            .safe
+            .ok_or_else(|| BynarError::from("Plugin safe_to_remove response is missing safe"))?;
+        Ok((safe, response.reason))
+    }
+
+    fn safe_to_add(&self, device: &Path, simulate: bool) -> BynarResult<(bool, Option<String>)> {
+        if simulate {
+            return Ok((true, None));
+        }
+        let response = self.call("safe_to_add", Some(device), None, simulate, None)?;
+        match response.safe {
+            Some(safe) => Ok((safe, response.reason)),
+            // Adding a disk the cluster isn't ready for is worse than waiting
+            None if response.unsupported => Ok((
+                false,
+                Some("Plugin doesn't support safe_to_add".to_string()),
+            )),
+            None => Ok((
+                false,
+                Some("Plugin safe_to_add response is missing safe".to_string()),
+            )),
+        }
+    }
+
+    fn clear_maintenance(&self, expired_only: bool) -> BynarResult<()> {
+        self.call("clear_maintenance", None, None, false, Some(expired_only))?;
+        Ok(())
+    }
+
+    fn select_cluster(
+        &mut self,
+        name: Option<&str>,
+        _device: &Path,
+    ) -> BynarResult<Option<String>> {
+        self.cluster = name.map(|n| n.to_string());
+        Ok(self.cluster.clone())
+    }
+}
@@ -0,0 +1,591 @@
+//! ScaleIO/PowerFlex SDS devices.  Everything goes through the REST
+//! gateway.  Removing a device migrates its data to the rest of the
+//! storage pool before ScaleIO lets go of it.
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backend::Backend;
+
+use helpers::error::*;
+use hostname::get_hostname;
+use log::{debug, info};
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde_derive::*;
+use serde_json::{json, Value};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_stub::HttpStub;
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+
+    // Just enough of the gateway for the backend to talk to
+    #[derive(Default)]
+    struct MockGateway {
+        devices: Vec<Value>,
+        statistics: Value,
+        // Polls of the device list left before a removing device is gone
+        remove_polls: usize,
+        added: Vec<Value>,
+    }
+
+    impl MockGateway {
+        fn handle(&mut self, method: &str, path: &str, body: &str) -> (u16, String) {
+            match (method, path) {
+                ("GET", "/api/login") => (200, "\"token\"".to_string()),
+                ("GET", "/api/types/Sds/instances") => (
+                    200,
+                    json!([{"id": "sds1", "name": "host1", "ipList": [{"ip": "10.0.0.1"}]}])
+                        .to_string(),
+                ),
+                ("GET", "/api/instances/Sds::sds1/relationships/Device") => {
+                    let removing = self
+                        .devices
+                        .iter()
+                        .any(|d| d["deviceState"] == "RemovePending");
+                    if removing {
+                        if self.remove_polls == 0 {
+                            self.devices.retain(|d| d["deviceState"] != "RemovePending");
+                        } else {
+                            self.remove_polls -= 1;
+                        }
+                    }
+                    (200, Value::Array(self.devices.clone()).to_string())
+                }
+                ("GET", "/api/instances/StoragePool::pool1/relationships/Statistics") => {
+                    (200, self.statistics.to_string())
+                }
+                ("POST", "/api/instances/Device::dev1/action/removeDevice") => {
+                    for d in self.devices.iter_mut() {
+                        if d["id"] == "dev1" {
+                            d["deviceState"] = json!("RemovePending");
+                        }
+                    }
+                    (200, String::new())
+                }
+                ("POST", "/api/types/Device/instances") => {
+                    self.added.push(serde_json::from_str(body).unwrap());
+                    (200, json!({"id": "dev2"}).to_string())
+                }
+                _ => (
+                    500,
+                    json!({"message": "Not found", "httpStatusCode": 500}).to_string(),
+                ),
+            }
+        }
+    }
+
+    // Serve the mock gateway on a local port
+    fn start_gateway(gateway: MockGateway) -> (String, Arc<Mutex<MockGateway>>) {
+        let gateway = Arc::new(Mutex::new(gateway));
+        let state = Arc::clone(&gateway);
+        let stub =
+            HttpStub::start(move |r| state.lock().unwrap().handle(&r.method, &r.path, &r.body));
+        (stub.url, gateway)
+    }
+
+    fn test_backend(gateway: &str, dir: &Path) -> ScaleioBackend {
+        let config = ScaleioConfig {
+            gateway: gateway.to_string(),
+            user: "admin".to_string(),
+            password: "password".to_string(),
+            sds_name: Some("host1".to_string()),
+            storage_pool_id: None,
+            insecure: false,
+            poll_interval: 0,
+            timeout: 30,
+        };
+        ScaleioBackend::connect(config, dir.join("scaleio_devices.json")).unwrap()
+    }
+
+    fn healthy_gateway() -> MockGateway {
+        MockGateway {
+            devices: vec![json!({
+                "id": "dev1",
+                "name": "host1-sdb",
+                "deviceCurrentPathName": "/dev/sdb",
+                "sdsId": "sds1",
+                "storagePoolId": "pool1",
+                "deviceState": "Normal",
+                "mediaType": "HDD"
+            })],
+            statistics: json!({
+                "failedCapacityInKb": 0,
+                "degradedFailedCapacityInKb": 0,
+                "degradedHealthyCapacityInKb": 0,
+                "pendingRebuildCapacityInKb": 0,
+                "pendingRebalanceCapacityInKb": 0
+            }),
+            remove_polls: 2,
+            added: vec![],
+        }
+    }
+
+    #[test]
+    fn test_pool_safety() {
+        let mut stats = PoolStatistics::default();
+        assert_eq!(pool_safety(&stats), None);
+        stats.degraded_healthy_capacity_in_kb = 1024;
+        assert!(pool_safety(&stats).unwrap().contains("degraded"));
+        stats.failed_capacity_in_kb = 1024;
+        assert!(pool_safety(&stats).unwrap().contains("failed"));
+    }
+
+    #[test]
+    fn test_remove_and_replace() {
+        let (addr, gateway) = start_gateway(healthy_gateway());
+        let dir = TempDir::new("scaleio").unwrap();
+        let backend = test_backend(&addr, dir.path());
+        let device = Path::new("/dev/sdb");
+
+        assert_eq!(backend.safe_to_remove(device, false).unwrap(), (true, None));
+        backend.remove_disk(device, false).unwrap();
+        assert!(gateway.lock().unwrap().devices.is_empty());
+        assert_eq!(backend.remove_progress(device).unwrap(), None);
+        let records = backend.load_records().unwrap();
+        assert_eq!(records["/dev/sdb"].storage_pool_id, "pool1");
+
+        assert_eq!(backend.safe_to_add(device, false).unwrap(), (true, None));
+        backend.add_disk(device, None, false).unwrap();
+        let added = gateway.lock().unwrap().added.clone();
+        assert_eq!(
+            added,
+            vec![json!({
+                "deviceCurrentPathname": "/dev/sdb",
+                "sdsId": "sds1",
+                "storagePoolId": "pool1",
+                "name": "host1-sdb",
+                "mediaType": "HDD"
+            })]
+        );
+        assert!(backend.load_records().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unsafe_pool() {
+        let mut mock = healthy_gateway();
+        mock.statistics["degradedHealthyCapacityInKb"] = json!(4096);
+        let (addr, _) = start_gateway(mock);
+        let dir = TempDir::new("scaleio").unwrap();
+        let backend = test_backend(&addr, dir.path());
+
+        let (safe, reason) = backend
+            .safe_to_remove(Path::new("/dev/sdb"), false)
+            .unwrap();
+        assert!(!safe);
+        assert!(reason.unwrap().contains("pool1"));
+        // Still in the pool so it can't be added again
+        let (safe, reason) = backend.safe_to_add(Path::new("/dev/sdb"), false).unwrap();
+        assert!(!safe);
+        assert!(reason.unwrap().contains("dev1"));
+        // Devices ScaleIO doesn't know about are safe to take
+        assert_eq!(
+            backend
+                .safe_to_remove(Path::new("/dev/sdz"), false)
+                .unwrap(),
+            (true, None)
+        );
+        // Nothing is recorded for a disk that was never removed
+        assert!(backend
+            .add_disk(Path::new("/dev/sdz"), None, false)
+            .is_err());
+    }
+}
+
+/// scaleio.json
+#[derive(Clone, Debug, Deserialize)]
+struct ScaleioConfig {
+    /// The REST gateway.  Ex: https://gateway.example.com
+    gateway: String,
+    user: String,
+    password: String,
+    /// The name of this host's SDS.  Defaults to the hostname
+    sds_name: Option<String>,
+    /// The storage pool to add a disk to when there's no record of the
+    /// disk it replaces
+    storage_pool_id: Option<String>,
+    /// Skip checking the gateway's certificate
+    #[serde(default)]
+    insecure: bool,
+    /// Seconds to wait between checks on a removal
+    #[serde(default = "default_poll_interval")]
+    poll_interval: u64,
+    /// Fail a removal that hasn't finished within this many seconds
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+}
+
+fn default_poll_interval() -> u64 {
+    30
+}
+
+fn default_timeout() -> u64 {
+    86400
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Sds {
+    id: String,
+    name: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Device {
+    id: String,
+    name: Option<String>,
+    device_current_path_name: String,
+    storage_pool_id: String,
+    device_state: Option<String>,
+    media_type: Option<String>,
+}
+
+/// The parts of the storage pool statistics that say how healthy it is
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct PoolStatistics {
+    failed_capacity_in_kb: u64,
+    degraded_failed_capacity_in_kb: u64,
+    degraded_healthy_capacity_in_kb: u64,
+    pending_rebuild_capacity_in_kb: u64,
+    pending_rebalance_capacity_in_kb: u64,
+}
+
+/// Where a removed device lived so the replacement can go back there
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct DeviceRecord {
+    storage_pool_id: String,
+    name: Option<String>,
+    media_type: Option<String>,
+}
+
+/// ScaleIO SDS.  Devices removed are recorded in scaleio_devices.json
+/// under the config dir.
+pub struct ScaleioBackend {
+    config: ScaleioConfig,
+    client: Client,
+    token: String,
+    state_file: PathBuf,
+}
+
+// Why removing another device from a pool in this state isn't safe
+fn pool_safety(stats: &PoolStatistics) -> Option<String> {
+    if stats.failed_capacity_in_kb > 0 || stats.degraded_failed_capacity_in_kb > 0 {
+        Some(format!(
+            "{} KB has failed",
+            stats.failed_capacity_in_kb + stats.degraded_failed_capacity_in_kb
+        ))
+    } else if stats.degraded_healthy_capacity_in_kb > 0 || stats.pending_rebuild_capacity_in_kb > 0
+    {
+        Some(format!(
+            "{} KB is degraded and {} KB is waiting to rebuild",
+            stats.degraded_healthy_capacity_in_kb, stats.pending_rebuild_capacity_in_kb
+        ))
+    } else {
+        None
+    }
+}
+
+impl ScaleioBackend {
+    pub fn new(config_dir: Option<&Path>) -> BynarResult<ScaleioBackend> {
+        let dir = config_dir.unwrap_or_else(|| Path::new("/etc/bynar"));
+        let config_file = dir.join("scaleio.json");
+        if !config_file.exists() {
+            return Err(BynarError::new(format!(
+                "{} does not exist.  Please create",
+                config_file.display()
+            )));
+        }
+        let config: ScaleioConfig = serde_json::from_str(&read_to_string(&config_file)?)?;
+        ScaleioBackend::connect(config, dir.join("scaleio_devices.json"))
+    }
+
+    fn connect(config: ScaleioConfig, state_file: PathBuf) -> BynarResult<ScaleioBackend> {
+        let client = Client::builder()
+            .danger_accept_invalid_certs(config.insecure)
+            .build()?;
+        debug!("Logging into ScaleIO gateway {}", config.gateway);
+        let mut resp = client
+            .get(&format!("{}/api/login", config.gateway))
+            .basic_auth(&config.user, Some(&config.password))
+            .send()?
+            .error_for_status()?;
+        // Later requests use the token as the password
+        let token: String = resp.json()?;
+        Ok(ScaleioBackend {
+            config,
+            client,
+            token,
+            state_file,
+        })
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str) -> BynarResult<T> {
+        let url = format!("{}{}", self.config.gateway, path);
+        debug!("GET {}", url);
+        let mut resp = self
+            .client
+            .get(&url)
+            .basic_auth(&self.config.user, Some(&self.token))
+            .send()?
+            .error_for_status()?;
+        Ok(resp.json()?)
+    }
+
+    fn post(&self, path: &str, body: &Value, simulate: bool) -> BynarResult<Value> {
+        let url = format!("{}{}", self.config.gateway, path);
+        debug!("POST {} {}", url, body);
+        if simulate {
+            return Ok(Value::Null);
+        }
+        let mut resp = self
+            .client
+            .post(&url)
+            .basic_auth(&self.config.user, Some(&self.token))
+            .json(body)
+            .send()?
+            .error_for_status()?;
+        // Actions don't always return anything
+        let text = resp.text()?;
+        if text.trim().is_empty() {
+            return Ok(Value::Null);
+        }
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    // The SDS running on this host
+    fn local_sds(&self) -> BynarResult<Sds> {
+        let name = match self.config.sds_name {
+            Some(ref name) => name.clone(),
+            None => {
+                get_hostname().ok_or_else(|| BynarError::from("Unable to find this host's name"))?
+            }
+        };
+        let sds_list: Vec<Sds> = self.get("/api/types/Sds/instances")?;
+        sds_list
+            .into_iter()
+            .find(|s| s.name.as_ref() == Some(&name))
+            .ok_or_else(|| BynarError::new(format!("No ScaleIO SDS named {}", name)))
+    }
+
+    fn sds_devices(&self, sds: &Sds) -> BynarResult<Vec<Device>> {
+        self.get(&format!(
+            "/api/instances/Sds::{}/relationships/Device",
+            sds.id
+        ))
+    }
+
+    fn find_device(&self, sds: &Sds, device: &Path) -> BynarResult<Option<Device>> {
+        Ok(self
+            .sds_devices(sds)?
+            .into_iter()
+            .find(|d| Path::new(&d.device_current_path_name) == device))
+    }
+
+    fn pool_statistics(&self, pool_id: &str) -> BynarResult<PoolStatistics> {
+        self.get(&format!(
+            "/api/instances/StoragePool::{}/relationships/Statistics",
+            pool_id
+        ))
+    }
+
+    // Wait for ScaleIO to move the data off the device and let go of it
+    fn wait_for_removal(&self, sds: &Sds, device: &Device) -> BynarResult<()> {
+        let start = Instant::now();
+        loop {
+            let present = self.sds_devices(sds)?.iter().any(|d| d.id == device.id);
+            let stats = self.pool_statistics(&device.storage_pool_id)?;
+            if !present
+                && stats.pending_rebuild_capacity_in_kb == 0
+                && stats.pending_rebalance_capacity_in_kb == 0
+            {
+                return Ok(());
+            }
+            debug!(
+                "{} still removing.  {} KB left to rebalance",
+                device.device_current_path_name, stats.pending_rebalance_capacity_in_kb
+            );
+            if start.elapsed() > Duration::from_secs(self.config.timeout) {
+                return Err(BynarError::new(format!(
+                    "Removing {} didn't finish within {} seconds",
+                    device.device_current_path_name, self.config.timeout
+                )));
+            }
+            thread::sleep(Duration::from_secs(self.config.poll_interval));
+        }
+    }
+
+    fn load_records(&self) -> BynarResult<BTreeMap<String, DeviceRecord>> {
+        if !self.state_file.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let s = read_to_string(&self.state_file)?;
+        Ok(serde_json::from_str(&s)?)
+    }
+
+    fn save_records(&self, records: &BTreeMap<String, DeviceRecord>) -> BynarResult<()> {
+        let mut f = File::create(&self.state_file)?;
+        f.write_all(serde_json::to_string_pretty(records)?.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl Backend for ScaleioBackend {
+    /// Add the new disk to the storage pool the old one was removed from
+    fn add_disk(&self, device: &Path, _id: Option<u64>, simulate: bool) -> BynarResult<()> {
+        let mut records = self.load_records()?;
+        let key = device.display().to_string();
+        let record = match records.get(&key) {
+            Some(r) => r.clone(),
+            None => DeviceRecord {
+                storage_pool_id: self.config.storage_pool_id.clone().ok_or_else(|| {
+                    BynarError::new(format!(
+                        "No record of the storage pool {} was in and no storage_pool_id given",
+                        device.display()
+                    ))
+                })?,
+                name: None,
+                media_type: None,
+            },
+        };
+        let sds = self.local_sds()?;
+        let mut body = json!({
+            "deviceCurrentPathname": key,
+            "sdsId": sds.id,
+            "storagePoolId": record.storage_pool_id,
+        });
+        if let Some(ref name) = record.name {
+            body["name"] = json!(name);
+        }
+        if let Some(ref media_type) = record.media_type {
+            body["mediaType"] = json!(media_type);
+        }
+        let added = self.post("/api/types/Device/instances", &body, simulate)?;
+        info!("Added {} to ScaleIO as {}", device.display(), added["id"]);
+        if !simulate && records.remove(&key).is_some() {
+            self.save_records(&records)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the device from its storage pool and wait for ScaleIO to
+    /// move its data elsewhere
+    fn remove_disk(&self, device: &Path, simulate: bool) -> BynarResult<()> {
+        let sds = self.local_sds()?;
+        let dev = self.find_device(&sds, device)?.ok_or_else(|| {
+            BynarError::new(format!(
+                "{} is not a ScaleIO device on {}",
+                device.display(),
+                sds.id
+            ))
+        })?;
+        self.post(
+            &format!("/api/instances/Device::{}/action/removeDevice", dev.id),
+            &json!({}),
+            simulate,
+        )?;
+        if simulate {
+            return Ok(());
+        }
+        // Record it before waiting so a restart doesn't lose track of it
+        let mut records = self.load_records()?;
+        records.insert(
+            device.display().to_string(),
+            DeviceRecord {
+                storage_pool_id: dev.storage_pool_id.clone(),
+                name: dev.name.clone(),
+                media_type: dev.media_type.clone(),
+            },
+        );
+        self.save_records(&records)?;
+        self.wait_for_removal(&sds, &dev)
+    }
+
+    /// Safe to add once we know which storage pool the disk goes into and
+    /// ScaleIO doesn't already have it
+    fn safe_to_add(&self, device: &Path, simulate: bool) -> BynarResult<(bool, Option<String>)> {
+        if simulate {
+            return Ok((true, None));
+        }
+        let sds = self.local_sds()?;
+        if let Some(dev) = self.find_device(&sds, device)? {
+            let reason = format!(
+                "{} is already ScaleIO device {} in storage pool {}",
+                device.display(),
+                dev.id,
+                dev.storage_pool_id
+            );
+            info!("{}", reason);
+            return Ok((false, Some(reason)));
+        }
+        let storage_pool_id = match self.load_records()?.remove(&device.display().to_string()) {
+            Some(record) => record.storage_pool_id,
+            None => match self.config.storage_pool_id {
+                Some(ref id) => id.clone(),
+                None => {
+                    return Ok((
+                        false,
+                        Some(format!(
+                            "No record of the storage pool {} was in and no storage_pool_id given",
+                            device.display()
+                        )),
+                    ))
+                }
+            },
+        };
+        // Make sure the pool is still there
+        self.pool_statistics(&storage_pool_id)?;
+        Ok((true, None))
+    }
+
+    /// Waiting for the rebuild can take hours
+    fn removes_in_background(&self) -> bool {
+        true
+    }
+
+    fn remove_progress(&self, device: &Path) -> BynarResult<Option<String>> {
+        let sds = self.local_sds()?;
+        match self.find_device(&sds, device)? {
+            Some(dev) => {
+                let stats = self.pool_statistics(&dev.storage_pool_id)?;
+                Ok(Some(format!(
+                    "{} with {} KB left to rebalance",
+                    dev.device_state.unwrap_or_else(|| "Unknown".to_string()),
+                    stats.pending_rebalance_capacity_in_kb
+                )))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Only take a device out of a pool that isn't already degraded
+    fn safe_to_remove(&self, device: &Path, simulate: bool) -> BynarResult<(bool, Option<String>)> {
+        if simulate {
+            return Ok((true, None));
+        }
+        let sds = self.local_sds()?;
+        let dev = match self.find_device(&sds, device)? {
+            Some(dev) => dev,
+            None => {
+                debug!("{} is not a ScaleIO device", device.display());
+                return Ok((true, None));
+            }
+        };
+        let stats = self.pool_statistics(&dev.storage_pool_id)?;
+        match pool_safety(&stats) {
+            Some(problem) => {
+                let reason = format!("Storage pool {}: {}", dev.storage_pool_id, problem);
+                info!("{}", reason);
+                Ok((false, Some(reason)))
+            }
+            None => Ok((true, None)),
+        }
+    }
+}
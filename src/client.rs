@@ -1,21 +1,32 @@
 /// This is built into a separate binary called bynar-client
+use std::env;
 use std::fs::{read_to_string, File};
 use std::path::Path;
 use std::str::FromStr;
 
 use api::service::Disk;
 use clap::{crate_authors, crate_version, App, Arg, ArgMatches, SubCommand};
+use helpers::approval::{self, ApprovalStatus};
 use helpers::error::BynarResult;
+use helpers::DBConfig;
 use hostname::get_hostname;
 use log::{error, info};
+use postgres::Connection;
+use serde_derive::*;
 use simplelog::{CombinedLogger, Config, TermLogger, WriteLogger};
 use zmq::Socket;
 /*
     CLI client to call functions over RPC
 */
 
-fn add_disk(s: &mut Socket, path: &Path, id: Option<u64>, simulate: bool) -> BynarResult<()> {
-    helpers::add_disk_request(s, path, id, simulate)?;
+fn add_disk(
+    s: &mut Socket,
+    path: &Path,
+    id: Option<u64>,
+    cluster: Option<&str>,
+    simulate: bool,
+) -> BynarResult<()> {
+    helpers::add_disk_request(s, path, id, cluster, simulate)?;
     Ok(())
 }
 
@@ -26,9 +37,20 @@ fn list_disks(s: &mut Socket) -> BynarResult<Vec<Disk>> {
     Ok(disks)
 }
 
-fn remove_disk(s: &mut Socket, path: &Path, id: Option<u64>, simulate: bool) -> BynarResult<()> {
-    helpers::remove_disk_request(s, path, id, simulate)?;
-    Ok(())
+fn remove_disk(
+    s: &mut Socket,
+    path: &Path,
+    id: Option<u64>,
+    cluster: Option<&str>,
+    simulate: bool,
+) -> BynarResult<(Option<u64>, Option<String>)> {
+    let removed = helpers::remove_disk_request(s, path, id, cluster, simulate)?;
+    Ok(removed)
+}
+
+fn remove_status(s: &mut Socket, path: &Path) -> BynarResult<String> {
+    let status = helpers::remove_status_request(s, path)?;
+    Ok(status)
 }
 
 fn handle_add_disk(s: &mut Socket, matches: &ArgMatches<'_>) {
@@ -42,7 +64,8 @@ fn handle_add_disk(s: &mut Socket, matches: &ArgMatches<'_>) {
         Some(s) => bool::from_str(&s).unwrap(),
         None => false,
     };
-    match add_disk(s, &p, id, simulate) {
+    let cluster = matches.value_of("cluster");
+    match add_disk(s, &p, id, cluster, simulate) {
         Ok(_) => {
             println!("Adding disk successful");
         }
@@ -75,9 +98,34 @@ fn handle_remove_disk(s: &mut Socket, matches: &ArgMatches<'_>) {
         Some(s) => bool::from_str(&s).unwrap(),
         None => false,
     };
-    match remove_disk(s, &p, id, simulate) {
-        Ok(_) => {
-            println!("Removing disk successful");
+    let cluster = matches.value_of("cluster");
+    match remove_disk(s, &p, id, cluster, simulate) {
+        Ok((id, cluster)) => {
+            // Removals that drain are still running when disk-manager answers
+            let finished = match remove_status(s, &p) {
+                Ok(status) => status == "removed",
+                Err(e) => {
+                    error!("Checking removal of {} failed: {}", p.display(), e);
+                    false
+                }
+            };
+            let msg = if finished {
+                "Removing disk finished"
+            } else {
+                "Removing disk started.  Check on it with remove-status"
+            };
+            let mut add_args = String::new();
+            if let Some(id) = id {
+                add_args.push_str(&format!(" --id {}", id));
+            }
+            if let Some(cluster) = cluster {
+                add_args.push_str(&format!(" --cluster {}", cluster));
+            }
+            if add_args.is_empty() {
+                println!("{}", msg);
+            } else {
+                println!("{}.  Add the replacement with{}", msg, add_args);
+            }
         }
         Err(e) => {
             println!("Removing disk failed: {}", e);
@@ -85,6 +133,91 @@ fn handle_remove_disk(s: &mut Socket, matches: &ArgMatches<'_>) {
     }
 }
 
+fn handle_remove_status(s: &mut Socket, matches: &ArgMatches<'_>) {
+    let p = Path::new(matches.value_of("path").unwrap());
+    info!("Checking removal of disk: {}", p.display());
+    match remove_status(s, &p) {
+        Ok(status) => {
+            println!("{}: {}", p.display(), status);
+        }
+        Err(e) => {
+            println!("Checking removal failed: {}", e);
+        }
+    }
+}
+
+// The part of bynar.json the approval commands need
+#[derive(Deserialize)]
+struct BynarConfig {
+    database: DBConfig,
+}
+
+fn connect_db(config_dir: &Path) -> BynarResult<Connection> {
+    let config: BynarConfig = helpers::load_config(config_dir, "bynar.json")?;
+    approval::connect(&config.database)
+}
+
+// Who to record as deciding.  sudo keeps the name of the real user
+fn decided_by() -> String {
+    env::var("SUDO_USER")
+        .or_else(|_| env::var("USER"))
+        .unwrap_or_else(|_| "bynar-client".to_string())
+}
+
+fn handle_pending(conn: &Connection) {
+    info!("Listing actions waiting for approval");
+    match approval::get_outstanding_actions(conn) {
+        Ok(actions) => {
+            for action in actions {
+                println!(
+                    "{}: {} {} on {} requested {} status: {}{}{}",
+                    action.action_id,
+                    action.action,
+                    action.device_path,
+                    action.hostname,
+                    action.requested_time,
+                    action.status,
+                    action
+                        .auto_approve_time
+                        .map(|t| format!(" auto approves: {}", t))
+                        .unwrap_or_default(),
+                    action
+                        .tracking_id
+                        .map(|t| format!(" ticket: {}", t))
+                        .unwrap_or_default(),
+                );
+            }
+        }
+        Err(e) => {
+            println!("Listing actions failed: {}", e);
+        }
+    }
+}
+
+fn handle_decide(conn: &Connection, matches: &ArgMatches<'_>, status: ApprovalStatus) {
+    let id = u32::from_str(matches.value_of("id").unwrap()).unwrap();
+    info!("Marking action {} {}", id, status);
+    match approval::decide_action(conn, id, status, &decided_by()) {
+        Ok(_) => {
+            println!("Action {} {}", id, status);
+        }
+        Err(e) => {
+            println!("Deciding action {} failed: {}", id, e);
+        }
+    }
+}
+
+fn action_id_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("id")
+        .help("The action id shown by pending")
+        .required(true)
+        .takes_value(true)
+        .validator(|v| match u32::from_str(&v) {
+            Ok(_) => Ok(()),
+            Err(_) => Err("id must be a valid u32".to_string()),
+        })
+}
+
 fn get_cli_args(default_server_key: &str) -> ArgMatches<'_> {
     App::new("Ceph Disk Manager Client")
         .version(crate_version!())
@@ -114,6 +247,14 @@ fn get_cli_args(default_server_key: &str) -> ArgMatches<'_> {
                 .long("serverkey")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("configdir")
+                .default_value("/etc/bynar")
+                .help("The directory with bynar.json.  Used by the approval commands")
+                .long("configdir")
+                .required(false)
+                .takes_value(true),
+        )
         .subcommand(
             SubCommand::with_name("add")
                 .about("Add a disk into the cluster")
@@ -134,6 +275,13 @@ fn get_cli_args(default_server_key: &str) -> ArgMatches<'_> {
                             Err(_) => Err("id must be a valid u64".to_string()),
                         }),
                 )
+                .arg(
+                    Arg::with_name("cluster")
+                        .help("The ceph cluster in ceph.json to add the disk to")
+                        .long("cluster")
+                        .required(false)
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("simulate")
                         .default_value("false")
@@ -165,6 +313,13 @@ fn get_cli_args(default_server_key: &str) -> ArgMatches<'_> {
                             Err(_) => Err("id must be a valid u64".to_string()),
                         }),
                 )
+                .arg(
+                    Arg::with_name("cluster")
+                        .help("The ceph cluster the disk belongs to.  Found from the disk if not given")
+                        .long("cluster")
+                        .required(false)
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("simulate")
                         .default_value("false")
@@ -175,6 +330,30 @@ fn get_cli_args(default_server_key: &str) -> ArgMatches<'_> {
                         .takes_value(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("remove-status")
+                .about("Check on the progress of removing a disk")
+                .arg(
+                    Arg::with_name("path")
+                        .help("The disk path being removed: Ex: /dev/sda")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pending")
+                .about("List the actions bynar is waiting on or about to carry out"),
+        )
+        .subcommand(
+            SubCommand::with_name("approve")
+                .about("Let bynar go ahead with an action")
+                .arg(action_id_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("deny")
+                .about("Stop bynar from carrying out an action")
+                .arg(action_id_arg()),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -206,6 +385,26 @@ fn main() {
         ),
     ]);
     info!("Starting up");
+    // Approvals live in bynar's database rather than the disk-manager
+    if matches!(
+        matches.subcommand_name(),
+        Some("pending") | Some("approve") | Some("deny")
+    ) {
+        let config_dir = Path::new(matches.value_of("configdir").unwrap());
+        let conn = match connect_db(config_dir) {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Error connecting to database: {:?}", e);
+                return;
+            }
+        };
+        match matches.subcommand() {
+            ("approve", Some(m)) => handle_decide(&conn, m, ApprovalStatus::Approved),
+            ("deny", Some(m)) => handle_decide(&conn, m, ApprovalStatus::Denied),
+            _ => handle_pending(&conn),
+        };
+        return;
+    }
     let server_pubkey = read_to_string(matches.value_of("server_key").unwrap()).unwrap();
 
     let mut s = match helpers::connect(host, port, &server_pubkey) {
@@ -224,4 +423,7 @@ fn main() {
     if let Some(ref matches) = matches.subcommand_matches("remove") {
         handle_remove_disk(&mut s, matches);
     }
+    if let Some(ref matches) = matches.subcommand_matches("remove-status") {
+        handle_remove_status(&mut s, matches);
+    }
 }
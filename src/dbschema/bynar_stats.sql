@@ -60,7 +60,7 @@ $BODY$
 
 DECLARE
     new_row INTEGER; 
-    new_rev INTEGER := 4;
+    new_rev INTEGER := 9;
     current_revision INTEGER;
 BEGIN
     
@@ -223,8 +223,54 @@ BEGIN
     END IF;
 
 
+    IF (current_revision < 5)
+    THEN
+        -- Controller location of a failed drive behind a raid controller
+        -- so the replacement can be configured before it's added back
+        ALTER TABLE hardware ADD COLUMN raid_location VARCHAR;
+    END IF;
+
+    IF (current_revision < 6)
+    THEN
+        -- Id of a destroyed ceph osd so the replacement disk in this slot
+        -- comes back with the same id
+        ALTER TABLE hardware ADD COLUMN osd_id BIGINT;
+    END IF;
+
+    IF (current_revision < 7)
+    THEN
+        -- The last remediation step commented on a repair ticket so
+        -- each one is only reported once
+        ALTER TABLE operation_details ADD COLUMN remediation VARCHAR;
+    END IF;
+
+    IF (current_revision < 8)
+    THEN
+        -- Destructive actions waiting for a human to approve them
+        CREATE TABLE IF NOT EXISTS pending_actions (
+            action_id SERIAL PRIMARY KEY,
+            detail_id INTEGER REFERENCES storage_details(detail_id) ON DELETE CASCADE,
+            operation_id INTEGER REFERENCES operations(operation_id) ON DELETE CASCADE,
+            device_path VARCHAR NOT NULL,
+            action VARCHAR NOT NULL, -- one of remove_disk, reformat_disk
+            status VARCHAR(20) NOT NULL, -- one of pending, approved, denied
+            requested_time TIMESTAMPTZ NOT NULL,
+            auto_approve_time TIMESTAMPTZ, -- approved after this if nobody answers
+            decided_by VARCHAR, -- who approved or denied it
+            decided_time TIMESTAMPTZ,
+            done_time TIMESTAMPTZ -- when bynar acted on the decision
+            );
+    END IF;
+
+    IF (current_revision < 9)
+    THEN
+        -- Ceph cluster a removed disk belonged to so its replacement is
+        -- added back to the same one
+        ALTER TABLE hardware ADD COLUMN cluster VARCHAR;
+    END IF;
+
     -- Add next revision here
-    -- IF (current_revision < 5)
+    -- IF (current_revision < 10)
     -- THEN
     --      SQL statements
     -- END IF;
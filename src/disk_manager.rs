@@ -1,17 +1,24 @@
 use serde_derive::*;
 
-use std::fs::{create_dir, File};
+use std::collections::HashMap;
+use std::fs::{create_dir, read_to_string, rename, File};
 use std::io::{Error, ErrorKind, Write};
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use api::service::{
-    Disk, DiskType, Disks, Op, OpBoolResult, OpResult, Partition, PartitionInfo, ResultType,
+    Disk, DiskType, Disks, Op, OpBoolResult, OpResult, OpStringResult, Partition, PartitionInfo,
+    ResultType,
 };
 mod backend;
-use crate::backend::BackendType;
+// Only the scaleio tests use it here
+#[cfg(test)]
+#[allow(dead_code)]
+mod http_stub;
+use crate::backend::{Backend, BackendType};
 use block_utils::{Device, MediaType};
 use clap::{crate_authors, crate_version, App, Arg};
 use gpt::{disk, header::read_header, partition::read_partitions};
@@ -25,6 +32,190 @@ use protobuf::RepeatedField;
 use simplelog::{CombinedLogger, Config, SharedLogger, TermLogger, WriteLogger};
 use zmq::{Message, Socket};
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::recorded_calls;
+    use std::fs::{read_to_string, write};
+    use std::net::TcpListener;
+    use tempdir::TempDir;
+
+    // Start disk-manager with the mock backend on loopback and connect to
+    // it the way bynar does
+    fn start_disk_manager(mock_config: &str) -> (TempDir, Socket) {
+        let dir = TempDir::new("disk-manager").unwrap();
+        write(dir.path().join("mock.json"), mock_config).unwrap();
+        start_disk_manager_in(dir)
+    }
+
+    // Start disk-manager with a config dir that's already been set up
+    fn start_disk_manager_in(dir: TempDir) -> (TempDir, Socket) {
+        // Let the kernel pick a free port so parallel runs don't collide
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config_dir = dir.path().to_path_buf();
+        thread::spawn(move || {
+            listen(&BackendType::Mock, &config_dir, "127.0.0.1", port, false).unwrap();
+        });
+        // Wait for the public key to be written
+        let key_file = dir.path().join(format!("{}.pem", get_hostname().unwrap()));
+        let start = Instant::now();
+        let key = loop {
+            let key = read_to_string(&key_file).unwrap_or_default();
+            if !key.is_empty() {
+                break key;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        };
+        let socket = helpers::connect("127.0.0.1", &port.to_string(), &key).unwrap();
+        (dir, socket)
+    }
+
+    // Poll a removal until it stops running and return how it ended
+    fn wait_for_removal(s: &mut Socket, dev: &Path) -> String {
+        let start = Instant::now();
+        loop {
+            let status = helpers::remove_status_request(s, dev).unwrap();
+            if !status.starts_with("removing") {
+                return status;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    fn call_names(config_dir: &Path) -> Vec<String> {
+        recorded_calls(config_dir)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.call)
+            .collect()
+    }
+
+    #[test]
+    fn test_remove_and_add() {
+        let (dir, mut s) =
+            start_disk_manager(r#"{"replacement_id": 4, "latency": 100, "drain": true}"#);
+        let dev = Path::new("/dev/sdb");
+
+        let (id, cluster) = helpers::remove_disk_request(&mut s, dev, None, None, false).unwrap();
+        assert_eq!(cluster, None);
+        assert_eq!(id, Some(4));
+        // The removal drains in the background until it's done
+        assert_eq!(wait_for_removal(&mut s, dev), "removed");
+
+        assert!(helpers::safe_to_add_request(&mut s, dev, None).unwrap().0);
+        helpers::add_disk_request(&mut s, dev, id, None, false).unwrap();
+
+        assert_eq!(
+            call_names(dir.path()),
+            vec![
+                "clear_maintenance",
+                "safe_to_remove",
+                "replacement_id",
+                "remove_disk",
+                "safe_to_add",
+                "add_disk"
+            ]
+        );
+        let calls = recorded_calls(dir.path()).unwrap();
+        assert_eq!(calls[5].device, Some("/dev/sdb".to_string()));
+        assert_eq!(calls[5].id, Some(4));
+    }
+
+    #[test]
+    fn test_remove_without_drain() {
+        let (dir, mut s) = start_disk_manager(r#"{"latency": 100}"#);
+        let dev = Path::new("/dev/sdb");
+
+        // Nothing drains so the removal is done before it answers
+        helpers::remove_disk_request(&mut s, dev, None, None, false).unwrap();
+        assert_eq!(
+            helpers::remove_status_request(&mut s, dev).unwrap(),
+            "removed"
+        );
+        assert_eq!(
+            call_names(dir.path()),
+            vec![
+                "clear_maintenance",
+                "safe_to_remove",
+                "replacement_id",
+                "remove_disk"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_removals_survive_restart() {
+        let dir = TempDir::new("disk-manager").unwrap();
+        write(dir.path().join("mock.json"), r#"{"latency": 100}"#).unwrap();
+        write(
+            dir.path().join(REMOVALS_FILE),
+            r#"{
+                "/dev/sdb": {"status": "removing", "cluster": null},
+                "/dev/sdc": {"status": "removed", "cluster": null}
+            }"#,
+        )
+        .unwrap();
+        let (dir, mut s) = start_disk_manager_in(dir);
+
+        assert_eq!(
+            helpers::remove_status_request(&mut s, Path::new("/dev/sdc")).unwrap(),
+            "removed"
+        );
+        // The removal that was cut off is started again
+        let dev = Path::new("/dev/sdb");
+        assert_eq!(wait_for_removal(&mut s, dev), "removed");
+        assert_eq!(
+            call_names(dir.path()),
+            vec!["clear_maintenance", "remove_disk"]
+        );
+    }
+
+    #[test]
+    fn test_unsafe_and_failing() {
+        let (dir, mut s) = start_disk_manager(
+            r#"{
+                "unsafe_to_remove": ["/dev/sdc"],
+                "unsafe_to_add": ["/dev/sdd"],
+                "fail": ["add_disk"]
+            }"#,
+        );
+
+        let err = helpers::remove_disk_request(&mut s, Path::new("/dev/sdc"), None, None, false)
+            .unwrap_err();
+        assert!(err.to_string().contains("unsafe to remove"));
+        let (safe, reason) =
+            helpers::safe_to_remove_request(&mut s, Path::new("/dev/sdc")).unwrap();
+        assert!(!safe);
+        assert!(reason.is_some());
+        assert!(
+            !helpers::safe_to_add_request(&mut s, Path::new("/dev/sdd"), None)
+                .unwrap()
+                .0
+        );
+        assert!(
+            helpers::add_disk_request(&mut s, Path::new("/dev/sdb"), None, None, false).is_err()
+        );
+
+        // Nothing was removed
+        assert_eq!(
+            call_names(dir.path()),
+            vec![
+                "clear_maintenance",
+                "safe_to_remove",
+                "safe_to_remove",
+                "safe_to_add",
+                "add_disk"
+            ]
+        );
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 struct DiskManagerConfig {
     backend: BackendType,
@@ -46,6 +237,22 @@ fn convert_media_to_disk_type(m: &MediaType) -> DiskType {
     }
 }
 
+// A removal disk-manager started and how it's going
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Removal {
+    status: String,
+    cluster: Option<String>,
+}
+
+// Device -> removal.  Saved to REMOVALS_FILE so bynar can still check on a
+// removal after disk-manager restarts
+type Removals = Arc<Mutex<HashMap<String, Removal>>>;
+
+const REMOVALS_FILE: &str = "removals.json";
+
+// How often to check for expired maintenance
+const MAINTENANCE_CHECK: Duration = Duration::from_secs(300);
+
 fn setup_curve(s: &mut Socket, config_dir: &Path, vault: bool) -> BynarResult<()> {
     // will raise EINVAL if not linked against libsodium
     // The ubuntu package is linked so this shouldn't fail
@@ -94,20 +301,36 @@ fn listen(
     backend_type: &backend::BackendType,
     config_dir: &Path,
     listen_address: &str,
+    port: u16,
     vault: bool,
 ) -> BynarResult<()> {
     debug!("Starting zmq listener with version({:?})", zmq::version());
     let context = zmq::Context::new();
     let mut responder = context.socket(zmq::REP)?;
 
-    debug!("Listening on tcp://{}:5555", listen_address);
+    debug!("Listening on tcp://{}:{}", listen_address, port);
     // Fail to start if this fails
     setup_curve(&mut responder, config_dir, vault)?;
     assert!(responder
-        .bind(&format!("tcp://{}:5555", listen_address))
+        .bind(&format!("tcp://{}:{}", listen_address, port))
         .is_ok());
+    // Nothing can still be running from a previous run so clean up
+    // everything it left behind before resumed removals set flags again
+    clear_maintenance(backend_type, config_dir, false);
+    let removals: Removals = Arc::new(Mutex::new(load_removals(config_dir)?));
+    resume_removals(backend_type, config_dir, &removals)?;
+    let mut last_check = Instant::now();
 
     loop {
+        let ready = responder.poll(zmq::POLLIN, MAINTENANCE_CHECK.as_millis() as i64)?;
+        if last_check.elapsed() >= MAINTENANCE_CHECK {
+            // Clear anything that's outlasted its expiry
+            clear_maintenance(backend_type, config_dir, true);
+            last_check = Instant::now();
+        }
+        if ready == 0 {
+            continue;
+        }
         let msg = responder.recv_bytes(0)?;
         debug!("Got msg len: {}", msg.len());
         trace!("Parsing msg {:?} as hex", msg);
@@ -120,6 +343,11 @@ fn listen(
         };
 
         debug!("Operation requested: {:?}", operation.get_Op_type());
+        let cluster = if operation.has_cluster() {
+            Some(operation.get_cluster())
+        } else {
+            None
+        };
         match operation.get_Op_type() {
             Op::Add => {
                 let id = if operation.has_osd_id() {
@@ -144,6 +372,8 @@ fn listen(
                     operation.get_disk(),
                     &backend_type,
                     id,
+                    operation.get_replica_set(),
+                    cluster,
                     config_dir,
                 ) {
                     Ok(_) => {
@@ -173,27 +403,38 @@ fn listen(
                     continue;
                 }
                 let mut result = OpResult::new();
-                match safe_to_remove(&Path::new(operation.get_disk()), &backend_type, config_dir) {
-                    Ok(true) => {
+                match safe_to_remove(
+                    &Path::new(operation.get_disk()),
+                    &backend_type,
+                    cluster,
+                    config_dir,
+                ) {
+                    Ok((true, _)) => {
                         match remove_disk(
                             &mut responder,
                             operation.get_disk(),
                             &backend_type,
+                            cluster,
                             config_dir,
+                            &removals,
                         ) {
                             Ok(_) => {
-                                info!("Remove disk finished");
+                                info!("Remove disk started");
                             }
                             Err(e) => {
                                 error!("Remove disk error: {:?}", e);
                             }
                         };
                     }
-                    Ok(false) => {
-                        debug!("Disk is not safe to remove");
+                    Ok((false, reason)) => {
+                        debug!("Disk is not safe to remove: {:?}", reason);
                         //Response to client
                         result.set_result(ResultType::ERR);
-                        result.set_error_msg("Not safe to remove disk".to_string());
+                        let msg = match reason {
+                            Some(r) => format!("Not safe to remove disk: {}", r),
+                            None => "Not safe to remove disk".to_string(),
+                        };
+                        result.set_error_msg(msg);
                         let _ = respond_to_client(&result, &mut responder);
                     }
                     Err(e) => {
@@ -214,6 +455,7 @@ fn listen(
                     &mut responder,
                     operation.get_disk(),
                     &backend_type,
+                    cluster,
                     config_dir,
                 ) {
                     Ok(_) => {
@@ -224,11 +466,73 @@ fn listen(
                     }
                 };
             }
+            Op::SafeToAdd => {
+                if !operation.has_disk() {
+                    error!("SafeToAdd operation must include disk field.  Ignoring request");
+                    continue;
+                }
+                match safe_to_add_disk(
+                    &mut responder,
+                    operation.get_disk(),
+                    &backend_type,
+                    cluster,
+                    config_dir,
+                ) {
+                    Ok(_) => {
+                        info!("Safe to add disk finished");
+                    }
+                    Err(e) => {
+                        error!("Safe to add error: {:?}", e);
+                    }
+                };
+            }
+            Op::RemoveStatus => {
+                if !operation.has_disk() {
+                    error!("RemoveStatus operation must include disk field.  Ignoring request");
+                    continue;
+                }
+                match remove_status(
+                    &mut responder,
+                    operation.get_disk(),
+                    &backend_type,
+                    cluster,
+                    config_dir,
+                    &removals,
+                ) {
+                    Ok(_) => {
+                        info!("Remove status finished");
+                    }
+                    Err(e) => {
+                        error!("Remove status error: {:?}", e);
+                    }
+                };
+            }
         };
         thread::sleep(Duration::from_millis(10));
     }
 }
 
+fn clear_maintenance(backend: &BackendType, config_dir: &Path, expired_only: bool) {
+    if let Err(e) = backend::load_backend(backend, Some(config_dir), &[])
+        .and_then(|b| b.clear_maintenance(expired_only))
+    {
+        error!("Clearing maintenance failed: {:?}", e);
+    }
+}
+
+// Load the backend and point it at the cluster the request is for
+fn load_backend(
+    backend: &BackendType,
+    config_dir: &Path,
+    cluster: Option<&str>,
+    replica_set: &[String],
+    d: &Path,
+) -> BynarResult<Box<dyn Backend>> {
+    let mut backend = backend::load_backend(backend, Some(config_dir), replica_set)?;
+    backend.select_cluster(cluster, d)?;
+    Ok(backend)
+}
+
 fn respond_to_client(result: &OpResult, s: &mut Socket) -> BynarResult<()> {
     let encoded = result.write_to_bytes()?;
     let msg = Message::from_slice(&encoded)?;
@@ -242,10 +546,12 @@ fn add_disk(
     d: &str,
     backend: &BackendType,
     id: Option<u64>,
+    replica_set: &[String],
+    cluster: Option<&str>,
     config_dir: &Path,
 ) -> BynarResult<()> {
     let mut result = OpResult::new();
-    let backend = match backend::load_backend(backend, Some(config_dir)) {
+    let backend = match load_backend(backend, config_dir, cluster, replica_set, &Path::new(d)) {
         Ok(backend) => backend,
         Err(e) => {
             result.set_result(ResultType::ERR);
@@ -340,40 +646,252 @@ fn list_disks(s: &mut Socket) -> BynarResult<()> {
     Ok(())
 }
 
+// Removing a disk can take hours if the backend drains it first.  Those
+// removals run in the background and the client is told it started and can
+// check on it with RemoveStatus.  Everything else finishes before answering.
 fn remove_disk(
     s: &mut Socket,
     d: &str,
     backend: &BackendType,
+    cluster: Option<&str>,
     config_dir: &Path,
+    removals: &Removals,
 ) -> BynarResult<()> {
     //Returns OpResult
     let mut result = OpResult::new();
-    let backend = match backend::load_backend(backend, Some(config_dir)) {
-        Ok(b) => b,
+    let in_progress = removals
+        .lock()
+        .map_err(|e| BynarError::new(e.to_string()))?
+        .get(d)
+        .map(|r| r.status == "removing")
+        == Some(true);
+    if in_progress {
+        result.set_result(ResultType::ERR);
+        result.set_error_msg(format!("Removal of {} is already in progress", d));
+        let _ = respond_to_client(&result, s);
+        return Ok(());
+    }
+    let cluster = cluster.map(|c| c.to_string());
+    // Without a record bynar would never hear how the removal went
+    if let Err(e) = set_removal_status(removals, config_dir, d, &cluster, "removing".to_string()) {
+        error!("Unable to save the removal status of {}: {:?}", d, e);
+        result.set_result(ResultType::ERR);
+        result.set_error_msg(e.to_string());
+        let _ = respond_to_client(&result, s);
+        return Ok(());
+    }
+    // The replacement goes back to the cluster this disk is in
+    let picked = backend::load_backend(backend, Some(config_dir), &[]).and_then(|mut b| {
+        let picked = b.select_cluster(cluster.as_ref().map(|c| c.as_str()), &Path::new(d))?;
+        Ok((b, picked))
+    });
+    let (b, cluster) = match picked {
+        Ok((b, picked)) => {
+            let cluster = picked.or(cluster);
+            if let Some(ref cluster) = cluster {
+                result.set_cluster(cluster.clone());
+            }
+            (b, cluster)
+        }
         Err(e) => {
+            error!("Unable to load the backend to remove {}: {:?}", d, e);
+            if let Err(e) =
+                set_removal_status(removals, config_dir, d, &cluster, format!("failed: {}", e))
+            {
+                error!("Unable to save the removal status of {}: {:?}", d, e);
+            }
             result.set_result(ResultType::ERR);
             result.set_error_msg(e.to_string());
-
-            // Bail early.  We can't load the backend
             let _ = respond_to_client(&result, s);
             return Ok(());
         }
     };
-    match backend.remove_disk(&Path::new(d), false) {
-        Ok(_) => {
-            result.set_result(ResultType::OK);
+    // Look this up before the disk is wiped
+    match b.replacement_id(&Path::new(d)) {
+        Ok(Some(id)) => {
+            debug!("Replacement for {} will reuse id {}", d, id);
+            result.set_osd_id(id);
         }
+        Ok(None) => {}
         Err(e) => {
-            result.set_result(ResultType::ERR);
-            result.set_error_msg(e.to_string());
+            warn!("Unable to find the replacement id for {}: {}", d, e);
         }
     };
+    if !b.removes_in_background() {
+        let status = match b.remove_disk(&Path::new(d), false) {
+            Ok(_) => {
+                info!("Removing {} finished", d);
+                result.set_result(ResultType::OK);
+                "removed".to_string()
+            }
+            Err(e) => {
+                error!("Removing {} failed: {:?}", d, e);
+                result.set_result(ResultType::ERR);
+                result.set_error_msg(e.to_string());
+                format!("failed: {}", e)
+            }
+        };
+        if let Err(e) = set_removal_status(removals, config_dir, d, &cluster, status) {
+            error!("Unable to save the removal status of {}: {:?}", d, e);
+        }
+        let _ = respond_to_client(&result, s);
+        return Ok(());
+    }
+    spawn_removal(d, backend, cluster, config_dir, removals);
+    result.set_result(ResultType::OK);
     let _ = respond_to_client(&result, s);
     Ok(())
 }
 
-fn safe_to_remove(d: &Path, backend: &BackendType, config_dir: &Path) -> BynarResult<bool> {
-    let backend = backend::load_backend(backend, Some(config_dir))?;
+// Backends aren't Send so the removal thread loads its own
+fn spawn_removal(
+    d: &str,
+    backend: &BackendType,
+    cluster: Option<String>,
+    config_dir: &Path,
+    removals: &Removals,
+) {
+    let device = d.to_string();
+    let backend = backend.clone();
+    let config_dir = config_dir.to_path_buf();
+    let removals = Arc::clone(removals);
+    thread::spawn(move || {
+        let dev_path = Path::new(&device);
+        let status = match load_backend(
+            &backend,
+            &config_dir,
+            cluster.as_ref().map(|c| c.as_str()),
+            &[],
+            dev_path,
+        )
+        .and_then(|b| b.remove_disk(dev_path, false))
+        {
+            Ok(_) => {
+                info!("Removing {} finished", device);
+                "removed".to_string()
+            }
+            Err(e) => {
+                error!("Removing {} failed: {:?}", device, e);
+                format!("failed: {}", e)
+            }
+        };
+        if let Err(e) = set_removal_status(&removals, &config_dir, &device, &cluster, status) {
+            error!("Unable to save the removal status of {}: {:?}", device, e);
+        }
+    });
+}
+
+fn load_removals(config_dir: &Path) -> BynarResult<HashMap<String, Removal>> {
+    let path = config_dir.join(REMOVALS_FILE);
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let s = read_to_string(&path)?;
+    Ok(serde_json::from_str(&s)?)
+}
+
+// Record how a removal is going.  The file is replaced in one go so a
+// crash never leaves half of it behind
+fn set_removal_status(
+    removals: &Removals,
+    config_dir: &Path,
+    d: &str,
+    cluster: &Option<String>,
+    status: String,
+) -> BynarResult<()> {
+    let mut r = removals
+        .lock()
+        .map_err(|e| BynarError::new(e.to_string()))?;
+    r.insert(
+        d.to_string(),
+        Removal {
+            status,
+            cluster: cluster.clone(),
+        },
+    );
+    let path = config_dir.join(REMOVALS_FILE);
+    let tmp = path.with_extension("tmp");
+    let mut f = File::create(&tmp)?;
+    f.write_all(serde_json::to_string_pretty(&*r)?.as_bytes())?;
+    f.sync_all()?;
+    rename(&tmp, &path)?;
+    Ok(())
+}
+
+// Removals that were still running when disk-manager went down are started
+// again.  A drain picks up from the osd's current weight so the work
+// already done isn't repeated
+fn resume_removals(
+    backend: &BackendType,
+    config_dir: &Path,
+    removals: &Removals,
+) -> BynarResult<()> {
+    let interrupted: Vec<(String, Option<String>)> = removals
+        .lock()
+        .map_err(|e| BynarError::new(e.to_string()))?
+        .iter()
+        .filter(|(_, r)| r.status == "removing")
+        .map(|(d, r)| (d.clone(), r.cluster.clone()))
+        .collect();
+    for (d, cluster) in interrupted {
+        info!("Resuming the removal of {}", d);
+        spawn_removal(&d, backend, cluster, config_dir, removals);
+    }
+    Ok(())
+}
+
+fn remove_status(
+    s: &mut Socket,
+    d: &str,
+    backend: &BackendType,
+    cluster: Option<&str>,
+    config_dir: &Path,
+    removals: &Removals,
+) -> BynarResult<()> {
+    let mut result = OpStringResult::new();
+    let status = removals
+        .lock()
+        .map_err(|e| BynarError::new(e.to_string()))?
+        .get(d)
+        .map(|r| r.status.clone());
+    match status {
+        Some(ref status) if status == "removing" => {
+            // The backend may know more about how far along it is
+            let progress = load_backend(backend, config_dir, cluster, &[], &Path::new(d))
+                .and_then(|b| b.remove_progress(&Path::new(d)));
+            result.set_result(ResultType::OK);
+            match progress {
+                Ok(Some(p)) => result.set_value(format!("removing: {}", p)),
+                Ok(None) => result.set_value(status.clone()),
+                Err(e) => {
+                    debug!("Unable to get remove progress for {}: {}", d, e);
+                    result.set_value(status.clone());
+                }
+            };
+        }
+        Some(status) => {
+            result.set_result(ResultType::OK);
+            result.set_value(status);
+        }
+        None => {
+            result.set_result(ResultType::ERR);
+            result.set_error_msg(format!("No removal of {} has been started", d));
+        }
+    };
+    let encoded = result.write_to_bytes()?;
+    let msg = Message::from_slice(&encoded)?;
+    debug!("Responding to client with msg len: {}", msg.len());
+    s.send_msg(msg, 0)?;
+    Ok(())
+}
+
+fn safe_to_remove(
+    d: &Path,
+    backend: &BackendType,
+    cluster: Option<&str>,
+    config_dir: &Path,
+) -> BynarResult<(bool, Option<String>)> {
+    let backend = load_backend(backend, config_dir, cluster, &[], d)?;
     let safe = backend.safe_to_remove(d, false)?;
 
     Ok(safe)
@@ -383,15 +901,20 @@ fn safe_to_remove_disk(
     s: &mut Socket,
     d: &str,
     backend: &BackendType,
+    cluster: Option<&str>,
     config_dir: &Path,
 ) -> BynarResult<()> {
     debug!("Checking if {} is safe to remove", d);
     let mut result = OpBoolResult::new();
-    match safe_to_remove(&Path::new(d), &backend, &config_dir) {
-        Ok(val) => {
+    match safe_to_remove(&Path::new(d), &backend, cluster, &config_dir) {
+        Ok((val, reason)) => {
             debug!("Safe to remove: {}", val);
             result.set_result(ResultType::OK);
             result.set_value(val);
+            // Let the client know why it isn't safe
+            if let Some(r) = reason {
+                result.set_error_msg(r);
+            }
         }
         Err(e) => {
             debug!("Safe to remove err: {}", e);
@@ -411,6 +934,55 @@ fn safe_to_remove_disk(
     Ok(())
 }
 
+fn safe_to_add(
+    d: &Path,
+    backend: &BackendType,
+    cluster: Option<&str>,
+    config_dir: &Path,
+) -> BynarResult<(bool, Option<String>)> {
+    let backend = load_backend(backend, config_dir, cluster, &[], d)?;
+    let safe = backend.safe_to_add(d, false)?;
+
+    Ok(safe)
+}
+
+fn safe_to_add_disk(
+    s: &mut Socket,
+    d: &str,
+    backend: &BackendType,
+    cluster: Option<&str>,
+    config_dir: &Path,
+) -> BynarResult<()> {
+    debug!("Checking if {} is safe to add", d);
+    let mut result = OpBoolResult::new();
+    match safe_to_add(&Path::new(d), &backend, cluster, &config_dir) {
+        Ok((val, reason)) => {
+            debug!("Safe to add: {}", val);
+            result.set_result(ResultType::OK);
+            result.set_value(val);
+            // Let the client know why it isn't safe
+            if let Some(r) = reason {
+                result.set_error_msg(r);
+            }
+        }
+        Err(e) => {
+            debug!("Safe to add err: {}", e);
+            result.set_result(ResultType::ERR);
+            result.set_error_msg(e.to_string());
+            let encoded = result.write_to_bytes()?;
+            let msg = Message::from_slice(&encoded)?;
+            debug!("Responding to client with msg len: {}", msg.len());
+            s.send_msg(msg, 0)?;
+            return Err(BynarError::new(format!("safe to add error: {}", e)));
+        }
+    };
+    let encoded = result.write_to_bytes()?;
+    let msg = Message::from_slice(&encoded)?;
+    debug!("Responding to client with msg len: {}", msg.len());
+    s.send_msg(msg, 0)?;
+    Ok(())
+}
+
 fn main() {
     let matches = App::new("Disk Manager")
         .version(crate_version!())
@@ -421,7 +993,7 @@ fn main() {
                 .default_value("ceph")
                 .help("Backend cluster type to manage disks for")
                 .long("backend")
-                .possible_values(&["ceph"])
+                .possible_values(&["ceph", "gluster", "local", "scaleio", "mock", "plugin"])
                 .takes_value(true)
                 .required(false),
         )
@@ -433,6 +1005,14 @@ fn main() {
                 .takes_value(true)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("port")
+                .default_value("5555")
+                .help("Port to listen on")
+                .long("port")
+                .takes_value(true)
+                .required(false),
+        )
         .arg(
             Arg::with_name("configdir")
                 .default_value("/etc/bynar")
@@ -491,6 +1071,8 @@ fn main() {
     let log = Path::new(matches.value_of("log").unwrap());
     let backend = BackendType::from_str(matches.value_of("backend").unwrap())
         .expect("unable to convert backend option to BackendType");
+    let port = u16::from_str(matches.value_of("port").unwrap())
+        .expect("unable to convert port option to u16");
     let vault_support = {
         bool::from_str(matches.value_of("vault").unwrap())
             .expect("unable to convert vault option to bool")
@@ -510,6 +1092,7 @@ fn main() {
         &backend,
         config_dir,
         matches.value_of("listen").unwrap(),
+        port,
         vault_support,
     ) {
         Ok(_) => {
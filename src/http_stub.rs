@@ -0,0 +1,125 @@
+//! A local HTTP server standing in for ticketing systems, webhooks and
+//! storage gateways in tests.  Every request is recorded and answered by
+//! the handler the test gives.
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    /// Includes the query string
+    pub path: String,
+    pub body: String,
+}
+
+impl Request {
+    /// The body decoded as a form
+    pub fn form(&self) -> Vec<(String, String)> {
+        self.body
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                let key = decode(parts.next().unwrap_or(""));
+                let value = decode(parts.next().unwrap_or(""));
+                (key, value)
+            })
+            .collect()
+    }
+
+    /// The value of a form field
+    pub fn field(&self, key: &str) -> Option<String> {
+        self.form()
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+}
+
+// Undo application/x-www-form-urlencoded escaping
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = String::from_utf8_lossy(&bytes[i + 1..i + 3]).into_owned();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+pub struct HttpStub {
+    /// Ex: http://127.0.0.1:34567
+    pub url: String,
+    pub requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl HttpStub {
+    pub fn start<F>(handler: F) -> HttpStub
+    where
+        F: Fn(&Request) -> (u16, String) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    let lower = header.to_lowercase();
+                    if let Some(length) = lower.strip_prefix("content-length:") {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let parts: Vec<&str> = request_line.split_whitespace().collect();
+                let request = Request {
+                    method: parts[0].to_string(),
+                    path: parts[1].to_string(),
+                    body: String::from_utf8_lossy(&body).into_owned(),
+                };
+                let (status, response) = handler(&request);
+                recorded.lock().unwrap().push(request);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    response.len(),
+                    response
+                );
+            }
+        });
+        HttpStub { url, requests }
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
@@ -23,6 +23,22 @@ mod tests {
     use std::path::{Path, PathBuf};
     use uuid::Uuid;
 
+    #[test]
+    fn test_remediation_round_trip() {
+        for stage in &[
+            super::Remediation::Removing,
+            super::Remediation::Drained,
+            super::Remediation::RemovalFailed,
+            super::Remediation::ReplacementMissing,
+            super::Remediation::ReplacementDetected,
+            super::Remediation::AddFailed,
+        ] {
+            let parsed: super::Remediation = stage.to_string().parse().unwrap();
+            assert_eq!(&parsed, stage);
+        }
+        assert!("unplugged".parse::<super::Remediation>().is_err());
+    }
+
     #[test]
     fn test_new_host() {
         TermLogger::new(log::LevelFilter::Debug, Config::default()).unwrap();
@@ -68,6 +84,10 @@ mod tests {
             state: crate::test_disk::State::Unscanned,
             storage_detail_id: result.storage_detail_id,
             operation_id: None,
+            surface_scan: None,
+            raid_location: None,
+            reformat_approval: None,
+            reformat_action_id: None,
         };
 
         println!("Adding disk {:#?}", d);
@@ -188,8 +208,18 @@ mod tests {
 #[derive(Debug)]
 pub struct DiskRepairTicket {
     pub ticket_id: String,
+    /// Several disks can share a ticket.  This is the disk's own operation
+    pub operation_id: u32,
     pub device_name: String,
     pub device_path: String,
+    pub raid_location: Option<String>,
+    pub osd_id: Option<u64>,
+    /// Serial of the disk that failed so its replacement can be told apart
+    pub serial_number: Option<String>,
+    /// The last remediation step commented on the ticket
+    pub remediation: Option<Remediation>,
+    /// Ceph cluster the disk was removed from
+    pub cluster: Option<String>,
 }
 #[derive(Debug)]
 pub struct HostDetailsMapping {
@@ -284,6 +314,53 @@ impl Display for OperationStatus {
     }
 }
 
+/// How far a disk repair has gotten since its ticket was filed.  A step is
+/// commented on the ticket when it's reached and not again until another
+/// step has been.  Steps are declared in the order a repair goes through
+/// them.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Remediation {
+    /// disk-manager is draining and removing the disk
+    Removing,
+    /// The disk is out of the cluster and can be pulled
+    Drained,
+    RemovalFailed,
+    /// The ticket was resolved but no new disk showed up
+    ReplacementMissing,
+    ReplacementDetected,
+    AddFailed,
+}
+
+impl Display for Remediation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fResult {
+        let message = match *self {
+            Remediation::Removing => "removing",
+            Remediation::Drained => "drained",
+            Remediation::RemovalFailed => "removal_failed",
+            Remediation::ReplacementMissing => "replacement_missing",
+            Remediation::ReplacementDetected => "replacement_detected",
+            Remediation::AddFailed => "add_failed",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl FromStr for Remediation {
+    type Err = BynarError;
+
+    fn from_str(s: &str) -> BynarResult<Self> {
+        match s {
+            "removing" => Ok(Remediation::Removing),
+            "drained" => Ok(Remediation::Drained),
+            "removal_failed" => Ok(Remediation::RemovalFailed),
+            "replacement_missing" => Ok(Remediation::ReplacementMissing),
+            "replacement_detected" => Ok(Remediation::ReplacementDetected),
+            "add_failed" => Ok(Remediation::AddFailed),
+            _ => Err(BynarError::new(format!("Unknown remediation step {}", s))),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct OperationDetail {
     pub op_detail_id: Option<u32>,
@@ -857,6 +934,125 @@ pub fn save_state(
     }
 }
 
+/// Record where a failed raid backed drive lives on its controller
+pub fn save_raid_location(
+    pool: &Pool<ConnectionManager>,
+    device_detail: &BlockDevice,
+    raid_location: &str,
+) -> BynarResult<()> {
+    let conn = get_connection_from_pool(pool)?;
+    match device_detail.device_database_id {
+        Some(dev_id) => {
+            debug!(
+                "Saving raid location {} for device {}",
+                raid_location, device_detail.device.name
+            );
+            conn.execute(
+                "UPDATE hardware SET raid_location = $1 WHERE device_id = $2",
+                &[&raid_location, &(dev_id as i32)],
+            )?;
+            Ok(())
+        }
+        None => Err(BynarError::new(format!(
+            "Device {} for storage detail with id {} is not in database",
+            device_detail.device.name, device_detail.storage_detail_id
+        ))),
+    }
+}
+
+/// The failed drive has left the array and its replacement is in service.
+/// Forget where it was so a later failure can't be sent to the old bay
+pub fn clear_raid_location(pool: &Pool<ConnectionManager>, operation_id: u32) -> BynarResult<()> {
+    let conn = get_connection_from_pool(pool)?;
+    debug!("Clearing raid location for operation {}", operation_id);
+    conn.execute(
+        "UPDATE hardware SET raid_location = NULL WHERE device_id = \
+         (SELECT device_id FROM operations WHERE operation_id = $1)",
+        &[&(operation_id as i32)],
+    )?;
+    Ok(())
+}
+
+/// Forget the id a removed disk had once its replacement took it over so a
+/// later failure doesn't reuse a stale id
+pub fn clear_osd_id(pool: &Pool<ConnectionManager>, operation_id: u32) -> BynarResult<()> {
+    let conn = get_connection_from_pool(pool)?;
+    debug!("Clearing osd id for operation {}", operation_id);
+    conn.execute(
+        "UPDATE hardware SET osd_id = NULL WHERE device_id = \
+         (SELECT device_id FROM operations WHERE operation_id = $1)",
+        &[&(operation_id as i32)],
+    )?;
+    Ok(())
+}
+
+/// Forget the cluster a removed disk belonged to once its replacement
+/// has been added back to it
+pub fn clear_cluster(pool: &Pool<ConnectionManager>, operation_id: u32) -> BynarResult<()> {
+    let conn = get_connection_from_pool(pool)?;
+    debug!("Clearing cluster for operation {}", operation_id);
+    conn.execute(
+        "UPDATE hardware SET cluster = NULL WHERE device_id = \
+         (SELECT device_id FROM operations WHERE operation_id = $1)",
+        &[&(operation_id as i32)],
+    )?;
+    Ok(())
+}
+
+/// Record the cluster a removed disk belonged to so its replacement can
+/// be added back to the same cluster
+pub fn save_cluster(
+    pool: &Pool<ConnectionManager>,
+    device_detail: &BlockDevice,
+    cluster: &str,
+) -> BynarResult<()> {
+    let conn = get_connection_from_pool(pool)?;
+    match device_detail.device_database_id {
+        Some(dev_id) => {
+            debug!(
+                "Saving cluster {} for device {}",
+                cluster, device_detail.device.name
+            );
+            conn.execute(
+                "UPDATE hardware SET cluster = $1 WHERE device_id = $2",
+                &[&cluster, &(dev_id as i32)],
+            )?;
+            Ok(())
+        }
+        None => Err(BynarError::new(format!(
+            "Device {} for storage detail with id {} is not in database",
+            device_detail.device.name, device_detail.storage_detail_id
+        ))),
+    }
+}
+
+/// Record the id a removed disk had in the cluster so its replacement
+/// can be added back with the same id
+pub fn save_osd_id(
+    pool: &Pool<ConnectionManager>,
+    device_detail: &BlockDevice,
+    osd_id: u64,
+) -> BynarResult<()> {
+    let conn = get_connection_from_pool(pool)?;
+    match device_detail.device_database_id {
+        Some(dev_id) => {
+            debug!(
+                "Saving osd id {} for device {}",
+                osd_id, device_detail.device.name
+            );
+            conn.execute(
+                "UPDATE hardware SET osd_id = $1 WHERE device_id = $2",
+                &[&(osd_id as i64), &(dev_id as i32)],
+            )?;
+            Ok(())
+        }
+        None => Err(BynarError::new(format!(
+            "Device {} for storage detail with id {} is not in database",
+            device_detail.device.name, device_detail.storage_detail_id
+        ))),
+    }
+}
+
 pub fn save_smart_result(
     pool: &Pool<ConnectionManager>,
     device_detail: &BlockDevice,
@@ -1005,8 +1201,16 @@ pub fn get_smart_result(
 fn row_to_ticket(row: &Row<'_>) -> DiskRepairTicket {
     DiskRepairTicket {
         ticket_id: row.get(0),
+        operation_id: row.get::<_, i32>(4) as u32,
         device_name: row.get(1),
         device_path: row.get(2),
+        raid_location: row.get(3),
+        osd_id: row.get::<_, Option<i64>>(5).map(|id| id as u64),
+        serial_number: row.get(6),
+        remediation: row
+            .get::<_, Option<String>>(7)
+            .and_then(|r| Remediation::from_str(&r).ok()),
+        cluster: row.get(8),
     }
 }
 
@@ -1019,7 +1223,7 @@ pub fn get_outstanding_repair_tickets(
     let conn = get_connection_from_pool(pool)?;
 
     // Get all tickets of myself with device.state=WaitingForReplacement and operation_detail.status = pending or in_progress
-    let stmt = "SELECT tracking_id, device_name, device_path FROM operation_details JOIN operations USING (operation_id)
+    let stmt = "SELECT tracking_id, device_name, device_path, raid_location, operation_id, osd_id, serial_number, remediation, cluster FROM operation_details JOIN operations USING (operation_id)
      JOIN hardware USING (device_id) WHERE 
      (status=$1 OR status=$2) AND 
      type_id = (SELECT type_id FROM operation_types WHERE op_name= $3) AND 
@@ -1060,23 +1264,53 @@ pub fn get_outstanding_repair_tickets(
     }
 }
 
-/// Sets status=Complete for the record that has the given ticket_id.
+/// Sets status=Complete for the disk's operation on the given ticket_id.
 /// Equivalent to calling add_or_update_operation_detail() with appropriate fields set
-pub fn resolve_ticket_in_db(pool: &Pool<ConnectionManager>, ticket_id: &str) -> BynarResult<()> {
+pub fn resolve_ticket_in_db(
+    pool: &Pool<ConnectionManager>,
+    ticket_id: &str,
+    operation_id: u32,
+) -> BynarResult<()> {
     let conn = get_connection_from_pool(pool)?;
-    debug!("Attempting to resolve ticket {}", ticket_id);
-
-    // TODO[SD]: make sure there is one ticket with this ID
-    let stmt = format!(
-        "UPDATE operation_details SET status='{}' WHERE ticket_id='{}'",
-        OperationStatus::Complete,
-        ticket_id
+    debug!(
+        "Attempting to resolve operation {} on ticket {}",
+        operation_id, ticket_id
     );
-    let stmt_query = conn.execute(&stmt, &[])?;
+
+    // Other disks on the same ticket may still be waiting
+    let stmt_query = conn.execute(
+        "UPDATE operation_details SET status = $1 WHERE tracking_id = $2 AND operation_id = $3",
+        &[
+            &OperationStatus::Complete.to_string(),
+            &ticket_id,
+            &(operation_id as i32),
+        ],
+    )?;
     info!(
-        "Updated {} rows in database. Ticket {} marked as complete.",
-        stmt_query, ticket_id
+        "Updated {} rows in database. Operation {} on ticket {} marked as complete.",
+        stmt_query, operation_id, ticket_id
+    );
+    Ok(())
+}
+
+/// Record the remediation step that was last commented on a ticket for
+/// the disk the operation is about
+pub fn save_remediation(
+    pool: &Pool<ConnectionManager>,
+    operation_id: u32,
+    ticket_id: &str,
+    remediation: Remediation,
+) -> BynarResult<()> {
+    let conn = get_connection_from_pool(pool)?;
+    debug!(
+        "Saving remediation {} for operation {} on ticket {}",
+        remediation, operation_id, ticket_id
     );
+    conn.execute(
+        "UPDATE operation_details SET remediation = $1, snapshot_time = now() \
+         WHERE operation_id = $2 AND tracking_id = $3",
+        &[&remediation.to_string(), &(operation_id as i32), &ticket_id],
+    )?;
     Ok(())
 }
 
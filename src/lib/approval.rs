@@ -0,0 +1,313 @@
+//! Destructive actions that wait for a human to approve them.  bynar records
+//! them in the database and bynar-client approves or denies them.
+use crate::error::{BynarError, BynarResult};
+use crate::DBConfig;
+use log::debug;
+use postgres::{params::ConnectParams, params::Host, rows::Row, Connection, TlsMode};
+use serde_derive::*;
+use std::fmt::{Display, Formatter, Result as fResult};
+use std::str::FromStr;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for action in &[Action::RemoveDisk, Action::ReformatDisk] {
+            assert_eq!(&Action::from_str(&action.to_string()).unwrap(), action);
+        }
+        for status in &[
+            ApprovalStatus::Pending,
+            ApprovalStatus::Approved,
+            ApprovalStatus::Denied,
+        ] {
+            assert_eq!(
+                &ApprovalStatus::from_str(&status.to_string()).unwrap(),
+                status
+            );
+        }
+        assert!(Action::from_str("wipe_disk").is_err());
+    }
+
+    #[test]
+    fn test_approval_config() {
+        let config: ApprovalConfig = serde_json::from_str("{}").unwrap();
+        assert!(config.requires(Action::RemoveDisk));
+        assert!(config.requires(Action::ReformatDisk));
+        assert_eq!(config.auto_approve_minutes, None);
+
+        let config: ApprovalConfig = serde_json::from_str(
+            r#"{"actions": ["remove_disk"], "auto_approve_minutes": 120,
+                "ticket_status": "Approved"}"#,
+        )
+        .unwrap();
+        assert!(config.requires(Action::RemoveDisk));
+        assert!(!config.requires(Action::ReformatDisk));
+        assert_eq!(config.auto_approve_minutes, Some(120));
+        assert_eq!(config.ticket_status, Some("Approved".to_string()));
+    }
+}
+
+/// Destructive things bynar can be told to ask about first
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Drain a failed disk and remove it from its cluster
+    RemoveDisk,
+    /// Reformat a disk whose filesystem couldn't be repaired
+    ReformatDisk,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fResult {
+        let message = match *self {
+            Action::RemoveDisk => "remove_disk",
+            Action::ReformatDisk => "reformat_disk",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl FromStr for Action {
+    type Err = BynarError;
+
+    fn from_str(s: &str) -> BynarResult<Self> {
+        match s {
+            "remove_disk" => Ok(Action::RemoveDisk),
+            "reformat_disk" => Ok(Action::ReformatDisk),
+            _ => Err(BynarError::new(format!("Unknown action {}", s))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApprovalStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+impl Display for ApprovalStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fResult {
+        let message = match *self {
+            ApprovalStatus::Pending => "pending",
+            ApprovalStatus::Approved => "approved",
+            ApprovalStatus::Denied => "denied",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl FromStr for ApprovalStatus {
+    type Err = BynarError;
+
+    fn from_str(s: &str) -> BynarResult<Self> {
+        match s {
+            "pending" => Ok(ApprovalStatus::Pending),
+            "approved" => Ok(ApprovalStatus::Approved),
+            "denied" => Ok(ApprovalStatus::Denied),
+            _ => Err(BynarError::new(format!("Unknown approval status {}", s))),
+        }
+    }
+}
+
+/// The approval section of bynar.json
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApprovalConfig {
+    /// The actions that need approval.  Defaults to all of them
+    #[serde(default = "all_actions")]
+    pub actions: Vec<Action>,
+    /// Approve an action nobody has answered after this many minutes.
+    /// Actions wait forever when it's unset
+    pub auto_approve_minutes: Option<u32>,
+    /// Moving a disk's repair ticket to this status approves removing it
+    pub ticket_status: Option<String>,
+}
+
+fn all_actions() -> Vec<Action> {
+    vec![Action::RemoveDisk, Action::ReformatDisk]
+}
+
+impl ApprovalConfig {
+    pub fn requires(&self, action: Action) -> bool {
+        self.actions.contains(&action)
+    }
+}
+
+/// An action bynar hasn't finished with yet
+#[derive(Debug)]
+pub struct PendingAction {
+    pub action_id: u32,
+    pub action: Action,
+    pub status: ApprovalStatus,
+    pub hostname: String,
+    pub device_path: String,
+    pub operation_id: Option<u32>,
+    /// The disk's repair ticket if one has been filed
+    pub tracking_id: Option<String>,
+    pub requested_time: String,
+    /// When it's approved if nobody answers
+    pub auto_approve_time: Option<String>,
+    pub decided_by: Option<String>,
+}
+
+const SELECT_ACTIONS: &str = "SELECT action_id, action, status, hostname, device_path, \
+     operation_id, (SELECT tracking_id FROM operation_details d \
+     WHERE d.operation_id = a.operation_id AND tracking_id IS NOT NULL LIMIT 1), \
+     to_char(requested_time, 'YYYY-MM-DD HH24:MI TZ'), \
+     to_char(auto_approve_time, 'YYYY-MM-DD HH24:MI TZ'), decided_by \
+     FROM pending_actions a JOIN storage_details USING (detail_id)";
+
+fn row_to_action(row: &Row<'_>) -> BynarResult<PendingAction> {
+    Ok(PendingAction {
+        action_id: row.get::<_, i32>(0) as u32,
+        action: Action::from_str(&row.get::<_, String>(1))?,
+        status: ApprovalStatus::from_str(&row.get::<_, String>(2))?,
+        hostname: row.get(3),
+        device_path: row.get(4),
+        operation_id: row.get::<_, Option<i32>>(5).map(|id| id as u32),
+        tracking_id: row.get(6),
+        requested_time: row.get(7),
+        auto_approve_time: row.get(8),
+        decided_by: row.get(9),
+    })
+}
+
+/// Connect to the database bynar keeps its actions in
+pub fn connect(db_config: &DBConfig) -> BynarResult<Connection> {
+    debug!(
+        "Connecting to database {} at {}:{} using {}",
+        db_config.dbname, db_config.endpoint, db_config.port, db_config.username
+    );
+    let params = ConnectParams::builder()
+        .user(&db_config.username, db_config.password.as_deref())
+        .port(db_config.port)
+        .database(&db_config.dbname)
+        .build(Host::Tcp(db_config.endpoint.to_string()));
+    Ok(Connection::connect(params, TlsMode::None)?)
+}
+
+/// Record an action that needs approval and return its id
+pub fn request_action(
+    conn: &Connection,
+    storage_detail_id: u32,
+    operation_id: Option<u32>,
+    device_path: &str,
+    action: Action,
+    auto_approve_minutes: Option<u32>,
+) -> BynarResult<u32> {
+    debug!("Requesting approval to {} {}", action, device_path);
+    let rows = conn.query(
+        "INSERT INTO pending_actions (detail_id, operation_id, device_path, action, status, \
+         requested_time, auto_approve_time) \
+         VALUES ($1, $2, $3, $4, $5, now(), now() + make_interval(mins => $6)) \
+         RETURNING action_id",
+        &[
+            &(storage_detail_id as i32),
+            &operation_id.map(|id| id as i32),
+            &device_path,
+            &action.to_string(),
+            &ApprovalStatus::Pending.to_string(),
+            &auto_approve_minutes.map(|m| m as i32),
+        ],
+    )?;
+    match rows.iter().next() {
+        Some(row) => Ok(row.get::<_, i32>(0) as u32),
+        None => Err(BynarError::new(format!(
+            "Recording approval to {} {} failed",
+            action, device_path
+        ))),
+    }
+}
+
+// Nobody answered in time
+fn auto_approve(conn: &Connection) -> BynarResult<()> {
+    let approved = conn.execute(
+        "UPDATE pending_actions SET status = $1, decided_by = 'auto-approve', \
+         decided_time = now() WHERE status = $2 AND auto_approve_time <= now()",
+        &[
+            &ApprovalStatus::Approved.to_string(),
+            &ApprovalStatus::Pending.to_string(),
+        ],
+    )?;
+    if approved > 0 {
+        debug!("Auto approved {} action(s)", approved);
+    }
+    Ok(())
+}
+
+/// The unfinished action for the device, if there is one
+pub fn get_action(
+    conn: &Connection,
+    storage_detail_id: u32,
+    device_path: &str,
+    action: Action,
+) -> BynarResult<Option<PendingAction>> {
+    auto_approve(conn)?;
+    let rows = conn.query(
+        &format!(
+            "{} WHERE detail_id = $1 AND device_path = $2 AND action = $3 \
+             AND done_time IS NULL ORDER BY action_id DESC LIMIT 1",
+            SELECT_ACTIONS
+        ),
+        &[
+            &(storage_detail_id as i32),
+            &device_path,
+            &action.to_string(),
+        ],
+    )?;
+    match rows.iter().next() {
+        Some(row) => Ok(Some(row_to_action(&row)?)),
+        None => Ok(None),
+    }
+}
+
+/// Every action bynar hasn't finished with, oldest first
+pub fn get_outstanding_actions(conn: &Connection) -> BynarResult<Vec<PendingAction>> {
+    auto_approve(conn)?;
+    let rows = conn.query(
+        &format!(
+            "{} WHERE done_time IS NULL ORDER BY action_id",
+            SELECT_ACTIONS
+        ),
+        &[],
+    )?;
+    rows.iter().map(|row| row_to_action(&row)).collect()
+}
+
+/// Approve or deny an action that's waiting
+pub fn decide_action(
+    conn: &Connection,
+    action_id: u32,
+    status: ApprovalStatus,
+    decided_by: &str,
+) -> BynarResult<()> {
+    debug!("{} {} action {}", decided_by, status, action_id);
+    let updated = conn.execute(
+        "UPDATE pending_actions SET status = $1, decided_by = $2, decided_time = now() \
+         WHERE action_id = $3 AND status = $4 AND done_time IS NULL",
+        &[
+            &status.to_string(),
+            &decided_by,
+            &(action_id as i32),
+            &ApprovalStatus::Pending.to_string(),
+        ],
+    )?;
+    if updated != 1 {
+        return Err(BynarError::new(format!(
+            "Action {} isn't waiting for approval",
+            action_id
+        )));
+    }
+    Ok(())
+}
+
+/// bynar has carried out the action or given up on it
+pub fn finish_action(conn: &Connection, action_id: u32) -> BynarResult<()> {
+    conn.execute(
+        "UPDATE pending_actions SET done_time = now() WHERE action_id = $1",
+        &[&(action_id as i32)],
+    )?;
+    Ok(())
+}
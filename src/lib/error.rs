@@ -2,6 +2,7 @@ use blkid::BlkidError;
 use block_utils::BlockUtilsError;
 use ceph::error::RadosError;
 use derive_error as de;
+use gluster::GlusterError;
 use goji::Error as GojiError;
 use hashicorp_vault::client::error::Error as VaultError;
 use lvm::LvmError;
@@ -68,6 +69,7 @@ pub enum BynarError {
     BlockUtilsError(BlockUtilsError),
     #[error(msg_embedded, non_std, no_from)]
     Error(String),
+    GlusterError(GlusterError),
     GojiError(GojiError),
     #[error(msg, non_std, no_from)]
     HardwareError(HardwareError),
@@ -101,6 +103,7 @@ impl BynarError {
             BynarError::BlkidError(ref err) => err.to_string(),
             BynarError::BlockUtilsError(ref err) => err.to_string(),
             BynarError::Error(ref err) => err.to_string(),
+            BynarError::GlusterError(ref err) => err.to_string(),
             BynarError::GojiError(ref err) => err.to_string(),
             BynarError::HardwareError(HardwareError {
                 ref name,
@@ -4,17 +4,29 @@ use std::fs::read_to_string;
 use std::path::Path;
 
 use crate::error::{BynarError, BynarResult};
-use api::service::{Disk, Op, OpBoolResult, Operation, ResultType};
+use api::service::{Disk, Op, OpBoolResult, OpStringResult, Operation, ResultType};
 use hashicorp_vault::client::VaultClient;
 use log::{debug, error};
 use protobuf::parse_from_bytes;
 use protobuf::Message as ProtobufMsg;
 use serde::de::DeserializeOwned;
+use serde_derive::*;
 use zmq::{Message, Socket};
 
+pub mod approval;
 pub mod error;
 pub mod host_information;
 
+/// The database section of bynar.json
+#[derive(Clone, Debug, Deserialize)]
+pub struct DBConfig {
+    pub username: String,
+    pub password: Option<String>,
+    pub port: u16,
+    pub endpoint: String,
+    pub dbname: String,
+}
+
 pub fn load_config<T>(config_dir: &Path, name: &str) -> BynarResult<T>
 where
     T: DeserializeOwned,
@@ -56,6 +68,7 @@ pub fn add_disk_request(
     s: &mut Socket,
     path: &Path,
     id: Option<u64>,
+    cluster: Option<&str>,
     simulate: bool,
 ) -> BynarResult<()> {
     let mut o = Operation::new();
@@ -66,6 +79,9 @@ pub fn add_disk_request(
     if let Some(id) = id {
         o.set_osd_id(id);
     }
+    if let Some(cluster) = cluster {
+        o.set_cluster(cluster.to_string());
+    }
 
     let encoded = o.write_to_bytes().unwrap();
     let msg = Message::from_slice(&encoded)?;
@@ -141,7 +157,17 @@ pub fn list_disks_request(s: &mut Socket) -> BynarResult<Vec<Disk>> {
     Ok(d)
 }
 
-pub fn safe_to_remove_request(s: &mut Socket, path: &Path) -> BynarResult<bool> {
+// The backends explain an unsafe answer in error_msg
+fn reason(op_result: &OpBoolResult) -> Option<String> {
+    if op_result.get_value() || op_result.get_error_msg().is_empty() {
+        None
+    } else {
+        Some(op_result.get_error_msg().to_string())
+    }
+}
+
+/// Returns whether it's safe along with the backend's reason when it isn't
+pub fn safe_to_remove_request(s: &mut Socket, path: &Path) -> BynarResult<(bool, Option<String>)> {
     let mut o = Operation::new();
     debug!("Creating safe to remove operation request");
     o.set_Op_type(Op::SafeToRemove);
@@ -156,17 +182,80 @@ pub fn safe_to_remove_request(s: &mut Socket, path: &Path) -> BynarResult<bool>
     debug!("Decoding msg len: {}", safe_response.len());
     let op_result = parse_from_bytes::<OpBoolResult>(&safe_response)?;
     match op_result.get_result() {
-        ResultType::OK => Ok(op_result.get_value()),
+        ResultType::OK => {
+            let reason = reason(&op_result);
+            if let Some(ref reason) = reason {
+                debug!("Not safe to remove: {}", reason);
+            }
+            Ok((op_result.get_value(), reason))
+        }
+        ResultType::ERR => Err(BynarError::from(op_result.get_error_msg())),
+    }
+}
+
+/// Returns whether it's safe along with the backend's reason when it isn't
+pub fn safe_to_add_request(
+    s: &mut Socket,
+    path: &Path,
+    cluster: Option<&str>,
+) -> BynarResult<(bool, Option<String>)> {
+    let mut o = Operation::new();
+    debug!("Creating safe to add operation request");
+    o.set_Op_type(Op::SafeToAdd);
+    o.set_disk(format!("{}", path.display()));
+    if let Some(cluster) = cluster {
+        o.set_cluster(cluster.to_string());
+    }
+    let encoded = o.write_to_bytes()?;
+    let msg = Message::from_slice(&encoded)?;
+    debug!("Sending message");
+    s.send_msg(msg, 0)?;
+
+    debug!("Waiting for response");
+    let safe_response = s.recv_bytes(0)?;
+    debug!("Decoding msg len: {}", safe_response.len());
+    let op_result = parse_from_bytes::<OpBoolResult>(&safe_response)?;
+    match op_result.get_result() {
+        ResultType::OK => {
+            let reason = reason(&op_result);
+            if let Some(ref reason) = reason {
+                debug!("Not safe to add: {}", reason);
+            }
+            Ok((op_result.get_value(), reason))
+        }
         ResultType::ERR => Err(BynarError::from(op_result.get_error_msg())),
     }
 }
 
+pub fn remove_status_request(s: &mut Socket, path: &Path) -> BynarResult<String> {
+    let mut o = Operation::new();
+    debug!("Creating remove status operation request");
+    o.set_Op_type(Op::RemoveStatus);
+    o.set_disk(format!("{}", path.display()));
+    let encoded = o.write_to_bytes()?;
+    let msg = Message::from_slice(&encoded)?;
+    debug!("Sending message");
+    s.send_msg(msg, 0)?;
+
+    debug!("Waiting for response");
+    let status_response = s.recv_bytes(0)?;
+    debug!("Decoding msg len: {}", status_response.len());
+    let op_result = parse_from_bytes::<OpStringResult>(&status_response)?;
+    match op_result.get_result() {
+        ResultType::OK => Ok(op_result.get_value().to_string()),
+        ResultType::ERR => Err(BynarError::from(op_result.get_error_msg())),
+    }
+}
+
+/// Returns the id the backend kept for the disk's replacement and the
+/// cluster the disk was removed from, if any
 pub fn remove_disk_request(
     s: &mut Socket,
     path: &Path,
     id: Option<u64>,
+    cluster: Option<&str>,
     simulate: bool,
-) -> BynarResult<()> {
+) -> BynarResult<(Option<u64>, Option<String>)> {
     let mut o = Operation::new();
     debug!("Creating remove operation request");
     o.set_Op_type(Op::Remove);
@@ -175,6 +264,9 @@ pub fn remove_disk_request(
     if id.is_some() {
         o.set_osd_id(id.unwrap());
     }
+    if let Some(cluster) = cluster {
+        o.set_cluster(cluster.to_string());
+    }
 
     let encoded = o.write_to_bytes()?;
     let msg = Message::from_slice(&encoded)?;
@@ -187,8 +279,18 @@ pub fn remove_disk_request(
     let op_result = parse_from_bytes::<api::service::OpResult>(&remove_response)?;
     match op_result.get_result() {
         ResultType::OK => {
-            debug!("Add disk successful");
-            Ok(())
+            debug!("Remove disk successful");
+            let osd_id = if op_result.has_osd_id() {
+                Some(op_result.get_osd_id())
+            } else {
+                None
+            };
+            let cluster = if op_result.has_cluster() {
+                Some(op_result.get_cluster().to_string())
+            } else {
+                None
+            };
+            Ok((osd_id, cluster))
         }
         ResultType::ERR => {
             if op_result.has_error_msg() {
@@ -7,25 +7,40 @@
 
 use serde_derive::*;
 
-mod create_support_ticket;
+#[cfg(test)]
+mod http_stub;
 mod in_progress;
+mod notify;
+mod raid;
+mod support_tickets;
+mod surface_scan;
 mod test_disk;
 mod test_hardware;
 #[macro_use]
 mod util;
 
-use crate::create_support_ticket::{create_support_ticket, ticket_resolved};
 use crate::in_progress::*;
-use crate::test_disk::State;
+use crate::notify::{Event, Notifications, NotificationsConfig};
+use crate::raid::RaidLocation;
+use crate::support_tickets::phabricator::PhabricatorConfig;
+use crate::support_tickets::template::{
+    disk_group_variables, host_variables, render, TicketTemplates, TicketVariables,
+};
+use crate::support_tickets::{TicketGrouping, TicketStatus, TicketSystem, TicketSystemType};
+use crate::surface_scan::SurfaceScanConfig;
+use crate::test_disk::{BlockDevice, State, StateMachine};
 use clap::{crate_authors, crate_version, App, Arg};
-use helpers::{error::*, host_information::Host};
+use helpers::approval::{self, Action, ApprovalConfig, ApprovalStatus};
+use helpers::{error::*, host_information::Host, DBConfig};
 use log::{debug, error, info, warn};
 use r2d2::Pool;
 use r2d2_postgres::PostgresConnectionManager as ConnectionManager;
 use simplelog::{CombinedLogger, Config, SharedLogger, TermLogger, WriteLogger};
-use slack_hook::{PayloadBuilder, Slack};
-use std::fs::{create_dir, read_to_string, File};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{create_dir, read_to_string, File, OpenOptions};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use zmq::Socket;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct ConfigSettings {
@@ -39,52 +54,44 @@ pub struct ConfigSettings {
     redfish_password: Option<String>,
     /// The port redfish is listening on
     redfish_port: Option<u16>,
-    slack_webhook: Option<String>,
-    slack_channel: Option<String>,
-    slack_botname: Option<String>,
+    pub slack_webhook: Option<String>,
+    pub slack_channel: Option<String>,
+    pub slack_botname: Option<String>,
     vault_endpoint: Option<String>,
     vault_token: Option<String>,
-    pub jira_user: String,
-    pub jira_password: String,
-    pub jira_host: String,
-    pub jira_issue_type: String,
-    pub jira_priority: String,
-    pub jira_project_id: String,
-    pub jira_ticket_assignee: String,
+    pub jira_user: Option<String>,
+    pub jira_password: Option<String>,
+    pub jira_host: Option<String>,
+    pub jira_issue_type: Option<String>,
+    pub jira_priority: Option<String>,
+    pub jira_project_id: Option<String>,
+    pub jira_ticket_assignee: Option<String>,
+    /// Component for JIRA tickets.  Defaults to Ceph.  Empty means none
+    pub jira_component: Option<String>,
+    #[serde(default)]
+    pub jira_labels: Vec<String>,
+    /// Custom field id to value.  Ex: datacenter or asset tag fields
+    #[serde(default)]
+    pub jira_custom_fields: BTreeMap<String, serde_json::Value>,
+    /// Ticket summary and description templates
+    #[serde(default)]
+    pub ticket_templates: TicketTemplates,
+    /// Whether disks that fail together share a ticket per host, per
+    /// enclosure or get one each.  Defaults to host
+    #[serde(default)]
+    pub ticket_grouping: TicketGrouping,
+    /// Which ticketing system to file repair tickets with.  Defaults to jira
+    #[serde(default)]
+    pub ticket_system: TicketSystemType,
+    pub phabricator: Option<PhabricatorConfig>,
+    /// Where events are sent.  Without it every event goes to slack_webhook
+    pub notifications: Option<NotificationsConfig>,
+    /// Destructive actions wait for a human to approve them when it's set
+    pub approval: Option<ApprovalConfig>,
     pub proxy: Option<String>,
     pub database: DBConfig,
-}
-
-#[derive(Clone, Debug, Deserialize)]
-pub struct DBConfig {
-    pub username: String,
-    pub password: Option<String>,
-    pub port: u16,
-    pub endpoint: String,
-    pub dbname: String,
-}
-
-fn notify_slack(config: &ConfigSettings, msg: &str) -> BynarResult<()> {
-    let c = config.clone();
-    let slack = Slack::new(
-        c.slack_webhook
-            .expect("slack webhook option is None")
-            .as_ref(),
-    )?;
-    let slack_channel = c.slack_channel.unwrap_or_else(|| "".to_string());
-    let bot_name = c.slack_botname.unwrap_or_else(|| "".to_string());
-    let p = PayloadBuilder::new()
-        .text(msg)
-        .channel(slack_channel)
-        .username(bot_name)
-        .build()?;
-
-    let res = slack.send(&p);
-    match res {
-        Ok(_) => debug!("Slack notified"),
-        Err(e) => error!("Slack error: {:?}", e),
-    };
-    Ok(())
+    /// Optional read only surface scan of suspect disks
+    pub surface_scan: Option<SurfaceScanConfig>,
 }
 
 fn get_public_key(config: &ConfigSettings, host_info: &Host) -> BynarResult<String> {
@@ -119,6 +126,8 @@ fn get_public_key(config: &ConfigSettings, host_info: &Host) -> BynarResult<Stri
 
 fn check_for_failed_disks(
     config: &ConfigSettings,
+    tickets: &dyn TicketSystem,
+    notifications: &Notifications,
     host_info: &Host,
     pool: &Pool<ConnectionManager>,
     host_mapping: &HostDetailsMapping,
@@ -126,18 +135,18 @@ fn check_for_failed_disks(
 ) -> BynarResult<()> {
     let public_key = get_public_key(config, &host_info)?;
     //Host information to use in ticket creation
-    let mut description = format!("A disk on {} failed. Please replace.", host_info.hostname);
-    description.push_str(&format!(
-        "\nHostname: {}\nServer type: {}\nServer Serial: {}\nMachine Architecture: {}\nKernel: {}",
-        host_info.hostname,
-        host_info.server_type,
-        host_info.serial_number,
-        host_info.machine_architecture,
-        host_info.kernel,
-    ));
+    let host_vars = host_variables(host_info);
+    // Disks that fail together are filed together
+    let mut failed_disks: BTreeMap<String, Vec<FailedDisk>> = BTreeMap::new();
 
     info!("Checking all drives");
-    for result in test_disk::check_all_disks(&host_info, pool, host_mapping)? {
+    for result in test_disk::check_all_disks(
+        &host_info,
+        pool,
+        host_mapping,
+        config.surface_scan.as_ref(),
+        config.approval.as_ref(),
+    )? {
         match result {
             Ok(state_machine) => {
                 info!(
@@ -147,88 +156,153 @@ fn check_for_failed_disks(
                 let mut dev_path = PathBuf::from("/dev");
                 let dev_name = &state_machine.block_device.device.name;
                 dev_path.push(&dev_name);
+                if let Some(approval) = approval_required(config, Action::ReformatDisk) {
+                    if let Err(e) = track_reformat_approval(
+                        approval,
+                        notifications,
+                        host_info,
+                        pool,
+                        &state_machine.block_device,
+                    ) {
+                        error!("Reformat approval for {} failed: {}", dev_path.display(), e);
+                    }
+                }
 
                 if state_machine.block_device.state == State::WaitingForReplacement {
-                    description.push_str(&format!("\nDisk path: {}", dev_path.display()));
-                    if let Some(serial) = state_machine.block_device.device.serial_number {
-                        description.push_str(&format!("\nDisk serial: {}", serial));
-                    }
-                    description.push_str(&format!(
-                        "\nSCSI host: {}, channel: {} id: {} lun: {}",
-                        state_machine.block_device.scsi_info.host,
-                        state_machine.block_device.scsi_info.channel,
-                        state_machine.block_device.scsi_info.id,
-                        state_machine.block_device.scsi_info.lun
-                    ));
-                    description.push_str(&format!(
-                        "\nDisk vendor: {:?}",
-                        state_machine.block_device.scsi_info.vendor
-                    ));
+                    let vars = disk_variables(&host_vars, &state_machine);
                     info!("Connecting to database to check if disk is in progress");
-                    let in_progress = in_progress::is_hardware_waiting_repair(
+                    // A disk that can't be checked is skipped so the rest
+                    // still get their tickets filed
+                    let in_progress = match in_progress::is_hardware_waiting_repair(
                         pool,
                         host_mapping.storage_detail_id,
                         &dev_name,
                         None,
-                    )?;
+                    ) {
+                        Ok(in_progress) => in_progress,
+                        Err(e) => {
+                            error!(
+                                "Failed to check if {} is being repaired: {}",
+                                dev_path.display(),
+                                e
+                            );
+                            continue;
+                        }
+                    };
                     match (simulate, in_progress) {
                         (false, true) => {
                             debug!("Device is already in the repair queue");
+                            if let Err(e) = run_approved_removal(
+                                config,
+                                tickets,
+                                notifications,
+                                host_info,
+                                pool,
+                                &public_key,
+                                &state_machine.block_device,
+                                &dev_path,
+                            ) {
+                                error!("Approved removal of {} failed: {}", dev_path.display(), e);
+                            }
                         }
                         (false, false) => {
                             debug!("Asking disk-manager if it's safe to remove disk");
                             // CALL RPC
-                            let mut socket = helpers::connect(
+                            let mut socket = match helpers::connect(
                                 &config.manager_host,
                                 &config.manager_port.to_string(),
                                 &public_key,
-                            )?;
-                            match (
-                                helpers::safe_to_remove_request(&mut socket, &dev_path),
-                                config.slack_webhook.is_some(),
                             ) {
-                                (Ok(true), true) => {
-                                    debug!("safe to remove: true");
-                                    //Ok to remove the disk
-                                    let _ = notify_slack(
-                                        config,
-                                        &format!(
-                                            "Removing disk: {} on host: {}",
-                                            dev_path.display(),
-                                            host_info.hostname
-                                        ),
+                                Ok(socket) => socket,
+                                Err(e) => {
+                                    error!(
+                                        "Failed to connect to disk-manager about {}: {}",
+                                        dev_path.display(),
+                                        e
                                     );
-
-                                    match helpers::remove_disk_request(
-                                        &mut socket,
-                                        &dev_path,
-                                        None,
-                                        false,
-                                    ) {
-                                        Ok(_) => {
-                                            debug!("Disk removal successful");
-                                        }
-                                        Err(e) => {
-                                            error!("Disk removal failed: {}", e);
-                                        }
-                                    };
+                                    continue;
                                 }
-                                (Ok(false), true) => {
-                                    debug!("safe to remove: false");
-                                    let _ = notify_slack(
-                                        config,
+                            };
+                            // Commented on the ticket once it's filed
+                            let mut removal_comment = None;
+                            let mut remediation = None;
+                            match helpers::safe_to_remove_request(&mut socket, &dev_path) {
+                                Ok((true, _)) => {
+                                    debug!("safe to remove: true");
+                                    if let Some(approval) =
+                                        approval_required(config, Action::RemoveDisk)
+                                    {
+                                        // Removed on a later run once it's approved
+                                        match request_approval(
+                                            approval,
+                                            notifications,
+                                            host_info,
+                                            pool,
+                                            &state_machine.block_device,
+                                            &dev_path,
+                                            Action::RemoveDisk,
+                                        ) {
+                                            Ok(msg) => removal_comment = Some(msg),
+                                            // Leave it out of the repair queue so
+                                            // it's asked about again on the next run
+                                            Err(e) => {
+                                                error!(
+                                                    "Failed to request approval to remove {}: {}",
+                                                    dev_path.display(),
+                                                    e
+                                                );
+                                                continue;
+                                            }
+                                        };
+                                    } else {
+                                        //Ok to remove the disk
+                                        let (started, comment) = start_removal(
+                                            &mut socket,
+                                            notifications,
+                                            host_info,
+                                            pool,
+                                            &state_machine.block_device,
+                                            &dev_path,
+                                        );
+                                        remediation = started;
+                                        removal_comment = Some(comment);
+                                    }
+                                }
+                                Ok((false, reason)) => {
+                                    let reason =
+                                        reason.unwrap_or_else(|| "no reason given".to_string());
+                                    info!(
+                                        "It isn't safe to remove {}: {}",
+                                        dev_path.display(),
+                                        reason
+                                    );
+                                    removal_comment = Some(format!(
+                                        "It isn't safe to remove {} from the cluster: {}.  \
+                                         It needs to be removed by hand",
+                                        dev_path.display(),
+                                        reason
+                                    ));
+                                    notifications.notify(
+                                        Event::NotSafe,
                                         &format!(
                                             "Need to remove disk {} but it's not safe \
-                                             on host: {}. I need a human.  Filing a ticket",
+                                             on host: {}: {}. I need a human.  Filing a ticket",
                                             dev_path.display(),
                                             host_info.hostname,
+                                            reason,
                                         ),
                                     );
                                 }
-                                (Err(err), true) => {
+                                Err(err) => {
                                     //Not ok to remove the disk but we need to
-                                    let _ = notify_slack(
-                                        &config,
+                                    removal_comment = Some(format!(
+                                        "Unable to tell if it's safe to remove {} from \
+                                         the cluster: {}",
+                                        dev_path.display(),
+                                        err
+                                    ));
+                                    notifications.notify(
+                                        Event::NotSafe,
                                         &format!(
                                             "Need to remove disk {} but can't tell if it's \
                                              safe on host: {}. Error: {:?}.  Filing a ticket",
@@ -238,13 +312,8 @@ fn check_for_failed_disks(
                                         ),
                                     );
                                 }
-                                (..) => {}
                             };
-                            debug!("Creating support ticket");
-                            let ticket_id =
-                                create_support_ticket(config, "Bynar: Dead disk", &description)?;
-                            debug!("Recording ticket id {} in database", ticket_id);
-                            let op_id = match state_machine.block_device.operation_id {
+                            let operation_id = match state_machine.block_device.operation_id {
                                 None => {
                                     error!(
                                         "Operation not recorded for {}",
@@ -254,10 +323,17 @@ fn check_for_failed_disks(
                                 }
                                 Some(i) => i,
                             };
-                            let mut operation_detail =
-                                OperationDetail::new(op_id, OperationType::WaitingForReplacement);
-                            operation_detail.set_tracking_id(ticket_id);
-                            add_or_update_operation_detail(pool, &mut operation_detail)?;
+                            let group = match config.ticket_grouping {
+                                TicketGrouping::Host => host_info.hostname.clone(),
+                                TicketGrouping::Enclosure => vars["enclosure"].clone(),
+                                TicketGrouping::Disk => dev_path.display().to_string(),
+                            };
+                            failed_disks.entry(group).or_default().push(FailedDisk {
+                                vars,
+                                operation_id,
+                                removal_comment,
+                                remediation,
+                            });
                         }
                         (..) => {}
                     }
@@ -268,21 +344,393 @@ fn check_for_failed_disks(
                     // The rest should be State::Good ?
                 }
             }
-            Err(e) => {
-                error!("check_all_disks failed with error: {:?}", e);
-                return Err(BynarError::new(format!(
-                    "check_all_disks failed with error: {:?}",
+            // Skip it and carry on with the rest
+            Err(e) => error!("check_all_disks failed with error: {:?}", e),
+        };
+    }
+    for (group, disks) in failed_disks {
+        if let Err(e) = file_disk_ticket(config, tickets, notifications, host_info, pool, &disks) {
+            error!("Filing a ticket for {} failed: {}", group, e);
+        }
+    }
+    Ok(())
+}
+
+// Start draining and removing a disk.  Returns the remediation step it
+// reached and a comment for its ticket
+fn start_removal(
+    socket: &mut Socket,
+    notifications: &Notifications,
+    host_info: &Host,
+    pool: &Pool<ConnectionManager>,
+    device: &BlockDevice,
+    dev_path: &Path,
+) -> (Option<Remediation>, String) {
+    notifications.notify(
+        Event::DiskRemoved,
+        &format!(
+            "Removing disk: {} on host: {}",
+            dev_path.display(),
+            host_info.hostname
+        ),
+    );
+    match helpers::remove_disk_request(socket, dev_path, None, None, false) {
+        Ok((osd_id, cluster)) => {
+            debug!("Disk removal started");
+            // Remember the id and cluster so the replacement can reuse them
+            if let Some(id) = osd_id {
+                if let Err(e) = in_progress::save_osd_id(pool, device, id) {
+                    error!("Failed to save osd id {}: {}", id, e);
+                }
+            }
+            if let Some(ref cluster) = cluster {
+                if let Err(e) = in_progress::save_cluster(pool, device, cluster) {
+                    error!("Failed to save cluster {}: {}", cluster, e);
+                }
+            }
+            // Removals that drain the disk first keep running in the
+            // background.  Everything else is done by now
+            match helpers::remove_status_request(socket, dev_path) {
+                Ok(ref status) if status == "removed" => (
+                    Some(Remediation::Drained),
+                    format!(
+                        "{} has been removed from the cluster.  It's ready to be replaced",
+                        dev_path.display()
+                    ),
+                ),
+                _ => (
+                    Some(Remediation::Removing),
+                    format!(
+                        "Draining and removing {} from the cluster",
+                        dev_path.display()
+                    ),
+                ),
+            }
+        }
+        Err(e) => {
+            error!("Disk removal failed: {}", e);
+            (
+                None,
+                format!(
+                    "Removing {} from the cluster failed: {}",
+                    dev_path.display(),
                     e
-                )));
+                ),
+            )
+        }
+    }
+}
+
+// The approval settings if the action needs approval
+fn approval_required(config: &ConfigSettings, action: Action) -> Option<&ApprovalConfig> {
+    config.approval.as_ref().filter(|a| a.requires(action))
+}
+
+// Record an action that needs approval and tell people how to answer it.
+// Returns the message that was sent
+fn request_approval(
+    approval: &ApprovalConfig,
+    notifications: &Notifications,
+    host_info: &Host,
+    pool: &Pool<ConnectionManager>,
+    device: &BlockDevice,
+    dev_path: &Path,
+    action: Action,
+) -> BynarResult<String> {
+    let conn = get_connection_from_pool(pool)?;
+    let action_id = approval::request_action(
+        &conn,
+        device.storage_detail_id,
+        device.operation_id,
+        &dev_path.display().to_string(),
+        action,
+        approval.auto_approve_minutes,
+    )?;
+    let verb = match action {
+        Action::RemoveDisk => "remove",
+        Action::ReformatDisk => "reformat",
+    };
+    let mut msg = format!(
+        "Need approval to {} disk {} on host: {}.  Approve it with \
+         `bynar-client approve {}` or deny it with `bynar-client deny {}`.",
+        verb,
+        dev_path.display(),
+        host_info.hostname,
+        action_id,
+        action_id
+    );
+    if let (Action::RemoveDisk, Some(status)) = (action, &approval.ticket_status) {
+        msg.push_str(&format!(
+            "  Moving its repair ticket to {} approves it too.",
+            status
+        ));
+    }
+    if let Some(minutes) = approval.auto_approve_minutes {
+        msg.push_str(&format!(
+            "  It will be approved automatically in {} minutes.",
+            minutes
+        ));
+    }
+    notifications.notify(Event::ApprovalRequested, &msg);
+    Ok(msg)
+}
+
+// Ask once when a reformat starts waiting and close the request out once
+// the disk has moved on.  check_all_disks already looked the request up
+fn track_reformat_approval(
+    approval: &ApprovalConfig,
+    notifications: &Notifications,
+    host_info: &Host,
+    pool: &Pool<ConnectionManager>,
+    device: &BlockDevice,
+) -> BynarResult<()> {
+    match (device.state, device.reformat_action_id) {
+        (State::WaitingForApproval, None) => {
+            request_approval(
+                approval,
+                notifications,
+                host_info,
+                pool,
+                device,
+                &device.dev_path,
+                Action::ReformatDisk,
+            )?;
+        }
+        (State::WaitingForApproval, Some(_)) => debug!(
+            "Reformat of {} is waiting for approval",
+            device.dev_path.display()
+        ),
+        (_, Some(action_id)) => {
+            let conn = get_connection_from_pool(pool)?;
+            approval::finish_action(&conn, action_id)?;
+        }
+        (_, None) => {}
+    };
+    Ok(())
+}
+
+// Remove a disk whose removal waited for approval once someone answers.
+// Its repair ticket was filed when it failed so the outcome is commented
+// there
+#[allow(clippy::too_many_arguments)]
+fn run_approved_removal(
+    config: &ConfigSettings,
+    tickets: &dyn TicketSystem,
+    notifications: &Notifications,
+    host_info: &Host,
+    pool: &Pool<ConnectionManager>,
+    public_key: &str,
+    device: &BlockDevice,
+    dev_path: &Path,
+) -> BynarResult<()> {
+    let conn = get_connection_from_pool(pool)?;
+    let action = match approval::get_action(
+        &conn,
+        device.storage_detail_id,
+        &dev_path.display().to_string(),
+        Action::RemoveDisk,
+    )? {
+        Some(action) => action,
+        None => return Ok(()),
+    };
+    let mut status = action.status;
+    let ticket_status = config
+        .approval
+        .as_ref()
+        .and_then(|a| a.ticket_status.as_ref());
+    if let (ApprovalStatus::Pending, Some(wanted), Some(ticket_id)) =
+        (status, ticket_status, &action.tracking_id)
+    {
+        if tickets.status_name(ticket_id)?.eq_ignore_ascii_case(wanted) {
+            approval::decide_action(
+                &conn,
+                action.action_id,
+                ApprovalStatus::Approved,
+                &format!("ticket {}", ticket_id),
+            )?;
+            status = ApprovalStatus::Approved;
+        }
+    }
+    let (remediation, comment) = match status {
+        ApprovalStatus::Pending => {
+            debug!("Removal of {} is waiting for approval", dev_path.display());
+            return Ok(());
+        }
+        ApprovalStatus::Denied => (
+            None,
+            format!(
+                "Removing {} was denied by {}.  It needs to be removed by hand",
+                dev_path.display(),
+                action.decided_by.as_deref().unwrap_or("someone")
+            ),
+        ),
+        ApprovalStatus::Approved => {
+            let mut socket = helpers::connect(
+                &config.manager_host,
+                &config.manager_port.to_string(),
+                public_key,
+            )?;
+            // The cluster may have changed while it waited
+            let (safe, reason) = helpers::safe_to_remove_request(&mut socket, dev_path)?;
+            if !safe {
+                info!(
+                    "Removing {} is approved but not safe yet: {}.  Will retry later",
+                    dev_path.display(),
+                    reason.unwrap_or_else(|| "no reason given".to_string())
+                );
+                return Ok(());
             }
-        };
+            start_removal(
+                &mut socket,
+                notifications,
+                host_info,
+                pool,
+                device,
+                dev_path,
+            )
+        }
+    };
+    approval::finish_action(&conn, action.action_id)?;
+    if let Some(ref ticket_id) = action.tracking_id {
+        if let Err(e) = tickets.comment(ticket_id, &comment) {
+            error!("Failed to comment on ticket {}: {:?}", ticket_id, e);
+        }
+        if let (Some(remediation), Some(operation_id)) = (remediation, action.operation_id) {
+            if let Err(e) =
+                in_progress::save_remediation(pool, operation_id, ticket_id, remediation)
+            {
+                error!("Failed to save remediation for {}: {:?}", ticket_id, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+// A failed disk waiting for a ticket
+struct FailedDisk {
+    vars: TicketVariables,
+    operation_id: u32,
+    // What happened when it was removed
+    removal_comment: Option<String>,
+    remediation: Option<Remediation>,
+}
+
+// File one ticket for the disks and link each of their operations to it
+fn file_disk_ticket(
+    config: &ConfigSettings,
+    tickets: &dyn TicketSystem,
+    notifications: &Notifications,
+    host_info: &Host,
+    pool: &Pool<ConnectionManager>,
+    disks: &[FailedDisk],
+) -> BynarResult<()> {
+    let templates = &config.ticket_templates;
+    let disk_vars: Vec<TicketVariables> = disks.iter().map(|d| d.vars.clone()).collect();
+    let vars = disk_group_variables(&disk_vars, &templates.disk_item);
+    debug!("Creating support ticket for {} disk(s)", disks.len());
+    let ticket_id = tickets.create(
+        &render(&templates.disk_summary, &vars),
+        &render(&templates.disk_description, &vars),
+        &vars,
+    )?;
+    notifications.notify(
+        Event::TicketFiled,
+        &format!(
+            "Filed ticket {} for {} disk(s) on host: {}",
+            ticket_id,
+            disks.len(),
+            host_info.hostname
+        ),
+    );
+    debug!("Recording ticket id {} in database", ticket_id);
+    for disk in disks {
+        let mut operation_detail =
+            OperationDetail::new(disk.operation_id, OperationType::WaitingForReplacement);
+        operation_detail.set_tracking_id(ticket_id.clone());
+        add_or_update_operation_detail(pool, &mut operation_detail)?;
+        if let Some(remediation) = disk.remediation {
+            if let Err(e) =
+                in_progress::save_remediation(pool, disk.operation_id, &ticket_id, remediation)
+            {
+                error!("Failed to save remediation for {}: {:?}", ticket_id, e);
+            }
+        }
+    }
+    let comments: Vec<&str> = disks
+        .iter()
+        .filter_map(|d| d.removal_comment.as_deref())
+        .collect();
+    if !comments.is_empty() {
+        if let Err(e) = tickets.comment(&ticket_id, &comments.join("\n")) {
+            error!("Failed to comment on ticket {}: {:?}", ticket_id, e);
+        }
     }
     Ok(())
 }
 
+// Disks behind the same controller or enclosure are usually swapped together
+fn enclosure(device: &BlockDevice) -> String {
+    match device.raid_location {
+        // The drive is port:box:bay
+        Some(RaidLocation::Hp {
+            ref slot,
+            ref drive,
+        }) => match drive.rsplitn(2, ':').nth(1) {
+            Some(port_box) => format!("hp/{}/{}", slot, port_box),
+            None => format!("hp/{}", slot),
+        },
+        Some(RaidLocation::Lsi {
+            controller,
+            enclosure: Some(enclosure),
+            ..
+        }) => format!("lsi/{}/{}", controller, enclosure),
+        Some(RaidLocation::Lsi { controller, .. }) => format!("lsi/{}", controller),
+        None => format!("scsi{}", device.scsi_info.host),
+    }
+}
+
+// Variables for a disk ticket on top of the host ones
+fn disk_variables(host_vars: &TicketVariables, state_machine: &StateMachine) -> TicketVariables {
+    let device = &state_machine.block_device;
+    let scsi = &device.scsi_info;
+    let history: Vec<String> = state_machine
+        .state_history()
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let mut vars = host_vars.clone();
+    vars.insert(
+        "disk_path".to_string(),
+        device.dev_path.display().to_string(),
+    );
+    vars.insert(
+        "disk_serial".to_string(),
+        device.device.serial_number.clone().unwrap_or_default(),
+    );
+    vars.insert(
+        "scsi_address".to_string(),
+        format!("{}:{}:{}:{}", scsi.host, scsi.channel, scsi.id, scsi.lun),
+    );
+    vars.insert("vendor".to_string(), format!("{:?}", scsi.vendor));
+    vars.insert(
+        "slot".to_string(),
+        device
+            .raid_location
+            .as_ref()
+            .map(|l| l.to_string())
+            .unwrap_or_default(),
+    );
+    vars.insert("enclosure".to_string(), enclosure(device));
+    vars.insert("state_history".to_string(), history.join(" -> "));
+    vars
+}
+
 fn evaluate(
     results: Vec<BynarResult<()>>,
     config: &ConfigSettings,
+    tickets: &dyn TicketSystem,
+    notifications: &Notifications,
+    host_vars: &TicketVariables,
     pool: &Pool<ConnectionManager>,
     host_mapping: &HostDetailsMapping,
 ) -> BynarResult<()> {
@@ -303,15 +751,34 @@ fn evaluate(
                         serial,
                     )?;
                     if !in_progress {
+                        notifications.notify(
+                            Event::HardwareFailure,
+                            &format!("Hardware failure on host: {}. {}", host_vars["hostname"], e),
+                        );
                         //file a ticket
                         debug!("Creating support ticket");
                         let mut op_info = OperationInfo::new(host_mapping.entry_id, 0);
                         add_or_update_operation(pool, &mut op_info)?;
-                        let ticket_id = create_support_ticket(
-                            config,
-                            "Bynar: Hardware Failure",
-                            &format!("{}", e),
+                        let mut vars = host_vars.clone();
+                        vars.insert("hardware_name".to_string(), name.clone());
+                        vars.insert(
+                            "hardware_serial".to_string(),
+                            serial.unwrap_or_default().to_string(),
+                        );
+                        vars.insert("error".to_string(), e.to_string());
+                        let templates = &config.ticket_templates;
+                        let ticket_id = tickets.create(
+                            &render(&templates.hardware_summary, &vars),
+                            &render(&templates.hardware_description, &vars),
+                            &vars,
                         )?;
+                        notifications.notify(
+                            Event::TicketFiled,
+                            &format!(
+                                "Filed ticket {} for {} on host: {}",
+                                ticket_id, name, host_vars["hostname"]
+                            ),
+                        );
                         let op_id = match op_info.operation_id {
                             None => {
                                 error!("Operation not recorded for {}", "",);
@@ -339,37 +806,71 @@ fn evaluate(
 
 fn check_for_failed_hardware(
     config: &ConfigSettings,
+    tickets: &dyn TicketSystem,
+    notifications: &Notifications,
     host_info: &Host,
     pool: &Pool<ConnectionManager>,
     host_mapping: &HostDetailsMapping,
     simulate: bool,
 ) -> BynarResult<()> {
     info!("Checking hardware");
-    let mut description = String::new();
-    description.push_str(&format!(
-        "\nHostname: {}\nServer type: {}\nServer Serial: {}\nMachine Architecture: {}\nKernel: {}",
-        host_info.hostname,
-        host_info.server_type,
-        host_info.serial_number,
-        host_info.machine_architecture,
-        host_info.kernel,
-    ));
+    let host_vars = host_variables(host_info);
     let results = test_hardware::check_hardware(&config)?;
     if !simulate {
         // Check if evaluate found any errors and log anything other then hardware errors
-        if let Err(e) = evaluate(results.disk_drives, config, pool, host_mapping) {
+        if let Err(e) = evaluate(
+            results.disk_drives,
+            config,
+            tickets,
+            notifications,
+            &host_vars,
+            pool,
+            host_mapping,
+        ) {
             error!("Disk drive evaluation error: {:?}", e);
         }
-        if let Err(e) = evaluate(results.manager, config, pool, host_mapping) {
+        if let Err(e) = evaluate(
+            results.manager,
+            config,
+            tickets,
+            notifications,
+            &host_vars,
+            pool,
+            host_mapping,
+        ) {
             error!("Hardware manager evaluation error: {:?}", e);
         }
-        if let Err(e) = evaluate(results.power, config, pool, host_mapping) {
+        if let Err(e) = evaluate(
+            results.power,
+            config,
+            tickets,
+            notifications,
+            &host_vars,
+            pool,
+            host_mapping,
+        ) {
             error!("Power supply evaluation error: {:?}", e);
         }
-        if let Err(e) = evaluate(results.storage_enclosures, config, pool, host_mapping) {
+        if let Err(e) = evaluate(
+            results.storage_enclosures,
+            config,
+            tickets,
+            notifications,
+            &host_vars,
+            pool,
+            host_mapping,
+        ) {
             error!("Storage enclosures evaluation error: {:?}", e);
         }
-        if let Err(e) = evaluate(results.thermals, config, pool, host_mapping) {
+        if let Err(e) = evaluate(
+            results.thermals,
+            config,
+            tickets,
+            notifications,
+            &host_vars,
+            pool,
+            host_mapping,
+        ) {
             error!("Thermal evaluation error: {:?}", e);
         }
     }
@@ -377,8 +878,157 @@ fn check_for_failed_hardware(
     Ok(())
 }
 
+// A replacement is there once the device is back with a serial that isn't
+// the failed disk's
+fn replacement_detected(ticket: &DiskRepairTicket) -> bool {
+    match block_utils::get_device_info(Path::new(&ticket.device_path)) {
+        Ok(device) => {
+            ticket.serial_number.is_none() || device.serial_number != ticket.serial_number
+        }
+        Err(e) => {
+            debug!("No disk at {}: {:?}", ticket.device_path, e);
+            false
+        }
+    }
+}
+
+// Comment on the ticket unless this step was the last one reported
+fn report_remediation(
+    ticket_system: &dyn TicketSystem,
+    pool: &Pool<ConnectionManager>,
+    ticket: &DiskRepairTicket,
+    remediation: Remediation,
+    comment: &str,
+) {
+    if ticket.remediation == Some(remediation) {
+        return;
+    }
+    if let Err(e) = ticket_system.comment(&ticket.ticket_id, comment) {
+        error!("Failed to comment on ticket {}: {:?}", ticket.ticket_id, e);
+        return;
+    }
+    if let Err(e) =
+        in_progress::save_remediation(pool, ticket.operation_id, &ticket.ticket_id, remediation)
+    {
+        error!(
+            "Failed to save remediation for {}: {:?}",
+            ticket.ticket_id, e
+        );
+    }
+}
+
+// Whether the old disk may still be in the cluster
+fn removal_pending(ticket: &DiskRepairTicket) -> bool {
+    matches!(
+        ticket.remediation,
+        Some(Remediation::Removing) | Some(Remediation::RemovalFailed)
+    )
+}
+
+// Ask disk-manager how a removal running in the background is doing and
+// report it on the ticket.  A removal that failed is started again.
+// Returns true once the disk is out of the cluster
+fn poll_removal(
+    config: &ConfigSettings,
+    ticket_system: &dyn TicketSystem,
+    pool: &Pool<ConnectionManager>,
+    public_key: &str,
+    ticket: &DiskRepairTicket,
+) -> BynarResult<bool> {
+    let mut socket = helpers::connect(
+        &config.manager_host,
+        &config.manager_port.to_string(),
+        public_key,
+    )?;
+    match helpers::remove_status_request(&mut socket, Path::new(&ticket.device_path)) {
+        Ok(ref status) if status == "removed" => {
+            report_remediation(
+                ticket_system,
+                pool,
+                ticket,
+                Remediation::Drained,
+                &format!(
+                    "{} has been drained and removed from the cluster.  It's ready to be \
+                     replaced",
+                    ticket.device_path
+                ),
+            );
+            return Ok(true);
+        }
+        Ok(ref status) if status.starts_with("failed") => {
+            report_remediation(
+                ticket_system,
+                pool,
+                ticket,
+                Remediation::RemovalFailed,
+                &format!(
+                    "Removing {} from the cluster {}",
+                    ticket.device_path, status
+                ),
+            );
+            info!("Retrying the removal of {}", ticket.device_path);
+            if let Err(e) = helpers::remove_disk_request(
+                &mut socket,
+                Path::new(&ticket.device_path),
+                None,
+                ticket.cluster.as_ref().map(|c| c.as_str()),
+                false,
+            ) {
+                error!(
+                    "Retrying the removal of {} failed: {:?}",
+                    ticket.device_path, e
+                );
+            }
+        }
+        Ok(status) => debug!("{} is {}", ticket.device_path, status),
+        // The removal was never started or its record was lost
+        Err(e) => warn!("No removal status for {}: {:?}", ticket.device_path, e),
+    };
+    Ok(false)
+}
+
+// Report progress on a ticket that's still waiting on a technician
+fn update_open_ticket(
+    config: &ConfigSettings,
+    ticket_system: &dyn TicketSystem,
+    pool: &Pool<ConnectionManager>,
+    public_key: &str,
+    ticket: &DiskRepairTicket,
+) -> BynarResult<()> {
+    // Reporting anything else would stop the removal being checked on
+    if removal_pending(ticket) && !poll_removal(config, ticket_system, pool, public_key, ticket)? {
+        return Ok(());
+    }
+    if replacement_detected(ticket) {
+        report_remediation(
+            ticket_system,
+            pool,
+            ticket,
+            Remediation::ReplacementDetected,
+            &format!("A replacement disk was detected at {}", ticket.device_path),
+        );
+    }
+    Ok(())
+}
+
+fn finish_removal_action(
+    pool: &Pool<ConnectionManager>,
+    storage_detail_id: u32,
+    device_path: &str,
+) -> BynarResult<()> {
+    let conn = get_connection_from_pool(pool)?;
+    if let Some(action) =
+        approval::get_action(&conn, storage_detail_id, device_path, Action::RemoveDisk)?
+    {
+        approval::finish_action(&conn, action.action_id)?;
+    }
+    Ok(())
+}
+
 fn add_repaired_disks(
     config: &ConfigSettings,
+    ticket_system: &dyn TicketSystem,
+    notifications: &Notifications,
     host_info: &Host,
     pool: &Pool<ConnectionManager>,
     storage_detail_id: u32,
@@ -389,10 +1039,74 @@ fn add_repaired_disks(
     info!("Getting outstanding repair tickets");
     let tickets = in_progress::get_outstanding_repair_tickets(&pool, storage_detail_id)?;
     debug!("outstanding tickets: {:?}", tickets);
+    // A ticket is closed once every disk on it is back in service
+    let mut disks_remaining: HashMap<String, usize> = HashMap::new();
+    for ticket in &tickets {
+        *disks_remaining.entry(ticket.ticket_id.clone()).or_insert(0) += 1;
+    }
     info!("Checking for resolved repair tickets");
     for ticket in tickets {
-        match ticket_resolved(config, &ticket.ticket_id.to_string()) {
-            Ok(true) => {
+        match ticket_system.status(&ticket.ticket_id.to_string()) {
+            Ok(TicketStatus::Resolved) => {
+                // The old disk has to be out of the cluster before its
+                // replacement goes in
+                if removal_pending(&ticket) {
+                    match poll_removal(config, ticket_system, pool, &public_key, &ticket) {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            info!(
+                                "{} is still being removed.  Will add it later",
+                                ticket.device_path
+                            );
+                            continue;
+                        }
+                        Err(e) => {
+                            error!(
+                                "Failed to check on the removal of {}: {:?}",
+                                ticket.device_path, e
+                            );
+                            continue;
+                        }
+                    }
+                }
+                // Drives behind a raid controller need a logical drive
+                // before linux can see the replacement
+                if let Some(ref location) = ticket.raid_location {
+                    if let Err(e) = RaidLocation::from_str(location)
+                        .and_then(|l| raid::configure_replaced_drive(&l, simulate))
+                    {
+                        error!(
+                            "Failed to configure raid controller for {}: {:?}",
+                            ticket.device_path, e
+                        );
+                        continue;
+                    }
+                }
+                if !replacement_detected(&ticket) {
+                    info!("No replacement disk at {} yet", ticket.device_path);
+                    report_remediation(
+                        ticket_system,
+                        pool,
+                        &ticket,
+                        Remediation::ReplacementMissing,
+                        &format!(
+                            "This ticket was resolved but no new disk is visible at {}.  \
+                             It will be added once it shows up",
+                            ticket.device_path
+                        ),
+                    );
+                    continue;
+                }
+                // A failed add was already told about the replacement
+                if ticket.remediation != Some(Remediation::AddFailed) {
+                    report_remediation(
+                        ticket_system,
+                        pool,
+                        &ticket,
+                        Remediation::ReplacementDetected,
+                        &format!("A replacement disk was detected at {}", ticket.device_path),
+                    );
+                }
                 //CALL RPC
                 debug!("Connecting to disk-manager");
                 let mut socket = helpers::connect(
@@ -401,27 +1115,129 @@ fn add_repaired_disks(
                     &public_key,
                 )?;
 
+                // The cluster may not be ready for the disk yet.  Try again
+                // on the next run
+                match helpers::safe_to_add_request(
+                    &mut socket,
+                    &Path::new(&ticket.device_path),
+                    ticket.cluster.as_ref().map(|c| c.as_str()),
+                ) {
+                    Ok((true, _)) => {}
+                    Ok((false, reason)) => {
+                        info!(
+                            "Not safe to add {} yet: {}.  Will retry later",
+                            ticket.device_path,
+                            reason.unwrap_or_else(|| "no reason given".to_string())
+                        );
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Safe to add {} failed: {:?}", ticket.device_path, e);
+                        continue;
+                    }
+                };
                 match helpers::add_disk_request(
                     &mut socket,
                     &Path::new(&ticket.device_path),
-                    None,
+                    ticket.osd_id,
+                    ticket.cluster.as_ref().map(|c| c.as_str()),
                     simulate,
                 ) {
                     Ok(_) => {
+                        notifications.notify(
+                            Event::DiskReadded,
+                            &format!(
+                                "Added disk: {} back into service on host: {}",
+                                ticket.device_path, host_info.hostname
+                            ),
+                        );
                         debug!("Disk added successfully. Updating database record");
-                        match in_progress::resolve_ticket_in_db(pool, &ticket.ticket_id) {
+                        match in_progress::resolve_ticket_in_db(
+                            pool,
+                            &ticket.ticket_id,
+                            ticket.operation_id,
+                        ) {
                             Ok(_) => debug!("Database updated"),
                             Err(e) => {
                                 error!("Failed to resolve ticket {}.  {:?}", ticket.ticket_id, e)
                             }
                         };
+                        if ticket.raid_location.is_some() {
+                            if let Err(e) =
+                                in_progress::clear_raid_location(pool, ticket.operation_id)
+                            {
+                                error!(
+                                    "Failed to clear raid location of {}: {:?}",
+                                    ticket.device_path, e
+                                );
+                            }
+                        }
+                        if ticket.osd_id.is_some() {
+                            if let Err(e) = in_progress::clear_osd_id(pool, ticket.operation_id) {
+                                error!("Failed to clear osd id of {}: {:?}", ticket.device_path, e);
+                            }
+                        }
+                        if ticket.cluster.is_some() {
+                            if let Err(e) = in_progress::clear_cluster(pool, ticket.operation_id) {
+                                error!(
+                                    "Failed to clear cluster of {}: {:?}",
+                                    ticket.device_path, e
+                                );
+                            }
+                        }
+                        // A removal still waiting for approval isn't needed now
+                        if let Err(e) =
+                            finish_removal_action(pool, storage_detail_id, &ticket.device_path)
+                        {
+                            error!(
+                                "Failed to close out removal of {}: {:?}",
+                                ticket.device_path, e
+                            );
+                        }
+                        if let Err(e) = ticket_system.comment(
+                            &ticket.ticket_id,
+                            &format!("{} was added back into service", ticket.device_path),
+                        ) {
+                            error!("Failed to comment on ticket {}: {:?}", ticket.ticket_id, e);
+                        }
+                        let remaining = disks_remaining
+                            .get_mut(&ticket.ticket_id)
+                            .map(|count| {
+                                *count -= 1;
+                                *count
+                            })
+                            .unwrap_or(0);
+                        if remaining > 0 {
+                            debug!(
+                                "{} disk(s) on ticket {} still waiting",
+                                remaining, ticket.ticket_id
+                            );
+                        } else if let Err(e) = ticket_system.close(&ticket.ticket_id) {
+                            error!("Failed to close ticket {}: {:?}", ticket.ticket_id, e);
+                        }
                     }
                     Err(e) => {
                         error!("Failed to add disk: {:?}", e);
+                        report_remediation(
+                            ticket_system,
+                            pool,
+                            &ticket,
+                            Remediation::AddFailed,
+                            &format!(
+                                "Adding {} back into service failed: {}.  Will retry later",
+                                ticket.device_path, e
+                            ),
+                        );
                     }
                 };
             }
-            Ok(false) => {}
+            Ok(TicketStatus::Open) => {
+                if let Err(e) =
+                    update_open_ticket(config, ticket_system, pool, &public_key, &ticket)
+                {
+                    error!("Failed to update ticket {}.  {:?}", &ticket.ticket_id, e);
+                }
+            }
             Err(e) => {
                 error!(
                     "Error getting resolved ticket status for {}.  {:?}",
@@ -433,6 +1249,29 @@ fn add_repaired_disks(
     Ok(())
 }
 
+// Runs in its own process so a long scan doesn't hold up the other disks.
+// It logs to a separate file because bynar.log is truncated on every run
+fn run_surface_scan(device: &Path, config: &str, level: log::LevelFilter) {
+    if let Ok(f) = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open("/var/log/bynar-surface-scan.log")
+    {
+        let _ = WriteLogger::init(level, Config::default(), f);
+    }
+    let config: SurfaceScanConfig = match serde_json::from_str(config) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Invalid surface scan config: {}", e);
+            return;
+        }
+    };
+    match surface_scan::run_background_scan(device, &config) {
+        Ok(_) => info!("Surface scan of {} finished", device.display()),
+        Err(e) => error!("Surface scan of {} failed: {:?}", device.display(), e),
+    };
+}
+
 // 1. Gather a list of all the disks
 // 2. Check every disk
 // 3. Decide if a disk needs to be replaced
@@ -464,12 +1303,34 @@ fn main() {
                 .multiple(true)
                 .help("Sets the level of verbosity"),
         )
+        // bynar starts itself with these to run a surface scan in the background
+        .arg(
+            Arg::with_name("surface_scan")
+                .long("surface-scan")
+                .takes_value(true)
+                .requires("surface_scan_config")
+                .hidden(true),
+        )
+        .arg(
+            Arg::with_name("surface_scan_config")
+                .long("surface-scan-config")
+                .takes_value(true)
+                .hidden(true),
+        )
         .get_matches();
     let level = match matches.occurrences_of("v") {
         0 => log::LevelFilter::Info, //default
         1 => log::LevelFilter::Debug,
         _ => log::LevelFilter::Trace,
     };
+    if let Some(device) = matches.value_of("surface_scan") {
+        run_surface_scan(
+            Path::new(device),
+            matches.value_of("surface_scan_config").unwrap(),
+            level,
+        );
+        return;
+    }
     let mut loggers: Vec<Box<dyn SharedLogger>> = vec![];
     if let Some(term_logger) = TermLogger::new(level, Config::default()) {
         //systemd doesn't use a terminal
@@ -518,6 +1379,20 @@ fn main() {
         return;
     }
     let config: ConfigSettings = config.expect("Failed to load config");
+    let tickets = match support_tickets::load_ticket_system(&config) {
+        Err(e) => {
+            error!("Failed to set up the ticketing system {}", e);
+            return;
+        }
+        Ok(t) => t,
+    };
+    let notifications = match notify::load_notifications(&config, config_dir) {
+        Err(e) => {
+            error!("Failed to set up notifications {}", e);
+            return;
+        }
+        Ok(n) => n,
+    };
 
     let db_pool = match create_db_connection_pool(&config.database) {
         Err(e) => {
@@ -542,6 +1417,8 @@ fn main() {
 
     match check_for_failed_disks(
         &config,
+        tickets.as_ref(),
+        &notifications,
         &host_info,
         &db_pool,
         &host_details_mapping,
@@ -556,6 +1433,8 @@ fn main() {
     };
     match check_for_failed_hardware(
         &config,
+        tickets.as_ref(),
+        &notifications,
         &host_info,
         &db_pool,
         &host_details_mapping,
@@ -570,6 +1449,8 @@ fn main() {
     };
     match add_repaired_disks(
         &config,
+        tickets.as_ref(),
+        &notifications,
         &host_info,
         &db_pool,
         host_details_mapping.storage_detail_id,
@@ -0,0 +1,75 @@
+//! Email through an SMTP relay
+use super::{Event, Notifier};
+use helpers::error::*;
+use lettre::smtp::authentication::Credentials;
+use lettre::{ClientSecurity, SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+use serde_derive::*;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EmailConfig {
+    pub smtp_server: String,
+    /// Defaults to 25.  Ignored with tls, which always uses submission on 587
+    pub port: Option<u16>,
+    /// Use STARTTLS
+    #[serde(default)]
+    pub tls: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+pub struct EmailNotifier {
+    config: EmailConfig,
+}
+
+impl EmailNotifier {
+    pub fn new(config: EmailConfig) -> EmailNotifier {
+        EmailNotifier { config }
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn notify(&self, event: Event, msg: &str) -> BynarResult<()> {
+        let mut builder = EmailBuilder::new()
+            .from(self.config.from.as_str())
+            .subject(format!("Bynar: {}", event))
+            .text(msg);
+        for to in &self.config.to {
+            builder = builder.to(to.as_str());
+        }
+        let email = builder
+            .build()
+            .map_err(|e| BynarError::new(format!("Unable to build email: {}", e)))?;
+
+        let client = if self.config.tls {
+            SmtpClient::new_simple(&self.config.smtp_server)
+        } else {
+            SmtpClient::new(
+                (
+                    self.config.smtp_server.as_str(),
+                    self.config.port.unwrap_or(25),
+                ),
+                ClientSecurity::None,
+            )
+        }
+        .map_err(|e| {
+            BynarError::new(format!(
+                "Unable to reach {}: {}",
+                self.config.smtp_server, e
+            ))
+        })?;
+        let client = match (&self.config.username, &self.config.password) {
+            (Some(user), Some(password)) => {
+                client.credentials(Credentials::new(user.clone(), password.clone()))
+            }
+            _ => client,
+        };
+        client
+            .transport()
+            .send(email.into())
+            .map_err(|e| BynarError::new(format!("Sending email failed: {}", e)))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,386 @@
+//! Notifications about what Bynar is doing.  Every event type is routed to
+//! any number of destinations and each destination has its own rate limit.
+//! A destination that fails is logged and skipped so it can't hold up a
+//! repair.
+pub mod email;
+pub mod slack;
+pub mod syslog;
+pub mod webhook;
+
+use self::email::{EmailConfig, EmailNotifier};
+use self::slack::{SlackConfig, SlackNotifier};
+use self::syslog::{SyslogConfig, SyslogNotifier};
+use self::webhook::{WebhookConfig, WebhookNotifier};
+use crate::ConfigSettings;
+use helpers::error::*;
+use log::{debug, error, info};
+use serde_derive::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use tempdir::TempDir;
+
+    struct Recorder {
+        sent: Rc<RefCell<Vec<(Event, String)>>>,
+        fail: bool,
+    }
+
+    impl Notifier for Recorder {
+        fn notify(&self, event: Event, msg: &str) -> BynarResult<()> {
+            if self.fail {
+                return Err(BynarError::from("destination is down"));
+            }
+            self.sent.borrow_mut().push((event, msg.to_string()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config: NotificationsConfig = serde_json::from_str(
+            r##"{
+                "destinations": {
+                    "ops": {"type": "slack", "webhook": "https://hooks.slack.com/x",
+                            "channel": "#storage",
+                            "rate_limit": {"max_messages": 5, "per_seconds": 60}},
+                    "pager": {"type": "webhook", "url": "https://pager/hook"},
+                    "mail": {"type": "email", "smtp_server": "smtp.example.com",
+                             "from": "bynar@example.com", "to": ["ops@example.com"]},
+                    "log": {"type": "syslog"}
+                },
+                "routes": {
+                    "disk_removed": ["ops", "log"],
+                    "hardware_failure": ["mail", "pager"]
+                }
+            }"##,
+        )
+        .unwrap();
+        assert_eq!(config.destinations.len(), 4);
+        match config.destinations["ops"].kind {
+            DestinationConfig::Slack(ref slack) => {
+                assert_eq!(slack.channel, Some("#storage".to_string()))
+            }
+            ref other => panic!("Expected slack, got {:?}", other),
+        };
+        assert_eq!(
+            config.destinations["ops"].rate_limit,
+            Some(RateLimit {
+                max_messages: 5,
+                per_seconds: 60
+            })
+        );
+        assert_eq!(config.routes[&Event::DiskRemoved], vec!["ops", "log"]);
+        assert!(!config.routes.contains_key(&Event::NotSafe));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limit = RateLimit {
+            max_messages: 2,
+            per_seconds: 60,
+        };
+        let mut history = vec![];
+        assert!(allowed(&mut history, &limit, 1000));
+        assert!(allowed(&mut history, &limit, 1010));
+        assert!(!allowed(&mut history, &limit, 1020));
+        // The first message ages out of the window
+        assert!(allowed(&mut history, &limit, 1061));
+        assert_eq!(history, vec![1010, 1061]);
+    }
+
+    #[test]
+    fn test_routing() {
+        let dir = TempDir::new("notify").unwrap();
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let mut notifications = Notifications::empty(dir.path());
+        notifications.add_destination(
+            "ops",
+            Box::new(Recorder {
+                sent: Rc::clone(&sent),
+                fail: false,
+            }),
+            Some(RateLimit {
+                max_messages: 1,
+                per_seconds: 3600,
+            }),
+        );
+        notifications.add_destination(
+            "down",
+            Box::new(Recorder {
+                sent: Rc::clone(&sent),
+                fail: true,
+            }),
+            None,
+        );
+        notifications.route(Event::DiskRemoved, &["down", "ops"]);
+        notifications.route(Event::TicketFiled, &["missing"]);
+
+        notifications.notify(Event::DiskRemoved, "Removing /dev/sdb");
+        // Over the limit for ops
+        notifications.notify(Event::DiskRemoved, "Removing /dev/sdc");
+        // Nothing routed here
+        notifications.notify(Event::NotSafe, "Not safe to remove /dev/sdd");
+        notifications.notify(Event::TicketFiled, "Filed OPS-1");
+        assert_eq!(
+            *sent.borrow(),
+            vec![(Event::DiskRemoved, "Removing /dev/sdb".to_string())]
+        );
+
+        // The limit holds across runs
+        let sent_later = Rc::new(RefCell::new(Vec::new()));
+        let mut later = Notifications::empty(dir.path());
+        later.add_destination(
+            "ops",
+            Box::new(Recorder {
+                sent: Rc::clone(&sent_later),
+                fail: false,
+            }),
+            Some(RateLimit {
+                max_messages: 1,
+                per_seconds: 3600,
+            }),
+        );
+        later.route(Event::DiskRemoved, &["ops"]);
+        later.notify(Event::DiskRemoved, "Removing /dev/sde");
+        assert!(sent_later.borrow().is_empty());
+    }
+}
+
+/// Things Bynar tells people about
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    /// A disk is being removed from its cluster
+    DiskRemoved,
+    /// A disk needs removing but it isn't safe or it can't be checked
+    NotSafe,
+    TicketFiled,
+    /// A replacement disk was added back into service
+    DiskReadded,
+    HardwareFailure,
+    /// A destructive action is waiting for someone to approve it
+    ApprovalRequested,
+}
+
+impl Event {
+    pub fn all() -> Vec<Event> {
+        vec![
+            Event::DiskRemoved,
+            Event::NotSafe,
+            Event::TicketFiled,
+            Event::DiskReadded,
+            Event::HardwareFailure,
+            Event::ApprovalRequested,
+        ]
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            Event::DiskRemoved => "disk_removed",
+            Event::NotSafe => "not_safe",
+            Event::TicketFiled => "ticket_filed",
+            Event::DiskReadded => "disk_readded",
+            Event::HardwareFailure => "hardware_failure",
+            Event::ApprovalRequested => "approval_requested",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub trait Notifier {
+    /// Deliver a message about an event
+    fn notify(&self, event: Event, msg: &str) -> BynarResult<()>;
+}
+
+/// At most max_messages are sent to a destination every per_seconds
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RateLimit {
+    pub max_messages: usize,
+    pub per_seconds: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum DestinationConfig {
+    Slack(SlackConfig),
+    Webhook(WebhookConfig),
+    Email(EmailConfig),
+    Syslog(SyslogConfig),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Destination {
+    #[serde(flatten)]
+    pub kind: DestinationConfig,
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// The notifications section of bynar.json
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct NotificationsConfig {
+    /// Destination name to how to reach it
+    #[serde(default)]
+    pub destinations: BTreeMap<String, Destination>,
+    /// Event to the names of the destinations that hear about it
+    #[serde(default)]
+    pub routes: BTreeMap<Event, Vec<String>>,
+}
+
+// Drop sends that fell out of the window and check there's room for one more
+fn allowed(history: &mut Vec<u64>, limit: &RateLimit, now: u64) -> bool {
+    history.retain(|sent| now.saturating_sub(*sent) < limit.per_seconds);
+    if history.len() >= limit.max_messages {
+        return false;
+    }
+    history.push(now);
+    true
+}
+
+pub struct Notifications {
+    destinations: BTreeMap<String, (Box<dyn Notifier>, Option<RateLimit>)>,
+    routes: BTreeMap<Event, Vec<String>>,
+    // Bynar runs once per invocation so recent sends are remembered here
+    history_file: PathBuf,
+}
+
+impl Notifications {
+    fn empty(config_dir: &Path) -> Notifications {
+        Notifications {
+            destinations: BTreeMap::new(),
+            routes: BTreeMap::new(),
+            history_file: config_dir.join("notify_history.json"),
+        }
+    }
+
+    fn add_destination(
+        &mut self,
+        name: &str,
+        notifier: Box<dyn Notifier>,
+        rate_limit: Option<RateLimit>,
+    ) {
+        self.destinations
+            .insert(name.to_string(), (notifier, rate_limit));
+    }
+
+    fn route(&mut self, event: Event, destinations: &[&str]) {
+        self.routes
+            .insert(event, destinations.iter().map(|d| d.to_string()).collect());
+    }
+
+    pub fn new(
+        config: &NotificationsConfig,
+        config_dir: &Path,
+        proxy: Option<&str>,
+    ) -> BynarResult<Notifications> {
+        let mut notifications = Notifications::empty(config_dir);
+        for (name, destination) in &config.destinations {
+            let notifier: Box<dyn Notifier> = match destination.kind {
+                DestinationConfig::Slack(ref c) => Box::new(SlackNotifier::new(c.clone())),
+                DestinationConfig::Webhook(ref c) => {
+                    Box::new(WebhookNotifier::new(c.clone(), proxy)?)
+                }
+                DestinationConfig::Email(ref c) => Box::new(EmailNotifier::new(c.clone())),
+                DestinationConfig::Syslog(ref c) => Box::new(SyslogNotifier::new(c)?),
+            };
+            notifications.add_destination(name, notifier, destination.rate_limit.clone());
+        }
+        for (event, names) in &config.routes {
+            for name in names {
+                if !notifications.destinations.contains_key(name) {
+                    return Err(BynarError::new(format!(
+                        "{} is routed to unknown destination {}",
+                        event, name
+                    )));
+                }
+            }
+        }
+        notifications.routes = config.routes.clone();
+        Ok(notifications)
+    }
+
+    fn load_history(&self) -> BTreeMap<String, Vec<u64>> {
+        read_to_string(&self.history_file)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    /// Send the message to every destination routed for the event
+    pub fn notify(&self, event: Event, msg: &str) {
+        let names = match self.routes.get(&event) {
+            Some(names) => names,
+            None => {
+                debug!("No destinations for {}", event);
+                return;
+            }
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut history = self.load_history();
+        for name in names {
+            let (notifier, rate_limit) = match self.destinations.get(name) {
+                Some(d) => d,
+                None => continue,
+            };
+            if let Some(ref limit) = rate_limit {
+                if !allowed(history.entry(name.clone()).or_default(), limit, now) {
+                    info!("Not notifying {} about {}.  Rate limited", name, event);
+                    continue;
+                }
+            }
+            debug!("Notifying {} about {}", name, event);
+            if let Err(e) = notifier.notify(event, msg) {
+                error!("Failed to notify {} about {}: {}", name, event, e);
+            }
+        }
+        let saved = serde_json::to_string(&history)
+            .map_err(BynarError::from)
+            .and_then(|h| write(&self.history_file, h).map_err(BynarError::from));
+        if let Err(e) = saved {
+            error!("Failed to save {}: {}", self.history_file.display(), e);
+        }
+    }
+}
+
+/// The destinations bynar.json asks for.  The older slack_* settings send
+/// every event to Slack when there's no notifications section.
+pub fn load_notifications(
+    settings: &ConfigSettings,
+    config_dir: &Path,
+) -> BynarResult<Notifications> {
+    let config = match (&settings.notifications, &settings.slack_webhook) {
+        (Some(config), _) => config.clone(),
+        (None, Some(webhook)) => {
+            let mut config = NotificationsConfig::default();
+            config.destinations.insert(
+                "slack".to_string(),
+                Destination {
+                    kind: DestinationConfig::Slack(SlackConfig {
+                        webhook: webhook.clone(),
+                        channel: settings.slack_channel.clone(),
+                        botname: settings.slack_botname.clone(),
+                    }),
+                    rate_limit: None,
+                },
+            );
+            for event in Event::all() {
+                config.routes.insert(event, vec!["slack".to_string()]);
+            }
+            config
+        }
+        (None, None) => NotificationsConfig::default(),
+    };
+    Notifications::new(&config, config_dir, settings.proxy.as_deref())
+}
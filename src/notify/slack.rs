@@ -0,0 +1,36 @@
+//! Slack through an incoming webhook
+use super::{Event, Notifier};
+use helpers::error::*;
+use serde_derive::*;
+use slack_hook::{PayloadBuilder, Slack};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SlackConfig {
+    /// Ex: https://hooks.slack.com/services/ID
+    pub webhook: String,
+    pub channel: Option<String>,
+    pub botname: Option<String>,
+}
+
+pub struct SlackNotifier {
+    config: SlackConfig,
+}
+
+impl SlackNotifier {
+    pub fn new(config: SlackConfig) -> SlackNotifier {
+        SlackNotifier { config }
+    }
+}
+
+impl Notifier for SlackNotifier {
+    fn notify(&self, _event: Event, msg: &str) -> BynarResult<()> {
+        let slack = Slack::new(self.config.webhook.as_ref())?;
+        let p = PayloadBuilder::new()
+            .text(msg)
+            .channel(self.config.channel.clone().unwrap_or_default())
+            .username(self.config.botname.clone().unwrap_or_default())
+            .build()?;
+        slack.send(&p)?;
+        Ok(())
+    }
+}
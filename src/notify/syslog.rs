@@ -0,0 +1,98 @@
+//! The local syslog
+use super::{Event, Notifier};
+use helpers::error::*;
+use libc::c_int;
+use serde_derive::*;
+use std::ffi::CString;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_facility() {
+        let config: SyslogConfig = serde_json::from_str(r#"{"facility": "local3"}"#).unwrap();
+        assert_eq!(facility(&config.facility).unwrap(), libc::LOG_LOCAL3);
+        assert_eq!(facility("daemon").unwrap(), libc::LOG_DAEMON);
+        assert!(facility("kern2").is_err());
+    }
+
+    #[test]
+    fn test_message() {
+        let config: SyslogConfig = serde_json::from_str(r#"{"facility": "local3"}"#).unwrap();
+        let notifier = SyslogNotifier::new(&config).unwrap();
+        let (priority, msg) = notifier
+            .message(Event::TicketFiled, "Filed OPS-1 100% done")
+            .unwrap();
+        assert_eq!(priority, libc::LOG_LOCAL3 | libc::LOG_NOTICE);
+        assert_eq!(msg.to_str().unwrap(), "ticket_filed: Filed OPS-1 100% done");
+        let (priority, _) = notifier.message(Event::NotSafe, "sdb").unwrap();
+        assert_eq!(priority, libc::LOG_LOCAL3 | libc::LOG_WARNING);
+        assert!(notifier.message(Event::NotSafe, "sd\0b").is_err());
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SyslogConfig {
+    /// Ex: daemon, user, local0-local7.  Defaults to daemon
+    #[serde(default = "default_facility")]
+    pub facility: String,
+}
+
+fn default_facility() -> String {
+    "daemon".to_string()
+}
+
+fn facility(name: &str) -> BynarResult<c_int> {
+    match name {
+        "daemon" => Ok(libc::LOG_DAEMON),
+        "user" => Ok(libc::LOG_USER),
+        "local0" => Ok(libc::LOG_LOCAL0),
+        "local1" => Ok(libc::LOG_LOCAL1),
+        "local2" => Ok(libc::LOG_LOCAL2),
+        "local3" => Ok(libc::LOG_LOCAL3),
+        "local4" => Ok(libc::LOG_LOCAL4),
+        "local5" => Ok(libc::LOG_LOCAL5),
+        "local6" => Ok(libc::LOG_LOCAL6),
+        "local7" => Ok(libc::LOG_LOCAL7),
+        _ => Err(BynarError::new(format!("Unknown syslog facility {}", name))),
+    }
+}
+
+pub struct SyslogNotifier {
+    facility: c_int,
+}
+
+impl SyslogNotifier {
+    pub fn new(config: &SyslogConfig) -> BynarResult<SyslogNotifier> {
+        Ok(SyslogNotifier {
+            facility: facility(&config.facility)?,
+        })
+    }
+
+    // The priority and text syslog is given for an event
+    fn message(&self, event: Event, msg: &str) -> BynarResult<(c_int, CString)> {
+        let priority = match event {
+            Event::NotSafe | Event::HardwareFailure => libc::LOG_WARNING,
+            _ => libc::LOG_NOTICE,
+        };
+        let msg = CString::new(format!("{}: {}", event, msg))
+            .map_err(|e| BynarError::new(e.to_string()))?;
+        Ok((self.facility | priority, msg))
+    }
+}
+
+impl Notifier for SyslogNotifier {
+    fn notify(&self, event: Event, msg: &str) -> BynarResult<()> {
+        let (priority, msg) = self.message(event, msg)?;
+        // The message goes through %s so it's never read as a format string
+        unsafe {
+            libc::syslog(
+                priority,
+                b"%s\0".as_ptr() as *const libc::c_char,
+                msg.as_ptr(),
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+//! A JSON POST to any URL.  The body is {"event": .., "message": ..}
+use super::{Event, Notifier};
+use helpers::error::*;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_derive::*;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_stub::HttpStub;
+    use serde_json::Value;
+
+    #[test]
+    fn test_webhook() {
+        let stub = HttpStub::start(|_| (200, "{}".to_string()));
+        let mut headers = BTreeMap::new();
+        headers.insert("X-Api-Key".to_string(), "secret".to_string());
+        let webhook = WebhookNotifier::new(
+            WebhookConfig {
+                url: format!("{}/hooks/bynar", stub.url),
+                headers,
+            },
+            None,
+        )
+        .unwrap();
+        webhook
+            .notify(Event::NotSafe, "Not safe to remove /dev/sdb")
+            .unwrap();
+
+        let requests = stub.requests();
+        assert_eq!(requests[0].path, "/hooks/bynar");
+        let body: Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["event"], "not_safe");
+        assert_eq!(body["message"], "Not safe to remove /dev/sdb");
+
+        let failing = HttpStub::start(|_| (500, "{}".to_string()));
+        let webhook = WebhookNotifier::new(
+            WebhookConfig {
+                url: failing.url.clone(),
+                headers: BTreeMap::new(),
+            },
+            None,
+        )
+        .unwrap();
+        assert!(webhook.notify(Event::NotSafe, "down").is_err());
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Extra headers such as an api key
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+pub struct WebhookNotifier {
+    config: WebhookConfig,
+    client: reqwest::Client,
+}
+
+impl WebhookNotifier {
+    pub fn new(config: WebhookConfig, proxy: Option<&str>) -> BynarResult<WebhookNotifier> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| BynarError::new(format!("Invalid header {}: {}", name, e)))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|e| BynarError::new(format!("Invalid value for {}: {}", name, e)))?;
+            headers.insert(name, value);
+        }
+        let mut builder = reqwest::Client::builder().default_headers(headers);
+        if let Some(url) = proxy {
+            builder = builder.proxy(reqwest::Proxy::all(url)?);
+        }
+        Ok(WebhookNotifier {
+            config,
+            client: builder.build()?,
+        })
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, event: Event, msg: &str) -> BynarResult<()> {
+        self.client
+            .post(&self.config.url)
+            .json(&json!({"event": event.to_string(), "message": msg}))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+}
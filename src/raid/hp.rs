@@ -1,3 +1,450 @@
-pub fn scan_for_smart_errors() {
-    //
+//! HP Smart Array support.  The controller configuration is gathered by
+//! parsing `ssacli ctrl all show config detail` output.  Disks behind these
+//! controllers show up to linux as logical drives so the physical drive
+//! status has to come from the controller.
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use helpers::error::*;
+use log::{debug, error};
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_config_detail() {
+        let output = include_str!("fixtures/ssacli_config_detail.txt");
+        let controllers = super::parse_config_detail(output).unwrap();
+        assert_eq!(controllers.len(), 1);
+        let ctrl = &controllers[0];
+        assert_eq!(ctrl.name, "Smart Array P440ar in Slot 0 (Embedded)");
+        assert_eq!(ctrl.slot, Some("0".to_string()));
+        assert_eq!(ctrl.serial_number, Some("PDNLH0BRH8P1R8".to_string()));
+        assert_eq!(ctrl.status, super::Status::Ok);
+        assert_eq!(ctrl.arrays.len(), 2);
+
+        let a = &ctrl.arrays[0];
+        assert_eq!(a.id, "A");
+        assert_eq!(a.status, super::Status::Ok);
+        assert_eq!(a.logical_drives.len(), 1);
+        assert_eq!(a.logical_drives[0].id, 1);
+        assert_eq!(a.logical_drives[0].fault_tolerance, Some("1".to_string()));
+        assert_eq!(
+            a.logical_drives[0].disk_name,
+            Some(Path::new("/dev/sda").to_path_buf())
+        );
+        assert_eq!(a.physical_drives.len(), 2);
+        assert_eq!(a.physical_drives[1].id, "1I:1:2");
+        assert_eq!(a.physical_drives[1].port, "1I");
+        assert_eq!(a.physical_drives[1].box_number, 1);
+        assert_eq!(a.physical_drives[1].bay, 2);
+        assert_eq!(
+            a.physical_drives[1].serial_number,
+            Some("W8G0R7J40000E72105C3".to_string())
+        );
+
+        let b = &ctrl.arrays[1];
+        assert_eq!(
+            b.status,
+            super::Status::Other("Failed Physical Drive".into())
+        );
+        assert_eq!(b.logical_drives[0].status, super::Status::Failed);
+        assert_eq!(b.physical_drives[0].status, super::Status::Failed);
+
+        assert_eq!(ctrl.unassigned.len(), 1);
+        assert_eq!(ctrl.unassigned[0].bay, 4);
+    }
+
+    #[test]
+    fn test_physical_drives_for_device() {
+        let output = include_str!("fixtures/ssacli_config_detail.txt");
+        let controllers = super::parse_config_detail(output).unwrap();
+        let drives = super::physical_drives_for_device(&controllers, Path::new("/dev/sdb"));
+        assert_eq!(drives.len(), 1);
+        assert_eq!(drives[0].id, "1I:1:3");
+        assert!(drives[0].needs_replacement());
+
+        let drives = super::physical_drives_for_device(&controllers, Path::new("/dev/sda"));
+        assert_eq!(drives.len(), 2);
+        assert!(drives.iter().all(|d| !d.needs_replacement()));
+
+        let drives = super::physical_drives_for_device(&controllers, Path::new("/dev/sdz"));
+        assert!(drives.is_empty());
+    }
+}
+
+/// Status reported by ssacli for controllers, arrays, logical and physical drives
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    Ok,
+    Failed,
+    PredictiveFailure,
+    Rebuilding,
+    Other(String),
+}
+
+impl FromStr for Status {
+    type Err = BynarError;
+
+    fn from_str(s: &str) -> BynarResult<Self> {
+        match s.trim() {
+            "OK" => Ok(Status::Ok),
+            "Failed" => Ok(Status::Failed),
+            "Predictive Failure" => Ok(Status::PredictiveFailure),
+            "Rebuilding" => Ok(Status::Rebuilding),
+            other => Ok(Status::Other(other.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SmartArrayController {
+    pub name: String,
+    pub slot: Option<String>,
+    pub serial_number: Option<String>,
+    pub status: Status,
+    pub arrays: Vec<Array>,
+    /// Physical drives that don't belong to any array
+    pub unassigned: Vec<PhysicalDrive>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Array {
+    pub id: String,
+    pub status: Status,
+    pub logical_drives: Vec<LogicalDrive>,
+    pub physical_drives: Vec<PhysicalDrive>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LogicalDrive {
+    pub id: u32,
+    pub status: Status,
+    pub size: Option<String>,
+    pub fault_tolerance: Option<String>,
+    pub unique_identifier: Option<String>,
+    /// The /dev/sdX device linux sees for this logical drive
+    pub disk_name: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PhysicalDrive {
+    /// port:box:bay
+    pub id: String,
+    pub port: String,
+    pub box_number: u32,
+    pub bay: u32,
+    pub status: Status,
+    pub serial_number: Option<String>,
+    pub model: Option<String>,
+    pub size: Option<String>,
+}
+
+impl PhysicalDrive {
+    /// The controller has given up on this drive or expects it to fail soon
+    pub fn needs_replacement(&self) -> bool {
+        self.status == Status::Failed || self.status == Status::PredictiveFailure
+    }
+}
+
+// Which part of the output the key: value lines currently belong to
+enum Section {
+    Controller,
+    Array,
+    LogicalDrive,
+    PhysicalDrive,
+    // Ports, drive cages, SEPs and summaries that we don't track
+    Ignored,
+}
+
+fn split_key_value(line: &str) -> Option<(&str, &str)> {
+    let mut parts = line.splitn(2, ':');
+    let key = parts.next()?.trim();
+    let value = parts.next()?.trim();
+    Some((key, value))
+}
+
+/// Parse the output of `ssacli ctrl all show config detail`
+pub fn parse_config_detail(output: &str) -> BynarResult<Vec<SmartArrayController>> {
+    let mut controllers: Vec<SmartArrayController> = Vec::new();
+    // Physical drives are listed after the array they belong to or
+    // under the Unassigned section
+    let mut in_array = false;
+    let mut section = Section::Ignored;
+
+    for line in output.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let trimmed = line.trim();
+        if !line.starts_with(' ') {
+            // Controllers are the only thing that isn't indented
+            controllers.push(SmartArrayController {
+                name: trimmed.to_string(),
+                slot: None,
+                serial_number: None,
+                status: Status::Other("Unknown".into()),
+                arrays: Vec::new(),
+                unassigned: Vec::new(),
+            });
+            in_array = false;
+            section = Section::Controller;
+            continue;
+        }
+        let ctrl = match controllers.last_mut() {
+            Some(c) => c,
+            None => continue,
+        };
+        if trimmed.starts_with("Array: ") {
+            ctrl.arrays.push(Array {
+                id: trimmed.trim_start_matches("Array: ").trim().to_string(),
+                status: Status::Other("Unknown".into()),
+                logical_drives: Vec::new(),
+                physical_drives: Vec::new(),
+            });
+            in_array = true;
+            section = Section::Array;
+            continue;
+        }
+        if trimmed.starts_with("Logical Drive: ") {
+            let id = u32::from_str(trimmed.trim_start_matches("Logical Drive: ").trim())?;
+            match ctrl.arrays.last_mut() {
+                Some(array) => array.logical_drives.push(LogicalDrive {
+                    id,
+                    status: Status::Other("Unknown".into()),
+                    size: None,
+                    fault_tolerance: None,
+                    unique_identifier: None,
+                    disk_name: None,
+                }),
+                None => {
+                    return Err(BynarError::new(format!(
+                        "Logical drive {} found outside of an array",
+                        id
+                    )));
+                }
+            };
+            section = Section::LogicalDrive;
+            continue;
+        }
+        if trimmed.starts_with("physicaldrive ") {
+            // Summary lines look like physicaldrive 1I:1:1 (port 1I:box 1:bay 1, ...)
+            // The detailed entries only have the id
+            if trimmed.contains('(') {
+                continue;
+            }
+            let drive = PhysicalDrive {
+                id: trimmed
+                    .trim_start_matches("physicaldrive ")
+                    .trim()
+                    .to_string(),
+                port: String::new(),
+                box_number: 0,
+                bay: 0,
+                status: Status::Other("Unknown".into()),
+                serial_number: None,
+                model: None,
+                size: None,
+            };
+            match (in_array, ctrl.arrays.last_mut()) {
+                (true, Some(array)) => array.physical_drives.push(drive),
+                _ => ctrl.unassigned.push(drive),
+            };
+            section = Section::PhysicalDrive;
+            continue;
+        }
+        if trimmed == "Unassigned" {
+            in_array = false;
+            section = Section::Ignored;
+            continue;
+        }
+        let (key, value) = match split_key_value(trimmed) {
+            Some(kv) => kv,
+            None => {
+                // Headers like "Physical Drives" or "SEP (Vendor ID ...) 380"
+                section = Section::Ignored;
+                continue;
+            }
+        };
+        match section {
+            Section::Controller => match key {
+                // Port Name starts a new port section
+                "Port Name" => section = Section::Ignored,
+                "Slot" => ctrl.slot = Some(value.to_string()),
+                "Serial Number" => ctrl.serial_number = Some(value.to_string()),
+                "Controller Status" => ctrl.status = Status::from_str(value)?,
+                _ => {}
+            },
+            Section::Array => {
+                if key == "Status" {
+                    if let Some(array) = ctrl.arrays.last_mut() {
+                        array.status = Status::from_str(value)?;
+                    }
+                }
+            }
+            Section::LogicalDrive => {
+                let ld = match ctrl
+                    .arrays
+                    .last_mut()
+                    .and_then(|a| a.logical_drives.last_mut())
+                {
+                    Some(ld) => ld,
+                    None => continue,
+                };
+                match key {
+                    "Status" => ld.status = Status::from_str(value)?,
+                    "Size" => ld.size = Some(value.to_string()),
+                    "Fault Tolerance" => ld.fault_tolerance = Some(value.to_string()),
+                    "Unique Identifier" => ld.unique_identifier = Some(value.to_string()),
+                    "Disk Name" => ld.disk_name = Some(PathBuf::from(value)),
+                    _ => {}
+                }
+            }
+            Section::PhysicalDrive => {
+                let pd = if in_array {
+                    ctrl.arrays
+                        .last_mut()
+                        .and_then(|a| a.physical_drives.last_mut())
+                } else {
+                    ctrl.unassigned.last_mut()
+                };
+                let pd = match pd {
+                    Some(pd) => pd,
+                    None => continue,
+                };
+                match key {
+                    "Port" => pd.port = value.to_string(),
+                    "Box" => pd.box_number = u32::from_str(value)?,
+                    "Bay" => pd.bay = u32::from_str(value)?,
+                    "Status" => pd.status = Status::from_str(value)?,
+                    "Serial Number" => pd.serial_number = Some(value.to_string()),
+                    "Model" => pd.model = Some(value.to_string()),
+                    "Size" => pd.size = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            Section::Ignored => {}
+        };
+    }
+
+    Ok(controllers)
+}
+
+/// Run ssacli and parse the configuration of every Smart Array controller
+pub fn get_config() -> BynarResult<Vec<SmartArrayController>> {
+    debug!("Running ssacli ctrl all show config detail");
+    let output = Command::new("ssacli")
+        .args(&["ctrl", "all", "show", "config", "detail"])
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        error!("ssacli failed: {}", stderr);
+        return Err(BynarError::new(stderr));
+    }
+    parse_config_detail(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Find the physical drives that make up the logical drive linux sees as dev_path
+pub fn physical_drives_for_device<'a>(
+    controllers: &'a [SmartArrayController],
+    dev_path: &Path,
+) -> Vec<&'a PhysicalDrive> {
+    for ctrl in controllers {
+        for array in &ctrl.arrays {
+            if array
+                .logical_drives
+                .iter()
+                .any(|ld| ld.disk_name.as_deref() == Some(dev_path))
+            {
+                return array.physical_drives.iter().collect();
+            }
+        }
+    }
+    vec![]
+}
+
+fn run_ssacli(args: &[&str], simulate: bool) -> BynarResult<()> {
+    debug!("Running ssacli {:?}", args);
+    if simulate {
+        return Ok(());
+    }
+    let output = Command::new("ssacli").args(args).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        error!("ssacli {:?} failed: {}", args, stderr);
+        return Err(BynarError::new(stderr));
+    }
+    Ok(())
+}
+
+/// Make a replaced drive visible to linux again.  If the drive was part of
+/// an array the failed logical drives are re-enabled.  If it shows up
+/// unassigned a single disk raid 0 logical drive is created on it.
+pub fn configure_replaced_drive(slot: &str, drive_id: &str, simulate: bool) -> BynarResult<()> {
+    let controllers = get_config()?;
+    let ctrl = controllers
+        .iter()
+        .find(|c| c.slot.as_deref() == Some(slot))
+        .ok_or_else(|| BynarError::new(format!("Smart Array in slot {} not found", slot)))?;
+    let ctrl_arg = format!("slot={}", slot);
+
+    if let Some(drive) = ctrl.unassigned.iter().find(|d| d.id == drive_id) {
+        if drive.status != Status::Ok {
+            return Err(BynarError::new(format!(
+                "Unassigned drive {} status is {:?}",
+                drive_id, drive.status
+            )));
+        }
+        debug!("Creating raid 0 logical drive on {}", drive_id);
+        return run_ssacli(
+            &[
+                "ctrl",
+                &ctrl_arg,
+                "create",
+                "type=ld",
+                &format!("drives={}", drive_id),
+                "raid=0",
+            ],
+            simulate,
+        );
+    }
+    for array in &ctrl.arrays {
+        let drive = match array.physical_drives.iter().find(|d| d.id == drive_id) {
+            Some(d) => d,
+            None => continue,
+        };
+        if drive.needs_replacement() {
+            // Still the old drive
+            return Err(BynarError::new(format!(
+                "Drive {} has not been replaced. Status: {:?}",
+                drive_id, drive.status
+            )));
+        }
+        for ld in array
+            .logical_drives
+            .iter()
+            .filter(|ld| ld.status == Status::Failed)
+        {
+            debug!("Re-enabling failed logical drive {}", ld.id);
+            run_ssacli(
+                &[
+                    "ctrl",
+                    &ctrl_arg,
+                    "ld",
+                    &ld.id.to_string(),
+                    "modify",
+                    "reenable",
+                    "forced",
+                ],
+                simulate,
+            )?;
+        }
+        return Ok(());
+    }
+    Err(BynarError::new(format!(
+        "Drive {} not found on Smart Array in slot {}",
+        drive_id, slot
+    )))
 }
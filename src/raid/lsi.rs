@@ -1,3 +1,467 @@
-pub fn scan_for_smart_errors() {
-    //
+//! LSI/Broadcom MegaRAID support.  storcli is asked for its json output
+//! and the controller, virtual drive and physical drive details are merged
+//! together.  Like the HP controllers linux only sees the virtual drives so
+//! the physical drive health has to come from the controller.
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use helpers::error::*;
+use log::{debug, error};
+use serde_json::value::Value;
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    fn controllers() -> Vec<super::MegaRaidController> {
+        let mut controllers =
+            super::parse_controllers(include_str!("fixtures/storcli_show_all.json")).unwrap();
+        super::parse_virtual_drives(
+            include_str!("fixtures/storcli_vall_show_all.json"),
+            &mut controllers,
+        )
+        .unwrap();
+        super::parse_drive_details(
+            include_str!("fixtures/storcli_drives_show_all.json"),
+            &mut controllers,
+        )
+        .unwrap();
+        controllers
+    }
+
+    #[test]
+    fn test_parse_storcli() {
+        let controllers = controllers();
+        assert_eq!(controllers.len(), 1);
+        let ctrl = &controllers[0];
+        assert_eq!(ctrl.id, 0);
+        assert_eq!(ctrl.model, "AVAGO MegaRAID SAS 9361-8i");
+        assert_eq!(ctrl.serial_number, Some("SK71436397".to_string()));
+        assert_eq!(ctrl.virtual_drives.len(), 2);
+        assert_eq!(ctrl.physical_drives.len(), 3);
+
+        let vd = &ctrl.virtual_drives[1];
+        assert_eq!(vd.drive_group, 1);
+        assert_eq!(vd.id, 1);
+        assert_eq!(vd.raid_type, "RAID0");
+        assert_eq!(vd.os_drive_name, Some(Path::new("/dev/sdd").to_path_buf()));
+        assert_eq!(vd.physical_drives, vec!["252:1".to_string()]);
+
+        let pd = &ctrl.physical_drives[1];
+        assert_eq!(pd.enclosure_id, Some(252));
+        assert_eq!(pd.slot, 1);
+        assert_eq!(pd.device_id, 9);
+        assert_eq!(pd.state, super::DriveState::Online);
+        assert_eq!(pd.drive_group, Some(1));
+        assert_eq!(pd.serial_number, Some("ZC13AC9L".to_string()));
+        assert_eq!(pd.media_error_count, 12);
+        assert_eq!(pd.predictive_failure_count, 3);
+        assert!(pd.smart_alert);
+
+        let pd = &ctrl.physical_drives[2];
+        assert_eq!(pd.state, super::DriveState::UnconfiguredGood);
+        assert_eq!(pd.drive_group, None);
+        assert!(!pd.foreign);
+    }
+
+    #[test]
+    fn test_physical_drives_for_device() {
+        let controllers = controllers();
+        let drives = super::physical_drives_for_device(&controllers, Path::new("/dev/sdd"));
+        assert_eq!(drives.len(), 1);
+        assert_eq!(drives[0].slot, 1);
+        assert!(drives[0].needs_replacement());
+
+        let drives = super::physical_drives_for_device(&controllers, Path::new("/dev/sdc"));
+        assert_eq!(drives.len(), 1);
+        assert!(!drives[0].needs_replacement());
+
+        let drives = super::physical_drives_for_device(&controllers, Path::new("/dev/sda"));
+        assert!(drives.is_empty());
+    }
+}
+
+/// Physical drive states as abbreviated by storcli
+#[derive(Clone, Debug, PartialEq)]
+pub enum DriveState {
+    Online,
+    Offline,
+    Failed,
+    Rebuilding,
+    UnconfiguredGood,
+    UnconfiguredBad,
+    Jbod,
+    HotSpare,
+    Other(String),
+}
+
+impl FromStr for DriveState {
+    type Err = BynarError;
+
+    fn from_str(s: &str) -> BynarResult<Self> {
+        match s.trim() {
+            "Onln" => Ok(DriveState::Online),
+            "Offln" => Ok(DriveState::Offline),
+            "F" | "Failed" => Ok(DriveState::Failed),
+            "Rbld" => Ok(DriveState::Rebuilding),
+            "UGood" => Ok(DriveState::UnconfiguredGood),
+            "UBad" => Ok(DriveState::UnconfiguredBad),
+            "JBOD" => Ok(DriveState::Jbod),
+            "GHS" | "DHS" => Ok(DriveState::HotSpare),
+            other => Ok(DriveState::Other(other.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MegaRaidController {
+    pub id: u32,
+    pub model: String,
+    pub serial_number: Option<String>,
+    pub virtual_drives: Vec<VirtualDrive>,
+    pub physical_drives: Vec<PhysicalDrive>,
+}
+
+#[derive(Clone, Debug)]
+pub struct VirtualDrive {
+    pub drive_group: u32,
+    pub id: u32,
+    pub raid_type: String,
+    /// Optl, Dgrd, Pdgd, OfLn etc
+    pub state: String,
+    pub size: String,
+    /// The /dev/sdX device linux sees for this virtual drive
+    pub os_drive_name: Option<PathBuf>,
+    /// EID:Slt of the physical drives in this virtual drive
+    pub physical_drives: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PhysicalDrive {
+    /// None for drives attached directly to the controller
+    pub enclosure_id: Option<u32>,
+    pub slot: u32,
+    pub device_id: u32,
+    pub state: DriveState,
+    pub drive_group: Option<u32>,
+    /// The drive carries a raid config from another controller or a
+    /// previous life that can be imported
+    pub foreign: bool,
+    pub size: String,
+    pub model: String,
+    pub serial_number: Option<String>,
+    pub media_error_count: u64,
+    pub other_error_count: u64,
+    pub predictive_failure_count: u64,
+    pub smart_alert: bool,
+}
+
+impl PhysicalDrive {
+    /// EID:Slt the way storcli prints it
+    pub fn eid_slot(&self) -> String {
+        match self.enclosure_id {
+            Some(eid) => format!("{}:{}", eid, self.slot),
+            None => format!(" :{}", self.slot),
+        }
+    }
+
+    /// The controller has failed this drive or the drive is predicting
+    /// its own failure.  Media errors alone are logged but the controller
+    /// remaps those.
+    pub fn needs_replacement(&self) -> bool {
+        match self.state {
+            DriveState::Offline | DriveState::Failed | DriveState::UnconfiguredBad => true,
+            _ => self.predictive_failure_count > 0 || self.smart_alert,
+        }
+    }
+}
+
+fn get_str<'a>(v: &'a Value, key: &str) -> BynarResult<&'a str> {
+    v[key]
+        .as_str()
+        .ok_or_else(|| BynarError::new(format!("storcli json missing {}", key)))
+}
+
+fn get_u64(v: &Value, key: &str) -> BynarResult<u64> {
+    v[key]
+        .as_u64()
+        .ok_or_else(|| BynarError::new(format!("storcli json missing {}", key)))
+}
+
+// Split "252:1" or " :1" into enclosure and slot
+fn parse_eid_slot(s: &str) -> BynarResult<(Option<u32>, u32)> {
+    let mut parts = s.splitn(2, ':');
+    let eid = parts.next().unwrap_or("").trim();
+    let slot = parts
+        .next()
+        .ok_or_else(|| BynarError::new(format!("Invalid EID:Slt {}", s)))?;
+    let eid = if eid.is_empty() {
+        None
+    } else {
+        Some(u32::from_str(eid)?)
+    };
+    Ok((eid, u32::from_str(slot.trim())?))
+}
+
+// Returns (controller id, response data) for each controller that
+// successfully answered
+fn responses(output: &str) -> BynarResult<Vec<(u32, Value)>> {
+    let v: Value = serde_json::from_str(output)?;
+    let controllers = v["Controllers"]
+        .as_array()
+        .ok_or_else(|| BynarError::from("storcli json missing Controllers"))?;
+    let mut responses = Vec::new();
+    for c in controllers {
+        let status = &c["Command Status"];
+        let id = get_u64(status, "Controller")? as u32;
+        if get_str(status, "Status")? != "Success" {
+            error!(
+                "storcli controller {} failed: {}",
+                id,
+                status["Description"].as_str().unwrap_or("unknown")
+            );
+            continue;
+        }
+        responses.push((id, c["Response Data"].clone()));
+    }
+    Ok(responses)
+}
+
+fn parse_pd_list_entry(pd: &Value) -> BynarResult<PhysicalDrive> {
+    let (enclosure_id, slot) = parse_eid_slot(get_str(pd, "EID:Slt")?)?;
+    Ok(PhysicalDrive {
+        enclosure_id,
+        slot,
+        device_id: get_u64(pd, "DID")? as u32,
+        state: DriveState::from_str(get_str(pd, "State")?)?,
+        // Unconfigured drives have a DG of "-"
+        drive_group: pd["DG"].as_u64().map(|dg| dg as u32),
+        foreign: pd["DG"].as_str() == Some("F"),
+        size: get_str(pd, "Size")?.to_string(),
+        model: get_str(pd, "Model")?.trim().to_string(),
+        serial_number: None,
+        media_error_count: 0,
+        other_error_count: 0,
+        predictive_failure_count: 0,
+        smart_alert: false,
+    })
+}
+
+/// Parse the output of `storcli /call show all J`
+pub fn parse_controllers(output: &str) -> BynarResult<Vec<MegaRaidController>> {
+    let mut controllers = Vec::new();
+    for (id, data) in responses(output)? {
+        let basics = &data["Basics"];
+        let mut ctrl = MegaRaidController {
+            id,
+            model: get_str(basics, "Model")?.to_string(),
+            serial_number: basics["Serial Number"].as_str().map(|s| s.to_string()),
+            virtual_drives: Vec::new(),
+            physical_drives: Vec::new(),
+        };
+        if let Some(vds) = data["VD LIST"].as_array() {
+            for vd in vds {
+                let dg_vd = get_str(vd, "DG/VD")?;
+                let mut parts = dg_vd.splitn(2, '/');
+                let dg = u32::from_str(parts.next().unwrap_or(""))?;
+                let vd_id = u32::from_str(
+                    parts
+                        .next()
+                        .ok_or_else(|| BynarError::new(format!("Invalid DG/VD {}", dg_vd)))?,
+                )?;
+                ctrl.virtual_drives.push(VirtualDrive {
+                    drive_group: dg,
+                    id: vd_id,
+                    raid_type: get_str(vd, "TYPE")?.to_string(),
+                    state: get_str(vd, "State")?.to_string(),
+                    size: get_str(vd, "Size")?.to_string(),
+                    os_drive_name: None,
+                    physical_drives: Vec::new(),
+                });
+            }
+        }
+        if let Some(pds) = data["PD LIST"].as_array() {
+            for pd in pds {
+                ctrl.physical_drives.push(parse_pd_list_entry(pd)?);
+            }
+        }
+        controllers.push(ctrl);
+    }
+    Ok(controllers)
+}
+
+/// Merge the output of `storcli /call/vall show all J` into the controllers.
+/// This is where the OS drive name and the drives in each virtual drive come from
+pub fn parse_virtual_drives(
+    output: &str,
+    controllers: &mut [MegaRaidController],
+) -> BynarResult<()> {
+    for (id, data) in responses(output)? {
+        let ctrl = match controllers.iter_mut().find(|c| c.id == id) {
+            Some(c) => c,
+            None => continue,
+        };
+        for vd in ctrl.virtual_drives.iter_mut() {
+            let properties = &data[&format!("VD{} Properties", vd.id)];
+            vd.os_drive_name = properties["OS Drive Name"]
+                .as_str()
+                .map(|s| PathBuf::from(s.trim()));
+            if let Some(pds) = data[&format!("PDs for VD {}", vd.id)].as_array() {
+                for pd in pds {
+                    vd.physical_drives.push(get_str(pd, "EID:Slt")?.to_string());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Merge the output of `storcli /call/eall/sall show all J` into the
+/// controllers.  This is where the error counts and serial numbers come from
+pub fn parse_drive_details(
+    output: &str,
+    controllers: &mut [MegaRaidController],
+) -> BynarResult<()> {
+    for (id, data) in responses(output)? {
+        let ctrl = match controllers.iter_mut().find(|c| c.id == id) {
+            Some(c) => c,
+            None => continue,
+        };
+        for pd in ctrl.physical_drives.iter_mut() {
+            let name = match pd.enclosure_id {
+                Some(eid) => format!("Drive /c{}/e{}/s{}", id, eid, pd.slot),
+                None => format!("Drive /c{}/s{}", id, pd.slot),
+            };
+            let detail = &data[&format!("{} - Detailed Information", name)];
+            if detail.is_null() {
+                debug!("No detailed information for {}", name);
+                continue;
+            }
+            let state = &detail[&format!("{} State", name)];
+            pd.media_error_count = state["Media Error Count"].as_u64().unwrap_or(0);
+            pd.other_error_count = state["Other Error Count"].as_u64().unwrap_or(0);
+            pd.predictive_failure_count = state["Predictive Failure Count"].as_u64().unwrap_or(0);
+            pd.smart_alert = state["S.M.A.R.T alert flagged by drive"].as_str() == Some("Yes");
+            pd.serial_number = detail[&format!("{} Device attributes", name)]["SN"]
+                .as_str()
+                .map(|s| s.trim().to_string());
+        }
+    }
+    Ok(())
+}
+
+fn run_storcli(args: &[&str]) -> BynarResult<String> {
+    debug!("Running storcli {:?}", args);
+    let output = Command::new("storcli").args(args).output()?;
+    // storcli reports failures inside the json so the exit code alone
+    // isn't enough
+    if !output.status.success() && output.stdout.is_empty() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        error!("storcli failed: {}", stderr);
+        return Err(BynarError::new(stderr));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Run storcli and gather the configuration of every MegaRAID controller
+pub fn get_config() -> BynarResult<Vec<MegaRaidController>> {
+    let mut controllers = parse_controllers(&run_storcli(&["/call", "show", "all", "J"])?)?;
+    parse_virtual_drives(
+        &run_storcli(&["/call/vall", "show", "all", "J"])?,
+        &mut controllers,
+    )?;
+    parse_drive_details(
+        &run_storcli(&["/call/eall/sall", "show", "all", "J"])?,
+        &mut controllers,
+    )?;
+    Ok(controllers)
+}
+
+/// Find the physical drives that make up the virtual drive linux sees as dev_path
+pub fn physical_drives_for_device<'a>(
+    controllers: &'a [MegaRaidController],
+    dev_path: &Path,
+) -> Vec<&'a PhysicalDrive> {
+    for ctrl in controllers {
+        for vd in &ctrl.virtual_drives {
+            if vd.os_drive_name.as_deref() == Some(dev_path) {
+                return ctrl
+                    .physical_drives
+                    .iter()
+                    .filter(|pd| vd.physical_drives.contains(&pd.eid_slot()))
+                    .collect();
+            }
+        }
+    }
+    vec![]
+}
+
+fn run_storcli_action(args: &[&str], simulate: bool) -> BynarResult<()> {
+    if simulate {
+        debug!("Simulate: storcli {:?}", args);
+        return Ok(());
+    }
+    let output = run_storcli(args)?;
+    // Non json commands print Status = Success or Failure
+    if !output.contains("Status = Success") {
+        error!("storcli {:?} failed: {}", args, output);
+        return Err(BynarError::new(output));
+    }
+    Ok(())
+}
+
+/// Make a replaced drive visible to linux again.  A drive carrying the old
+/// configuration is imported.  An unconfigured drive gets a single disk
+/// raid 0 virtual drive.
+pub fn configure_replaced_drive(
+    controller: u32,
+    enclosure: Option<u32>,
+    slot: u32,
+    simulate: bool,
+) -> BynarResult<()> {
+    let controllers = get_config()?;
+    let ctrl = controllers
+        .iter()
+        .find(|c| c.id == controller)
+        .ok_or_else(|| BynarError::new(format!("MegaRAID controller {} not found", controller)))?;
+    let drive = ctrl
+        .physical_drives
+        .iter()
+        .find(|d| d.enclosure_id == enclosure && d.slot == slot)
+        .ok_or_else(|| {
+            BynarError::new(format!(
+                "Drive in slot {} not found on controller {}",
+                slot, controller
+            ))
+        })?;
+    let ctrl_arg = format!("/c{}", controller);
+    match drive.state {
+        DriveState::UnconfiguredGood if drive.foreign => {
+            debug!("Importing foreign config for {}", drive.eid_slot());
+            run_storcli_action(&[&format!("{}/fall", ctrl_arg), "import"], simulate)
+        }
+        DriveState::UnconfiguredGood => {
+            debug!("Creating raid 0 virtual drive on {}", drive.eid_slot());
+            run_storcli_action(
+                &[
+                    &ctrl_arg,
+                    "add",
+                    "vd",
+                    "type=raid0",
+                    &format!("drives={}", drive.eid_slot().trim()),
+                ],
+                simulate,
+            )
+        }
+        DriveState::Online | DriveState::Jbod => {
+            debug!("{} is already configured", drive.eid_slot());
+            Ok(())
+        }
+        _ => Err(BynarError::new(format!(
+            "Drive {} is in state {:?}. Unable to configure it",
+            drive.eid_slot(),
+            drive.state
+        ))),
+    }
 }
@@ -0,0 +1,126 @@
+//! Hardware raid controller support
+use std::fmt;
+use std::str::FromStr;
+
+use helpers::error::*;
+
+pub mod hp;
+pub mod lsi;
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    #[test]
+    fn test_raid_location() {
+        let hp = super::RaidLocation::Hp {
+            slot: "0".into(),
+            drive: "1I:1:3".into(),
+        };
+        assert_eq!(hp.to_string(), "hp/0/1I:1:3");
+        assert_eq!(super::RaidLocation::from_str("hp/0/1I:1:3").unwrap(), hp);
+
+        let lsi = super::RaidLocation::Lsi {
+            controller: 0,
+            enclosure: Some(252),
+            slot: 1,
+        };
+        assert_eq!(lsi.to_string(), "lsi/0/252:1");
+        assert_eq!(super::RaidLocation::from_str("lsi/0/252:1").unwrap(), lsi);
+
+        let direct = super::RaidLocation::Lsi {
+            controller: 1,
+            enclosure: None,
+            slot: 4,
+        };
+        assert_eq!(
+            super::RaidLocation::from_str(&direct.to_string()).unwrap(),
+            direct
+        );
+        assert!(super::RaidLocation::from_str("adaptec/0/1").is_err());
+    }
+}
+
+/// Where a physical drive behind a raid controller lives.  This is saved
+/// when a drive fails so the controller can be reconfigured after the
+/// technician swaps it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RaidLocation {
+    /// Controller slot and port:box:bay
+    Hp { slot: String, drive: String },
+    /// Controller number, enclosure and slot
+    Lsi {
+        controller: u32,
+        enclosure: Option<u32>,
+        slot: u32,
+    },
+}
+
+impl fmt::Display for RaidLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RaidLocation::Hp {
+                ref slot,
+                ref drive,
+            } => write!(f, "hp/{}/{}", slot, drive),
+            RaidLocation::Lsi {
+                controller,
+                enclosure,
+                slot,
+            } => match enclosure {
+                Some(e) => write!(f, "lsi/{}/{}:{}", controller, e, slot),
+                None => write!(f, "lsi/{}/:{}", controller, slot),
+            },
+        }
+    }
+}
+
+impl FromStr for RaidLocation {
+    type Err = BynarError;
+
+    fn from_str(s: &str) -> BynarResult<Self> {
+        let parts: Vec<&str> = s.splitn(3, '/').collect();
+        if parts.len() != 3 {
+            return Err(BynarError::new(format!("Invalid raid location: {}", s)));
+        }
+        match parts[0] {
+            "hp" => Ok(RaidLocation::Hp {
+                slot: parts[1].to_string(),
+                drive: parts[2].to_string(),
+            }),
+            "lsi" => {
+                let mut eid_slot = parts[2].splitn(2, ':');
+                let enclosure = match eid_slot.next() {
+                    Some("") | None => None,
+                    Some(e) => Some(u32::from_str(e)?),
+                };
+                let slot = eid_slot
+                    .next()
+                    .ok_or_else(|| BynarError::new(format!("Invalid raid location: {}", s)))?;
+                Ok(RaidLocation::Lsi {
+                    controller: u32::from_str(parts[1])?,
+                    enclosure,
+                    slot: u32::from_str(slot)?,
+                })
+            }
+            _ => Err(BynarError::new(format!("Unknown raid location: {}", s))),
+        }
+    }
+}
+
+/// After a drive is swapped the controller usually won't present it to
+/// linux until a logical drive is created or re-enabled for it.  Do that
+/// for the bay the failed drive was in.
+pub fn configure_replaced_drive(location: &RaidLocation, simulate: bool) -> BynarResult<()> {
+    match *location {
+        RaidLocation::Hp {
+            ref slot,
+            ref drive,
+        } => hp::configure_replaced_drive(slot, drive, simulate),
+        RaidLocation::Lsi {
+            controller,
+            enclosure,
+            slot,
+        } => lsi::configure_replaced_drive(controller, enclosure, slot, simulate),
+    }
+}
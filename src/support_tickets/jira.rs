@@ -0,0 +1,309 @@
+//! JIRA.  Issues are fetched with goji.  Creating, commenting and
+//! transitions go straight to the REST API because goji can't set labels
+//! or custom fields.
+use super::template::{render, render_value, TicketVariables};
+use super::{http_client, TicketStatus, TicketSystem};
+use crate::ConfigSettings;
+use goji::issues::Issues;
+use goji::{Credentials, Jira};
+use helpers::error::*;
+use log::debug;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_stub::HttpStub;
+
+    fn stub_jira() -> HttpStub {
+        HttpStub::start(|req| {
+            let path = req.path.split('?').next().unwrap_or("").to_string();
+            let issue = |id: &str, resolved: Value| {
+                let status = if resolved.is_null() {
+                    "Open"
+                } else {
+                    "Resolved"
+                };
+                json!({
+                    "self": format!("http://jira/rest/api/2/issue/{}", id),
+                    "key": "OPS-1",
+                    "id": id,
+                    "fields": {"resolutiondate": resolved, "status": {"name": status}}
+                })
+                .to_string()
+            };
+            match req.method.as_str() {
+                "POST" if path.ends_with("/issue") => (
+                    201,
+                    json!({"id": "10001", "key": "OPS-1", "self": "http://jira"}).to_string(),
+                ),
+                "GET" if path.ends_with("/issue/10001") => (200, issue("10001", Value::Null)),
+                "GET" if path.ends_with("/issue/10002") => {
+                    (200, issue("10002", json!("2019-03-22T12:00:00.000+0000")))
+                }
+                "POST" if path.ends_with("/issue/10001/comment") => {
+                    (201, json!({"id": "1"}).to_string())
+                }
+                "GET" if path.ends_with("/issue/10001/transitions") => (
+                    200,
+                    json!({"transitions": [
+                        {"id": "11", "name": "Start Progress",
+                         "to": {"statusCategory": {"key": "indeterminate"}}},
+                        {"id": "31", "name": "Resolve Issue",
+                         "to": {"statusCategory": {"key": "done"}}}
+                    ]})
+                    .to_string(),
+                ),
+                "POST" if path.ends_with("/issue/10001/transitions") => (204, String::new()),
+                _ => (404, "{}".to_string()),
+            }
+        })
+    }
+
+    fn jira_config(host: &str) -> JiraConfig {
+        JiraConfig {
+            host: host.to_string(),
+            user: "bynar".to_string(),
+            password: "password".to_string(),
+            issue_type: "3".to_string(),
+            priority: "4".to_string(),
+            project_id: "OPS".to_string(),
+            ticket_assignee: "storage".to_string(),
+            component: Some("Ceph".to_string()),
+            labels: vec!["bynar".to_string(), "{region}".to_string()],
+            custom_fields: [
+                (
+                    "customfield_10100".to_string(),
+                    json!({"value": "{region}"}),
+                ),
+                ("customfield_10200".to_string(), json!("{server_serial}")),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+        }
+    }
+
+    #[test]
+    fn test_jira_tickets() {
+        let stub = stub_jira();
+        let jira = JiraTickets::new(jira_config(&stub.url), None).unwrap();
+        let mut vars = TicketVariables::new();
+        vars.insert("region".to_string(), "east".to_string());
+        vars.insert("server_serial".to_string(), "MXQ123".to_string());
+
+        assert_eq!(
+            jira.create("Bynar: Dead disk", "sdb died", &vars).unwrap(),
+            "10001"
+        );
+        assert_eq!(jira.status("10001").unwrap(), TicketStatus::Open);
+        assert_eq!(jira.status("10002").unwrap(), TicketStatus::Resolved);
+        jira.comment("10001", "Disk removed").unwrap();
+        jira.close("10001").unwrap();
+
+        let requests = stub.requests();
+        let created: Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(created["fields"]["summary"], "Bynar: Dead disk");
+        assert_eq!(created["fields"]["project"]["key"], "OPS");
+        assert_eq!(created["fields"]["components"][0]["name"], "Ceph");
+        assert_eq!(created["fields"]["labels"], json!(["bynar", "east"]));
+        assert_eq!(created["fields"]["customfield_10100"]["value"], "east");
+        assert_eq!(created["fields"]["customfield_10200"], "MXQ123");
+        let comment: Value = serde_json::from_str(&requests[3].body).unwrap();
+        assert_eq!(comment["body"], "Disk removed");
+        // The transition into the done category is the one that resolves it
+        let transition: Value = serde_json::from_str(&requests[5].body).unwrap();
+        assert_eq!(transition["transition"]["id"], "31");
+        assert_eq!(jira.status_name("10002").unwrap(), "Resolved");
+    }
+}
+
+/// The jira_* settings from bynar.json
+#[derive(Clone, Debug)]
+pub struct JiraConfig {
+    pub host: String,
+    pub user: String,
+    pub password: String,
+    pub issue_type: String,
+    pub priority: String,
+    pub project_id: String,
+    pub ticket_assignee: String,
+    /// Component to file tickets under.  Defaults to Ceph
+    pub component: Option<String>,
+    /// Labels to add.  These can be templates
+    pub labels: Vec<String>,
+    /// Custom field id to value.  String values can be templates.
+    /// Ex: {"customfield_10100": {"value": "{region}"}}
+    pub custom_fields: BTreeMap<String, Value>,
+}
+
+impl JiraConfig {
+    pub fn from_settings(settings: &ConfigSettings) -> BynarResult<JiraConfig> {
+        let required = |name: &str, value: &Option<String>| {
+            value
+                .clone()
+                .ok_or_else(|| BynarError::new(format!("{} must be set to use JIRA", name)))
+        };
+        Ok(JiraConfig {
+            host: required("jira_host", &settings.jira_host)?,
+            user: required("jira_user", &settings.jira_user)?,
+            password: required("jira_password", &settings.jira_password)?,
+            issue_type: required("jira_issue_type", &settings.jira_issue_type)?,
+            priority: required("jira_priority", &settings.jira_priority)?,
+            project_id: required("jira_project_id", &settings.jira_project_id)?,
+            ticket_assignee: required("jira_ticket_assignee", &settings.jira_ticket_assignee)?,
+            component: Some(
+                settings
+                    .jira_component
+                    .clone()
+                    .unwrap_or_else(|| "Ceph".to_string()),
+            )
+            .filter(|c| !c.is_empty()),
+            labels: settings.jira_labels.clone(),
+            custom_fields: settings.jira_custom_fields.clone(),
+        })
+    }
+}
+
+pub struct JiraTickets {
+    config: JiraConfig,
+    jira: Jira,
+    client: reqwest::Client,
+}
+
+impl JiraTickets {
+    pub fn new(config: JiraConfig, proxy: Option<&str>) -> BynarResult<JiraTickets> {
+        let client = http_client(proxy)?;
+        let jira = Jira::from_client(
+            config.host.clone(),
+            Credentials::Basic(config.user.clone(), config.password.clone()),
+            client.clone(),
+        )?;
+        Ok(JiraTickets {
+            config,
+            jira,
+            client,
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!(
+            "{}/rest/api/latest{}",
+            self.config.host.trim_end_matches('/'),
+            path
+        )
+    }
+}
+
+impl TicketSystem for JiraTickets {
+    /// Create a new JIRA support ticket and return the ticket ID associated with it
+    fn create(
+        &self,
+        title: &str,
+        description: &str,
+        vars: &TicketVariables,
+    ) -> BynarResult<String> {
+        let mut fields = Map::new();
+        fields.insert("summary".into(), json!(title));
+        fields.insert("description".into(), json!(description));
+        fields.insert("project".into(), json!({ "key": self.config.project_id }));
+        fields.insert("issuetype".into(), json!({ "id": self.config.issue_type }));
+        fields.insert("priority".into(), json!({ "id": self.config.priority }));
+        fields.insert(
+            "assignee".into(),
+            json!({ "name": self.config.ticket_assignee }),
+        );
+        if let Some(ref component) = self.config.component {
+            fields.insert("components".into(), json!([{ "name": component }]));
+        }
+        if !self.config.labels.is_empty() {
+            let labels: Vec<String> = self.config.labels.iter().map(|l| render(l, vars)).collect();
+            fields.insert("labels".into(), json!(labels));
+        }
+        for (field, value) in &self.config.custom_fields {
+            fields.insert(field.clone(), render_value(value, vars));
+        }
+        let issue_description = json!({ "fields": fields });
+        debug!(
+            "Creating JIRA ticket with information: {}",
+            issue_description
+        );
+        let results: Value = self
+            .client
+            .post(&self.url("/issue"))
+            .basic_auth(&self.config.user, Some(&self.config.password))
+            .json(&issue_description)
+            .send()?
+            .error_for_status()?
+            .json()?;
+        results["id"]
+            .as_str()
+            .map(|id| id.to_string())
+            .ok_or_else(|| BynarError::from("JIRA didn't return an issue id"))
+    }
+
+    /// Check to see if a JIRA support ticket is marked as resolved
+    fn status(&self, id: &str) -> BynarResult<TicketStatus> {
+        let issue = Issues::new(&self.jira);
+        debug!("Fetching issue: {} for resolution information", id);
+        let results = issue.get(id)?;
+        match results.fields.get("resolutiondate") {
+            Some(Value::String(_)) => Ok(TicketStatus::Resolved),
+            // null or resolutiondate doesn't exist
+            _ => Ok(TicketStatus::Open),
+        }
+    }
+
+    fn status_name(&self, id: &str) -> BynarResult<String> {
+        let issue = Issues::new(&self.jira).get(id)?;
+        issue
+            .fields
+            .get("status")
+            .and_then(|status| status["name"].as_str())
+            .map(|name| name.to_string())
+            .ok_or_else(|| BynarError::new(format!("JIRA ticket {} has no status", id)))
+    }
+
+    fn comment(&self, id: &str, comment: &str) -> BynarResult<()> {
+        debug!("Commenting on JIRA ticket {}", id);
+        self.client
+            .post(&self.url(&format!("/issue/{}/comment", id)))
+            .basic_auth(&self.config.user, Some(&self.config.password))
+            .json(&json!({ "body": comment }))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Resolve the ticket with the first transition into the done category.
+    /// Workflows name their transitions differently
+    fn close(&self, id: &str) -> BynarResult<()> {
+        let url = self.url(&format!("/issue/{}/transitions", id));
+        let transitions: Value = self
+            .client
+            .get(&url)
+            .basic_auth(&self.config.user, Some(&self.config.password))
+            .send()?
+            .error_for_status()?
+            .json()?;
+        let transition = transitions["transitions"]
+            .as_array()
+            .and_then(|t| {
+                t.iter()
+                    .find(|t| t["to"]["statusCategory"]["key"].as_str() == Some("done"))
+            })
+            .ok_or_else(|| BynarError::new(format!("No transition resolves JIRA ticket {}", id)))?;
+        debug!(
+            "Closing JIRA ticket {} with transition {}",
+            id, transition["name"]
+        );
+        self.client
+            .post(&url)
+            .basic_auth(&self.config.user, Some(&self.config.password))
+            .json(&json!({"transition": {"id": transition["id"]}}))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+}
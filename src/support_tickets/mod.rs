@@ -0,0 +1,89 @@
+//! Ticketing systems Bynar files repair tickets with.  Which one is used
+//! is chosen with ticket_system in bynar.json.
+pub mod jira;
+pub mod phabricator;
+pub mod template;
+
+use self::jira::{JiraConfig, JiraTickets};
+use self::phabricator::PhabricatorTickets;
+use self::template::TicketVariables;
+use crate::ConfigSettings;
+use helpers::error::*;
+use serde_derive::*;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TicketStatus {
+    Open,
+    /// Whoever the ticket was for says the work is done
+    Resolved,
+}
+
+pub trait TicketSystem {
+    /// Open a new ticket and return its id.  vars fill in any templated
+    /// fields the ticketing system is configured with
+    fn create(&self, title: &str, description: &str, vars: &TicketVariables)
+        -> BynarResult<String>;
+
+    /// Check if the ticket is still open or has been resolved
+    fn status(&self, id: &str) -> BynarResult<TicketStatus>;
+
+    /// The workflow status of the ticket as the ticketing system names it
+    fn status_name(&self, id: &str) -> BynarResult<String>;
+
+    /// Add a comment to the ticket
+    fn comment(&self, id: &str, comment: &str) -> BynarResult<()>;
+
+    /// Resolve the ticket
+    fn close(&self, id: &str) -> BynarResult<()>;
+}
+
+/// The supported ticketing systems
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TicketSystemType {
+    #[default]
+    Jira,
+    Phabricator,
+}
+
+/// How failed disks that are found together share tickets
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TicketGrouping {
+    /// One ticket per host
+    #[default]
+    Host,
+    /// One ticket per drive enclosure or controller
+    Enclosure,
+    /// One ticket per disk
+    Disk,
+}
+
+// A client that goes through the proxy if one is configured
+fn http_client(proxy: Option<&str>) -> BynarResult<reqwest::Client> {
+    let client = match proxy {
+        Some(url) => reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all(url)?)
+            .build()?,
+        None => reqwest::Client::new(),
+    };
+    Ok(client)
+}
+
+/// The ticketing system bynar.json asks for
+pub fn load_ticket_system(settings: &ConfigSettings) -> BynarResult<Box<dyn TicketSystem>> {
+    let proxy = settings.proxy.as_deref();
+    let tickets: Box<dyn TicketSystem> = match settings.ticket_system {
+        TicketSystemType::Jira => {
+            let config = JiraConfig::from_settings(settings)?;
+            Box::new(JiraTickets::new(config, proxy)?)
+        }
+        TicketSystemType::Phabricator => {
+            let config = settings.phabricator.clone().ok_or_else(|| {
+                BynarError::from("ticket_system is phabricator but phabricator isn't configured")
+            })?;
+            Box::new(PhabricatorTickets::new(config, proxy)?)
+        }
+    };
+    Ok(tickets)
+}
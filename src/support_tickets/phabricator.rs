@@ -0,0 +1,281 @@
+//! Phabricator Maniphest tasks through the Conduit API.  Ticket ids are
+//! the task's monogram.  Ex: T123
+use super::template::TicketVariables;
+use super::{http_client, TicketStatus, TicketSystem};
+use helpers::error::*;
+use log::debug;
+use serde_derive::*;
+use serde_json::{json, Value};
+use std::str::FromStr;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_stub::HttpStub;
+
+    fn stub_phabricator() -> HttpStub {
+        HttpStub::start(|req| {
+            let reply = |result: Value| {
+                json!({"result": result, "error_code": null, "error_info": null}).to_string()
+            };
+            if req.field("api.token") != Some("api-token".to_string()) {
+                return (
+                    200,
+                    json!({"result": null, "error_code": "ERR-INVALID-AUTH",
+                           "error_info": "API token is not valid"})
+                    .to_string(),
+                );
+            }
+            match req.path.as_str() {
+                "/api/maniphest.edit" => (
+                    200,
+                    reply(json!({"object": {"id": 42, "phid": "PHID-TASK-1"}, "transactions": []})),
+                ),
+                "/api/maniphest.search" => {
+                    let status = match req.field("constraints[ids][0]").as_deref() {
+                        Some("43") => "resolved",
+                        _ => "open",
+                    };
+                    (
+                        200,
+                        reply(
+                            json!({"data": [{"id": 42, "fields": {"status": {"value": status}}}]}),
+                        ),
+                    )
+                }
+                _ => (404, "{}".to_string()),
+            }
+        })
+    }
+
+    fn phabricator_config(host: &str, api_token: &str) -> PhabricatorConfig {
+        PhabricatorConfig {
+            host: host.to_string(),
+            api_token: api_token.to_string(),
+            projects: vec!["PHID-PROJ-storage".to_string()],
+            owner: None,
+            priority: Some("high".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_phabricator_tickets() {
+        let stub = stub_phabricator();
+        let phab =
+            PhabricatorTickets::new(phabricator_config(&stub.url, "api-token"), None).unwrap();
+
+        assert_eq!(
+            phab.create("Bynar: Dead disk", "sdb died", &TicketVariables::new())
+                .unwrap(),
+            "T42"
+        );
+        assert_eq!(phab.status("T42").unwrap(), TicketStatus::Open);
+        assert_eq!(phab.status("T43").unwrap(), TicketStatus::Resolved);
+        phab.comment("T42", "Disk removed").unwrap();
+        phab.close("T42").unwrap();
+
+        let requests = stub.requests();
+        let created = &requests[0];
+        assert_eq!(created.field("objectIdentifier"), None);
+        assert_eq!(
+            created.field("transactions[0][type]"),
+            Some("title".to_string())
+        );
+        assert_eq!(
+            created.field("transactions[0][value]"),
+            Some("Bynar: Dead disk".to_string())
+        );
+        assert_eq!(
+            created.field("transactions[2][type]"),
+            Some("projects.add".to_string())
+        );
+        assert_eq!(
+            created.field("transactions[2][value][0]"),
+            Some("PHID-PROJ-storage".to_string())
+        );
+        assert_eq!(
+            created.field("transactions[3][value]"),
+            Some("high".to_string())
+        );
+        let comment = &requests[3];
+        assert_eq!(comment.field("objectIdentifier"), Some("T42".to_string()));
+        assert_eq!(
+            comment.field("transactions[0][value]"),
+            Some("Disk removed".to_string())
+        );
+        let close = &requests[4];
+        assert_eq!(
+            close.field("transactions[0][type]"),
+            Some("status".to_string())
+        );
+        assert_eq!(
+            close.field("transactions[0][value]"),
+            Some("resolved".to_string())
+        );
+        assert_eq!(phab.status_name("T43").unwrap(), "resolved");
+    }
+
+    #[test]
+    fn test_conduit_error() {
+        let stub = stub_phabricator();
+        let phab = PhabricatorTickets::new(phabricator_config(&stub.url, "wrong"), None).unwrap();
+        let err = phab
+            .create("Bynar: Dead disk", "sdb died", &TicketVariables::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("ERR-INVALID-AUTH"));
+        assert!(phab.status("42").is_err());
+    }
+}
+
+/// The phabricator section of bynar.json
+#[derive(Clone, Debug, Deserialize)]
+pub struct PhabricatorConfig {
+    /// Ex: https://phabricator.example.com
+    pub host: String,
+    /// Conduit API token.  Ex: api-xxxxxxxxxxxxxxxxxxxxxxxxxxxx
+    pub api_token: String,
+    /// PHIDs of the projects to tag new tasks with
+    #[serde(default)]
+    pub projects: Vec<String>,
+    /// PHID of the user to assign new tasks to
+    pub owner: Option<String>,
+    /// Priority keyword for new tasks.  Ex: high, normal, low
+    pub priority: Option<String>,
+}
+
+pub struct PhabricatorTickets {
+    config: PhabricatorConfig,
+    client: reqwest::Client,
+}
+
+// Flatten maniphest.edit transactions into Conduit form fields.  Lists
+// become transactions[i][value][j]
+fn transaction_params(transactions: &[(&str, Value)]) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    for (i, (kind, value)) in transactions.iter().enumerate() {
+        params.push((format!("transactions[{}][type]", i), kind.to_string()));
+        match *value {
+            Value::Array(ref values) => {
+                for (j, v) in values.iter().enumerate() {
+                    params.push((format!("transactions[{}][value][{}]", i, j), param(v)));
+                }
+            }
+            ref v => params.push((format!("transactions[{}][value]", i), param(v))),
+        }
+    }
+    params
+}
+
+fn param(value: &Value) -> String {
+    match *value {
+        Value::String(ref s) => s.clone(),
+        ref v => v.to_string(),
+    }
+}
+
+// T123 -> 123
+fn task_number(id: &str) -> BynarResult<u64> {
+    Ok(u64::from_str(id.trim_start_matches('T'))?)
+}
+
+impl PhabricatorTickets {
+    pub fn new(config: PhabricatorConfig, proxy: Option<&str>) -> BynarResult<PhabricatorTickets> {
+        Ok(PhabricatorTickets {
+            config,
+            client: http_client(proxy)?,
+        })
+    }
+
+    // Call a Conduit method and return its result
+    fn conduit(&self, method: &str, params: Vec<(String, String)>) -> BynarResult<Value> {
+        let url = format!("{}/api/{}", self.config.host.trim_end_matches('/'), method);
+        debug!("Calling {}", url);
+        let mut form = vec![("api.token".to_string(), self.config.api_token.clone())];
+        form.extend(params);
+        let response: Value = self
+            .client
+            .post(&url)
+            .form(&form)
+            .send()?
+            .error_for_status()?
+            .json()?;
+        if !response["error_code"].is_null() {
+            return Err(BynarError::new(format!(
+                "{} failed: {} {}",
+                method,
+                param(&response["error_code"]),
+                param(&response["error_info"])
+            )));
+        }
+        Ok(response["result"].clone())
+    }
+
+    // Apply transactions to a task, or create one if no task is given
+    fn edit(&self, task: Option<&str>, transactions: &[(&str, Value)]) -> BynarResult<Value> {
+        let mut params = transaction_params(transactions);
+        if let Some(task) = task {
+            params.push(("objectIdentifier".to_string(), task.to_string()));
+        }
+        self.conduit("maniphest.edit", params)
+    }
+}
+
+impl TicketSystem for PhabricatorTickets {
+    fn create(
+        &self,
+        title: &str,
+        description: &str,
+        _vars: &TicketVariables,
+    ) -> BynarResult<String> {
+        let mut transactions = vec![("title", json!(title)), ("description", json!(description))];
+        if !self.config.projects.is_empty() {
+            transactions.push(("projects.add", json!(self.config.projects)));
+        }
+        if let Some(ref priority) = self.config.priority {
+            transactions.push(("priority", json!(priority)));
+        }
+        if let Some(ref owner) = self.config.owner {
+            transactions.push(("owner", json!(owner)));
+        }
+        debug!("Creating Maniphest task: {}", title);
+        let result = self.edit(None, &transactions)?;
+        let id = result["object"]["id"]
+            .as_u64()
+            .ok_or_else(|| BynarError::from("maniphest.edit didn't return a task id"))?;
+        Ok(format!("T{}", id))
+    }
+
+    fn status(&self, id: &str) -> BynarResult<TicketStatus> {
+        let status = self.status_name(id)?;
+        debug!("Maniphest task {} is {}", id, status);
+        // Other closed statuses like wontfix or invalid don't mean the
+        // repair happened
+        if status == "resolved" {
+            Ok(TicketStatus::Resolved)
+        } else {
+            Ok(TicketStatus::Open)
+        }
+    }
+
+    fn status_name(&self, id: &str) -> BynarResult<String> {
+        let params = vec![(
+            "constraints[ids][0]".to_string(),
+            task_number(id)?.to_string(),
+        )];
+        let result = self.conduit("maniphest.search", params)?;
+        let status = result["data"][0]["fields"]["status"]["value"]
+            .as_str()
+            .ok_or_else(|| BynarError::new(format!("Maniphest task {} not found", id)))?;
+        Ok(status.to_string())
+    }
+
+    fn comment(&self, id: &str, comment: &str) -> BynarResult<()> {
+        self.edit(Some(id), &[("comment", json!(comment))])?;
+        Ok(())
+    }
+
+    fn close(&self, id: &str) -> BynarResult<()> {
+        self.edit(Some(id), &[("status", json!("resolved"))])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+//! Ticket summaries and descriptions built from templates.  Templates
+//! name host and disk variables in braces.  Ex: "Dead disk {disk_path} on
+//! {hostname}".  Unknown variables are left as written.
+use helpers::host_information::Host;
+use serde_derive::*;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render() {
+        let mut vars = TicketVariables::new();
+        vars.insert("hostname".to_string(), "ceph-01".to_string());
+        vars.insert("disk_path".to_string(), "/dev/sdb".to_string());
+        assert_eq!(
+            render("Dead disk {disk_path} on {hostname}", &vars),
+            "Dead disk /dev/sdb on ceph-01"
+        );
+        // Unknown and unterminated variables pass through
+        assert_eq!(
+            render("{hostname}: {asset_tag} {disk_path", &vars),
+            "ceph-01: {asset_tag} {disk_path"
+        );
+        assert_eq!(render("{}", &vars), "{}");
+
+        let field = json!({"value": "{hostname}", "ids": ["{disk_path}", 4]});
+        assert_eq!(
+            render_value(&field, &vars),
+            json!({"value": "ceph-01", "ids": ["/dev/sdb", 4]})
+        );
+    }
+
+    #[test]
+    fn test_disk_group_variables() {
+        let disks: Vec<TicketVariables> = ["/dev/sdb", "/dev/sdc"]
+            .iter()
+            .map(|path| {
+                let mut vars = TicketVariables::new();
+                vars.insert("hostname".to_string(), "ceph-01".to_string());
+                vars.insert("disk_path".to_string(), path.to_string());
+                vars
+            })
+            .collect();
+        let vars = disk_group_variables(&disks, "- {disk_path}");
+        assert_eq!(vars["disk_count"], "2");
+        assert_eq!(vars["disks"], "- /dev/sdb\n\n- /dev/sdc");
+        assert_eq!(vars["hostname"], "ceph-01");
+        assert_eq!(vars["disk_path"], "/dev/sdb");
+        assert_eq!(
+            render("{disk_count} disk(s) on {hostname}", &vars),
+            "2 disk(s) on ceph-01"
+        );
+    }
+
+    #[test]
+    fn test_default_templates() {
+        let templates: TicketTemplates =
+            serde_json::from_str(r#"{"disk_summary": "[{region}] Dead disk"}"#).unwrap();
+        assert_eq!(templates.disk_summary, "[{region}] Dead disk");
+        assert_eq!(templates.hardware_summary, "Bynar: Hardware Failure");
+        assert!(templates.disk_description.contains("{disks}"));
+        assert!(templates.disk_item.contains("{scsi_address}"));
+    }
+}
+
+/// Values templates can refer to by name
+pub type TicketVariables = BTreeMap<String, String>;
+
+/// The ticket_templates section of bynar.json.  disk_item is rendered for
+/// every disk on a ticket with the host variables plus disk_path,
+/// disk_serial, scsi_address, vendor, slot, enclosure and state_history.
+/// The disk tickets themselves get the items joined as disks, the
+/// disk_count and the first disk's variables.  Hardware tickets can use
+/// the host variables plus hardware_name, hardware_serial and error.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct TicketTemplates {
+    pub disk_summary: String,
+    pub disk_description: String,
+    pub disk_item: String,
+    pub hardware_summary: String,
+    pub hardware_description: String,
+}
+
+impl Default for TicketTemplates {
+    fn default() -> Self {
+        TicketTemplates {
+            disk_summary: "Bynar: Dead disk".to_string(),
+            disk_description: "{disk_count} disk(s) on {hostname} failed. Please replace.\n\
+                               Hostname: {hostname}\n\
+                               Server type: {server_type}\n\
+                               Server Serial: {server_serial}\n\
+                               Machine Architecture: {machine_architecture}\n\
+                               Kernel: {kernel}\n\
+                               \n\
+                               {disks}"
+                .to_string(),
+            disk_item: "Disk path: {disk_path}\n\
+                        Disk serial: {disk_serial}\n\
+                        SCSI address: {scsi_address}\n\
+                        Disk vendor: {vendor}\n\
+                        Slot: {slot}\n\
+                        State history: {state_history}"
+                .to_string(),
+            hardware_summary: "Bynar: Hardware Failure".to_string(),
+            hardware_description: "{error}\n\
+                                   Hostname: {hostname}\n\
+                                   Server type: {server_type}\n\
+                                   Server Serial: {server_serial}"
+                .to_string(),
+        }
+    }
+}
+
+/// The variables every ticket can use
+pub fn host_variables(host_info: &Host) -> TicketVariables {
+    let mut vars = TicketVariables::new();
+    vars.insert("hostname".to_string(), host_info.hostname.clone());
+    vars.insert("ip".to_string(), host_info.ip.to_string());
+    vars.insert("region".to_string(), host_info.region.clone());
+    vars.insert("server_type".to_string(), host_info.server_type.clone());
+    vars.insert("server_serial".to_string(), host_info.serial_number.clone());
+    vars.insert(
+        "machine_architecture".to_string(),
+        host_info.machine_architecture.clone(),
+    );
+    vars.insert("kernel".to_string(), host_info.kernel.clone());
+    vars
+}
+
+/// Variables for a ticket covering several disks.  Each disk's variables
+/// already include the host's.  The first disk's are kept so single disk
+/// templates still work
+pub fn disk_group_variables(disks: &[TicketVariables], item_template: &str) -> TicketVariables {
+    let mut vars = disks.first().cloned().unwrap_or_default();
+    let items: Vec<String> = disks.iter().map(|d| render(item_template, d)).collect();
+    vars.insert("disks".to_string(), items.join("\n\n"));
+    vars.insert("disk_count".to_string(), disks.len().to_string());
+    vars
+}
+
+/// Replace every {variable} in the template
+pub fn render(template: &str, vars: &TicketVariables) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('}') {
+            Some(end) => {
+                match vars.get(&after[..end]) {
+                    Some(value) => out.push_str(value),
+                    None => out.push_str(&rest[start..start + end + 2]),
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Render every string inside a JSON value.  Custom fields can be plain
+/// strings or objects like {"value": "{region}"} for select lists
+pub fn render_value(value: &Value, vars: &TicketVariables) -> Value {
+    match *value {
+        Value::String(ref s) => Value::String(render(s, vars)),
+        Value::Array(ref values) => {
+            Value::Array(values.iter().map(|v| render_value(v, vars)).collect())
+        }
+        Value::Object(ref map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), render_value(v, vars)))
+                .collect(),
+        ),
+        ref v => v.clone(),
+    }
+}
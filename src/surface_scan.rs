@@ -0,0 +1,520 @@
+//! Read only surface scan of a block device.  This is used to confirm
+//! media damage on suspect disks without touching the data on them.
+//! Reads are done with O_DIRECT so the page cache can't hide bad sectors
+//! and are rate limited so a scan doesn't starve the cluster of I/O.
+//! A full scan can take hours so bynar starts it as a separate process and
+//! picks up the verdict on a later run.
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use std::env;
+use std::fs::{create_dir_all, read_to_string, remove_file, rename, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::slice;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use helpers::error::*;
+use log::{debug, error, warn};
+use serde_derive::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> SurfaceScanConfig {
+        SurfaceScanConfig {
+            max_bandwidth_mb: 50,
+            read_size_kb: 1024,
+            sample_regions: None,
+            sample_region_mb: 16,
+            latency_threshold_ms: 500,
+            max_unreadable_sectors: 0,
+            max_slow_reads: 2,
+            allowed_mounted_devices: vec![],
+            results_dir: PathBuf::from("/tmp"),
+            rescan_hours: 24,
+        }
+    }
+
+    #[test]
+    fn test_full_scan_ranges() {
+        let c = config();
+        // 2.5MB device in 1MB reads
+        let ranges = scan_ranges(2_621_440, 4096, &c);
+        assert_eq!(
+            ranges,
+            vec![(0, 1_048_576), (1_048_576, 1_048_576), (2_097_152, 524_288)]
+        );
+    }
+
+    #[test]
+    fn test_sampled_scan_ranges() {
+        let mut c = config();
+        c.sample_regions = Some(4);
+        c.sample_region_mb = 1;
+        // 100MB device, 4 regions of 1MB spread evenly
+        let ranges = scan_ranges(104_857_600, 4096, &c);
+        let offsets: Vec<u64> = ranges.iter().map(|r| r.0).collect();
+        assert_eq!(offsets, vec![0, 26_214_400, 52_428_800, 78_643_200]);
+        assert!(ranges.iter().all(|r| r.1 == 1_048_576));
+    }
+
+    #[test]
+    fn test_verdict() {
+        let c = config();
+        let mut result = ScanResult::default();
+        assert_eq!(result.verdict(&c), ScanVerdict::Clean);
+        result.slow_reads.push((0, Duration::from_millis(900)));
+        result.slow_reads.push((4096, Duration::from_millis(900)));
+        assert_eq!(result.verdict(&c), ScanVerdict::Clean);
+        result.slow_reads.push((8192, Duration::from_millis(900)));
+        assert_eq!(result.verdict(&c), ScanVerdict::MediaDamaged);
+        let mut result = ScanResult::default();
+        result.unreadable_sectors.push(1024);
+        assert_eq!(result.verdict(&c), ScanVerdict::MediaDamaged);
+    }
+
+    #[test]
+    fn test_scan_status() {
+        let dir = tempdir::TempDir::new("surface_scan").unwrap();
+        let mut c = config();
+        c.results_dir = dir.path().join("results");
+        let dev = Path::new("/dev/sdz");
+        assert!(scan_status(dev, &c).unwrap().is_none());
+
+        // Our own pid is alive so the scan looks like it's still going
+        save_status(dev, &c, &ScanStatus::Running { pid: process::id() }).unwrap();
+        assert_eq!(
+            scan_status(dev, &c).unwrap(),
+            Some(ScanStatus::Running { pid: process::id() })
+        );
+
+        let mut result = ScanResult::default();
+        result.unreadable_sectors.push(4096);
+        save_status(dev, &c, &ScanStatus::finished(&result, &c)).unwrap();
+        match scan_status(dev, &c).unwrap() {
+            Some(ScanStatus::Finished { damaged, .. }) => assert!(damaged),
+            other => panic!("Unexpected scan status {:?}", other),
+        };
+
+        // Old verdicts are thrown away so the disk gets scanned again
+        save_status(
+            dev,
+            &c,
+            &ScanStatus::Finished {
+                damaged: false,
+                unreadable_sectors: 0,
+                slow_reads: 0,
+                finished: 0,
+            },
+        )
+        .unwrap();
+        assert!(scan_status(dev, &c).unwrap().is_none());
+    }
+}
+
+fn default_bandwidth() -> u64 {
+    50
+}
+
+fn default_read_size() -> u64 {
+    1024
+}
+
+fn default_region_size() -> u64 {
+    16
+}
+
+fn default_latency_threshold() -> u64 {
+    500
+}
+
+fn default_max_slow_reads() -> u64 {
+    10
+}
+
+fn default_results_dir() -> PathBuf {
+    PathBuf::from("/var/lib/bynar/surface_scan")
+}
+
+fn default_rescan_hours() -> u64 {
+    168
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SurfaceScanConfig {
+    /// Maximum read bandwidth the scan is allowed to use in MB/s
+    #[serde(default = "default_bandwidth")]
+    pub max_bandwidth_mb: u64,
+    /// Size of each read in KB
+    #[serde(default = "default_read_size")]
+    pub read_size_kb: u64,
+    /// If set only this many evenly spaced regions of the device are read
+    /// instead of the whole device
+    pub sample_regions: Option<u64>,
+    /// Size of each sampled region in MB
+    #[serde(default = "default_region_size")]
+    pub sample_region_mb: u64,
+    /// Reads slower than this are recorded as latency outliers
+    #[serde(default = "default_latency_threshold")]
+    pub latency_threshold_ms: u64,
+    /// More unreadable sectors than this means the media is damaged
+    #[serde(default)]
+    pub max_unreadable_sectors: u64,
+    /// More latency outliers than this means the media is damaged
+    #[serde(default = "default_max_slow_reads")]
+    pub max_slow_reads: u64,
+    /// Mounted devices are skipped unless they're listed here
+    #[serde(default)]
+    pub allowed_mounted_devices: Vec<PathBuf>,
+    /// Where background scans record their progress and verdict
+    #[serde(default = "default_results_dir")]
+    pub results_dir: PathBuf,
+    /// A verdict older than this is thrown away and the disk scanned again
+    #[serde(default = "default_rescan_hours")]
+    pub rescan_hours: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct ScanResult {
+    pub bytes_read: u64,
+    /// Byte offsets of sectors that could not be read
+    pub unreadable_sectors: Vec<u64>,
+    /// Byte offset and latency of reads over the latency threshold
+    pub slow_reads: Vec<(u64, Duration)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ScanVerdict {
+    Clean,
+    MediaDamaged,
+}
+
+impl ScanResult {
+    pub fn verdict(&self, config: &SurfaceScanConfig) -> ScanVerdict {
+        if self.unreadable_sectors.len() as u64 > config.max_unreadable_sectors
+            || self.slow_reads.len() as u64 > config.max_slow_reads
+        {
+            return ScanVerdict::MediaDamaged;
+        }
+        ScanVerdict::Clean
+    }
+}
+
+/// Where a background scan of a device is at
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub enum ScanStatus {
+    Running {
+        pid: u32,
+    },
+    Finished {
+        damaged: bool,
+        unreadable_sectors: usize,
+        slow_reads: usize,
+        /// Seconds since the epoch
+        finished: u64,
+    },
+}
+
+impl ScanStatus {
+    fn finished(result: &ScanResult, config: &SurfaceScanConfig) -> ScanStatus {
+        ScanStatus::Finished {
+            damaged: result.verdict(config) == ScanVerdict::MediaDamaged,
+            unreadable_sectors: result.unreadable_sectors.len(),
+            slow_reads: result.slow_reads.len(),
+            finished: now_secs(),
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn status_path(dev_path: &Path, config: &SurfaceScanConfig) -> BynarResult<PathBuf> {
+    match dev_path.file_name() {
+        Some(name) => Ok(config
+            .results_dir
+            .join(format!("{}.json", name.to_string_lossy()))),
+        None => Err(BynarError::new(format!(
+            "{} isn't a device",
+            dev_path.display()
+        ))),
+    }
+}
+
+// Written to a temp file and renamed so a reader never sees half of it
+fn save_status(
+    dev_path: &Path,
+    config: &SurfaceScanConfig,
+    status: &ScanStatus,
+) -> BynarResult<()> {
+    create_dir_all(&config.results_dir)?;
+    let path = status_path(dev_path, config)?;
+    let tmp = path.with_extension("json.tmp");
+    let mut f = File::create(&tmp)?;
+    f.write_all(serde_json::to_string(status)?.as_bytes())?;
+    f.sync_all()?;
+    rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// The state of the last background scan of the device.  None if it was
+/// never scanned, the scan died or its verdict is too old to trust
+pub fn scan_status(dev_path: &Path, config: &SurfaceScanConfig) -> BynarResult<Option<ScanStatus>> {
+    let path = status_path(dev_path, config)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let status: ScanStatus = serde_json::from_str(&read_to_string(&path)?)?;
+    let current = match status {
+        ScanStatus::Running { pid } => Path::new("/proc").join(pid.to_string()).exists(),
+        ScanStatus::Finished { finished, .. } => {
+            now_secs().saturating_sub(finished) < config.rescan_hours * 3600
+        }
+    };
+    if !current {
+        debug!(
+            "Discarding stale surface scan status for {}: {:?}",
+            dev_path.display(),
+            status
+        );
+        remove_file(&path)?;
+        return Ok(None);
+    }
+    Ok(Some(status))
+}
+
+/// Start scanning the device in a separate bynar process that outlives
+/// this run.  Its verdict is read back with scan_status
+pub fn start_background_scan(dev_path: &Path, config: &SurfaceScanConfig) -> BynarResult<()> {
+    let child = Command::new(env::current_exe()?)
+        .arg("--surface-scan")
+        .arg(dev_path)
+        .arg("--surface-scan-config")
+        .arg(serde_json::to_string(config)?)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    debug!(
+        "Started surface scan of {} in pid {}",
+        dev_path.display(),
+        child.id()
+    );
+    save_status(dev_path, config, &ScanStatus::Running { pid: child.id() })
+}
+
+/// The body of the background process started by start_background_scan
+pub fn run_background_scan(dev_path: &Path, config: &SurfaceScanConfig) -> BynarResult<()> {
+    match scan_device(dev_path, config) {
+        Ok(result) => save_status(dev_path, config, &ScanStatus::finished(&result, config)),
+        Err(e) => {
+            // Let the next run try again
+            let _ = remove_file(status_path(dev_path, config)?);
+            Err(e)
+        }
+    }
+}
+
+// O_DIRECT requires the buffer to be aligned to the logical block size
+struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    fn new(size: usize, align: usize) -> BynarResult<AlignedBuffer> {
+        let layout = Layout::from_size_align(size, align)
+            .map_err(|e| BynarError::new(format!("Invalid buffer layout: {}", e)))?;
+        if layout.size() == 0 {
+            return Err(BynarError::from("Scan buffer can't be empty"));
+        }
+        // Zeroed so the slices handed out are always initialized memory
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            handle_alloc_error(layout);
+        }
+        Ok(AlignedBuffer { ptr, layout })
+    }
+
+    fn as_mut_slice(&mut self, len: usize) -> &mut [u8] {
+        let len = len.min(self.layout.size());
+        unsafe { slice::from_raw_parts_mut(self.ptr, len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+}
+
+// Returns the (offset, length) pairs to read.  Either the whole device or
+// evenly spaced sample regions.  Everything is aligned to the sector size
+fn scan_ranges(capacity: u64, sector_size: u64, config: &SurfaceScanConfig) -> Vec<(u64, u64)> {
+    let read_size = (config.read_size_kb * 1024 / sector_size).max(1) * sector_size;
+    let mut regions: Vec<(u64, u64)> = Vec::new();
+    match config.sample_regions {
+        Some(count) if count > 0 => {
+            let region_size = config.sample_region_mb * 1_048_576;
+            let stride = capacity / count;
+            for i in 0..count {
+                let start = (stride * i) / sector_size * sector_size;
+                let end = (start + region_size).min(capacity);
+                regions.push((start, end));
+            }
+        }
+        _ => regions.push((0, capacity)),
+    };
+
+    let mut ranges = Vec::new();
+    for (start, end) in regions {
+        let mut offset = start;
+        while offset < end {
+            let len = read_size.min(end - offset);
+            ranges.push((offset, len));
+            offset += len;
+        }
+    }
+    ranges
+}
+
+fn logical_block_size(dev_path: &Path) -> u64 {
+    let sys_path = match dev_path.file_name() {
+        Some(name) => Path::new("/sys/class/block")
+            .join(name)
+            .join("queue/logical_block_size"),
+        None => return 512,
+    };
+    match read_to_string(&sys_path) {
+        Ok(s) => s.trim().parse::<u64>().unwrap_or(512),
+        // Partitions don't have a queue directory
+        Err(_) => 512,
+    }
+}
+
+// Sleep long enough to keep the average read rate under the bandwidth limit
+fn throttle(start: &Instant, bytes_read: u64, max_bandwidth_mb: u64) {
+    if max_bandwidth_mb == 0 {
+        return;
+    }
+    let expected = Duration::from_millis(bytes_read * 1000 / (max_bandwidth_mb * 1_048_576));
+    let elapsed = start.elapsed();
+    if expected > elapsed {
+        thread::sleep(expected - elapsed);
+    }
+}
+
+// A chunk failed to read.  Go back over it one sector at a time to find
+// out exactly which sectors are bad.
+fn find_unreadable_sectors(
+    f: &File,
+    buffer: &mut AlignedBuffer,
+    offset: u64,
+    len: u64,
+    sector_size: u64,
+) -> Vec<u64> {
+    let mut bad = Vec::new();
+    let mut sector = offset;
+    while sector < offset + len {
+        match f.read_at(buffer.as_mut_slice(sector_size as usize), sector) {
+            Ok(n) if n as u64 == sector_size => {}
+            Ok(n) => {
+                debug!("Sector at offset {} short read: {} bytes", sector, n);
+                bad.push(sector);
+            }
+            Err(e) => {
+                debug!("Sector at offset {} unreadable: {}", sector, e);
+                bad.push(sector);
+            }
+        }
+        sector += sector_size;
+    }
+    bad
+}
+
+/// Read the device (or samples of it) and record any sectors that can't be
+/// read along with any reads that were unusually slow.  This never writes
+/// to the device.
+pub fn scan_device(dev_path: &Path, config: &SurfaceScanConfig) -> BynarResult<ScanResult> {
+    let sector_size = logical_block_size(dev_path);
+    let mut f = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(dev_path)?;
+    let capacity = f.seek(SeekFrom::End(0))?;
+    let ranges = scan_ranges(capacity, sector_size, config);
+    let max_read = ranges.iter().map(|r| r.1).max().unwrap_or(sector_size);
+    let mut buffer = AlignedBuffer::new(max_read as usize, sector_size.max(4096) as usize)?;
+    let threshold = Duration::from_millis(config.latency_threshold_ms);
+    debug!(
+        "thread {} Surface scanning {} with {} reads.  Capacity: {} sector size: {}",
+        process::id(),
+        dev_path.display(),
+        ranges.len(),
+        capacity,
+        sector_size
+    );
+
+    let mut result = ScanResult::default();
+    let start = Instant::now();
+    for (offset, len) in ranges {
+        let read_start = Instant::now();
+        match f.read_at(buffer.as_mut_slice(len as usize), offset) {
+            Ok(n) if n as u64 == len => {
+                let latency = read_start.elapsed();
+                if latency > threshold {
+                    warn!(
+                        "{} slow read at offset {}: {:?}",
+                        dev_path.display(),
+                        offset,
+                        latency
+                    );
+                    result.slow_reads.push((offset, latency));
+                }
+            }
+            // A short read stopped at something it couldn't read
+            Ok(n) => {
+                error!(
+                    "{} short read at offset {} length {}: {} bytes",
+                    dev_path.display(),
+                    offset,
+                    len,
+                    n
+                );
+                let bad = find_unreadable_sectors(&f, &mut buffer, offset, len, sector_size);
+                result.unreadable_sectors.extend(bad);
+            }
+            Err(e) => {
+                error!(
+                    "{} read failed at offset {} length {}: {}",
+                    dev_path.display(),
+                    offset,
+                    len,
+                    e
+                );
+                let bad = find_unreadable_sectors(&f, &mut buffer, offset, len, sector_size);
+                result.unreadable_sectors.extend(bad);
+            }
+        };
+        result.bytes_read += len;
+        throttle(&start, result.bytes_read, config.max_bandwidth_mb);
+    }
+    debug!(
+        "thread {} Surface scan of {} finished. {} bytes read, {} unreadable sectors, {} slow reads",
+        process::id(),
+        dev_path.display(),
+        result.bytes_read,
+        result.unreadable_sectors.len(),
+        result.slow_reads.len()
+    );
+
+    Ok(result)
+}
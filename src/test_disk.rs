@@ -11,15 +11,19 @@
 use mocktopus::*;
 
 use crate::in_progress::{
-    add_disk_detail, add_or_update_operation, get_devices_from_db, get_state,
-    is_hardware_waiting_repair, save_state, HostDetailsMapping, OperationInfo,
+    add_disk_detail, add_or_update_operation, get_connection_from_pool, get_devices_from_db,
+    get_state, is_hardware_waiting_repair, save_raid_location, save_state, HostDetailsMapping,
+    OperationInfo,
 };
+use crate::raid::{hp, lsi, RaidLocation};
+use crate::surface_scan::{scan_status, start_background_scan, ScanStatus, SurfaceScanConfig};
 use blkid::BlkId;
 use block_utils::{
     format_block_device, get_device_info, mount_device, unmount_device, Device, DeviceState,
     Filesystem, FilesystemType, MediaType, ScsiDeviceType, ScsiInfo, Vendor,
 };
 use gpt::{disk, header::read_header, partition::read_partitions, partition::Partition};
+use helpers::approval::{self, Action, ApprovalConfig, ApprovalStatus, PendingAction};
 use helpers::{error::*, host_information::Host};
 use log::{debug, error, trace, warn};
 use lvm::*;
@@ -32,10 +36,11 @@ use r2d2_postgres::PostgresConnectionManager as ConnectionManager;
 use std::collections::{BTreeMap, HashSet};
 use std::ffi::OsStr;
 use std::fmt;
-use std::fs::OpenOptions;
+use std::fs::{read_to_string, OpenOptions};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::{self, Command};
+use std::slice;
 use std::str::FromStr;
 use tempdir::TempDir;
 use uuid::Uuid;
@@ -56,6 +61,15 @@ pub struct BlockDevice {
     pub state: State,
     pub storage_detail_id: u32,
     pub operation_id: Option<u32>,
+    // Set when a surface scan is allowed to run against this device
+    pub surface_scan: Option<SurfaceScanConfig>,
+    // Controller location of a failed drive behind a raid controller
+    pub raid_location: Option<RaidLocation>,
+    // Set when reformatting this device needs approval.  Pending until
+    // someone approves or denies it
+    pub reformat_approval: Option<ApprovalStatus>,
+    // The outstanding reformat request if one was already made
+    pub reformat_action_id: Option<u32>,
 }
 
 impl BlockDevice {
@@ -174,6 +188,10 @@ mod tests {
             state: super::State::Unscanned,
             storage_detail_id: 1,
             operation_id: None,
+            surface_scan: None,
+            raid_location: None,
+            reformat_approval: None,
+            reformat_action_id: None,
         };
         let mut s = super::StateMachine::new(d, None, true);
         s.setup_state_machine();
@@ -229,6 +247,10 @@ mod tests {
             state: super::State::Unscanned,
             storage_detail_id: 1,
             operation_id: None,
+            surface_scan: None,
+            raid_location: None,
+            reformat_approval: None,
+            reformat_action_id: None,
         };
         let mut s = super::StateMachine::new(d, None, true);
         s.setup_state_machine();
@@ -283,6 +305,10 @@ mod tests {
             state: super::State::Unscanned,
             storage_detail_id: 1,
             operation_id: None,
+            surface_scan: None,
+            raid_location: None,
+            reformat_approval: None,
+            reformat_action_id: None,
         };
         let mut s = super::StateMachine::new(d, None, false);
         s.setup_state_machine();
@@ -295,6 +321,87 @@ mod tests {
         assert_eq!(s.block_device.state, super::State::WaitingForReplacement);
     }
 
+    #[test]
+    fn test_state_machine_reformat_needs_approval() {
+        use helpers::approval::ApprovalStatus;
+        use helpers::error::*;
+        // Smart passes, write fails, check_filesystem fails and attemptRepair fails
+        TermLogger::new(log::LevelFilter::Debug, Config::default()).unwrap();
+
+        super::run_smart_checks.mock_safe(|_| MockResult::Return(Ok(true)));
+        super::check_writable
+            .mock_safe(|_| MockResult::Return(Err(BynarError::from("Mock Error"))));
+        super::check_filesystem.mock_safe(|_, _| MockResult::Return(Ok(super::Fsck::Corrupt)));
+        super::repair_filesystem
+            .mock_safe(|_, _| MockResult::Return(Err(BynarError::from("Mock Error"))));
+        // A reformat that ran anyway would fail and wait for replacement
+        super::format_device.mock_safe(|_| MockResult::Return(Err(BynarError::from("error"))));
+
+        let dev = create_loop_device();
+
+        let blkid = BlkId::new(&dev).unwrap();
+        blkid.do_probe().unwrap();
+        let drive_uuid = blkid.lookup_value("UUID").unwrap();
+        let drive_id = Uuid::parse_str(&drive_uuid).unwrap();
+
+        let d = super::BlockDevice {
+            device: super::Device {
+                id: Some(drive_id),
+                name: dev.file_name().unwrap().to_str().unwrap().to_string(),
+                media_type: super::MediaType::Rotational,
+                capacity: 26214400,
+                fs_type: super::FilesystemType::Xfs,
+                serial_number: Some("123456".into()),
+            },
+            dev_path: PathBuf::from(""),
+            device_database_id: None,
+            mount_point: None,
+            partitions: BTreeMap::new(),
+            scsi_info: super::ScsiInfo::default(),
+            state: super::State::Unscanned,
+            storage_detail_id: 1,
+            operation_id: None,
+            surface_scan: None,
+            raid_location: None,
+            reformat_approval: Some(ApprovalStatus::Pending),
+            reformat_action_id: None,
+        };
+        let mut s = super::StateMachine::new(d, None, false);
+        s.setup_state_machine();
+        s.run();
+        println!("final state: {}", s.block_device.state);
+        assert_eq!(s.block_device.state, super::State::WaitingForApproval);
+
+        // Denying it sends the disk for replacement without a reformat
+        s.block_device.reformat_approval = Some(ApprovalStatus::Denied);
+        s.run();
+        cleanup_loop_device(&dev);
+        assert_eq!(s.block_device.state, super::State::WaitingForReplacement);
+    }
+
+    #[test]
+    fn test_mount_is_read_only() {
+        let mounts = "/dev/sda1 / ext4 rw,relatime,errors=remount-ro 0 0\n\
+                      /dev/sdb /var/lib/ceph/osd/ceph-1 xfs ro,noatime,attr2 0 0\n\
+                      tmpfs /run tmpfs rw,nosuid,noexec 0 0\n";
+        assert!(super::mount_is_read_only(
+            mounts,
+            Path::new("/dev/sdb"),
+            None
+        ));
+        assert!(super::mount_is_read_only(
+            mounts,
+            Path::new("/dev/sdc"),
+            Some(Path::new("/var/lib/ceph/osd/ceph-1"))
+        ));
+        // errors=remount-ro is not the ro flag
+        assert!(!super::mount_is_read_only(
+            mounts,
+            Path::new("/dev/sda1"),
+            Some(Path::new("/"))
+        ));
+    }
+
     #[test]
     fn test_state_machine_replaced_disk() {
         TermLogger::new(log::LevelFilter::Debug, Config::default()).unwrap();
@@ -328,6 +435,10 @@ mod tests {
             state: super::State::Replaced,
             storage_detail_id: 1,
             operation_id: None,
+            surface_scan: None,
+            raid_location: None,
+            reformat_approval: None,
+            reformat_action_id: None,
         };
         // restore state?
         let mut s = super::StateMachine::new(d, None, true);
@@ -378,13 +489,20 @@ impl Transition for CheckForCorruption {
     fn transition(
         to_state: State,
         device: &mut BlockDevice,
-        _scsi_info: &Option<(ScsiInfo, Option<ScsiInfo>)>,
+        scsi_info: &Option<(ScsiInfo, Option<ScsiInfo>)>,
         simulate: bool,
     ) -> State {
         debug!(
             "thread {} running CheckForCorruption transition",
             process::id()
         );
+        // A clean surface scan of a disk that failed to write rules out the
+        // media so the filesystem gets repaired
+        if device.state == State::WriteFailed
+            && SurfaceScan::transition(to_state, device, scsi_info, simulate) == to_state
+        {
+            return to_state;
+        }
         if !simulate {
             match check_filesystem(&device.device.fs_type, &device.dev_path) {
                 Ok(fsck) => match fsck {
@@ -408,14 +526,26 @@ impl Transition for CheckForCorruption {
 
 impl Transition for CheckReadOnly {
     fn transition(
-        _to_state: State,
-        _device: &mut BlockDevice,
+        to_state: State,
+        device: &mut BlockDevice,
         _scsi_info: &Option<(ScsiInfo, Option<ScsiInfo>)>,
         _simulate: bool,
     ) -> State {
         debug!("thread {} running CheckReadOnly transition", process::id());
-        // Try again
-        State::Fail
+        let mount_point = device.mount_point.as_ref().map(|p| p.as_path());
+        match is_device_read_only(&device.dev_path, mount_point) {
+            Ok(true) => to_state,
+            // Writes failed for some other reason.  Let fsck have a look
+            Ok(false) => State::Fail,
+            Err(e) => {
+                error!(
+                    "Checking if {} is read only failed: {:?}",
+                    device.dev_path.display(),
+                    e
+                );
+                State::Fail
+            }
+        }
     }
 }
 
@@ -582,6 +712,19 @@ impl Transition for Reformat {
         _simulate: bool,
     ) -> State {
         debug!("thread {} running Reformat transition", process::id());
+        match device.reformat_approval {
+            Some(ApprovalStatus::Pending) => {
+                debug!(
+                    "thread {} {} needs approval before it's reformatted",
+                    process::id(),
+                    device.dev_path.display()
+                );
+                return State::WaitingForApproval;
+            }
+            // It'll be replaced instead
+            Some(ApprovalStatus::Denied) => return State::Fail,
+            _ => {}
+        }
         // Ensure we're not mounted before this it run
         if let Some(ref mnt) = device.mount_point {
             if let Err(e) = unmount_device(&mnt) {
@@ -620,16 +763,72 @@ impl Transition for Reformat {
 impl Transition for Remount {
     fn transition(
         to_state: State,
-        _device: &mut BlockDevice,
+        device: &mut BlockDevice,
         _scsi_info: &Option<(ScsiInfo, Option<ScsiInfo>)>,
-        _simulate: bool,
+        simulate: bool,
     ) -> State {
         debug!("thread {} running Remount transition", process::id());
+        let mount_point = match device.mount_point {
+            Some(ref mnt) => mnt.clone(),
+            None => match block_utils::get_mountpoint(&device.dev_path) {
+                Ok(Some(mnt)) => mnt,
+                Ok(None) => {
+                    error!(
+                        "{} is not mounted.  Unable to remount",
+                        device.dev_path.display()
+                    );
+                    return State::Fail;
+                }
+                Err(e) => {
+                    error!(
+                        "Unable to find mount point for {}: {:?}",
+                        device.dev_path.display(),
+                        e
+                    );
+                    return State::Fail;
+                }
+            },
+        };
+        if simulate {
+            return to_state;
+        }
+        // The kernel refuses a read/write remount while the block device
+        // itself is flagged read only
+        match block_device_read_only(&device.dev_path) {
+            Ok(true) => {
+                if let Err(e) = set_block_device_rw(&device.dev_path) {
+                    error!("Unable to set {} rw: {}", device.dev_path.display(), e);
+                    return State::Fail;
+                }
+            }
+            Ok(false) => {}
+            Err(e) => {
+                warn!(
+                    "Unable to read ro attribute for {}: {:?}",
+                    device.dev_path.display(),
+                    e
+                );
+            }
+        };
         // TODO: Investigate using libmount here
-        match Command::new("mount").args(&["-o", "remount"]).output() {
+        let mnt = mount_point.to_string_lossy().into_owned();
+        match Command::new("mount")
+            .args(&["-o", "remount,rw", &mnt])
+            .output()
+        {
             Ok(output) => {
                 if output.status.success() {
-                    to_state
+                    // Make sure the remount actually fixed the problem
+                    match check_writable(&mount_point) {
+                        Ok(_) => {
+                            device.mount_point = Some(mount_point);
+                            to_state
+                        }
+                        Err(e) => {
+                            error!("{} still not writable after remount: {:?}", mnt, e);
+                            State::Fail
+                        }
+                    }
                 } else {
                     let stderr = String::from_utf8_lossy(&output.stderr);
                     error!("Remount failed: {}", stderr);
@@ -691,7 +890,20 @@ impl Transition for Scan {
                     State::Fail
                 }
             },
-            (_, Vendor::Hp) => {
+            (_, Vendor::Hp) | (_, Vendor::Lsi) => {
+                // Ask the controller about the physical drives behind this
+                // logical drive first.  The scsi state only says whether the
+                // logical drive is still up.
+                match raid_drive_needs_replacement(&device.dev_path, &raid_backed.1) {
+                    Ok((true, location)) => {
+                        device.raid_location = location;
+                        return State::WaitingForReplacement;
+                    }
+                    Ok((false, _)) => {}
+                    Err(e) => {
+                        error!("Unable to get {:?} raid config: {:?}", raid_backed.1, e);
+                    }
+                };
                 // is_raid_backed unpacks the Option so this should be safe
                 match &scsi_info
                     .as_ref()
@@ -722,6 +934,116 @@ impl Transition for Scan {
     }
 }
 
+// Read only surface scan to confirm media damage on a suspect disk.  The
+// scan runs in the background so this only starts it or reads its verdict
+impl Transition for SurfaceScan {
+    fn transition(
+        to_state: State,
+        device: &mut BlockDevice,
+        _scsi_info: &Option<(ScsiInfo, Option<ScsiInfo>)>,
+        simulate: bool,
+    ) -> State {
+        debug!("thread {} running SurfaceScan transition", process::id());
+        let config = match device.surface_scan {
+            Some(ref c) => c.clone(),
+            None => {
+                debug!("thread {} Surface scanning is disabled", process::id());
+                return State::Fail;
+            }
+        };
+        // A failed write is suspect enough.  Otherwise only scan drives that
+        // failed the SMART check and have started remapping sectors
+        if device.state != State::WriteFailed {
+            match smart_bad_sectors(&device.dev_path) {
+                Ok(0) => return State::Fail,
+                Ok(bad) => debug!(
+                    "thread {} {} has {} bad sectors",
+                    process::id(),
+                    device.dev_path.display(),
+                    bad
+                ),
+                Err(e) => {
+                    debug!(
+                        "thread {} Unable to read SMART bad sectors for {}: {:?}",
+                        process::id(),
+                        device.dev_path.display(),
+                        e
+                    );
+                    return State::Fail;
+                }
+            };
+        }
+        let mounted = device.mount_point.is_some() || is_device_mounted(&device.dev_path);
+        if mounted
+            && !config
+                .allowed_mounted_devices
+                .iter()
+                .any(|d| d == &device.dev_path)
+        {
+            debug!(
+                "thread {} {} is mounted and not in allowed_mounted_devices. Skipping surface scan",
+                process::id(),
+                device.dev_path.display()
+            );
+            return State::Fail;
+        }
+        match scan_status(&device.dev_path, &config) {
+            Ok(Some(ScanStatus::Running { pid })) => {
+                debug!(
+                    "thread {} Surface scan of {} is still running in pid {}",
+                    process::id(),
+                    device.dev_path.display(),
+                    pid
+                );
+                State::Fail
+            }
+            Ok(Some(ScanStatus::Finished {
+                damaged,
+                unreadable_sectors,
+                slow_reads,
+                ..
+            })) => {
+                if unreadable_sectors > 0 || slow_reads > 0 {
+                    warn!(
+                        "{} surface scan found {} unreadable sectors and {} slow reads",
+                        device.dev_path.display(),
+                        unreadable_sectors,
+                        slow_reads
+                    );
+                }
+                match (damaged, to_state) {
+                    (true, State::WornOut) => to_state,
+                    // Media looks fine so the failed write is down to the
+                    // filesystem
+                    (false, State::Corrupt) => to_state,
+                    // Let the other checks decide
+                    _ => State::Fail,
+                }
+            }
+            Ok(None) => {
+                if !simulate {
+                    if let Err(e) = start_background_scan(&device.dev_path, &config) {
+                        error!(
+                            "Unable to start surface scan of {}: {:?}",
+                            device.dev_path.display(),
+                            e
+                        );
+                    }
+                }
+                State::Fail
+            }
+            Err(e) => {
+                error!(
+                    "Surface scan status of {} unavailable: {:?}",
+                    device.dev_path.display(),
+                    e
+                );
+                State::Fail
+            }
+        }
+    }
+}
+
 pub struct StateMachine {
     // A record of the transitions so they can be written as a dot graph
     // for later visual debugging
@@ -729,6 +1051,9 @@ pub struct StateMachine {
     // Mapping of valid From -> To transitions
     graph: GraphMap<State, TransitionFn, Directed>,
     pub block_device: BlockDevice,
+    // Every state the disk has been in during this run, starting with the
+    // one it was loaded in
+    history: Vec<State>,
     // optional info of this device and optional scsi host information
     // used to determine whether this device is behind a raid controller
     pub scsi_info: Option<(ScsiInfo, Option<ScsiInfo>)>,
@@ -750,6 +1075,7 @@ impl StateMachine {
         StateMachine {
             dot_graph: Vec::new(),
             graph: GraphMap::new(),
+            history: vec![block_device.state],
             block_device,
             scsi_info,
             simulate,
@@ -808,11 +1134,20 @@ impl StateMachine {
                             process::id()
                         );
                         self.block_device.state = state;
+                        self.history.push(state);
+                        break 'outer;
+                    }
+                    // Picked up again on a later run once someone answers
+                    State::WaitingForApproval => {
+                        debug!("thread {} state==State::WaitingForApproval", process::id());
+                        self.block_device.state = state;
+                        self.history.push(state);
                         break 'outer;
                     }
                     State::Good => {
                         debug!("thread {} state==State::Good", process::id());
                         self.block_device.state = state;
+                        self.history.push(state);
                         break 'outer;
                     }
                     _ => {}
@@ -822,6 +1157,7 @@ impl StateMachine {
                 if state == e.1 {
                     debug!("thread {} state==e.1 {}=={}", process::id(), state, e.1);
                     self.block_device.state = state;
+                    self.history.push(state);
                     break;
                 }
             }
@@ -841,6 +1177,11 @@ impl StateMachine {
         }
     }
 
+    /// The states the disk went through on its last run
+    pub fn state_history(&self) -> &[State] {
+        &self.history
+    }
+
     #[allow(dead_code)]
     fn print_graph(&self) {
         // FIXME: Too simple.  Doesn't label the transitions
@@ -869,6 +1210,13 @@ impl StateMachine {
         // states are ordered from most to least ideal outcome.
         self.add_transition(State::Unscanned, State::Scanned, Scan::transition, "Scan");
         self.add_transition(State::Unscanned, State::Fail, Scan::transition, "Scan");
+        // SMART is borderline and a surface scan found damaged media
+        self.add_transition(
+            State::Unscanned,
+            State::WornOut,
+            SurfaceScan::transition,
+            "SurfaceScan",
+        );
         self.add_transition(
             State::NotMounted,
             State::Mounted,
@@ -908,8 +1256,8 @@ impl StateMachine {
         self.add_transition(
             State::ReadOnly,
             State::MountFailed,
-            Remount::transition,
-            "Remount",
+            NoOp::transition,
+            "NoOp",
         );
 
         self.add_transition(
@@ -938,6 +1286,19 @@ impl StateMachine {
             "NoOp",
         );
 
+        self.add_transition(
+            State::WaitingForApproval,
+            State::Reformatted,
+            Reformat::transition,
+            "Reformat",
+        );
+        self.add_transition(
+            State::WaitingForApproval,
+            State::ReformatFailed,
+            NoOp::transition,
+            "NoOp",
+        );
+
         self.add_transition(
             State::ReformatFailed,
             State::WaitingForReplacement,
@@ -974,7 +1335,15 @@ impl StateMachine {
             CheckReadOnly::transition,
             "CheckReadOnly",
         );
-        // Fsck can either conclude here that everything is fine or the filesystem is corrupt
+        // Unreadable sectors mean the disk is worn out rather than corrupt
+        self.add_transition(
+            State::WriteFailed,
+            State::WornOut,
+            SurfaceScan::transition,
+            "SurfaceScan",
+        );
+        // Fsck can either conclude here that everything is fine or the filesystem is corrupt.
+        // A clean surface scan sends it here too
         self.add_transition(
             State::WriteFailed,
             State::Corrupt,
@@ -1005,6 +1374,8 @@ pub enum State {
     Replaced,
     Scanned,
     Unscanned,
+    // A reformat is waiting for someone to approve it
+    WaitingForApproval,
     // The disk could not be repaired and needs to be replaced
     WaitingForReplacement,
     WornOut,
@@ -1030,6 +1401,7 @@ impl FromStr for State {
             "replaced" => Ok(State::Replaced),
             "scanned" => Ok(State::Scanned),
             "unscanned" => Ok(State::Unscanned),
+            "waiting_for_approval" => Ok(State::WaitingForApproval),
             "waiting_for_replacement" => Ok(State::WaitingForReplacement),
             "worn_out" => Ok(State::WornOut),
             _ => Err(BynarError::new(format!("Unknown state: {}", s))),
@@ -1054,6 +1426,7 @@ impl fmt::Display for State {
             State::Replaced => write!(f, "replaced"),
             State::Scanned => write!(f, "scanned"),
             State::Unscanned => write!(f, "unscanned"),
+            State::WaitingForApproval => write!(f, "waiting_for_approval"),
             State::WaitingForReplacement => write!(f, "waiting_for_replacement"),
             State::WriteFailed => write!(f, "write_failed"),
             State::WornOut => write!(f, "worn_out"),
@@ -1097,6 +1470,9 @@ struct Reformat;
 
 #[derive(Debug)]
 struct Scan;
+
+#[derive(Debug)]
+struct SurfaceScan;
 // Transitions
 
 enum Fsck {
@@ -1138,6 +1514,10 @@ fn filter_disks(devices: &[PathBuf], storage_detail_id: u32) -> BynarResult<Vec<
                 state: State::Unscanned,
                 storage_detail_id,
                 operation_id: None,
+                surface_scan: None,
+                raid_location: None,
+                reformat_approval: None,
+                reformat_action_id: None,
             }
         })
         .collect();
@@ -1231,6 +1611,10 @@ fn add_previous_devices(
                     state: State::WaitingForReplacement,
                     storage_detail_id: host_mapping.storage_detail_id,
                     operation_id: None,
+                    surface_scan: None,
+                    raid_location: None,
+                    reformat_approval: None,
+                    reformat_action_id: None,
                 };
                 save_state(pool, &b, State::WaitingForReplacement)?;
                 devices.push(b);
@@ -1248,6 +1632,8 @@ pub fn check_all_disks(
     host_info: &Host,
     pool: &Pool<ConnectionManager>,
     host_mapping: &HostDetailsMapping,
+    surface_scan: Option<&SurfaceScanConfig>,
+    approval: Option<&ApprovalConfig>,
 ) -> BynarResult<Vec<BynarResult<StateMachine>>> {
     // Udev will only show the disks that are currently attached to the tree
     // It will fail to show disks that have died and disconnected but are still
@@ -1284,6 +1670,7 @@ pub fn check_all_disks(
 
         // store the operation_id in BlockDevice struct
         dev.operation_id = op_info.operation_id;
+        dev.surface_scan = surface_scan.cloned();
     }
     //TODO: Add nvme devices to block-utils
 
@@ -1313,15 +1700,47 @@ pub fn check_all_disks(
         let mut s = StateMachine::new(device, scsi_info, false);
         s.setup_state_machine();
         s.block_device.state = get_state(pool, &s.block_device)?;
+        if approval.map_or(false, |a| a.requires(Action::ReformatDisk)) {
+            // Only a disk that was already waiting can have been asked
+            // about.  One that hasn't been asked yet is pending
+            let action = if s.block_device.state == State::WaitingForApproval {
+                reformat_action(pool, &s.block_device)?
+            } else {
+                None
+            };
+            s.block_device.reformat_approval = Some(
+                action
+                    .as_ref()
+                    .map_or(ApprovalStatus::Pending, |a| a.status),
+            );
+            s.block_device.reformat_action_id = action.map(|a| a.action_id);
+        }
         s.run();
         // Save the state to database after state machine finishes its run
         save_state(pool, &s.block_device, s.block_device.state)?;
+        if let Some(ref location) = s.block_device.raid_location {
+            save_raid_location(pool, &s.block_device, &location.to_string())?;
+        }
         disk_states.push(Ok(s));
     }
 
     Ok(disk_states)
 }
 
+// The device's outstanding reformat request, if there is one
+fn reformat_action(
+    pool: &Pool<ConnectionManager>,
+    device: &BlockDevice,
+) -> BynarResult<Option<PendingAction>> {
+    let conn = get_connection_from_pool(pool)?;
+    approval::get_action(
+        &conn,
+        device.storage_detail_id,
+        &device.dev_path.display().to_string(),
+        Action::ReformatDisk,
+    )
+}
+
 #[cfg_attr(test, mockable)]
 fn check_filesystem(filesystem_type: &FilesystemType, device: &Path) -> BynarResult<Fsck> {
     match *filesystem_type {
@@ -1375,6 +1794,73 @@ fn check_writable(path: &Path) -> BynarResult<()> {
     Ok(())
 }
 
+// Checks both the mount flags in /proc/mounts and the block device ro
+// attribute to decide if a device is read only
+#[cfg_attr(test, mockable)]
+fn is_device_read_only(dev_path: &Path, mount_point: Option<&Path>) -> BynarResult<bool> {
+    debug!(
+        "thread {} Checking if {} is read only",
+        process::id(),
+        dev_path.display()
+    );
+    let mounts = read_to_string("/proc/mounts")?;
+    if mount_is_read_only(&mounts, dev_path, mount_point) {
+        return Ok(true);
+    }
+    block_device_read_only(dev_path)
+}
+
+// Returns true if /proc/mounts lists the device or mount point with the ro flag
+fn mount_is_read_only(mounts: &str, dev_path: &Path, mount_point: Option<&Path>) -> bool {
+    for line in mounts.lines() {
+        // device mount_point fs_type options dump pass
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() < 4 {
+            continue;
+        }
+        let device_match = Path::new(parts[0]) == dev_path;
+        let mount_match = match mount_point {
+            Some(mnt) => Path::new(parts[1]) == mnt,
+            None => false,
+        };
+        if (device_match || mount_match) && parts[3].split(',').any(|o| o == "ro") {
+            return true;
+        }
+    }
+    false
+}
+
+// Read /sys/class/block/<name>/ro.  The kernel sets this when the device
+// is write protected or has been forced read only after errors
+fn block_device_read_only(dev_path: &Path) -> BynarResult<bool> {
+    let name = dev_path.file_name().ok_or_else(|| {
+        BynarError::new(format!("device {} missing filename", dev_path.display()))
+    })?;
+    let ro_path = Path::new("/sys/class/block").join(name).join("ro");
+    if !ro_path.exists() {
+        return Ok(false);
+    }
+    let buff = read_to_string(&ro_path)?;
+    Ok(buff.trim() == "1")
+}
+
+fn set_block_device_rw(dev_path: &Path) -> BynarResult<()> {
+    debug!(
+        "thread {} Running blockdev --setrw {}",
+        process::id(),
+        dev_path.display()
+    );
+    let output = Command::new("blockdev")
+        .args(&["--setrw", &dev_path.to_string_lossy()])
+        .output()?;
+    if !output.status.success() {
+        return Err(BynarError::new(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+    Ok(())
+}
+
 // TODO: How do you tell if an lvm device is functioning properly?
 fn check_lvm(device: &Path) -> BynarResult<Fsck> {
     // lv display should show whether lvm can even access the device
@@ -1503,6 +1989,14 @@ fn run_smart_checks(device: &Path) -> BynarResult<bool> {
     Ok(status)
 }
 
+// Reallocated and pending sectors.  A drive can still pass SMART with these
+#[cfg_attr(test, mockable)]
+fn smart_bad_sectors(device: &Path) -> BynarResult<u64> {
+    let mut smart = libatasmart::Disk::new(device)?;
+    let bad = smart.get_bad_sectors()?;
+    Ok(bad)
+}
+
 #[cfg_attr(test, mockable)]
 fn format_device(device: &Device) -> BynarResult<()> {
     let tmp = format!("/dev/{}", device.name);
@@ -1629,6 +2123,84 @@ fn is_disk_blank(dev: &Path) -> BynarResult<bool> {
     Ok(true)
 }
 
+// Check the physical drives behind a raid backed device.  Any drive the
+// controller has failed or that is predicting failure is logged with
+// its location so the technician can find it.  The controller location of
+// the first failed drive is also returned so the controller can be
+// reconfigured once it's replaced.
+fn raid_drive_needs_replacement(
+    dev_path: &Path,
+    vendor: &Vendor,
+) -> BynarResult<(bool, Option<RaidLocation>)> {
+    let mut failed = false;
+    let mut location: Option<RaidLocation> = None;
+    match vendor {
+        Vendor::Hp => {
+            let controllers = hp::get_config()?;
+            for ctrl in &controllers {
+                for drive in hp::physical_drives_for_device(slice::from_ref(ctrl), dev_path)
+                    .iter()
+                    .filter(|d| d.needs_replacement())
+                {
+                    error!(
+                        "{} physical drive {} (port {} box {} bay {} serial {:?}) status: {:?}",
+                        dev_path.display(),
+                        drive.id,
+                        drive.port,
+                        drive.box_number,
+                        drive.bay,
+                        drive.serial_number,
+                        drive.status
+                    );
+                    if !failed {
+                        location = ctrl.slot.as_ref().map(|slot| RaidLocation::Hp {
+                            slot: slot.clone(),
+                            drive: drive.id.clone(),
+                        });
+                    }
+                    failed = true;
+                }
+            }
+        }
+        Vendor::Lsi => {
+            let controllers = lsi::get_config()?;
+            for ctrl in &controllers {
+                for drive in lsi::physical_drives_for_device(slice::from_ref(ctrl), dev_path) {
+                    if drive.media_error_count > 0 {
+                        warn!(
+                            "{} physical drive {} has {} media errors",
+                            dev_path.display(),
+                            drive.eid_slot(),
+                            drive.media_error_count
+                        );
+                    }
+                    if drive.needs_replacement() {
+                        error!(
+                            "{} physical drive {} (serial {:?}) state: {:?} predictive failures: {} smart alert: {}",
+                            dev_path.display(),
+                            drive.eid_slot(),
+                            drive.serial_number,
+                            drive.state,
+                            drive.predictive_failure_count,
+                            drive.smart_alert
+                        );
+                        if !failed {
+                            location = Some(RaidLocation::Lsi {
+                                controller: ctrl.id,
+                                enclosure: drive.enclosure_id,
+                                slot: drive.slot,
+                            });
+                        }
+                        failed = true;
+                    }
+                }
+            }
+        }
+        _ => {}
+    };
+    Ok((failed, location))
+}
+
 fn is_raid_backed(scsi_info: &Option<(ScsiInfo, Option<ScsiInfo>)>) -> (bool, Vendor) {
     if let Some(scsi_info) = scsi_info {
         if let Some(ref dev_host) = scsi_info.1 {
@@ -1641,6 +2213,10 @@ fn is_raid_backed(scsi_info: &Option<(ScsiInfo, Option<ScsiInfo>)>) -> (bool, Ve
                         debug!("thread {} HP raid device found", process::id());
                         return (true, Vendor::Hp);
                     }
+                    Vendor::Lsi => {
+                        debug!("thread {} LSI raid device found", process::id());
+                        return (true, Vendor::Lsi);
+                    }
                     _ => {
                         // Don't know how to access these yet.
                         warn!(